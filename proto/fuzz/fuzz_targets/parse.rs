@@ -0,0 +1,54 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use trojan_proto::{Progress, Request, RequestParser, UdpHeaderParser, UdpPacket, MAX_REQUEST_HEADER_LEN, MAX_UDP_HEADER_LEN};
+
+// the first byte picks the fragment size, the streaming parsers must agree with the one-shot ones
+fuzz_target!(|data: &[u8]| {
+    if data.is_empty() {
+        return;
+    }
+    let chunk_size = data[0] as usize % 16 + 1;
+    let data = &data[1..];
+
+    let expected = Request::parse(data);
+    let mut parser = RequestParser::new();
+    let mut offset = 0;
+    let mut result = Err(trojan_proto::Error::Incomplete);
+    for chunk in data.chunks(chunk_size) {
+        match parser.feed(chunk) {
+            Ok(Progress::Pending) => offset += chunk.len(),
+            Ok(Progress::Done(header, size)) => {
+                assert!(offset + size <= MAX_REQUEST_HEADER_LEN);
+                result = Ok((header, offset + size));
+                break;
+            }
+            Err(err) => {
+                assert!(offset + chunk.len() <= MAX_REQUEST_HEADER_LEN + chunk_size);
+                result = Err(err);
+                break;
+            }
+        }
+    }
+    match (expected, result) {
+        (Ok(request), Ok((header, size))) => {
+            assert_eq!(request.password, header.password.as_bytes());
+            assert_eq!(request.command, header.command);
+            assert_eq!(request.address, header.address);
+            assert_eq!(request.payload, &data[size..]);
+        }
+        (Err(expected), Err(err)) => assert_eq!(expected, err),
+        (expected, result) => panic!("one-shot {:?} but streaming {:?}", expected, result),
+    }
+
+    let mut buffer = data;
+    while let Ok((packet, size)) = UdpPacket::parse(buffer) {
+        assert!(size >= packet.payload.len() && size - packet.payload.len() <= MAX_UDP_HEADER_LEN);
+        buffer = &buffer[size..];
+    }
+    let mut parser = UdpHeaderParser::new();
+    for chunk in data.chunks(chunk_size) {
+        if parser.feed(chunk).is_err() {
+            break;
+        }
+    }
+});
@@ -0,0 +1,84 @@
+/// The length of a frame header, the data length and the padding length, both big endian.
+pub const FRAME_HEADER_LEN: usize = 4;
+/// The most data a single frame carries.
+pub const MAX_FRAME_DATA: usize = 0xffff;
+
+/// Appends a frame of `data` followed by `padding` zero bytes.
+///
+/// # Panics
+///
+/// Panics if the data or the padding is longer than [`MAX_FRAME_DATA`].
+pub fn write_frame<B: Extend<u8>>(buffer: &mut B, data: &[u8], padding: usize) {
+    assert!(data.len() <= MAX_FRAME_DATA && padding <= MAX_FRAME_DATA, "frame longer than 65535 bytes");
+    buffer.extend((data.len() as u16).to_be_bytes().iter().cloned());
+    buffer.extend((padding as u16).to_be_bytes().iter().cloned());
+    buffer.extend(data.iter().cloned());
+    buffer.extend((0..padding).map(|_| 0));
+}
+
+/// Appends a keepalive, a frame without data or padding, which the server answers with the same to keep an idle
+/// connection open along the path. A frame of padding alone is not a keepalive.
+pub fn write_keepalive<B: Extend<u8>>(buffer: &mut B) {
+    write_frame(buffer, &[], 0);
+}
+
+/// Takes the data out of the frames of a `CONNECT_PADDED` connection as it arrives, dropping the padding.
+/// Any lengths are valid, so feeding never fails.
+pub struct FrameParser {
+    header: Vec<u8>,
+    data_left: usize,
+    padding_left: usize,
+    keepalives: usize,
+}
+
+impl FrameParser {
+    pub fn new() -> FrameParser {
+        FrameParser {
+            header: Vec::with_capacity(FRAME_HEADER_LEN),
+            data_left: 0,
+            padding_left: 0,
+            keepalives: 0,
+        }
+    }
+
+    /// Appends the data of the frames in `input` to `output`, keeping what it needs of a frame split across feeds.
+    pub fn feed(&mut self, mut input: &[u8], output: &mut Vec<u8>) {
+        while !input.is_empty() {
+            if self.data_left > 0 {
+                let size = self.data_left.min(input.len());
+                output.extend_from_slice(&input[..size]);
+                self.data_left -= size;
+                input = &input[size..];
+            } else if self.padding_left > 0 {
+                let size = self.padding_left.min(input.len());
+                self.padding_left -= size;
+                input = &input[size..];
+            } else {
+                let size = (FRAME_HEADER_LEN - self.header.len()).min(input.len());
+                self.header.extend_from_slice(&input[..size]);
+                input = &input[size..];
+                if self.header.len() == FRAME_HEADER_LEN {
+                    self.data_left = (self.header[0] as usize) << 8 | self.header[1] as usize;
+                    self.padding_left = (self.header[2] as usize) << 8 | self.header[3] as usize;
+                    if self.data_left == 0 && self.padding_left == 0 {
+                        self.keepalives += 1;
+                    }
+                    self.header.clear();
+                }
+            }
+        }
+    }
+}
+
+impl FrameParser {
+    /// The keepalives fed since the last call.
+    pub fn take_keepalives(&mut self) -> usize {
+        std::mem::replace(&mut self.keepalives, 0)
+    }
+}
+
+impl Default for FrameParser {
+    fn default() -> Self {
+        FrameParser::new()
+    }
+}
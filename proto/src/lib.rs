@@ -0,0 +1,634 @@
+//! Parsing and serialization of the [trojan](https://trojan-gfw.github.io/trojan/protocol) protocol.
+//!
+//! A trojan request is sent by the client first thing after the TLS handshake:
+//!
+//! ```text
+//! hex(sha224(password)) CRLF command address CRLF payload
+//! ```
+//!
+//! where the address is a socks5 one, an address type byte followed by an ipv4 address, a length prefixed domain
+//! or an ipv6 address, and a big endian port. Once a `UDP_ASSOCIATE` request is accepted, both ends exchange
+//! udp packets framed as:
+//!
+//! ```text
+//! address length CRLF payload
+//! ```
+//!
+//! A `CONNECT_PADDED` request relays a tcp connection like `CONNECT`, but the payload and all the data after it,
+//! in both directions, are framed so that padding can be added to blur the lengths of the first packets:
+//!
+//! ```text
+//! data_length padding_length data padding
+//! ```
+//!
+//! A frame without data or padding, written with [`write_keepalive`], is a keepalive of an idle connection, which the
+//! server answers with the same.
+//!
+//! A `UDP_OVER_TCP` request relays udp packets like `UDP_ASSOCIATE`, but the stream is kept open by keepalives
+//! instead of being closed once udp is idle. A keepalive is a packet to `0.0.0.0:0` without payload, written with
+//! [`UdpPacket::write_keepalive`], which the server answers with the same.
+//!
+//! Parsing never panics, whatever the input. [`Request::parse`] and [`UdpPacket::parse`] work on borrowed buffers,
+//! a buffer holding only a part of a request or packet gives [`Error::Incomplete`], so that the caller can wait for
+//! more data. [`RequestParser`] and [`UdpHeaderParser`] are fed with data as it arrives instead, keeping what they
+//! need of a header split across reads.
+//!
+//! The [`replay`] module records connections as traces and replays them through these parsers, to keep protocol
+//! problems seen in the wild as tests.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+pub use frame::{FRAME_HEADER_LEN, FrameParser, MAX_FRAME_DATA, write_frame, write_keepalive};
+pub use parser::{MAX_ADDRESS_LEN, MAX_REQUEST_HEADER_LEN, MAX_UDP_HEADER_LEN, Progress, RequestHeader, RequestParser, UdpHeader, UdpHeaderParser};
+
+mod frame;
+mod parser;
+pub mod replay;
+
+/// The command of a request relaying a tcp connection.
+pub const CONNECT: u8 = 0x01;
+/// The command of a request relaying udp packets.
+pub const UDP_ASSOCIATE: u8 = 0x03;
+/// The command of a request relaying a tcp connection with framed and padded data, servers not knowing it reject it.
+pub const CONNECT_PADDED: u8 = 0x11;
+/// The command of a request relaying udp packets with keepalives, servers not knowing it reject it.
+pub const UDP_OVER_TCP: u8 = 0x12;
+/// The largest udp payload, as the length field is 16 bits.
+pub const MAX_UDP_SIZE: usize = 65535;
+/// The length of the hex encoded sha224 digest of the password at the start of a request.
+pub const PASSWORD_LEN: usize = 56;
+
+const IPV4: u8 = 0x01;
+const DOMAIN: u8 = 0x03;
+const IPV6: u8 = 0x04;
+const CRLF: &[u8] = b"\r\n";
+
+/// Errors of parsing requests and udp packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The buffer ends before the request or packet does.
+    Incomplete,
+    /// A CRLF is expected after the password, the address or the udp length.
+    MissingCrlf,
+    /// The command is not `CONNECT`, `UDP_ASSOCIATE`, `CONNECT_PADDED` or `UDP_OVER_TCP`.
+    InvalidCommand(u8),
+    /// The address type is not ipv4, domain or ipv6.
+    InvalidAddressType(u8),
+    /// The password is not hex encoded.
+    InvalidPassword,
+    /// The domain of the address has a zero length.
+    EmptyDomain,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Incomplete => write!(f, "incomplete data"),
+            Error::MissingCrlf => write!(f, "expected CRLF"),
+            Error::InvalidCommand(command) => write!(f, "invalid command:{}", command),
+            Error::InvalidAddressType(atyp) => write!(f, "invalid address type:{}", atyp),
+            Error::InvalidPassword => write!(f, "invalid password"),
+            Error::EmptyDomain => write!(f, "empty domain"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// The destination of a request or a udp packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Socket(SocketAddr),
+    Domain(String, u16),
+}
+
+impl Address {
+    pub fn port(&self) -> u16 {
+        match self {
+            Address::Socket(addr) => addr.port(),
+            Address::Domain(_, port) => *port,
+        }
+    }
+
+    /// Parses an address starting with its type byte, returning it with the number of bytes it takes.
+    /// Domains which are not valid utf-8 are converted lossily.
+    pub fn parse(buffer: &[u8]) -> Result<(Address, usize), Error> {
+        let atyp = *buffer.first().ok_or(Error::Incomplete)?;
+        let buffer = &buffer[1..];
+        match atyp {
+            IPV4 => {
+                if buffer.len() < 6 {
+                    return Err(Error::Incomplete);
+                }
+                let ip = Ipv4Addr::new(buffer[0], buffer[1], buffer[2], buffer[3]);
+                Ok((Address::Socket(SocketAddr::new(IpAddr::V4(ip), to_u16(&buffer[4..]))), 7))
+            }
+            DOMAIN => {
+                let length = *buffer.first().ok_or(Error::Incomplete)? as usize;
+                if length == 0 {
+                    return Err(Error::EmptyDomain);
+                }
+                if buffer.len() < length + 3 {
+                    return Err(Error::Incomplete);
+                }
+                let domain = String::from_utf8_lossy(&buffer[1..length + 1]).into_owned();
+                Ok((Address::Domain(domain, to_u16(&buffer[length + 1..])), length + 4))
+            }
+            IPV6 => {
+                if buffer.len() < 18 {
+                    return Err(Error::Incomplete);
+                }
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&buffer[..16]);
+                let ip = Ipv6Addr::from(octets);
+                Ok((Address::Socket(SocketAddr::new(IpAddr::V6(ip), to_u16(&buffer[16..]))), 19))
+            }
+            _ => Err(Error::InvalidAddressType(atyp)),
+        }
+    }
+
+    /// Appends the address with its type byte.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the domain is longer than 255 bytes.
+    pub fn write<B: Extend<u8>>(&self, buffer: &mut B) {
+        match self {
+            Address::Socket(SocketAddr::V4(addr)) => {
+                buffer.extend(Some(IPV4));
+                buffer.extend(addr.ip().octets().iter().cloned());
+            }
+            Address::Socket(SocketAddr::V6(addr)) => {
+                buffer.extend(Some(IPV6));
+                buffer.extend(addr.ip().octets().iter().cloned());
+            }
+            Address::Domain(domain, _) => {
+                assert!(domain.len() <= 255, "domain longer than 255 bytes");
+                buffer.extend(Some(DOMAIN));
+                buffer.extend(Some(domain.len() as u8));
+                buffer.extend(domain.bytes());
+            }
+        }
+        buffer.extend(self.port().to_be_bytes().iter().cloned());
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Address::Socket(addr) => write!(f, "{}", addr),
+            Address::Domain(domain, port) => write!(f, "{}:{}", domain, port),
+        }
+    }
+}
+
+/// A trojan request, the first data sent by a client.
+#[derive(Debug, PartialEq)]
+pub struct Request<'a> {
+    /// The hex encoded sha224 digest of the password, which is checked by the caller.
+    pub password: &'a [u8],
+    pub command: u8,
+    pub address: Address,
+    /// The data following the request, to be relayed to the address.
+    pub payload: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    pub fn parse(buffer: &'a [u8]) -> Result<Request<'a>, Error> {
+        match RequestParser::new().feed(buffer)? {
+            Progress::Done(header, size) => Ok(Request {
+                password: &buffer[..PASSWORD_LEN],
+                command: header.command,
+                address: header.address,
+                payload: &buffer[size..],
+            }),
+            Progress::Pending => Err(Error::Incomplete),
+        }
+    }
+
+    /// Appends a request without payload, password being the hex encoded sha224 digest.
+    pub fn write<B: Extend<u8>>(buffer: &mut B, password: &str, command: u8, address: &Address) {
+        buffer.extend(password.bytes());
+        buffer.extend(CRLF.iter().cloned());
+        buffer.extend(Some(command));
+        address.write(buffer);
+        buffer.extend(CRLF.iter().cloned());
+    }
+}
+
+/// A udp packet relayed after a `UDP_ASSOCIATE` request.
+#[derive(Debug, PartialEq)]
+pub struct UdpPacket<'a> {
+    pub address: Address,
+    pub payload: &'a [u8],
+}
+
+impl<'a> UdpPacket<'a> {
+    /// Parses the packet at the start of the buffer, returning it with the number of bytes it takes,
+    /// so that the next one starts right after.
+    pub fn parse(buffer: &'a [u8]) -> Result<(UdpPacket<'a>, usize), Error> {
+        match UdpHeaderParser::new().feed(buffer)? {
+            Progress::Done(header, size) => {
+                let length = header.length as usize;
+                if buffer.len() < size + length {
+                    return Err(Error::Incomplete);
+                }
+                Ok((UdpPacket {
+                    address: header.address,
+                    payload: &buffer[size..size + length],
+                }, size + length))
+            }
+            Progress::Pending => Err(Error::Incomplete),
+        }
+    }
+
+    /// A keepalive of a `UDP_OVER_TCP` stream, relayed nowhere.
+    pub fn is_keepalive(&self) -> bool {
+        self.payload.is_empty() && self.address == Address::Socket(keepalive_address())
+    }
+
+    /// Appends a keepalive of a `UDP_OVER_TCP` stream.
+    pub fn write_keepalive<B: Extend<u8>>(buffer: &mut B) {
+        UdpPacket::write_header(buffer, &Address::Socket(keepalive_address()), 0);
+    }
+
+    /// Appends the header of a packet, to be followed by `length` bytes of payload.
+    pub fn write_header<B: Extend<u8>>(buffer: &mut B, address: &Address, length: u16) {
+        address.write(buffer);
+        buffer.extend(length.to_be_bytes().iter().cloned());
+        buffer.extend(CRLF.iter().cloned());
+    }
+}
+
+fn keepalive_address() -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)
+}
+
+fn to_u16(buffer: &[u8]) -> u16 {
+    (buffer[0] as u16) << 8 | buffer[1] as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const PASSWORD: &str = "a0c2f5e1c5b6b1a0d6c2e3b1c8a7d6f5e4c3b2a1a0b9c8d7e6f5a4b3";
+
+    fn request(address: &Address, payload: &[u8]) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        Request::write(&mut buffer, PASSWORD, CONNECT, address);
+        buffer.extend_from_slice(payload);
+        buffer
+    }
+
+    #[test]
+    fn request_round_trip() {
+        let addresses = vec![
+            Address::Socket("1.2.3.4:443".parse().unwrap()),
+            Address::Socket("[2001:db8::1]:8443".parse().unwrap()),
+            Address::Domain("example.com".to_string(), 80),
+        ];
+        for address in addresses {
+            let buffer = request(&address, b"hello");
+            let request = Request::parse(&buffer).unwrap();
+            assert_eq!(request.password, PASSWORD.as_bytes());
+            assert_eq!(request.command, CONNECT);
+            assert_eq!(request.address, address);
+            assert_eq!(request.payload, b"hello");
+        }
+    }
+
+    #[test]
+    fn request_incomplete() {
+        let buffer = request(&Address::Domain("example.com".to_string(), 80), b"");
+        for len in 0..buffer.len() {
+            assert_eq!(Request::parse(&buffer[..len]), Err(Error::Incomplete), "length {}", len);
+        }
+        assert!(Request::parse(&buffer).is_ok());
+    }
+
+    #[test]
+    fn request_invalid() {
+        let mut buffer = request(&Address::Socket("1.2.3.4:443".parse().unwrap()), b"");
+        buffer[PASSWORD_LEN] = b'x';
+        assert_eq!(Request::parse(&buffer), Err(Error::MissingCrlf));
+
+        let mut buffer = request(&Address::Socket("1.2.3.4:443".parse().unwrap()), b"");
+        buffer[PASSWORD_LEN + 2] = 0x02;
+        assert_eq!(Request::parse(&buffer), Err(Error::InvalidCommand(0x02)));
+
+        let mut buffer = request(&Address::Socket("1.2.3.4:443".parse().unwrap()), b"");
+        buffer[PASSWORD_LEN + 3] = 0x05;
+        assert_eq!(Request::parse(&buffer), Err(Error::InvalidAddressType(0x05)));
+
+        let mut buffer = request(&Address::Socket("1.2.3.4:443".parse().unwrap()), b"");
+        let len = buffer.len();
+        buffer[len - 1] = b'x';
+        assert_eq!(Request::parse(&buffer), Err(Error::MissingCrlf));
+    }
+
+    #[test]
+    fn domain_lossy() {
+        let buffer = [DOMAIN, 2, 0xff, b'a', 0, 80];
+        let (address, size) = Address::parse(&buffer).unwrap();
+        assert_eq!(address, Address::Domain("\u{fffd}a".to_string(), 80));
+        assert_eq!(size, buffer.len());
+    }
+
+    #[test]
+    fn udp_packets() {
+        let first = Address::Socket("8.8.8.8:53".parse().unwrap());
+        let second = Address::Domain("example.com".to_string(), 53);
+        let mut buffer = Vec::new();
+        UdpPacket::write_header(&mut buffer, &first, 3);
+        buffer.extend_from_slice(b"abc");
+        UdpPacket::write_header(&mut buffer, &second, 0);
+        let (packet, size) = UdpPacket::parse(&buffer).unwrap();
+        assert_eq!(packet, UdpPacket { address: first, payload: b"abc" });
+        let (packet, rest) = UdpPacket::parse(&buffer[size..]).unwrap();
+        assert_eq!(packet, UdpPacket { address: second, payload: b"" });
+        assert_eq!(size + rest, buffer.len());
+        for len in 0..size {
+            assert_eq!(UdpPacket::parse(&buffer[..len]), Err(Error::Incomplete), "length {}", len);
+        }
+    }
+
+    #[test]
+    fn request_fragmented() {
+        let address = Address::Domain("example.com".to_string(), 443);
+        let buffer = request(&address, b"payload");
+        let header_len = buffer.len() - 7;
+        for chunk_size in 1..buffer.len() {
+            let mut parser = RequestParser::new();
+            let mut done = None;
+            for (i, chunk) in buffer.chunks(chunk_size).enumerate() {
+                match parser.feed(chunk).unwrap() {
+                    Progress::Done(header, size) => {
+                        done = Some((header, i * chunk_size + size));
+                        break;
+                    }
+                    Progress::Pending => assert!((i + 1) * chunk_size < header_len),
+                }
+            }
+            let (header, size) = done.unwrap();
+            assert_eq!(header.password, PASSWORD);
+            assert_eq!(header.address, address);
+            assert_eq!(size, header_len);
+        }
+    }
+
+    #[test]
+    fn parser_rejects_early() {
+        let mut parser = RequestParser::new();
+        assert_eq!(parser.feed(b"GET"), Err(Error::InvalidPassword));
+        assert_eq!(parser.feed(PASSWORD.as_bytes()), Err(Error::InvalidPassword));
+
+        let mut buffer = request(&Address::Domain("a".to_string(), 80), b"");
+        buffer[PASSWORD_LEN + 4] = 0;
+        assert_eq!(Request::parse(&buffer), Err(Error::EmptyDomain));
+        assert_eq!(Address::parse(&[DOMAIN, 0, 0, 80]), Err(Error::EmptyDomain));
+    }
+
+    #[test]
+    fn max_header_len() {
+        let address = Address::Domain("a".repeat(255), 80);
+        assert_eq!(request(&address, b"").len(), MAX_REQUEST_HEADER_LEN);
+        let mut buffer = Vec::new();
+        UdpPacket::write_header(&mut buffer, &address, 0);
+        assert_eq!(buffer.len(), MAX_UDP_HEADER_LEN);
+    }
+
+    #[test]
+    fn udp_header_parser() {
+        let address = Address::Socket("[2001:db8::1]:53".parse().unwrap());
+        let mut buffer = Vec::new();
+        for _ in 0..3 {
+            UdpPacket::write_header(&mut buffer, &address, 2);
+            buffer.extend_from_slice(b"ok");
+        }
+        let mut parser = UdpHeaderParser::new();
+        let mut headers = 0;
+        let mut payload = 0;
+        for byte in buffer.iter() {
+            if payload > 0 {
+                payload -= 1;
+                continue;
+            }
+            if let Progress::Done(header, size) = parser.feed(&[*byte]).unwrap() {
+                assert_eq!(header, UdpHeader { address: address.clone(), length: 2 });
+                assert_eq!(size, 1);
+                headers += 1;
+                payload = 2;
+            }
+        }
+        assert_eq!(headers, 3);
+    }
+
+    #[test]
+    fn padded_frames() {
+        let mut buffer = request(&Address::Domain("example.com".to_string(), 443), b"");
+        buffer[PASSWORD_LEN + 2] = CONNECT_PADDED;
+        let header_len = buffer.len();
+        write_frame(&mut buffer, b"", 100);
+        write_frame(&mut buffer, b"hello", 7);
+        write_frame(&mut buffer, b" world", 0);
+        let request = Request::parse(&buffer).unwrap();
+        assert_eq!(request.command, CONNECT_PADDED);
+        assert_eq!(request.payload.len(), buffer.len() - header_len);
+        for chunk_size in 1..buffer.len() - header_len {
+            let mut parser = FrameParser::new();
+            let mut data = Vec::new();
+            for chunk in request.payload.chunks(chunk_size) {
+                parser.feed(chunk, &mut data);
+            }
+            assert_eq!(data, b"hello world");
+            assert_eq!(parser.take_keepalives(), 0);
+        }
+    }
+
+    #[test]
+    fn frame_keepalives() {
+        let mut buffer = Vec::new();
+        write_keepalive(&mut buffer);
+        write_frame(&mut buffer, b"", 3);
+        write_frame(&mut buffer, b"data", 0);
+        write_keepalive(&mut buffer);
+        for chunk_size in 1..buffer.len() {
+            let mut parser = FrameParser::new();
+            let mut data = Vec::new();
+            for chunk in buffer.chunks(chunk_size) {
+                parser.feed(chunk, &mut data);
+            }
+            assert_eq!(data, b"data");
+            assert_eq!(parser.take_keepalives(), 2);
+            assert_eq!(parser.take_keepalives(), 0);
+        }
+    }
+
+    #[test]
+    fn udp_keepalives() {
+        let mut buffer = request(&Address::Socket("0.0.0.0:0".parse().unwrap()), b"");
+        buffer[PASSWORD_LEN + 2] = UDP_OVER_TCP;
+        let request = Request::parse(&buffer).unwrap();
+        assert_eq!(request.command, UDP_OVER_TCP);
+        let mut buffer = Vec::new();
+        UdpPacket::write_keepalive(&mut buffer);
+        UdpPacket::write_header(&mut buffer, &Address::Socket("0.0.0.0:0".parse().unwrap()), 1);
+        buffer.push(0);
+        let (packet, size) = UdpPacket::parse(&buffer).unwrap();
+        assert!(packet.is_keepalive());
+        let (packet, _) = UdpPacket::parse(&buffer[size..]).unwrap();
+        assert!(!packet.is_keepalive());
+    }
+
+    #[test]
+    fn udp_missing_crlf() {
+        let mut buffer = Vec::new();
+        UdpPacket::write_header(&mut buffer, &Address::Socket("8.8.8.8:53".parse().unwrap()), 1);
+        buffer.push(0);
+        buffer[9] = b'x';
+        assert_eq!(UdpPacket::parse(&buffer), Err(Error::MissingCrlf));
+    }
+
+    fn decode(trace: &replay::Trace, decoder: &mut replay::Decoder) {
+        replay::replay(trace, &replay::ManualClock::new(), decoder);
+    }
+
+    #[test]
+    fn trace_text() {
+        let text = include_str!("../traces/fragmented_request.trace");
+        let trace = replay::Trace::parse(text).unwrap();
+        assert_eq!(trace.steps.len(), 8);
+        assert_eq!(trace.steps[3].data, [0x03, 0x0b]);
+        assert_eq!(replay::Trace::parse(trace.to_string().as_str()).unwrap(), trace);
+        let errors = ["x > 00", "0 = 00", "0 > 0", "0 > 0g", "10 > 00\n5 < 00"];
+        for (text, line) in errors.iter().zip([1, 1, 1, 1, 2].iter()) {
+            assert_eq!(replay::Trace::parse(text).unwrap_err().line, *line, "{}", text);
+        }
+    }
+
+    #[test]
+    fn replay_fragmented_request() {
+        use replay::{Direction, Event};
+        let trace = replay::Trace::parse(include_str!("../traces/fragmented_request.trace")).unwrap();
+        for chunk_size in 1..64 {
+            let mut decoder = replay::Decoder::new();
+            decode(&trace.rechunk(chunk_size), &mut decoder);
+            let header = RequestHeader {
+                password: PASSWORD.to_string(),
+                command: CONNECT,
+                address: Address::Domain("example.com".to_string(), 443),
+            };
+            assert_eq!(decoder.events_to(Direction::ToServer), vec![Event::Request(header), Event::Data(b"GET / HTTP/1.1\r\n\r\n".to_vec())]);
+            assert_eq!(decoder.events_to(Direction::ToClient), vec![Event::Data(b"HTTP/1.1 200 OK\r\n\r\n".to_vec())]);
+            assert_eq!(decoder.events()[0].0, Duration::from_millis(250));
+        }
+    }
+
+    #[test]
+    fn replay_interleaved_udp() {
+        use replay::{Direction, Event};
+        let trace = replay::Trace::parse(include_str!("../traces/interleaved_udp.trace")).unwrap();
+        let dns = Address::Socket("8.8.8.8:53".parse().unwrap());
+        let ntp = Address::Domain("pool.ntp.org".to_string(), 123);
+        for chunk_size in 1..64 {
+            let mut decoder = replay::Decoder::new();
+            decode(&trace.rechunk(chunk_size), &mut decoder);
+            let events = decoder.events_to(Direction::ToServer);
+            assert_eq!(events[1..].to_vec(), vec![
+                Event::Packet(dns.clone(), b"dns1".to_vec()),
+                Event::Packet(ntp.clone(), b"ntp".to_vec()),
+                Event::Packet(dns.clone(), b"dns2".to_vec()),
+                Event::Keepalive,
+            ]);
+            assert_eq!(decoder.events_to(Direction::ToClient), vec![
+                Event::Packet(dns.clone(), b"reply".to_vec()),
+                Event::Keepalive,
+                Event::Packet(ntp.clone(), b"ok".to_vec()),
+            ]);
+            let times: Vec<u128> = decoder.events().iter().map(|(time, _, _)| time.as_millis()).collect();
+            assert_eq!(times, [0, 0, 10, 30, 40, 40, 40, 1000]);
+        }
+    }
+
+    #[test]
+    fn replay_timeout_and_failure() {
+        use replay::{Direction, Event};
+        let trace = replay::Trace::parse(include_str!("../traces/fragmented_request.trace")).unwrap();
+        let mut decoder = replay::Decoder::with_request_timeout(Duration::from_millis(100));
+        decode(&trace, &mut decoder);
+        assert_eq!(decoder.events()[0], (Duration::from_millis(120), Direction::ToServer, Event::Timeout));
+        assert_eq!(decoder.events_to(Direction::ToServer), vec![Event::Timeout]);
+        let mut decoder = replay::Decoder::with_request_timeout(Duration::from_millis(300));
+        decode(&trace, &mut decoder);
+        assert!(!decoder.events_to(Direction::ToServer).contains(&Event::Timeout));
+
+        let mut decoder = replay::Decoder::new();
+        decode(&replay::Trace::parse("0 > 474554\n5 > 20\n10 < 48545450").unwrap(), &mut decoder);
+        assert_eq!(decoder.events_to(Direction::ToServer), vec![Event::Failed(Error::InvalidPassword)]);
+        assert_eq!(decoder.events_to(Direction::ToClient), vec![Event::Data(b"HTTP".to_vec())]);
+    }
+
+    // a stream reading from a buffer and writing to another
+    struct Loopback {
+        input: std::io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl std::io::Read for Loopback {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl std::io::Write for Loopback {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn record_padded() {
+        use std::io::{Read, Write};
+        use replay::{Clock, Direction, Event};
+        let mut reply = Vec::new();
+        write_frame(&mut reply, b"pong", 2);
+        write_keepalive(&mut reply);
+        let stream = Loopback {
+            input: std::io::Cursor::new(reply),
+            output: Vec::new(),
+        };
+        let clock = replay::ManualClock::new();
+        let mut recorder = replay::Recorder::client(stream, &clock);
+        let mut request = Vec::new();
+        Request::write(&mut request, PASSWORD, CONNECT_PADDED, &Address::Domain("example.com".to_string(), 443));
+        write_frame(&mut request, b"", 8);
+        write_frame(&mut request, b"pi", 0);
+        write_keepalive(&mut request);
+        write_frame(&mut request, b"ng", 3);
+        recorder.write_all(&request).unwrap();
+        clock.set(Duration::from_millis(20));
+        let mut buffer = [0u8; 64];
+        assert_eq!(recorder.read(&mut buffer).unwrap(), 14);
+        assert_eq!(recorder.read(&mut buffer).unwrap(), 0);
+        assert_eq!(clock.now(), Duration::from_millis(20));
+        let (stream, trace) = recorder.into_inner();
+        assert_eq!(stream.output, request);
+        assert_eq!(trace.steps.len(), 2);
+        assert_eq!(trace.steps[1].time, Duration::from_millis(20));
+        for chunk_size in 1..32 {
+            let mut decoder = replay::Decoder::new();
+            decode(&trace.rechunk(chunk_size), &mut decoder);
+            let events = decoder.events_to(Direction::ToServer);
+            assert_eq!(events[1..].to_vec(), vec![Event::Data(b"pi".to_vec()), Event::Keepalive, Event::Data(b"ng".to_vec())]);
+            assert_eq!(decoder.events_to(Direction::ToClient), vec![Event::Data(b"pong".to_vec()), Event::Keepalive]);
+        }
+    }
+}
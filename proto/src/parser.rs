@@ -0,0 +1,255 @@
+use crate::{Address, Error, CONNECT, CONNECT_PADDED, CRLF, DOMAIN, IPV4, IPV6, PASSWORD_LEN, UDP_ASSOCIATE, UDP_OVER_TCP};
+
+/// The longest address, a domain of 255 bytes with its type, length and port.
+pub const MAX_ADDRESS_LEN: usize = 1 + 1 + 255 + 2;
+/// The longest request header, no parser buffers more than this.
+pub const MAX_REQUEST_HEADER_LEN: usize = PASSWORD_LEN + 2 + 1 + MAX_ADDRESS_LEN + 2;
+/// The longest udp packet header, no parser buffers more than this.
+pub const MAX_UDP_HEADER_LEN: usize = MAX_ADDRESS_LEN + 2 + 2;
+
+/// The result of feeding data to a parser.
+#[derive(Debug, PartialEq)]
+pub enum Progress<T> {
+    /// More data is needed.
+    Pending,
+    /// The header is complete, with the number of bytes taken from the data just fed, the rest of which follows
+    /// the header.
+    Done(T, usize),
+}
+
+/// The header of a trojan request, without the payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestHeader {
+    /// The hex encoded sha224 digest of the password, which is checked by the caller.
+    pub password: String,
+    pub command: u8,
+    pub address: Address,
+}
+
+/// The header of a udp packet, followed by `length` bytes of payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpHeader {
+    pub address: Address,
+    pub length: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Password,
+    PasswordCrlf,
+    Command,
+    AddressType,
+    DomainLength,
+    Address,
+    Length,
+    EndCrlf,
+    Failed(Error),
+}
+
+// the fields of both headers, read one after another into a bounded buffer
+struct HeaderParser {
+    udp: bool,
+    state: State,
+    // bytes of the field being read, an address is kept whole from its type byte
+    field: Vec<u8>,
+    needed: usize,
+    password: String,
+    command: u8,
+    address: Option<Address>,
+    length: u16,
+}
+
+impl HeaderParser {
+    fn new(udp: bool) -> HeaderParser {
+        let mut parser = HeaderParser {
+            udp,
+            state: State::Password,
+            field: Vec::with_capacity(MAX_ADDRESS_LEN),
+            needed: 0,
+            password: String::new(),
+            command: 0,
+            address: None,
+            length: 0,
+        };
+        parser.reset();
+        parser
+    }
+
+    fn reset(&mut self) {
+        if self.udp {
+            self.enter(State::AddressType, 1);
+        } else {
+            self.enter(State::Password, PASSWORD_LEN);
+        }
+        self.field.clear();
+        self.address = None;
+    }
+
+    fn enter(&mut self, state: State, needed: usize) {
+        self.state = state;
+        self.needed = needed;
+    }
+
+    fn fail(&mut self, error: Error) -> Error {
+        self.state = State::Failed(error);
+        error
+    }
+
+    // returns the bytes of data taken once the header is complete
+    fn feed(&mut self, data: &[u8]) -> Result<Option<usize>, Error> {
+        if let State::Failed(error) = self.state {
+            return Err(error);
+        }
+        let mut pos = 0;
+        loop {
+            let n = std::cmp::min(self.needed - self.field.len(), data.len() - pos);
+            self.field.extend_from_slice(&data[pos..pos + n]);
+            pos += n;
+            // checked as they arrive, so that other protocols are told apart before a whole password
+            if self.state == State::Password && !data[pos - n..pos].iter().all(|c| c.is_ascii_hexdigit()) {
+                return Err(self.fail(Error::InvalidPassword));
+            }
+            if self.field.len() < self.needed {
+                return Ok(None);
+            }
+            if self.step()? {
+                return Ok(Some(pos));
+            }
+        }
+    }
+
+    // moves to the next field once the current one is read, returns true when the header is complete
+    fn step(&mut self) -> Result<bool, Error> {
+        match self.state {
+            State::Password => {
+                self.password = String::from_utf8_lossy(&self.field).into_owned();
+                self.field.clear();
+                self.enter(State::PasswordCrlf, 2);
+            }
+            State::PasswordCrlf | State::EndCrlf => {
+                if self.field.as_slice() != CRLF {
+                    return Err(self.fail(Error::MissingCrlf));
+                }
+                self.field.clear();
+                if self.state == State::EndCrlf {
+                    return Ok(true);
+                }
+                self.enter(State::Command, 1);
+            }
+            State::Command => {
+                self.command = self.field[0];
+                if self.command != CONNECT && self.command != UDP_ASSOCIATE && self.command != CONNECT_PADDED && self.command != UDP_OVER_TCP {
+                    return Err(self.fail(Error::InvalidCommand(self.command)));
+                }
+                self.field.clear();
+                self.enter(State::AddressType, 1);
+            }
+            State::AddressType => {
+                match self.field[0] {
+                    IPV4 => self.enter(State::Address, 1 + 4 + 2),
+                    IPV6 => self.enter(State::Address, 1 + 16 + 2),
+                    DOMAIN => self.enter(State::DomainLength, 2),
+                    atyp => return Err(self.fail(Error::InvalidAddressType(atyp))),
+                }
+            }
+            State::DomainLength => {
+                let length = self.field[1] as usize;
+                if length == 0 {
+                    return Err(self.fail(Error::EmptyDomain));
+                }
+                self.enter(State::Address, 2 + length + 2);
+            }
+            State::Address => {
+                let (address, _) = Address::parse(&self.field).map_err(|error| self.fail(error))?;
+                self.address = Some(address);
+                self.field.clear();
+                if self.udp {
+                    self.enter(State::Length, 2);
+                } else {
+                    self.enter(State::EndCrlf, 2);
+                }
+            }
+            State::Length => {
+                self.length = (self.field[0] as u16) << 8 | self.field[1] as u16;
+                self.field.clear();
+                self.enter(State::EndCrlf, 2);
+            }
+            State::Failed(error) => return Err(error),
+        }
+        Ok(false)
+    }
+}
+
+/// An incremental parser of request headers, fed with data as it arrives, however it is fragmented.
+///
+/// At most [`MAX_REQUEST_HEADER_LEN`] bytes are buffered, and data which can not start a request, like a password
+/// with other than hex digits, is rejected as soon as it is seen. Once an error is returned, the parser keeps
+/// returning it.
+pub struct RequestParser {
+    parser: HeaderParser,
+}
+
+impl RequestParser {
+    pub fn new() -> RequestParser {
+        RequestParser {
+            parser: HeaderParser::new(false),
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) -> Result<Progress<RequestHeader>, Error> {
+        match self.parser.feed(data)? {
+            Some(size) => {
+                let header = RequestHeader {
+                    password: std::mem::take(&mut self.parser.password),
+                    command: self.parser.command,
+                    address: self.parser.address.take().unwrap(),
+                };
+                self.parser.reset();
+                Ok(Progress::Done(header, size))
+            }
+            None => Ok(Progress::Pending),
+        }
+    }
+}
+
+impl Default for RequestParser {
+    fn default() -> RequestParser {
+        RequestParser::new()
+    }
+}
+
+/// An incremental parser of udp packet headers, ready for the next header once one is done.
+///
+/// At most [`MAX_UDP_HEADER_LEN`] bytes are buffered. Once an error is returned, the parser keeps returning it, as
+/// the packet boundaries are lost.
+pub struct UdpHeaderParser {
+    parser: HeaderParser,
+}
+
+impl UdpHeaderParser {
+    pub fn new() -> UdpHeaderParser {
+        UdpHeaderParser {
+            parser: HeaderParser::new(true),
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) -> Result<Progress<UdpHeader>, Error> {
+        match self.parser.feed(data)? {
+            Some(size) => {
+                let header = UdpHeader {
+                    address: self.parser.address.take().unwrap(),
+                    length: self.parser.length,
+                };
+                self.parser.reset();
+                Ok(Progress::Done(header, size))
+            }
+            None => Ok(Progress::Pending),
+        }
+    }
+}
+
+impl Default for UdpHeaderParser {
+    fn default() -> UdpHeaderParser {
+        UdpHeaderParser::new()
+    }
+}
@@ -0,0 +1,509 @@
+//! Recording connections and replaying them through the parsers, so that a problem seen once, like a header split
+//! at an odd place, can be kept as a test.
+//!
+//! A [`Recorder`] wraps a stream and notes the bytes going each way with the time of a [`Clock`], giving a
+//! [`Trace`], which is kept as text. [`replay`] feeds the steps of a trace to an [`Endpoint`] in order with a
+//! [`ManualClock`] set to the time of each, so that the result depends on nothing but the trace. A [`Decoder`] is
+//! the endpoint reading both directions of a trojan connection with the parsers of this crate. The event loops of the
+//! server and the proxy are replayed by the tests of the trojan binary, with traces of the same format and more steps.
+
+use std::cell::Cell;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+use crate::{keepalive_address, Address, Error, FrameParser, Progress, RequestHeader, RequestParser, UdpHeader, UdpHeaderParser,
+            CONNECT_PADDED, UDP_ASSOCIATE, UDP_OVER_TCP};
+
+/// The way the bytes of a step go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the client to the server, `>` in traces.
+    ToServer,
+    /// From the server to the client, `<` in traces.
+    ToClient,
+}
+
+/// The bytes of a read or a write, at a time since the start of the connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    pub time: Duration,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+/// A recorded connection, the bytes going both ways in the order they were seen.
+///
+/// As text, each step is a line with the time in milliseconds, `>` or `<` and the data in hex, which may be split by
+/// spaces. Empty lines and lines starting with `#` are skipped. A step without data moves the time alone, e.g. to
+/// let a timeout pass at the end of a trace.
+///
+/// ```text
+/// # a request split after the password
+/// 0 > 6130633266...
+/// 15 > 0d0a01...
+/// 5000 >
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace {
+    pub steps: Vec<Step>,
+}
+
+/// A line of a trace which can not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceError {
+    /// The number of the line, from 1.
+    pub line: usize,
+    pub message: &'static str,
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}:{}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|value| value as u8)
+}
+
+impl Trace {
+    pub fn parse(text: &str) -> Result<Trace, TraceError> {
+        let mut steps: Vec<Step> = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message| TraceError { line: i + 1, message };
+            let mut fields = line.split_whitespace();
+            let time = fields.next().and_then(|time| time.parse().ok()).map(Duration::from_millis).ok_or_else(|| error("invalid time"))?;
+            if let Some(last) = steps.last() {
+                if last.time > time {
+                    return Err(error("time goes backwards"));
+                }
+            }
+            let direction = match fields.next() {
+                Some(">") => Direction::ToServer,
+                Some("<") => Direction::ToClient,
+                _ => return Err(error("expected > or <")),
+            };
+            let mut data = Vec::new();
+            for field in fields {
+                if field.len() % 2 != 0 {
+                    return Err(error("odd number of hex digits"));
+                }
+                for pair in field.as_bytes().chunks(2) {
+                    match (hex_value(pair[0]), hex_value(pair[1])) {
+                        (Some(high), Some(low)) => data.push(high << 4 | low),
+                        _ => return Err(error("invalid hex digit")),
+                    }
+                }
+            }
+            steps.push(Step { time, direction, data });
+        }
+        Ok(Trace { steps })
+    }
+
+    /// Splits the data of each step into steps of at most `size` bytes at the same time, as if it had been read in
+    /// smaller pieces.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero.
+    pub fn rechunk(&self, size: usize) -> Trace {
+        assert!(size > 0, "chunk size of zero");
+        let mut steps = Vec::new();
+        for step in self.steps.iter() {
+            if step.data.is_empty() {
+                steps.push(step.clone());
+            }
+            for chunk in step.data.chunks(size) {
+                steps.push(Step {
+                    time: step.time,
+                    direction: step.direction,
+                    data: chunk.to_vec(),
+                });
+            }
+        }
+        Trace { steps }
+    }
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for step in self.steps.iter() {
+            let direction = if step.direction == Direction::ToServer { '>' } else { '<' };
+            write!(f, "{} {}", step.time.as_millis(), direction)?;
+            if !step.data.is_empty() {
+                write!(f, " ")?;
+            }
+            for byte in step.data.iter() {
+                write!(f, "{:02x}", byte)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// A source of time for code which is recorded or replayed, the time since some start.
+pub trait Clock {
+    fn now(&self) -> Duration;
+}
+
+impl<C: Clock + ?Sized> Clock for &C {
+    fn now(&self) -> Duration {
+        (**self).now()
+    }
+}
+
+/// The time of the system since the clock was made.
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> SystemClock {
+        SystemClock::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// A clock which only moves when it is set, so that a replay does not depend on how fast it runs.
+#[derive(Default)]
+pub struct ManualClock {
+    now: Cell<Duration>,
+}
+
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock::default()
+    }
+
+    pub fn set(&self, now: Duration) {
+        self.now.set(now);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        self.now.get()
+    }
+}
+
+/// Wraps a stream and records what goes through it.
+///
+/// [`Recorder::client`] takes the stream of a client, what it writes goes to the server, and [`Recorder::server`]
+/// the stream of a server. Reads of nothing, the end of the stream, are not recorded.
+pub struct Recorder<S, C> {
+    stream: S,
+    clock: C,
+    written: Direction,
+    trace: Trace,
+}
+
+impl<S, C: Clock> Recorder<S, C> {
+    pub fn client(stream: S, clock: C) -> Recorder<S, C> {
+        Recorder {
+            stream,
+            clock,
+            written: Direction::ToServer,
+            trace: Trace::default(),
+        }
+    }
+
+    pub fn server(stream: S, clock: C) -> Recorder<S, C> {
+        Recorder {
+            stream,
+            clock,
+            written: Direction::ToClient,
+            trace: Trace::default(),
+        }
+    }
+
+    pub fn trace(&self) -> &Trace {
+        &self.trace
+    }
+
+    /// The stream and the trace recorded.
+    pub fn into_inner(self) -> (S, Trace) {
+        (self.stream, self.trace)
+    }
+
+    fn record(&mut self, direction: Direction, data: &[u8]) {
+        if !data.is_empty() {
+            self.trace.steps.push(Step {
+                time: self.clock.now(),
+                direction,
+                data: data.to_vec(),
+            });
+        }
+    }
+}
+
+impl<S: Read, C: Clock> Read for Recorder<S, C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.stream.read(buf)?;
+        let direction = if self.written == Direction::ToServer { Direction::ToClient } else { Direction::ToServer };
+        self.record(direction, &buf[..size]);
+        Ok(size)
+    }
+}
+
+impl<S: Write, C: Clock> Write for Recorder<S, C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.stream.write(buf)?;
+        self.record(self.written, &buf[..size]);
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// A state machine driven by [`replay`].
+pub trait Endpoint {
+    /// Takes the data of a step, at the time of the step.
+    fn feed(&mut self, now: Duration, direction: Direction, data: &[u8]);
+
+    /// Called with the time of each step before its data, so that timeouts are checked where they would have been.
+    fn tick(&mut self, _now: Duration) {}
+}
+
+/// Feeds the steps of a trace to an endpoint in order, with the clock set to the time of each.
+pub fn replay<E: Endpoint>(trace: &Trace, clock: &ManualClock, endpoint: &mut E) {
+    for step in trace.steps.iter() {
+        clock.set(step.time);
+        endpoint.tick(step.time);
+        endpoint.feed(step.time, step.direction, &step.data);
+    }
+}
+
+/// What a [`Decoder`] read from a trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The request of the client.
+    Request(RequestHeader),
+    /// Relayed tcp data, with the data following it the same way merged in.
+    Data(Vec<u8>),
+    /// A udp packet.
+    Packet(Address, Vec<u8>),
+    /// A keepalive frame of `CONNECT_PADDED`, or a keepalive packet of `UDP_OVER_TCP`.
+    Keepalive,
+    /// The request did not complete within the timeout, nothing the client sends after is read.
+    Timeout,
+    /// The data can not be parsed, nothing after it is read.
+    Failed(Error),
+}
+
+// how the data of a direction is framed, which the request tells
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    Request,
+    Raw,
+    Padded,
+    Udp,
+    Closed,
+}
+
+// one direction of a connection
+struct StreamDecoder {
+    framing: Framing,
+    requests: RequestParser,
+    frames: FrameParser,
+    headers: UdpHeaderParser,
+    // a udp packet whose payload is still being read
+    packet: Option<(UdpHeader, Vec<u8>)>,
+}
+
+impl StreamDecoder {
+    fn new(framing: Framing) -> StreamDecoder {
+        StreamDecoder {
+            framing,
+            requests: RequestParser::new(),
+            frames: FrameParser::new(),
+            headers: UdpHeaderParser::new(),
+            packet: None,
+        }
+    }
+
+    fn feed(&mut self, mut data: &[u8], events: &mut Vec<Event>) {
+        while !data.is_empty() {
+            match self.framing {
+                Framing::Request => match self.requests.feed(data) {
+                    Ok(Progress::Done(header, size)) => {
+                        self.framing = match header.command {
+                            CONNECT_PADDED => Framing::Padded,
+                            UDP_ASSOCIATE | UDP_OVER_TCP => Framing::Udp,
+                            _ => Framing::Raw,
+                        };
+                        events.push(Event::Request(header));
+                        data = &data[size..];
+                    }
+                    Ok(Progress::Pending) => return,
+                    Err(error) => return self.fail(error, events),
+                },
+                Framing::Raw => {
+                    events.push(Event::Data(data.to_vec()));
+                    return;
+                }
+                Framing::Padded => {
+                    // a byte at a time, so that keepalives keep their place among the data whatever the chunking
+                    for byte in data.iter() {
+                        let mut output = Vec::new();
+                        self.frames.feed(&[*byte], &mut output);
+                        if !output.is_empty() {
+                            events.push(Event::Data(output));
+                        }
+                        if self.frames.take_keepalives() > 0 {
+                            events.push(Event::Keepalive);
+                        }
+                    }
+                    return;
+                }
+                Framing::Udp => match self.packet.take() {
+                    Some((header, mut payload)) => {
+                        let size = std::cmp::min(header.length as usize - payload.len(), data.len());
+                        payload.extend_from_slice(&data[..size]);
+                        data = &data[size..];
+                        if payload.len() == header.length as usize {
+                            events.push(packet_event(header.address, payload));
+                        } else {
+                            self.packet = Some((header, payload));
+                        }
+                    }
+                    None => match self.headers.feed(data) {
+                        Ok(Progress::Done(header, size)) => {
+                            data = &data[size..];
+                            if header.length == 0 {
+                                events.push(packet_event(header.address, Vec::new()));
+                            } else {
+                                let payload = Vec::with_capacity(header.length as usize);
+                                self.packet = Some((header, payload));
+                            }
+                        }
+                        Ok(Progress::Pending) => return,
+                        Err(error) => return self.fail(error, events),
+                    },
+                },
+                Framing::Closed => return,
+            }
+        }
+    }
+
+    fn fail(&mut self, error: Error, events: &mut Vec<Event>) {
+        self.framing = Framing::Closed;
+        events.push(Event::Failed(error));
+    }
+}
+
+fn packet_event(address: Address, payload: Vec<u8>) -> Event {
+    if payload.is_empty() && address == Address::Socket(keepalive_address()) {
+        Event::Keepalive
+    } else {
+        Event::Packet(address, payload)
+    }
+}
+
+/// Reads both directions of a trojan connection into a log of events.
+///
+/// The data going to the server is read as a request, then the data, the frames or the udp packets it announces. The
+/// data going to the client is framed the same way once the request is complete and as it is before. Both are built on the streaming parsers, so a trace gives the same events however
+/// it is chunked.
+pub struct Decoder {
+    server: StreamDecoder,
+    proxy: StreamDecoder,
+    request_timeout: Option<Duration>,
+    events: Vec<(Duration, Direction, Event)>,
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder {
+            server: StreamDecoder::new(Framing::Request),
+            proxy: StreamDecoder::new(Framing::Raw),
+            request_timeout: None,
+            events: Vec::new(),
+        }
+    }
+
+    /// Gives up on a request not complete `timeout` after the start, like the first packet timeout of the server.
+    pub fn with_request_timeout(timeout: Duration) -> Decoder {
+        Decoder {
+            request_timeout: Some(timeout),
+            ..Decoder::new()
+        }
+    }
+
+    /// The events with their time and direction, in the order they were read.
+    pub fn events(&self) -> &[(Duration, Direction, Event)] {
+        &self.events
+    }
+
+    /// The events of one direction alone.
+    pub fn events_to(&self, direction: Direction) -> Vec<Event> {
+        self.events.iter().filter(|(_, to, _)| *to == direction).map(|(_, _, event)| event.clone()).collect()
+    }
+
+    fn push(&mut self, now: Duration, direction: Direction, event: Event) {
+        if let (Event::Data(data), Some((_, to, Event::Data(last)))) = (&event, self.events.last_mut()) {
+            if *to == direction {
+                last.extend_from_slice(data);
+                return;
+            }
+        }
+        self.events.push((now, direction, event));
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Decoder {
+        Decoder::new()
+    }
+}
+
+impl Endpoint for Decoder {
+    fn feed(&mut self, now: Duration, direction: Direction, data: &[u8]) {
+        let mut events = Vec::new();
+        match direction {
+            Direction::ToServer => {
+                let requesting = self.server.framing == Framing::Request;
+                self.server.feed(data, &mut events);
+                // the answer to a failed request comes from the fallback, and is read as it is
+                if requesting && self.server.framing != Framing::Request && self.server.framing != Framing::Closed {
+                    self.proxy.framing = self.server.framing;
+                }
+            }
+            Direction::ToClient => self.proxy.feed(data, &mut events),
+        }
+        for event in events {
+            self.push(now, direction, event);
+        }
+    }
+
+    fn tick(&mut self, now: Duration) {
+        if let Some(timeout) = self.request_timeout {
+            if self.server.framing == Framing::Request && now >= timeout {
+                self.server.framing = Framing::Closed;
+                self.push(now, Direction::ToServer, Event::Timeout);
+            }
+        }
+    }
+}
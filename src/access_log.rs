@@ -0,0 +1,136 @@
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::time::Instant;
+
+use crate::flow_export;
+
+// records of the access log go through the logger with this target, which routes them to their own output
+pub const TARGET: &str = "access";
+
+// set once at startup, null when there is no access log
+static FORMAT: AtomicPtr<Vec<Part>> = AtomicPtr::new(ptr::null_mut());
+// turned off and on at runtime by the admin socket and SIGUSR1
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+enum Part {
+    Text(String),
+    TimeLocal,
+    TimeIso8601,
+    ConnId,
+    RemoteAddr,
+    User,
+    Target,
+    Protocol,
+    BytesSent,
+    BytesReceived,
+    Duration,
+}
+
+// a finished session, bytes are counted on the client side
+pub struct Entry<'a> {
+    pub conn_id: usize,
+    pub remote_addr: Option<SocketAddr>,
+    // the label of the password in server mode
+    pub user: Option<&'a str>,
+    pub target: &'a dyn std::fmt::Display,
+    // the address connected to, none for domains not resolved
+    pub target_addr: Option<SocketAddr>,
+    pub protocol: &'static str,
+    pub bytes_sent: usize,
+    pub bytes_received: usize,
+    pub start_time: Instant,
+}
+
+// nginx style, $name is replaced by the variable and anything else is kept as it is
+fn parse(format: &str) -> Result<Vec<Part>, String> {
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            text.push(c);
+            continue;
+        }
+        let mut name = String::new();
+        while let Some(c) = chars.peek() {
+            if !c.is_ascii_alphanumeric() && *c != '_' {
+                break;
+            }
+            name.push(*c);
+            chars.next();
+        }
+        let part = match name.as_str() {
+            "time_local" => Part::TimeLocal,
+            "time_iso8601" => Part::TimeIso8601,
+            "conn_id" => Part::ConnId,
+            "remote_addr" => Part::RemoteAddr,
+            "user" => Part::User,
+            "target" => Part::Target,
+            "protocol" => Part::Protocol,
+            "bytes_sent" => Part::BytesSent,
+            "bytes_received" => Part::BytesReceived,
+            "duration" => Part::Duration,
+            _ => return Err(format!("invalid access log variable:${}", name)),
+        };
+        if !text.is_empty() {
+            parts.push(Part::Text(std::mem::take(&mut text)));
+        }
+        parts.push(part);
+    }
+    if !text.is_empty() {
+        parts.push(Part::Text(text));
+    }
+    Ok(parts)
+}
+
+pub fn setup(format: &str) -> Result<(), String> {
+    let parts = Box::new(parse(format)?);
+    FORMAT.store(Box::into_raw(parts), Ordering::Release);
+    Ok(())
+}
+
+pub fn is_configured() -> bool {
+    !FORMAT.load(Ordering::Acquire).is_null()
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn write(entry: &Entry) {
+    // finished sessions are exported as flows as well, whether the access log is on or not
+    flow_export::export(entry);
+    if !is_enabled() {
+        return;
+    }
+    let parts = match unsafe { FORMAT.load(Ordering::Acquire).as_ref() } {
+        Some(parts) => parts,
+        None => return,
+    };
+    let mut line = String::new();
+    for part in parts {
+        let _ = match part {
+            Part::Text(text) => line.write_str(text.as_str()),
+            Part::TimeLocal => write!(line, "{}", chrono::Local::now().format("%d/%b/%Y:%H:%M:%S %z")),
+            Part::TimeIso8601 => line.write_str(chrono::Local::now().to_rfc3339().as_str()),
+            Part::ConnId => write!(line, "{}", entry.conn_id),
+            Part::RemoteAddr => match entry.remote_addr {
+                Some(addr) => write!(line, "{}", addr),
+                None => line.write_str("-"),
+            },
+            Part::User => line.write_str(entry.user.unwrap_or("-")),
+            Part::Target => write!(line, "{}", entry.target),
+            Part::Protocol => line.write_str(entry.protocol),
+            Part::BytesSent => write!(line, "{}", entry.bytes_sent),
+            Part::BytesReceived => write!(line, "{}", entry.bytes_received),
+            Part::Duration => write!(line, "{:.3}", entry.start_time.elapsed().as_secs_f64()),
+        };
+    }
+    log::info!(target: TARGET, "{}", line);
+}
@@ -0,0 +1,159 @@
+#[cfg(unix)]
+use std::io::{ErrorKind, Read, Write};
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::time::Duration;
+
+use std::io::Result;
+
+#[cfg(unix)]
+use mio::unix::EventedFd;
+use mio::{Poll, Token};
+#[cfg(unix)]
+use mio::{PollOpt, Ready};
+
+// commands longer than this are not expected, the client is dropped
+#[cfg(unix)]
+const MAX_COMMAND_LEN: usize = 1024;
+
+// a local unix socket taking one line of command per connection, answered with text before closing,
+// e.g. echo bans | socat - UNIX-CONNECT:/run/trojan-rs.sock
+#[cfg(unix)]
+pub struct Admin {
+    path: String,
+    listener: UnixListener,
+    client_token: Token,
+    clients: Vec<Client>,
+}
+
+#[cfg(unix)]
+struct Client {
+    stream: UnixStream,
+    buffer: Vec<u8>,
+}
+
+#[cfg(unix)]
+impl Admin {
+    // clients are all registered with client_token, as there are few of them
+    pub fn new(path: &str, poll: &Poll, token: Token, client_token: Token) -> Result<Admin> {
+        // a socket left by a crashed process would fail the bind
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        listener.set_nonblocking(true)?;
+        poll.register(&EventedFd(&listener.as_raw_fd()), token, Ready::readable(), PollOpt::level())?;
+        log::warn!("admin socket listening on {}", path);
+        Ok(Admin {
+            path: path.to_string(),
+            listener,
+            client_token,
+            clients: Vec::new(),
+        })
+    }
+
+    pub fn accept(&mut self, poll: &Poll) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(err) = stream.set_nonblocking(true) {
+                        log::error!("set admin client nonblocking failed:{}", err);
+                        continue;
+                    }
+                    if let Err(err) = poll.register(&EventedFd(&stream.as_raw_fd()), self.client_token, Ready::readable(), PollOpt::level()) {
+                        log::error!("register admin client failed:{}", err);
+                        continue;
+                    }
+                    self.clients.push(Client {
+                        stream,
+                        buffer: Vec::new(),
+                    });
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::error!("accept admin client failed:{}", err);
+                    break;
+                }
+            }
+        }
+    }
+
+    // runs the command of each client which has sent a whole line
+    pub fn ready<F: FnMut(&str) -> String>(&mut self, poll: &Poll, mut handler: F) {
+        let mut i = 0;
+        while i < self.clients.len() {
+            match self.clients[i].read_command() {
+                Ok(None) => {
+                    i += 1;
+                    continue;
+                }
+                Ok(Some(command)) => {
+                    log::info!("admin command:{}", command);
+                    let answer = handler(command.as_str());
+                    let client = &mut self.clients[i];
+                    // answers are small, and the client is waiting for them
+                    let _ = client.stream.set_nonblocking(false);
+                    let _ = client.stream.set_write_timeout(Some(Duration::from_secs(1)));
+                    if let Err(err) = client.stream.write_all(answer.as_bytes()) {
+                        log::warn!("write admin answer failed:{}", err);
+                    }
+                }
+                Err(err) => log::warn!("read admin command failed:{}", err),
+            }
+            let client = self.clients.swap_remove(i);
+            let _ = poll.deregister(&EventedFd(&client.stream.as_raw_fd()));
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Client {
+    fn read_command(&mut self) -> Result<Option<String>> {
+        let mut buffer = [0u8; 256];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) if self.buffer.is_empty() => return Err(ErrorKind::UnexpectedEof.into()),
+                // a command without the new line at the end
+                Ok(0) => break,
+                Ok(size) => self.buffer.extend_from_slice(&buffer[..size]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    if !self.buffer.contains(&b'\n') {
+                        return Ok(None);
+                    }
+                    break;
+                }
+                Err(err) => return Err(err),
+            }
+            if self.buffer.len() > MAX_COMMAND_LEN {
+                return Err(ErrorKind::InvalidData.into());
+            }
+        }
+        let line = self.buffer.split(|c| *c == b'\n').next().unwrap_or(&[]);
+        Ok(Some(String::from_utf8_lossy(line).trim().to_string()))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Admin {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(self.path.as_str());
+    }
+}
+
+#[cfg(not(unix))]
+pub struct Admin;
+
+#[cfg(not(unix))]
+impl Admin {
+    pub fn new(_path: &str, _poll: &Poll, _token: Token, _client_token: Token) -> Result<Admin> {
+        Err(std::io::Error::new(std::io::ErrorKind::Other, "admin socket is only supported on unix"))
+    }
+
+    pub fn accept(&mut self, _poll: &Poll) {}
+
+    pub fn ready<F: FnMut(&str) -> String>(&mut self, _poll: &Poll, _handler: F) {}
+}
@@ -0,0 +1,324 @@
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rustls::{ClientConfig, ClientSession, Session, StreamOwned};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use trojan_proto::{Address, Request, UdpPacket};
+use webpki::DNSNameRef;
+
+use crate::config::{self, BenchArgs};
+use crate::password;
+use crate::proto::{CONNECT, MAX_UDP_SIZE, UDP_ASSOCIATE};
+use crate::sys;
+
+// commands of the bench target, followed by the size in 8 bytes
+const UPLOAD: u8 = b'u';
+const DOWNLOAD: u8 = b'd';
+const CHUNK_SIZE: usize = 16384;
+// udp packets still missing after this long are counted as lost
+const UDP_WAIT_TIME: u64 = 2;
+
+pub fn run(args: &BenchArgs) {
+    if let Some(addr) = args.serve.as_ref() {
+        if let Err(err) = serve(addr.as_str()) {
+            log::error!("bench target on {} failed:{}", addr, err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let bench = match Bench::new(args) {
+        Ok(bench) => bench,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    if let Err(err) = bench.run(args) {
+        eprintln!("bench failed:{}", err);
+        std::process::exit(1);
+    }
+}
+
+struct Bench {
+    server_addr: SocketAddr,
+    server_name: String,
+    target: Address,
+    digest: String,
+    config: Arc<ClientConfig>,
+    send_buffer: u32,
+    recv_buffer: u32,
+    timeout: Duration,
+}
+
+impl Bench {
+    fn new(args: &BenchArgs) -> std::result::Result<Bench, String> {
+        let server = args.server.as_ref().ok_or("--server or --serve is required")?;
+        let target = args.target.as_ref().ok_or("--target is required, see --serve")?;
+        let password = args.password.clone().or_else(|| std::env::var(password::ENV_NAME).ok())
+            .ok_or_else(|| format!("no password given by --password or {}", password::ENV_NAME))?;
+        let server_addr = server.to_socket_addrs().ok().and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| format!("trojan server {} can not be resolved", server))?;
+        let hostname = match server.rfind(':') {
+            Some(pos) => server[..pos].trim_start_matches('[').trim_end_matches(']'),
+            None => server.as_str(),
+        };
+        let mut config = ClientConfig::new();
+        config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        Ok(Bench {
+            server_addr,
+            server_name: args.sni.clone().unwrap_or_else(|| hostname.to_string()),
+            target: parse_target(target)?,
+            digest: config::password_digest(password.as_str())?,
+            config: Arc::new(config),
+            send_buffer: args.send_buffer,
+            recv_buffer: args.recv_buffer,
+            timeout: Duration::new(args.timeout, 0),
+        })
+    }
+
+    fn run(&self, args: &BenchArgs) -> Result<()> {
+        println!("server: {} ({})", self.server_name, self.server_addr);
+        println!("target: {}", self.target);
+        let mut connect_times = Vec::new();
+        let mut handshake_times = Vec::new();
+        for _ in 0..args.count {
+            let (connect_time, handshake_time, _) = self.connect()?;
+            connect_times.push(connect_time);
+            handshake_times.push(handshake_time);
+        }
+        print_times("tcp connect", &connect_times);
+        print_times("tls handshake", &handshake_times);
+
+        let size = args.size * 1024 * 1024;
+        let duration = self.upload(size)?;
+        print_throughput("tcp upload", size, duration);
+        let duration = self.download(size)?;
+        print_throughput("tcp download", size, duration);
+
+        if !args.no_udp {
+            let (received, rtt) = self.udp(args.udp_packets, args.udp_size)?;
+            let loss = (args.udp_packets - received) as f64 * 100.0 / args.udp_packets as f64;
+            println!("udp: {} of {} packets of {} bytes echoed, {:.2}% loss, {}ms average round trip",
+                     received, args.udp_packets, args.udp_size, loss, rtt.as_millis());
+        }
+        Ok(())
+    }
+
+    // returns the time of the tcp connect and the tls handshake
+    fn connect(&self) -> Result<(Duration, Duration, StreamOwned<ClientSession, TcpStream>)> {
+        let domain = if self.server_addr.is_ipv4() {
+            Domain::ipv4()
+        } else {
+            Domain::ipv6()
+        };
+        let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+        // set before connecting, so that the window scale follows them
+        sys::set_buffer_size(&socket, self.send_buffer, self.recv_buffer)?;
+        let start = Instant::now();
+        socket.connect_timeout(&SockAddr::from(self.server_addr), self.timeout)?;
+        let connect_time = start.elapsed();
+        socket.set_read_timeout(Some(self.timeout))?;
+        socket.set_write_timeout(Some(self.timeout))?;
+        let mut stream = socket.into_tcp_stream();
+        let dns_name = DNSNameRef::try_from_ascii_str(self.server_name.as_str())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("invalid server name:{}", self.server_name)))?;
+        let mut session = ClientSession::new(&self.config, dns_name);
+        let start = Instant::now();
+        while session.is_handshaking() {
+            session.complete_io(&mut stream)?;
+        }
+        Ok((connect_time, start.elapsed(), StreamOwned::new(session, stream)))
+    }
+
+    fn request(&self, command: u8, address: &Address) -> Result<StreamOwned<ClientSession, TcpStream>> {
+        let (_, _, mut stream) = self.connect()?;
+        let mut request = Vec::new();
+        Request::write(&mut request, self.digest.as_str(), command, address);
+        stream.write_all(request.as_slice())?;
+        Ok(stream)
+    }
+
+    fn upload(&self, size: usize) -> Result<Duration> {
+        let mut stream = self.request(CONNECT, &self.target)?;
+        let start = Instant::now();
+        stream.write_all(&[UPLOAD])?;
+        stream.write_all(&(size as u64).to_be_bytes())?;
+        let chunk = [0u8; CHUNK_SIZE];
+        let mut left = size;
+        while left > 0 {
+            let n = std::cmp::min(left, CHUNK_SIZE);
+            stream.write_all(&chunk[..n])?;
+            left -= n;
+        }
+        stream.flush()?;
+        // acknowledged by the target once all the data arrived
+        let mut ack = [0u8; 1];
+        stream.read_exact(&mut ack)?;
+        Ok(start.elapsed())
+    }
+
+    fn download(&self, size: usize) -> Result<Duration> {
+        let mut stream = self.request(CONNECT, &self.target)?;
+        let start = Instant::now();
+        stream.write_all(&[DOWNLOAD])?;
+        stream.write_all(&(size as u64).to_be_bytes())?;
+        stream.flush()?;
+        let mut chunk = [0u8; CHUNK_SIZE];
+        let mut left = size;
+        while left > 0 {
+            let n = stream.read(&mut chunk)?;
+            if n == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed before all data received"));
+            }
+            left -= std::cmp::min(left, n);
+        }
+        Ok(start.elapsed())
+    }
+
+    // packets carry their index and are echoed by the target, returns the packets received and the average round trip
+    fn udp(&self, count: usize, size: usize) -> Result<(usize, Duration)> {
+        // the server only relays udp packets to ip addresses
+        let target = match &self.target {
+            Address::Domain(domain, port) => {
+                let addr = (domain.as_str(), *port).to_socket_addrs()?.next()
+                    .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("no address found for {}", domain)))?;
+                Address::Socket(addr)
+            }
+            address => address.clone(),
+        };
+        let mut stream = self.request(UDP_ASSOCIATE, &target)?;
+        let size = size.clamp(4, MAX_UDP_SIZE);
+        let mut sent_times = Vec::with_capacity(count);
+        let mut packet = vec![0u8; size];
+        for i in 0..count {
+            packet[..4].copy_from_slice(&(i as u32).to_be_bytes());
+            let mut frame = Vec::with_capacity(size + 32);
+            UdpPacket::write_header(&mut frame, &target, size as u16);
+            frame.extend_from_slice(packet.as_slice());
+            stream.write_all(frame.as_slice())?;
+            sent_times.push(Instant::now());
+        }
+        stream.flush()?;
+        stream.sock.set_read_timeout(Some(Duration::new(UDP_WAIT_TIME, 0)))?;
+        let mut received = vec![false; count];
+        let mut received_count = 0;
+        let mut rtt = Duration::new(0, 0);
+        let mut data = Vec::new();
+        let mut buffer = [0u8; CHUNK_SIZE];
+        while received_count < count {
+            let n = match stream.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => break,
+                Err(err) => return Err(err),
+            };
+            data.extend_from_slice(&buffer[..n]);
+            loop {
+                let consumed = match UdpPacket::parse(data.as_slice()) {
+                    Ok((echoed, consumed)) => {
+                        if echoed.payload.len() >= 4 {
+                            let index = u32::from_be_bytes([echoed.payload[0], echoed.payload[1], echoed.payload[2], echoed.payload[3]]) as usize;
+                            if index < count && !received[index] {
+                                received[index] = true;
+                                received_count += 1;
+                                rtt += sent_times[index].elapsed();
+                            }
+                        }
+                        consumed
+                    }
+                    Err(trojan_proto::Error::Incomplete) => break,
+                    Err(err) => return Err(Error::new(ErrorKind::InvalidData, err.to_string())),
+                };
+                data.drain(..consumed);
+            }
+        }
+        if received_count > 0 {
+            rtt /= received_count as u32;
+        }
+        Ok((received_count, rtt))
+    }
+}
+
+fn parse_target(target: &str) -> std::result::Result<Address, String> {
+    if let Ok(addr) = target.parse::<SocketAddr>() {
+        return Ok(Address::Socket(addr));
+    }
+    let invalid = || format!("invalid target:{}", target);
+    let pos = target.rfind(':').ok_or_else(invalid)?;
+    let port = target[pos + 1..].parse().map_err(|_| invalid())?;
+    if pos == 0 || pos > 255 {
+        return Err(invalid());
+    }
+    Ok(Address::Domain(target[..pos].to_string(), port))
+}
+
+fn print_times(name: &str, times: &[Duration]) {
+    if times.is_empty() {
+        return;
+    }
+    let total: Duration = times.iter().sum();
+    println!("{}: min {}ms, avg {}ms, max {}ms", name,
+             times.iter().min().unwrap().as_millis(),
+             (total / times.len() as u32).as_millis(),
+             times.iter().max().unwrap().as_millis());
+}
+
+fn print_throughput(name: &str, size: usize, duration: Duration) {
+    let mbps = size as f64 * 8.0 / 1_000_000.0 / duration.as_secs_f64();
+    println!("{}: {} bytes in {}ms, {:.2} Mbit/s", name, size, duration.as_millis(), mbps);
+}
+
+// the other end of the relayed traffic, uploads are read and acknowledged, downloads written, udp packets echoed
+fn serve(addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    let socket = UdpSocket::bind(listener.local_addr()?)?;
+    log::warn!("bench target listening on {}", listener.local_addr()?);
+    std::thread::spawn(move || {
+        let mut buffer = [0u8; 65536];
+        while let Ok((size, addr)) = socket.recv_from(&mut buffer) {
+            let _ = socket.send_to(&buffer[..size], addr);
+        }
+    });
+    for stream in listener.incoming() {
+        let stream = stream?;
+        std::thread::spawn(move || {
+            let peer = stream.peer_addr().ok();
+            if let Err(err) = serve_client(stream) {
+                log::info!("bench client {:?} failed:{}", peer, err);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn serve_client(mut stream: TcpStream) -> Result<()> {
+    let mut header = [0u8; 9];
+    stream.read_exact(&mut header)?;
+    let mut size_bytes = [0u8; 8];
+    size_bytes.copy_from_slice(&header[1..]);
+    let mut left = u64::from_be_bytes(size_bytes) as usize;
+    let mut chunk = [0u8; CHUNK_SIZE];
+    match header[0] {
+        UPLOAD => {
+            while left > 0 {
+                let n = stream.read(&mut chunk)?;
+                if n == 0 {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "upload closed early"));
+                }
+                left -= std::cmp::min(left, n);
+            }
+            stream.write_all(&[UPLOAD])
+        }
+        DOWNLOAD => {
+            while left > 0 {
+                let n = std::cmp::min(left, CHUNK_SIZE);
+                stream.write_all(&chunk[..n])?;
+                left -= n;
+            }
+            Ok(())
+        }
+        command => Err(Error::new(ErrorKind::InvalidData, format!("unknown bench command:{}", command))),
+    }
+}
@@ -0,0 +1,63 @@
+use std::time::{Duration, Instant};
+
+use crate::config::Opts;
+
+// connections queued data is left in for this long are the first closed when over budget
+const SHED_IDLE_TIME: Duration = Duration::from_secs(5);
+// the queue limit of each connection while shedding, a fraction of --max-pending
+const SHRINK: usize = 8;
+const MIN_PENDING: usize = 16384;
+
+// the bytes queued by all connections are compared with --memory-budget once a second, above it new connections are
+// not accepted, the queues of each connection shrink, and the idle connections queueing the most are closed
+pub struct MemoryBudget {
+    shedding: bool,
+}
+
+impl MemoryBudget {
+    pub fn new() -> MemoryBudget {
+        MemoryBudget {
+            shedding: false,
+        }
+    }
+
+    // returns whether load is to be shed, it stops once the queues drop below three quarters of the budget
+    pub fn check(&mut self, queued: usize, opts: &mut Opts) -> bool {
+        let budget = opts.relay_args().memory_budget;
+        if budget == 0 {
+            return false;
+        }
+        if !self.shedding && queued > budget {
+            log::warn!("{} bytes queued exceed the memory budget of {}, shedding load", queued, budget);
+            self.shedding = true;
+            let max_pending = opts.relay_args().max_pending;
+            opts.pending_limit = std::cmp::min(max_pending, std::cmp::max(max_pending / SHRINK, MIN_PENDING));
+        } else if self.shedding && queued < budget / 4 * 3 {
+            log::warn!("{} bytes queued are within the memory budget again", queued);
+            self.shedding = false;
+            opts.pending_limit = opts.relay_args().max_pending;
+        }
+        self.shedding
+    }
+
+    // picks connections from (queued, last active time, index) until enough is freed to get within the budget
+    pub fn victims(&self, mut conns: Vec<(usize, Instant, usize)>, now: Instant, opts: &Opts) -> Vec<usize> {
+        let queued: usize = conns.iter().map(|(queued, _, _)| *queued).sum();
+        let budget = opts.relay_args().memory_budget;
+        if !self.shedding || queued <= budget {
+            return Vec::new();
+        }
+        conns.retain(|(queued, active_time, _)| *queued > 0 && now - *active_time >= SHED_IDLE_TIME);
+        conns.sort_by_key(|conn| std::cmp::Reverse(conn.0));
+        let mut excess = queued - budget;
+        let mut victims = Vec::new();
+        for (queued, _, index) in conns {
+            if excess == 0 {
+                break;
+            }
+            victims.push(index);
+            excess = excess.saturating_sub(queued);
+        }
+        victims
+    }
+}
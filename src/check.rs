@@ -0,0 +1,264 @@
+use std::net::SocketAddr;
+#[cfg(target_os = "linux")]
+use std::process::Command;
+
+use crate::config::{FirstPacketAction, Mode, Opts, TransparentMode};
+#[cfg(target_os = "linux")]
+use crate::{firewall, privilege};
+use crate::{server, webhook};
+use crate::upstream_proxy::ProxyKind;
+
+// a dry run of server and proxy modes, nothing is bound, problems go to stderr and make the exit code 1
+pub fn run(opts: &mut Opts) -> ! {
+    match opts.mode {
+        Mode::Server(_) | Mode::Proxy(_) => {}
+        _ => {
+            eprintln!("only server and proxy modes can be checked");
+            std::process::exit(1);
+        }
+    }
+    if let Err(err) = opts.setup() {
+        eprintln!("{}", err);
+        if let Some(hint) = err.hint() {
+            eprintln!("{}", hint);
+        }
+        std::process::exit(err.exit_code());
+    }
+    let mut problems = Vec::new();
+    match opts.mode {
+        Mode::Server(_) => check_server(opts, &mut problems),
+        Mode::Proxy(_) => check_proxy(opts, &mut problems),
+        _ => unreachable!(),
+    }
+    if let Some(url) = opts.webhook.as_ref() {
+        if let Err(err) = webhook::check(url.as_str(), opts.webhook_event.as_slice()) {
+            problems.push(err.to_string());
+        }
+    }
+    print_options(opts);
+    if problems.is_empty() {
+        println!("configuration ok");
+        std::process::exit(0);
+    }
+    for problem in problems.iter() {
+        eprintln!("{}", problem);
+    }
+    std::process::exit(1);
+}
+
+fn check_server(opts: &Opts, problems: &mut Vec<String>) {
+    if let Err(err) = server::init_config(opts) {
+        problems.push(err.to_string());
+    }
+    if let Some(expiry) = server::cert_expiry(opts) {
+        if expiry < chrono::Utc::now() {
+            problems.push(format!("certificate expired at {}", expiry));
+        }
+    }
+    if let Some(path) = opts.back_path.as_ref() {
+        if !std::path::Path::new(path).exists() {
+            problems.push(format!("fallback unix socket {} does not exist", path));
+        }
+    }
+    if !opts.port_ranges.is_empty() {
+        check_port_ranges(opts, problems);
+    }
+}
+
+// the redirect rules of listen port ranges, as logged at startup
+#[cfg(target_os = "linux")]
+fn check_port_ranges(opts: &Opts, problems: &mut Vec<String>) {
+    let iptables_rules = command_output("iptables", &["-t", "nat", "-S"]);
+    let ip6tables_rules = command_output("ip6tables", &["-t", "nat", "-S"]);
+    let nftables_rules = command_output("nft", &["list", "ruleset"]);
+    for (addr, last_port) in opts.port_ranges.iter() {
+        let rules = if addr.is_ipv4() { &iptables_rules } else { &ip6tables_rules };
+        let iptables = format!("--dport {}:{} ", addr.port(), last_port);
+        let nftables = format!("dport {}-{} ", addr.port(), last_port);
+        let found = rules.as_ref().map_or(false, |rules| rules.contains(iptables.as_str()))
+            || nftables_rules.as_ref().map_or(false, |rules| rules.contains(nftables.as_str()));
+        if !found {
+            problems.push(format!("no redirect rule for ports {} to {}, e.g. {}", addr.port(), last_port, firewall::redirect_rule(addr, *last_port)));
+        }
+    }
+}
+
+// pf rules are not looked for
+#[cfg(not(target_os = "linux"))]
+fn check_port_ranges(_opts: &Opts, _problems: &mut Vec<String>) {}
+
+fn check_proxy(opts: &Opts, problems: &mut Vec<String>) {
+    let args = opts.proxy_args();
+    for upstream in opts.upstreams.iter() {
+        if !upstream.is_available() {
+            problems.push(format!("trojan server {} can not be resolved", upstream.name()));
+        }
+    }
+    for addr in args.dns_addr.iter().chain(Some(&args.direct_dns)) {
+        if let Err(err) = addr.parse::<SocketAddr>() {
+            problems.push(format!("invalid dns address {}:{}", addr, err));
+        }
+    }
+    if args.transparent_mode == TransparentMode::Tproxy {
+        check_tproxy(opts, problems);
+    }
+}
+
+// the rules setup-firewall prints, looked for in the running system
+#[cfg(target_os = "linux")]
+fn check_tproxy(opts: &Opts, problems: &mut Vec<String>) {
+    let relay = opts.relay_args();
+    if !privilege::has_net_admin() {
+        problems.push("CAP_NET_ADMIN is missing, tproxy sockets can not be set up".to_string());
+    }
+    if relay.marker == 0 {
+        problems.push("tproxy needs a non-zero --marker to route packets back to trojan".to_string());
+    } else {
+        let fwmark = format!("fwmark {:#x} ", relay.marker);
+        match command_output("ip", &["rule", "show"]) {
+            Some(rules) if rules.contains(fwmark.as_str()) => {}
+            Some(_) => problems.push(format!("no ip rule for marker {}, see setup-firewall", relay.marker)),
+            None => problems.push("run ip rule show failed".to_string()),
+        }
+    }
+    let iptables_rules = command_output("iptables", &["-t", "mangle", "-S"]);
+    let nftables_rules = command_output("nft", &["list", "ruleset"]);
+    let mut ports: Vec<u16> = opts.local_addrs.iter().chain(opts.udp_addrs.iter().map(|(addr, _)| addr)).map(|addr| addr.port()).collect();
+    ports.sort();
+    ports.dedup();
+    for port in ports {
+        let iptables = format!("--on-port {} ", port);
+        let nftables = format!("to :{} ", port);
+        let found = iptables_rules.as_ref().map_or(false, |rules| rules.contains(iptables.as_str()))
+            || nftables_rules.as_ref().map_or(false, |rules| rules.contains(nftables.as_str()));
+        if !found {
+            problems.push(format!("no iptables or nftables tproxy rule to port {}, see setup-firewall", port));
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn check_tproxy(_opts: &Opts, problems: &mut Vec<String>) {
+    problems.push("tproxy is only supported on linux".to_string());
+}
+
+#[cfg(target_os = "linux")]
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn print_options(opts: &Opts) {
+    let relay = opts.relay_args();
+    let addrs: Vec<String> = opts.local_addrs.iter().map(|addr| match opts.port_ranges.iter().find(|(range, _)| range == addr) {
+        Some((_, last_port)) => format!("{}-{}", addr, last_port),
+        None => addr.to_string(),
+    }).collect();
+    println!("listen: {}", addrs.join(", "));
+    if let Mode::Proxy(ref args) = opts.mode {
+        if !args.udp_addr.is_empty() {
+            let udp_addrs: Vec<String> = opts.udp_addrs.iter().map(|(addr, marker)| format!("{} marker {}", addr, marker)).collect();
+            println!("udp listen: {}", udp_addrs.join(", "));
+        }
+    }
+    println!("passwords: {}", opts.password_count());
+    println!("idle timeout: {}s", relay.idle_timeout);
+    if relay.no_udp {
+        println!("udp: disabled");
+    } else {
+        println!("udp timeout: {}s", relay.udp_timeout);
+        println!("max udp size: {}", relay.max_udp_size);
+    }
+    println!("connect timeout: {}s", relay.connect_timeout);
+    println!("tls handshake timeout: {}s", relay.tls_handshake_timeout);
+    println!("marker: {}", relay.marker);
+    println!("outbound marker: {}", opts.tcp_opts.marker);
+    if let Some(bind_addr) = relay.outbound_bind.as_ref() {
+        println!("outbound bind: {}", bind_addr);
+    }
+    if let Some(device) = relay.outbound_device.as_ref() {
+        println!("outbound device: {}", device);
+    }
+    if let Some(addr) = relay.health_addr.as_ref() {
+        println!("health endpoint: {}", addr);
+    }
+    if opts.webhook.is_some() {
+        let events = if opts.webhook_event.is_empty() { "all".to_string() } else { opts.webhook_event.join(",") };
+        println!("webhook: {} events every {}s", events, opts.webhook_interval);
+    }
+    match opts.mode {
+        Mode::Server(ref args) => {
+            println!("certificate: {}", args.cert);
+            println!("key: {}", args.key);
+            if let Some(expiry) = server::cert_expiry(opts) {
+                println!("certificate expiry: {}", expiry);
+            }
+            println!("fallback: {}", args.remote_addr);
+            if !args.alpn.is_empty() {
+                println!("alpn: {}", args.alpn.join(","));
+            }
+            let idle = &opts.idle_durations;
+            println!("idle timeouts: client read {}s, client write {}s, target read {}s, target write {}s", idle.client_read.as_secs(),
+                     idle.client_write.as_secs(), idle.target_read.as_secs(), idle.target_write.as_secs());
+            if let Some(addr) = args.api_addr.as_ref() {
+                println!("grpc api: {}", addr);
+            }
+            if let Some(hosts_file) = args.hosts_file.as_ref() {
+                println!("hosts file: {} {} domains", hosts_file, opts.hosts.count());
+            }
+            if args.target_max_connections > 0 {
+                println!("target limit: {} connections, {} waiting", args.target_max_connections, args.target_queue_size);
+            }
+            if args.ban_threshold > 0 {
+                println!("ban: {} failures in {}s for {}s", args.ban_threshold, args.ban_window, args.ban_time);
+            }
+            if args.first_packet_timeout > 0 {
+                let action = if args.first_packet_action == FirstPacketAction::Drop { "drop" } else { "fallback" };
+                println!("first packet timeout: {}s, then {}", args.first_packet_timeout, action);
+            }
+            if let Some(hook) = args.auth_hook.as_ref() {
+                println!("auth hook: {}, {}s timeout", hook, args.auth_hook_timeout);
+            }
+            if let Some(stats_file) = args.stats_file.as_ref() {
+                println!("stats file: {}", stats_file);
+            }
+            if let Some(range) = args.udp_port_range {
+                println!("udp port range: {}-{}", range.first, range.last);
+            }
+            if args.handshake_rate > 0 {
+                println!("handshake rate: {}/s", args.handshake_rate);
+            }
+            if args.handshake_rate_per_ip > 0 {
+                println!("handshake rate per ip: {}/s", args.handshake_rate_per_ip);
+            }
+            if let Some(addr) = opts.ss_addr.as_ref() {
+                println!("shadowsocks: {}", addr);
+            }
+        }
+        Mode::Proxy(ref args) => {
+            for upstream in opts.upstreams.iter() {
+                let addrs: Vec<String> = upstream.addrs().iter().map(|addr| addr.to_string()).collect();
+                println!("trojan server: {} {}", upstream.name(), addrs.join(","));
+            }
+            if let Some(proxy) = opts.upstream_proxy.as_ref() {
+                println!("upstream proxy: {} {}", if proxy.kind == ProxyKind::Socks5 { "socks5" } else { "http" }, proxy.addr);
+            }
+            if let Some(dns_addr) = args.dns_addr.as_ref() {
+                println!("fake ip dns: {} {}", dns_addr, args.fake_ip_range);
+            }
+            if let Some(remote_dns) = opts.remote_dns.as_ref() {
+                println!("remote dns: {}", remote_dns);
+            }
+            if let Some(route_file) = args.route_file.as_ref() {
+                println!("route file: {}", route_file);
+            }
+            if args.network_check_interval > 0 {
+                println!("network check: every {}s", args.network_check_interval);
+            }
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,147 @@
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::{Error, ErrorKind, Result as IoResult};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = if let Some(pos) = s.find('/') {
+            (&s[..pos], Some(&s[pos + 1..]))
+        } else {
+            (s, None)
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| format!("invalid cidr address:{}", s))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = if let Some(prefix) = prefix {
+            prefix.parse::<u8>().map_err(|_| format!("invalid cidr prefix:{}", s))?
+        } else {
+            max_prefix
+        };
+        if prefix > max_prefix {
+            return Err(format!("invalid cidr prefix:{}", s));
+        }
+        Ok(Cidr::new(addr, prefix))
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix: u8) -> Cidr {
+        let addr = match addr {
+            IpAddr::V4(v4) => IpAddr::V4((u32::from(v4) & mask32(prefix)).into()),
+            IpAddr::V6(v6) => IpAddr::V6((u128::from(v6) & mask128(prefix)).into()),
+        };
+        Cidr { addr, prefix }
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (&self.addr, ip) {
+            (IpAddr::V4(addr), IpAddr::V4(ip)) => u32::from(*ip) & mask32(self.prefix) == u32::from(*addr),
+            (IpAddr::V6(addr), IpAddr::V6(ip)) => u128::from(*ip) & mask128(self.prefix) == u128::from(*addr),
+            // ipv4-mapped and nat64 ipv6 addresses should match ipv4 ranges
+            (IpAddr::V4(_), IpAddr::V6(ip)) => match embedded_v4(ip) {
+                Some(ip) => self.contains(&IpAddr::V4(ip)),
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+}
+
+// written out already masked, as Cidr::new can not be used in a static
+pub static PRIVATE_RANGES: [Cidr; 13] = [
+    Cidr { addr: IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), prefix: 8 },
+    Cidr { addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), prefix: 8 },
+    Cidr { addr: IpAddr::V4(Ipv4Addr::new(100, 64, 0, 0)), prefix: 10 },
+    Cidr { addr: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 0)), prefix: 8 },
+    Cidr { addr: IpAddr::V4(Ipv4Addr::new(169, 254, 0, 0)), prefix: 16 },
+    Cidr { addr: IpAddr::V4(Ipv4Addr::new(172, 16, 0, 0)), prefix: 12 },
+    Cidr { addr: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 0)), prefix: 16 },
+    Cidr { addr: IpAddr::V4(Ipv4Addr::new(224, 0, 0, 0)), prefix: 3 },
+    Cidr { addr: IpAddr::V6(Ipv6Addr::UNSPECIFIED), prefix: 128 },
+    Cidr { addr: IpAddr::V6(Ipv6Addr::LOCALHOST), prefix: 128 },
+    Cidr { addr: IpAddr::V6(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0)), prefix: 7 },
+    Cidr { addr: IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0)), prefix: 10 },
+    Cidr { addr: IpAddr::V6(Ipv6Addr::new(0xff00, 0, 0, 0, 0, 0, 0, 0)), prefix: 8 },
+];
+
+// one range per line, # starts a comment
+pub fn load_list(path: &str) -> IoResult<Vec<Cidr>> {
+    let content = fs::read_to_string(path)?;
+    let mut list = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let cidr = line.parse().map_err(|err| Error::new(ErrorKind::InvalidData, format!("{} at line {} of {}", err, i + 1, path)))?;
+        list.push(cidr);
+    }
+    log::warn!("{} ip ranges loaded from {}", list.len(), path);
+    Ok(list)
+}
+
+// the ipv4 address of an ipv4-mapped ipv6 one, as dual stack sockets show ipv4 peers, other addresses as they are
+pub fn unmap(ip: IpAddr) -> IpAddr {
+    if let IpAddr::V6(v6) = ip {
+        let segments = v6.segments();
+        if segments[..5] == [0, 0, 0, 0, 0] && segments[5] == 0xffff {
+            return IpAddr::V4(Ipv4Addr::new((segments[6] >> 8) as u8, segments[6] as u8, (segments[7] >> 8) as u8, segments[7] as u8));
+        }
+    }
+    ip
+}
+
+// the ipv4 address an ipv4-mapped address or one of the well-known nat64 prefix 64:ff9b::/96 stands for
+fn embedded_v4(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let mapped = segments[..5] == [0, 0, 0, 0, 0] && segments[5] == 0xffff;
+    let nat64 = segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0];
+    if mapped || nat64 {
+        Some(Ipv4Addr::new((segments[6] >> 8) as u8, segments[6] as u8, (segments[7] >> 8) as u8, segments[7] as u8))
+    } else {
+        None
+    }
+}
+
+pub fn is_private(ip: &IpAddr) -> bool {
+    PRIVATE_RANGES.iter().any(|range| range.contains(ip))
+}
+
+fn mask32(prefix: u8) -> u32 {
+    if prefix == 0 {
+        0
+    } else {
+        !0u32 << (32 - prefix as u32)
+    }
+}
+
+fn mask128(prefix: u8) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        !0u128 << (128 - prefix as u32)
+    }
+}
@@ -1,50 +1,488 @@
 use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
-use std::time::{Duration, Instant};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
 
 use clap::Clap;
 use crypto::digest::Digest;
 use crypto::sha2::Sha224;
-use trust_dns_resolver::Resolver;
+use trust_dns_resolver::config::LookupIpStrategy;
 
-pub struct DnsEntry {
-    pub address: IpAddr,
-    pub expired_time: Instant,
-}
+use crate::cidr::{self, Cidr};
+use crate::dns_cache::DnsCache;
+use crate::error::{self, Error};
+use crate::replay_cache::ReplayCache;
+use crate::fake_dns::FakeDns;
+use crate::hosts::Hosts;
+use crate::log_rotate::RotatingFile;
+use crate::{access_log, http_client, log_format, log_level, log_target, password, plugin, security_log, state};
+use crate::password::Password;
+use crate::proto::MAX_UDP_SIZE;
+use crate::resolver;
+use crate::route::{Action, Router};
+use crate::subscription;
+use crate::sys::{self, TcpOpts};
+use crate::upstream_proxy::{Tunnel, UpstreamProxy};
+use crate::upstream::{Balance, MAX_UPSTREAMS, Upstream};
+
+pub const SHA224_PREFIX: &str = "sha224:";
+// each listener takes a token of its own
+pub const MAX_LISTENERS: usize = 16;
 
 #[derive(Clap)]
 #[clap(version = "0.3.2", author = "Hoping White", about = "a trojan implementation using rust")]
 pub struct Opts {
     #[clap(subcommand)]
     pub mode: Mode,
-    #[clap(short, long, help = "log file path")]
+    #[clap(short, long, about = "log file path")]
     pub log_file: Option<String>,
-    #[clap(short = "a", long, help = "listen address for server")]
-    pub local_addr: String,
-    #[clap(short, long, help = "passwords for negotiation")]
-    password: String,
-    #[clap(short = "L", long, default_value = "2", help = "log level, 0 for trace, 1 for debug, 2 for info, 3 for warning, 4 for error, 5 for off")]
+    #[clap(long, about = "where logs go, file, stdout, syslog or journald, defaults to file with --log-file and stdout otherwise")]
+    pub log_target: Option<LogTarget>,
+    #[clap(long, default_value = "text", about = "format of logs to file and stdout, text or json with one object per line")]
+    pub log_format: LogFormat,
+    #[clap(long, about = "security event log file path, with failed handshakes, replays and bans for fail2ban and the like")]
+    pub security_log: Option<String>,
+    #[clap(long, about = "access log file path, one line per finished session")]
+    pub access_log: Option<String>,
+    #[clap(long, about = "where the access log goes, file, stdout, syslog or journald, defaults to file with --access-log")]
+    pub access_log_target: Option<LogTarget>,
+    #[clap(long, default_value = "$remote_addr [$time_local] $protocol $target $bytes_sent $bytes_received $duration", about = "access log format, variables are $time_local, $time_iso8601, $conn_id, $remote_addr, $user, $target, $protocol, $bytes_sent, $bytes_received and $duration")]
+    pub access_log_format: String,
+    #[clap(long, about = "ipfix collector address, a flow record with the client, user, target, bytes and duration is sent for each finished session, e.g. 192.0.2.1:4739")]
+    pub flow_collector: Option<String>,
+    #[clap(long, default_value = "0", about = "observation domain id of exported flows")]
+    pub flow_domain: u32,
+    #[clap(long, about = "http or https url events are posted to as json arrays, sessions opened and closed in server mode, failed handshakes and bans")]
+    pub webhook: Option<String>,
+    #[clap(long, default_value = "5", about = "time in seconds the events of a webhook post are gathered for")]
+    pub webhook_interval: u64,
+    #[clap(long, about = "event posted to --webhook, may be repeated, session_open, session_close, auth_failure, auth_denied, replay, malformed_handshake, ban or unban, all of them by default")]
+    pub webhook_event: Vec<String>,
+    #[clap(long, default_value = "0", about = "size in megabytes at which the log file is rotated, 0 for no limit")]
+    pub log_max_size: u64,
+    #[clap(long, default_value = "never", about = "rotate the log file by time, never, hourly or daily")]
+    pub log_rotate: LogRotate,
+    #[clap(long, default_value = "7", about = "number of rotated log files kept, 0 to keep all")]
+    pub log_keep: usize,
+    #[clap(long, about = "gzip rotated log files")]
+    pub log_compress: bool,
+    #[clap(long, about = "check the options, certificates, trojan servers and tproxy rules of the mode and print them without running it, exits with 1 on problems")]
+    pub check: bool,
+    #[clap(long, about = "run as the windows service of the name, set by service install")]
+    pub service: Option<String>,
+    #[clap(long, about = "run in the background, logs to stdout are lost")]
+    pub daemon: bool,
+    #[clap(long, about = "pid file path, which is locked to keep a single instance running")]
+    pub pid_file: Option<String>,
+    #[clap(long, about = "user to switch to once listeners are bound")]
+    pub user: Option<String>,
+    #[clap(long, about = "group to switch to once listeners are bound, defaults to the group of --user")]
+    pub group: Option<String>,
+    #[clap(long, about = "restrict syscalls with seccomp and file system access with landlock once started, linux only")]
+    pub sandbox: bool,
+    #[clap(short = "L", long, default_value = "2", about = "log level, 0 for trace, 1 for debug, 2 for info, 3 for warning, 4 for error, 5 for off")]
     pub log_level: u8,
-    #[clap(short, long, default_value = "1", help = "set marker used by tproxy")]
-    pub marker: u8,
-    #[clap(short, long, default_value = "120", help = "time in seconds before closing an inactive connection")]
-    pub idle_timeout: u64,
     #[clap(skip)]
-    dns_cache_duration: Duration,
+    password: String,
     #[clap(skip)]
-    sha_pass: String,
+    passwords: HashMap<String, Password>,
     #[clap(skip)]
-    pub pass_len: usize,
+    password_file_time: Option<SystemTime>,
+    #[clap(skip)]
+    pub hosts: Hosts,
+    #[clap(skip)]
+    hosts_file_time: Option<SystemTime>,
+    #[clap(skip)]
+    pub local_addrs: Vec<SocketAddr>,
+    // udp listeners of tproxy mode and their markers, the listen addresses with --marker unless --udp-addr is given
+    #[clap(skip)]
+    pub udp_addrs: Vec<(SocketAddr, u8)>,
+    // listen addresses given with a port range and the last port of it, the listener takes the first one
+    #[clap(skip)]
+    pub port_ranges: Vec<(SocketAddr, u16)>,
     #[clap(skip)]
     pub back_addr: Option<SocketAddr>,
     #[clap(skip)]
-    pub dns_cache: HashMap<String, DnsEntry>,
+    pub back_path: Option<String>,
+    #[clap(skip)]
+    pub ss_addr: Option<SocketAddr>,
+    #[clap(skip)]
+    pub upstreams: Vec<Upstream>,
+    #[clap(skip)]
+    pub upstream_index: usize,
+    #[clap(skip)]
+    pub upstream_proxy: Option<UpstreamProxy>,
+    #[clap(skip)]
+    next_upstream: usize,
+    #[clap(skip)]
+    pub dns_cache: DnsCache,
+    #[clap(skip)]
+    pub replay_cache: ReplayCache,
     #[clap(skip)]
     pub udp_header_len: usize,
     #[clap(skip)]
     pub empty_addr: Option<SocketAddr>,
     #[clap(skip)]
+    pub fake_dns: FakeDns,
+    #[clap(skip)]
+    pub remote_dns: Option<SocketAddr>,
+    #[clap(skip)]
+    pub router: Router,
+    #[clap(skip)]
+    pub route_check_duration: Duration,
+    #[clap(skip)]
+    block_ports: Vec<u16>,
+    #[clap(skip)]
+    allow_ports: Vec<u16>,
+    #[clap(skip)]
+    allow_ips: Vec<Cidr>,
+    #[clap(skip)]
+    deny_ips: Vec<Cidr>,
+    #[clap(skip)]
     pub idle_duration: Duration,
+    #[clap(skip)]
+    pub idle_durations: IdleDurations,
+    #[clap(skip)]
+    pub udp_duration: Duration,
+    #[clap(skip)]
+    pub tcp_opts: TcpOpts,
+    #[clap(skip)]
+    user_routes: HashMap<String, UserRoute>,
+    #[clap(skip)]
+    pub connect_duration: Duration,
+    #[clap(skip)]
+    pub handshake_duration: Duration,
+    #[clap(skip)]
+    pub attempt_duration: Duration,
+    #[clap(skip)]
+    pub pending_limit: usize,
+    #[clap(skip)]
+    interface_addrs: Vec<IpAddr>,
+}
+
+// traffic a labeled user may relay since start
+#[derive(Clone)]
+pub struct UserQuota {
+    pub user: String,
+    pub bytes: u64,
+}
+
+impl FromStr for UserQuota {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pos = s.find('=').ok_or_else(|| format!("invalid user quota:{}", s))?;
+        let megabytes: u64 = s[pos + 1..].parse().map_err(|_| format!("invalid user quota:{}", s))?;
+        Ok(UserQuota {
+            user: s[..pos].to_string(),
+            bytes: megabytes * 1024 * 1024,
+        })
+    }
+}
+
+// destinations a labeled user is limited to
+#[derive(Clone)]
+pub struct UserAllow {
+    pub user: String,
+    pub ranges: Vec<Cidr>,
+}
+
+impl FromStr for UserAllow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pos = s.find('=').ok_or_else(|| format!("invalid user allow:{}", s))?;
+        let mut ranges = Vec::new();
+        for range in s[pos + 1..].split(',') {
+            ranges.push(range.trim().parse().map_err(|_| format!("invalid user allow:{}", s))?);
+        }
+        Ok(UserAllow {
+            user: s[..pos].to_string(),
+            ranges,
+        })
+    }
+}
+
+// source address of the connections of a labeled user to targets
+#[derive(Clone)]
+pub struct UserBind {
+    pub user: String,
+    pub addr: IpAddr,
+}
+
+impl FromStr for UserBind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pos = s.find('=').ok_or_else(|| format!("invalid user bind:{}", s))?;
+        let addr = s[pos + 1..].parse().map_err(|_| format!("invalid user bind:{}", s))?;
+        Ok(UserBind {
+            user: s[..pos].to_string(),
+            addr,
+        })
+    }
+}
+
+// a udp listener of the proxy and the marker of the packets relayed from it
+#[derive(Clone)]
+pub struct UdpListen {
+    pub addr: SocketAddr,
+    pub marker: Option<u8>,
+}
+
+impl FromStr for UdpListen {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, marker) = match s.rfind('=') {
+            Some(pos) => (&s[..pos], Some(s[pos + 1..].parse().map_err(|_| format!("invalid udp address:{}", s))?)),
+            None => (s, None),
+        };
+        Ok(UdpListen {
+            addr: addr.parse().map_err(|_| format!("invalid udp address:{}", s))?,
+            marker,
+        })
+    }
+}
+
+// local ports of outbound udp sockets, a port or first-last
+#[derive(Copy, Clone)]
+pub struct PortRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid port range:{}", s);
+        let (first, last) = match s.find('-') {
+            Some(pos) => (s[..pos].parse().map_err(|_| invalid())?, s[pos + 1..].parse().map_err(|_| invalid())?),
+            None => {
+                let port = s.parse().map_err(|_| invalid())?;
+                (port, port)
+            }
+        };
+        if first == 0 || last < first {
+            return Err(invalid());
+        }
+        Ok(PortRange { first, last })
+    }
+}
+
+// how long each direction of a server connection may be quiet, the connection is idle once all of them are
+#[derive(Default)]
+pub struct IdleDurations {
+    pub client_read: Duration,
+    pub client_write: Duration,
+    pub target_read: Duration,
+    pub target_write: Duration,
+}
+
+// how the targets of a user are reached, users without one are treated like everyone else
+#[derive(Default)]
+pub struct UserRoute {
+    pub allow: Vec<Cidr>,
+    pub bind: Option<IpAddr>,
+}
+
+impl UserRoute {
+    pub fn allows(&self, ip: &IpAddr) -> bool {
+        self.allow.is_empty() || self.allow.iter().any(|range| range.contains(ip))
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum IpStrategy {
+    PreferIpv4,
+    PreferIpv6,
+    OnlyIpv4,
+    OnlyIpv6,
+}
+
+impl FromStr for IpStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "prefer-ipv4" => Ok(IpStrategy::PreferIpv4),
+            "prefer-ipv6" => Ok(IpStrategy::PreferIpv6),
+            "only-ipv4" => Ok(IpStrategy::OnlyIpv4),
+            "only-ipv6" => Ok(IpStrategy::OnlyIpv6),
+            _ => Err(format!("invalid ip strategy:{}", s)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum TransparentMode {
+    Tproxy,
+    Redirect,
+    Socks5,
+    Http,
+    Tun,
+}
+
+impl FromStr for TransparentMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tproxy" => Ok(TransparentMode::Tproxy),
+            "redirect" => Ok(TransparentMode::Redirect),
+            "socks5" => Ok(TransparentMode::Socks5),
+            "http" => Ok(TransparentMode::Http),
+            "tun" => Ok(TransparentMode::Tun),
+            _ => Err(format!("invalid transparent mode:{}", s)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum LogRotate {
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl FromStr for LogRotate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(LogRotate::Never),
+            "hourly" => Ok(LogRotate::Hourly),
+            "daily" => Ok(LogRotate::Daily),
+            _ => Err(format!("invalid log rotate:{}", s)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum LogTarget {
+    File,
+    Stdout,
+    Syslog,
+    Journald,
+}
+
+impl FromStr for LogTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(LogTarget::File),
+            "stdout" => Ok(LogTarget::Stdout),
+            "syslog" => Ok(LogTarget::Syslog),
+            "journald" => Ok(LogTarget::Journald),
+            _ => Err(format!("invalid log target:{}", s)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum BanAction {
+    Drop,
+    Fallback,
+}
+
+impl FromStr for BanAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(BanAction::Drop),
+            "fallback" => Ok(BanAction::Fallback),
+            _ => Err(format!("invalid ban action:{}", s)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum FirstPacketAction {
+    Fallback,
+    Drop,
+}
+
+impl FromStr for FirstPacketAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fallback" => Ok(FirstPacketAction::Fallback),
+            "drop" => Ok(FirstPacketAction::Drop),
+            _ => Err(format!("invalid first packet action:{}", s)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum ShadowsocksMethod {
+    Aes128Gcm,
+    Aes256Gcm,
+    Chacha20Poly1305,
+}
+
+impl FromStr for ShadowsocksMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aes-128-gcm" => Ok(ShadowsocksMethod::Aes128Gcm),
+            "aes-256-gcm" => Ok(ShadowsocksMethod::Aes256Gcm),
+            "chacha20-ietf-poly1305" => Ok(ShadowsocksMethod::Chacha20Poly1305),
+            _ => Err(format!("invalid shadowsocks method:{}", s)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("invalid log format:{}", s)),
+        }
+    }
+}
+
+impl LogRotate {
+    // the log file is rotated when this changes
+    pub fn period(&self) -> String {
+        match self {
+            LogRotate::Never => String::new(),
+            LogRotate::Hourly => chrono::Local::now().format("%Y%m%d%H").to_string(),
+            LogRotate::Daily => chrono::Local::now().format("%Y%m%d").to_string(),
+        }
+    }
+}
+
+impl IpStrategy {
+    pub fn lookup_strategy(&self) -> LookupIpStrategy {
+        match self {
+            IpStrategy::OnlyIpv4 => LookupIpStrategy::Ipv4Only,
+            IpStrategy::OnlyIpv6 => LookupIpStrategy::Ipv6Only,
+            _ => LookupIpStrategy::Ipv4AndIpv6,
+        }
+    }
+
+    pub fn sort(&self, addrs: &mut Vec<IpAddr>) {
+        match self {
+            IpStrategy::PreferIpv4 => addrs.sort_by_key(|addr| !addr.is_ipv4()),
+            IpStrategy::PreferIpv6 => addrs.sort_by_key(|addr| !addr.is_ipv6()),
+            IpStrategy::OnlyIpv4 => addrs.retain(|addr| addr.is_ipv4()),
+            IpStrategy::OnlyIpv6 => addrs.retain(|addr| addr.is_ipv6()),
+        }
+    }
 }
 
 #[derive(Clap)]
@@ -53,29 +491,392 @@ pub enum Mode {
     Proxy(ProxyArgs),
     #[clap(name = "server", about = "run in server mode")]
     Server(ServerArgs),
+    #[clap(name = "setup-firewall", about = "print or apply the tproxy rules for proxy mode")]
+    SetupFirewall(FirewallArgs),
+    #[clap(name = "service", about = "install or uninstall the windows service")]
+    Service(ServiceArgs),
+    #[clap(name = "hash", about = "print the sha224 digests of passwords, as sent in trojan requests")]
+    Hash(HashArgs),
+    #[clap(name = "stats", about = "print the traffic of each user kept in a --stats-file of server mode")]
+    Stats(StatsArgs),
+    #[clap(name = "check-config", about = "check the mode given after -- without running it, the same as --check")]
+    CheckConfig(CheckArgs),
+    #[clap(name = "selftest", about = "relay tcp and udp traffic through a local server with a temporary certificate to verify the build")]
+    Selftest(SelftestArgs),
+    #[clap(name = "bench", about = "measure handshake latency, tcp throughput and udp loss through a trojan server")]
+    Bench(BenchArgs),
+}
+
+#[derive(Clap)]
+pub struct HashArgs {
+    #[clap(about = "passwords to hash, read from stdin one per line if none is given, so that they stay out of the shell history")]
+    pub passwords: Vec<String>,
+}
+
+#[derive(Clap)]
+pub struct StatsArgs {
+    #[clap(about = "path of the file given as --stats-file")]
+    pub file: String,
+    #[clap(long, about = "print a json array instead of one line per user")]
+    pub json: bool,
+}
+
+#[derive(Clap)]
+pub struct CheckArgs {
+    #[clap(last = true, about = "mode and its options, e.g. -- server -a [::]:443 -p secret -c cert.pem -k key.pem")]
+    pub args: Vec<String>,
+}
+
+#[derive(Clap)]
+pub struct SelftestArgs {
+    #[clap(long, default_value = "5", about = "time in seconds to wait for the server and each test")]
+    pub timeout: u64,
+    #[clap(long, default_value = "1048576", about = "bytes of tcp data echoed through the server")]
+    pub size: usize,
+}
+
+#[derive(Clap)]
+pub struct BenchArgs {
+    #[clap(short, long, about = "trojan server address, e.g. example.com:443")]
+    pub server: Option<String>,
+    #[clap(short, long, about = "password of the trojan server, read from TROJAN_PASSWORD if not given")]
+    pub password: Option<String>,
+    #[clap(long, about = "server name for tls, the host of --server by default")]
+    pub sni: Option<String>,
+    #[clap(short, long, about = "address of a bench target reachable from the server, started elsewhere with --serve")]
+    pub target: Option<String>,
+    #[clap(long, about = "run as the bench target listening on this address for tcp and udp, instead of measuring")]
+    pub serve: Option<String>,
+    #[clap(long, default_value = "5", about = "number of handshakes measured")]
+    pub count: usize,
+    #[clap(long, default_value = "16", about = "megabytes uploaded and downloaded for tcp throughput")]
+    pub size: usize,
+    #[clap(long, about = "skip the udp test")]
+    pub no_udp: bool,
+    #[clap(long, default_value = "1000", about = "number of udp packets sent")]
+    pub udp_packets: usize,
+    #[clap(long, default_value = "1000", about = "bytes of each udp packet")]
+    pub udp_size: usize,
+    #[clap(long, default_value = "0", about = "SO_SNDBUF in bytes of the connections to the server, 0 to keep the system default")]
+    pub send_buffer: u32,
+    #[clap(long, default_value = "0", about = "SO_RCVBUF in bytes of the connections to the server, 0 to keep the system default")]
+    pub recv_buffer: u32,
+    #[clap(long, default_value = "10", about = "time in seconds to wait for connections and data")]
+    pub timeout: u64,
+}
+
+#[derive(Clap)]
+pub struct ServiceArgs {
+    #[clap(subcommand)]
+    pub action: ServiceAction,
+}
+
+#[derive(Clap)]
+pub enum ServiceAction {
+    #[clap(name = "install", about = "install a service running trojan with the global options and the mode given after --")]
+    Install(ServiceInstallArgs),
+    #[clap(name = "uninstall", about = "uninstall the service")]
+    Uninstall(ServiceNameArgs),
+}
+
+#[derive(Clap)]
+pub struct ServiceInstallArgs {
+    #[clap(long, default_value = "trojan-rs", about = "service name")]
+    pub name: String,
+    #[clap(last = true, about = "mode and its options, e.g. -- proxy -a 127.0.0.1:1080 -p secret -H example.com --transparent-mode socks5")]
+    pub args: Vec<String>,
+}
+
+#[derive(Clap)]
+pub struct ServiceNameArgs {
+    #[clap(long, default_value = "trojan-rs", about = "service name")]
+    pub name: String,
+}
+
+#[derive(Clap)]
+pub struct FirewallArgs {
+    #[clap(short = "a", long, about = "listen address of the proxy, only the port is used")]
+    pub local_addr: String,
+    #[clap(short, long, default_value = "1", about = "marker set by the proxy, the same as its --marker")]
+    pub marker: u8,
+    #[clap(long, about = "outbound marker of the proxy, the same as its --outbound-marker")]
+    pub outbound_marker: Option<u8>,
+    #[clap(long, about = "the proxy runs with --no-udp, only tcp is redirected")]
+    pub no_udp: bool,
+    #[clap(long, about = "apply the rules instead of printing them")]
+    pub apply: bool,
+    #[clap(long, about = "generate nftables rules instead of iptables ones")]
+    pub nftables: bool,
+    #[clap(long, about = "generate rules for ipv6 as well")]
+    pub ipv6: bool,
+    #[clap(long, default_value = "100", about = "routing table used to deliver marked packets locally")]
+    pub table: u32,
+    #[clap(long, about = "addresses in cidr format bypassing the proxy besides the private ones, e.g. the trojan server")]
+    pub bypass: Vec<Cidr>,
+}
+
+#[derive(Clap, Clone)]
+pub struct RelayArgs {
+    #[clap(short = "a", long, required = true, about = "listen addresses, may be repeated for several addresses or ports, [::]:port listens on both ipv4 and ipv6, ip:first-last takes a port range in server mode")]
+    pub local_addr: Vec<String>,
+    #[clap(short, long, about = "passwords for negotiation, or its digest printed by the hash subcommand as sha224:<hex>, read from the TROJAN_PASSWORD environment variable if not given")]
+    pub password: Option<String>,
+    #[clap(long, about = "file of passwords, one [label:]password per line, - to read them from stdin, reloaded once changed in server mode")]
+    pub password_file: Option<String>,
+    #[clap(short, long, default_value = "1", about = "set marker used by tproxy")]
+    pub marker: u8,
+    #[clap(long, about = "marker for connections to the trojan server in proxy mode and to targets in server mode, defaults to marker")]
+    pub outbound_marker: Option<u8>,
+    #[clap(short, long, default_value = "120", about = "time in seconds before closing an inactive connection")]
+    pub idle_timeout: u64,
+    #[clap(long, about = "disable udp relay, udp associate requests are rejected in server mode and udp is not listened in proxy mode")]
+    pub no_udp: bool,
+    #[clap(long, default_value = "60", about = "time in seconds before closing an inactive udp session")]
+    pub udp_timeout: u64,
+    #[clap(long, default_value = "10", about = "time in seconds before giving up connecting to a server or target")]
+    pub connect_timeout: u64,
+    #[clap(long, default_value = "10", about = "time in seconds before closing a connection whose tls handshake is not done")]
+    pub tls_handshake_timeout: u64,
+    #[clap(long, about = "enable tcp fast open on the server listener and on connections to the trojan server, the happy eyeballs fallback is lost as connecting always succeeds at once")]
+    pub fast_open: bool,
+    #[clap(long, default_value = "0", about = "time in seconds before sending tcp keepalive probes on idle connections, 0 to disable")]
+    pub tcp_keepalive: u32,
+    #[clap(long, default_value = "0", about = "time in seconds before dropping a connection with unacknowledged data, sets TCP_USER_TIMEOUT, 0 to disable")]
+    pub tcp_user_timeout: u32,
+    #[clap(long, default_value = "0", about = "socket send buffer size in bytes, 0 to use the system default")]
+    pub send_buffer: u32,
+    #[clap(long, default_value = "0", about = "socket receive buffer size in bytes, 0 to use the system default")]
+    pub recv_buffer: u32,
+    #[clap(long, about = "tcp congestion control algorithm, e.g. bbr, linux only")]
+    pub congestion: Option<String>,
+    #[clap(long, about = "source address for connections to targets in server mode and to the trojan server in proxy mode")]
+    pub outbound_bind: Option<IpAddr>,
+    #[clap(long, about = "network interface for connections to targets in server mode and to the trojan server in proxy mode, linux only")]
+    pub outbound_device: Option<String>,
+    #[clap(long, about = "dscp value of relayed tcp traffic, 0 to 63")]
+    pub tcp_dscp: Option<u8>,
+    #[clap(long, about = "dscp value of relayed udp traffic, 0 to 63")]
+    pub udp_dscp: Option<u8>,
+    #[clap(long, default_value = "8192", about = "max udp datagram size in bytes up to 65535, larger datagrams are dropped instead of truncated")]
+    pub max_udp_size: usize,
+    #[clap(long, default_value = "prefer-ipv4", about = "address family used for resolving, prefer-ipv4, prefer-ipv6, only-ipv4 or only-ipv6")]
+    pub ip_strategy: IpStrategy,
+    #[clap(long, default_value = "250", about = "time in milliseconds before trying the next address when connecting, see RFC 8305")]
+    pub attempt_delay: u64,
+    #[clap(long, default_value = "30", about = "time in seconds to keep relaying existing connections once stopping, new ones are not accepted, 0 to close them at once")]
+    pub drain_timeout: u64,
+    #[clap(long, about = "address of an http endpoint for health checks, /healthz for liveness and /readyz for readiness, e.g. 127.0.0.1:8080")]
+    pub health_addr: Option<String>,
+    #[clap(long, default_value = "1048576", about = "max bytes queued for the slower side of a connection, reading from the other side pauses until it drains")]
+    pub max_pending: usize,
+    #[clap(long, default_value = "0", about = "max bytes queued by all connections together, above it new connections wait, queues shrink and idle connections queueing the most are closed, 0 to disable")]
+    pub memory_budget: usize,
 }
 
 #[derive(Clap)]
 pub struct ProxyArgs {
-    #[clap(short = "H", long, help = "trojan server hostname")]
-    pub hostname: String,
+    #[clap(flatten)]
+    pub relay: RelayArgs,
+    #[clap(short = "H", long, about = "trojan server hostname, [password@]hostname[:port] or trojan://password@hostname[:port][?sni=name][#label], the port can be a range like 20000-21000 to hop among")]
+    pub hostname: Option<String>,
+    #[clap(long, default_value = "tproxy", about = "how traffic is sent to the proxy, tproxy, redirect for iptables REDIRECT and DNAT rules or pf rdr rules, falling back to tun on macos without pf natlook, socks5 or http for clients configured to use a proxy, or tun for the packets routed to a tun device, where the listen addresses serve socks5, only tproxy and tun support udp")]
+    pub transparent_mode: TransparentMode,
+    #[clap(long, about = "name of the tun device in tun mode, tun0 if not given, utunN or utun for the first free one on macos")]
+    pub tun_name: Option<String>,
+    #[clap(long, default_value = "1500", about = "mtu of the tun device in tun mode, tcp segments sent to it are sized by it")]
+    pub tun_mtu: usize,
+    #[clap(short = "R", long, default_value = "600", about = "time in seconds before resolving trojan server hostname again")]
+    pub dns_refresh_time: u64,
+    #[clap(long, about = "backup trojan servers in the same format as hostname, used in order when the current one keeps failing")]
+    pub upstream: Vec<String>,
+    #[clap(long, default_value = "30", about = "time in seconds before switching to another port of trojan servers given with a port range, new connections use it")]
+    pub hop_interval: u64,
+    #[clap(long, default_value = "60", about = "time in seconds between probing the preferred trojan server after failing over")]
+    pub failback_time: u64,
+    #[clap(long, default_value = "failover", about = "how new connections choose trojan servers, failover, round-robin, least-connections or latency")]
+    pub balance: Balance,
+    #[clap(long, default_value = "0", about = "number of idle tls connections kept to the current trojan server, so that new connections skip the handshake")]
+    pub pool_size: usize,
+    #[clap(long, default_value = "60", about = "time in seconds before an idle pooled connection is dropped, keep it below the server idle timeout")]
+    pub pool_idle_time: u64,
+    #[clap(long, default_value = "5", about = "time in seconds between checks of the local address used to reach the trojan servers, once it changes the pool is made again and connections from a gone address are closed, 0 to disable")]
+    pub network_check_interval: u64,
+    #[clap(long, about = "http or https url of a subscription listing trojan:// urls, plain or base64 encoded, the servers are used after the ones given by hostname and upstream")]
+    pub subscription: Option<String>,
+    #[clap(long, default_value = "3600", about = "time in seconds between fetching the subscription again")]
+    pub subscription_time: u64,
+    #[clap(long, default_value = "30", about = "time in seconds between tls handshake checks against every trojan server, 0 to disable")]
+    pub health_check_time: u64,
+    #[clap(long, default_value = "5", about = "time in seconds before a health check is considered failed")]
+    pub health_check_timeout: u64,
+    #[clap(long, about = "listen address for the fake ip dns server")]
+    pub dns_addr: Option<String>,
+    #[clap(long, default_value = "198.18.0.0/15", about = "address range used by the fake ip dns server")]
+    pub fake_ip_range: Cidr,
+    #[clap(long, about = "udp listen addresses in tproxy mode instead of the listen addresses, may be repeated, ip:port=marker sets the marker of the packets relayed from it, defaults to --marker")]
+    pub udp_addr: Vec<UdpListen>,
+    #[clap(long, about = "dns server address, queries to port 53 are sent to it through the tunnel")]
+    pub remote_dns: Option<String>,
+    #[clap(long, about = "route rule file, each line is 'type,value,action' or 'final,action', type can be domain, domain-suffix, domain-keyword, geosite, ip-cidr, geoip or port, action can be proxy, proxy:name for the trojan server of that label or hostname, direct or block")]
+    pub route_file: Option<String>,
+    #[clap(long, about = "maxmind country database used by geoip route rules, e.g. GeoLite2-Country.mmdb")]
+    pub geoip_file: Option<String>,
+    #[clap(long, about = "v2ray geosite.dat file used by geosite route rules")]
+    pub geosite_file: Option<String>,
+    #[clap(long, about = "hosts format or domain list files, connections and dns queries to the domains are blocked")]
+    pub block_list: Vec<String>,
+    #[clap(long, about = "address of an http endpoint serving a proxy auto-config file generated from the route rules at /proxy.pac, e.g. 127.0.0.1:8081, socks5 and http transparent modes only")]
+    pub pac_addr: Option<String>,
+    #[clap(long, about = "file the addresses and health of the trojan servers and the fake ips are saved to on exit and loaded from on start, so that a restart does not begin cold")]
+    pub state_file: Option<String>,
+    #[clap(long, default_value = "5", about = "time in seconds between checking route files for changes, 0 to disable reloading")]
+    pub route_check_time: u64,
+    #[clap(long, default_value = "direct", about = "action for private, loopback and link-local destinations regardless of route rules, proxy, direct or block")]
+    pub lan_action: Action,
+    #[clap(long, about = "sniff tls sni and http host of connections to port 80 and 443, and send the domain to the server instead of the ip")]
+    pub sniff: bool,
+    #[clap(long, default_value = "114.114.114.114:53", about = "dns server used by the fake ip dns server for domains routed directly")]
+    pub direct_dns: String,
+    #[clap(long, about = "frame tcp data sent through trojan servers and pad the first packets to random lengths, the servers must be trojan-rs supporting it")]
+    pub padding: bool,
+    #[clap(long, default_value = "0", about = "time in seconds a tcp connection through a trojan server may go quiet before a keepalive is sent on it and answered by the server, so that nat mappings and firewalls along the path do not drop it, needs --padding, 0 to disable")]
+    pub tunnel_keepalive: u64,
+    #[clap(long, about = "send udp with UDP_OVER_TCP requests, which keep the stream to trojan servers open with keepalives instead of closing it once udp is idle, the servers must be trojan-rs supporting it")]
+    pub udp_over_tcp: bool,
+    #[clap(long, default_value = "15", about = "time in seconds between keepalives of udp streams with --udp-over-tcp, a stream without answer for three times it is closed")]
+    pub udp_keepalive: u64,
+    #[clap(long, about = "sip003 plugin started for each trojan server, e.g. v2ray-plugin, connections to the server go through the local port the plugin listens on")]
+    pub plugin: Option<String>,
+    #[clap(long, about = "socks5 or http proxy the connections to trojan servers go through, socks5://[user:password@]host:port or http://[user:password@]host:port, e.g. socks5://127.0.0.1:1081")]
+    pub upstream_proxy: Option<String>,
+    #[clap(long, about = "options passed to the plugin in SS_PLUGIN_OPTIONS, e.g. 'mode=websocket;host=example.com'")]
+    pub plugin_opts: Option<String>,
 }
 
 #[derive(Clap)]
 pub struct ServerArgs {
-    #[clap(short, long, help = "certificate file path, This should contain PEM-format certificates in the right order (the first certificate should certify KEYFILE, the last should be a root CA")]
+    #[clap(flatten)]
+    pub relay: RelayArgs,
+    #[clap(short, long, about = "certificate file path, This should contain PEM-format certificates in the right order (the first certificate should certify KEYFILE, the last should be a root CA")]
     pub cert: String,
-    #[clap(short, long, help = "private key file path,  This should be a RSA private key or PKCS8-encoded private key, in PEM format.")]
+    #[clap(short, long, about = "private key file path,  This should be a RSA private key or PKCS8-encoded private key, in PEM format.")]
     pub key: String,
-    #[clap(short, long, default_value = "127.0.0.1:80", help = "http backend server address")]
+    #[clap(short, long, default_value = "127.0.0.1:80", about = "http backend server address, or unix:<path> for a unix socket")]
     pub remote_addr: String,
-    #[clap(short, long, default_value = "300", help = "time in seconds for dns query cache")]
+    #[clap(short, long, default_value = "300", about = "maximum time in seconds for dns query cache")]
     dns_cache_time: u64,
-    #[clap(short = "n", long, help = "alpn protocol supported")]
+    #[clap(long, default_value = "10", about = "minimum time in seconds for dns query cache")]
+    dns_min_time: u64,
+    #[clap(long, default_value = "30", about = "time in seconds for caching domains without addresses of the record types --ip-strategy asks for")]
+    dns_negative_time: u64,
+    #[clap(long, default_value = "10240", about = "maximum number of domains in dns query cache")]
+    dns_cache_size: usize,
+    #[clap(long, about = "hosts format file giving the addresses of target domains instead of the resolver, 0.0.0.0 or :: blocks them and *.example.com takes subdomains, reloaded once changed")]
+    pub hosts_file: Option<String>,
+    #[clap(short = "n", long, about = "alpn protocol supported")]
     pub alpn: Vec<String>,
+    #[clap(long, about = "allow trojan requests to private, loopback and link-local destinations")]
+    pub allow_private: bool,
+    #[clap(long, use_delimiter = true, about = "destination ports refused by the server, e.g. 25,465,587")]
+    block_ports: Vec<u16>,
+    #[clap(long, use_delimiter = true, about = "destination ports allowed by the server, all ports are allowed if empty")]
+    allow_ports: Vec<u16>,
+    #[clap(long, use_delimiter = true, about = "client ip ranges allowed to connect, e.g. 10.0.0.0/8,2001:db8::/32, all are allowed if empty")]
+    allow_ips: Vec<Cidr>,
+    #[clap(long, about = "file of client ip ranges allowed to connect, one per line")]
+    allow_ips_file: Option<String>,
+    #[clap(long, use_delimiter = true, about = "client ip ranges dropped before the tls handshake, e.g. scanner ranges")]
+    deny_ips: Vec<Cidr>,
+    #[clap(long, about = "file of client ip ranges dropped before the tls handshake, one per line")]
+    deny_ips_file: Option<String>,
+    #[clap(long, default_value = "0", about = "failed trojan handshakes of a client ip within --ban-window to get it banned, 0 to disable")]
+    pub ban_threshold: usize,
+    #[clap(long, default_value = "60", about = "time in seconds within which failed handshakes are counted")]
+    pub ban_window: u64,
+    #[clap(long, default_value = "600", about = "time in seconds a client ip stays banned")]
+    pub ban_time: u64,
+    #[clap(long, default_value = "drop", about = "what to do with connections of banned ips, drop them before tls, or fallback to treat them as unauthenticated whatever they send")]
+    pub ban_action: BanAction,
+    #[clap(long, default_value = "0", about = "new connections accepted per second from all clients, those beyond are dropped before the tls handshake, 0 for no limit")]
+    pub handshake_rate: u32,
+    #[clap(long, default_value = "0", about = "new connections accepted at once after a quiet while under --handshake-rate, 0 for a second of the rate")]
+    pub handshake_burst: u32,
+    #[clap(long, default_value = "0", about = "new connections accepted per second from each client ip, those beyond are dropped before the tls handshake, 0 for no limit")]
+    pub handshake_rate_per_ip: u32,
+    #[clap(long, default_value = "0", about = "new connections accepted at once from a client ip under --handshake-rate-per-ip, 0 for a second of the rate")]
+    pub handshake_burst_per_ip: u32,
+    #[clap(long, about = "file kept up to date with the banned ips, one per line, for ipset scripts and the like")]
+    pub blocklist_file: Option<String>,
+    #[clap(long, default_value = "0", about = "time in seconds to wait for the trojan request once the tls handshake is done, 0 to wait as long as the idle timeout")]
+    pub first_packet_timeout: u64,
+    #[clap(long, default_value = "fallback", about = "what to do with connections sending no request within --first-packet-timeout, fallback to hand them to the fallback server as they are, so that they time out like on it, or drop to close them")]
+    pub first_packet_action: FirstPacketAction,
+    #[clap(long, default_value = "0", about = "bytes the first read after the tls handshake takes at least, shorter ones go to the fallback at once instead of waiting for the rest of a request, 0 to wait")]
+    pub first_packet_min_len: usize,
+    #[clap(long, default_value = "300", about = "time in seconds to remember first packets with a tls client hello, sending one of them again is taken as a replay and passed to the fallback, 0 to disable")]
+    replay_window: u64,
+    #[clap(long, default_value = "100000", about = "maximum number of first packets remembered for replay detection")]
+    replay_cache_size: usize,
+    #[clap(long, about = "command or http[s] url asked about each authenticated connection, a command gets the user label, - without one, and the client ip as arguments and allows it with exit status 0, a url gets them as user and ip of a GET query and allows it with a 2xx status, anything else or a failure closes the connection")]
+    pub auth_hook: Option<String>,
+    #[clap(long, default_value = "5", about = "time in seconds to wait for --auth-hook before closing the connection")]
+    pub auth_hook_timeout: u64,
+    #[clap(long, about = "local ports of the udp sockets to targets, e.g. 40000-50000, for firewalls opening exactly that range, a udp session fails once all of them are taken")]
+    pub udp_port_range: Option<PortRange>,
+    #[clap(long, default_value = "0", about = "max udp sessions of a client address in server mode, the least recently active one is closed beyond it, 0 for no limit")]
+    pub max_udp_sessions_per_user: usize,
+    #[clap(long, default_value = "0", about = "max tcp connections to a single target host and port in server mode, more wait for one of them to close, 0 for no limit")]
+    pub target_max_connections: usize,
+    #[clap(long, default_value = "32", about = "max connections waiting for a target at --target-max-connections, more are closed")]
+    pub target_queue_size: usize,
+    #[clap(long, about = "time in seconds a connection may go without reading from the client, defaults to --idle-timeout, a connection is closed once all four directions are idle")]
+    pub client_read_timeout: Option<u64>,
+    #[clap(long, about = "time in seconds a connection may go without writing to the client, defaults to --idle-timeout")]
+    pub client_write_timeout: Option<u64>,
+    #[clap(long, about = "time in seconds a connection may go without reading from the target, defaults to --idle-timeout, e.g. longer for long polling")]
+    pub target_read_timeout: Option<u64>,
+    #[clap(long, about = "time in seconds a connection may go without writing to the target, defaults to --idle-timeout")]
+    pub target_write_timeout: Option<u64>,
+    #[clap(long, about = "traffic in megabytes a labeled user of --password-file may relay since start, e.g. alice=1024, its connections are closed beyond it")]
+    pub user_quota: Vec<UserQuota>,
+    #[clap(long, about = "file the traffic of each labeled user is added to and kept in across restarts, updated in place every second, read with the stats subcommand")]
+    pub stats_file: Option<String>,
+    #[clap(long, about = "destination ip ranges a labeled user of --password-file is limited to, e.g. alice=10.0.0.0/8,192.0.2.0/24, domains are checked once resolved")]
+    pub user_allow: Vec<UserAllow>,
+    #[clap(long, about = "source address of the connections of a labeled user to targets, e.g. alice=192.0.2.1, overriding --outbound-bind")]
+    pub user_bind: Vec<UserBind>,
+    #[clap(long, about = "unix socket path for admin commands, e.g. bans, unix only")]
+    pub admin_socket: Option<String>,
+    #[clap(long, about = "token of the dashboard served on --health-addr, opened at http://<health-addr>/dashboard?token=<token>, anyone with it sees the users and their traffic")]
+    pub dashboard_token: Option<String>,
+    #[clap(long, about = "address of a grpc api speaking the stats service of v2ray and the user service of trojan-go, e.g. 127.0.0.1:10085, for panels counting the traffic of labeled users, there is no authentication so keep it local")]
+    pub api_addr: Option<String>,
+    #[clap(long, about = "address of a second listener for shadowsocks aead clients, e.g. 0.0.0.0:8388, tcp only")]
+    pub ss_addr: Option<String>,
+    #[clap(long, about = "password of the shadowsocks listener, [label:]password where the label names the user like in --password-file")]
+    pub ss_password: Option<String>,
+    #[clap(long, default_value = "chacha20-ietf-poly1305", about = "cipher of the shadowsocks listener, aes-128-gcm, aes-256-gcm or chacha20-ietf-poly1305")]
+    pub ss_method: ShadowsocksMethod,
 }
 
 impl Opts {
+    // no access log without --access-log or --access-log-target
+    pub fn access_log_target(&self) -> Option<LogTarget> {
+        match self.access_log_target {
+            Some(target) => Some(target),
+            None if self.access_log.is_some() => Some(LogTarget::File),
+            None => None,
+        }
+    }
+
+    pub fn log_target(&self) -> LogTarget {
+        match self.log_target {
+            Some(target) => target,
+            None if self.log_file.is_some() => LogTarget::File,
+            None => LogTarget::Stdout,
+        }
+    }
+
     pub fn server_args(&self) -> &ServerArgs {
         match self.mode {
             Mode::Server(ref args) => args,
@@ -90,124 +891,762 @@ impl Opts {
         }
     }
 
-    pub fn setup(&mut self) {
+    // [::] takes ipv4 as well, unless 0.0.0.0 is listened on the same port
+    pub fn only_v6(&self, addr: &SocketAddr) -> bool {
+        only_v6(addr, self.local_addrs.iter())
+    }
+
+    pub fn udp_only_v6(&self, addr: &SocketAddr) -> bool {
+        only_v6(addr, self.udp_addrs.iter().map(|(addr, _)| addr))
+    }
+
+    pub fn relay_args(&self) -> &RelayArgs {
+        match self.mode {
+            Mode::Server(ref args) => &args.relay,
+            Mode::Proxy(ref args) => &args.relay,
+            _ => panic!("not in server or proxy mode"),
+        }
+    }
+
+    pub fn firewall_args(&self) -> &FirewallArgs {
+        match self.mode {
+            Mode::SetupFirewall(ref args) => args,
+            _ => panic!("not in setup-firewall mode"),
+        }
+    }
+
+    pub fn service_args(&self) -> &ServiceArgs {
+        match self.mode {
+            Mode::Service(ref args) => args,
+            _ => panic!("not in service mode"),
+        }
+    }
+
+    pub fn setup(&mut self) -> error::Result<()> {
+        let relay = match self.mode {
+            Mode::Server(ref args) => args.relay.clone(),
+            Mode::Proxy(ref args) => args.relay.clone(),
+            _ => return Ok(()),
+        };
+        let passwords = read_passwords(&relay)?;
+        // proxy mode uses the first one for trojan servers without their own
+        self.password = format!("{}{}", SHA224_PREFIX, passwords[0].digest);
+        if let Some(path) = relay.password_file.as_ref() {
+            self.password_file_time = password::modified_time(path);
+        }
+        self.set_passwords(passwords);
+        self.local_addrs.clear();
+        self.port_ranges.clear();
+        for local_addr in relay.local_addr.iter() {
+            let (addr, last_port) = parse_local_addr(local_addr)?;
+            if addr.port() != last_port {
+                if let Mode::Proxy(_) = self.mode {
+                    return Err(Error::Config(format!("invalid --local-addr {}, port ranges are only supported in server mode", local_addr)));
+                }
+                self.port_ranges.push((addr, last_port));
+            }
+            if !self.local_addrs.contains(&addr) {
+                self.local_addrs.push(addr);
+            }
+        }
+        if self.local_addrs.len() > MAX_LISTENERS {
+            return Err(Error::Config(format!("too many listen addresses, at most {} are supported", MAX_LISTENERS)));
+        }
+        // without natlook of pf the redirected connections can not tell their destination on macos, the packets are
+        // taken from a utun device instead, windows refuses both below
+        if let Mode::Proxy(ref mut args) = self.mode {
+            if cfg!(target_os = "macos") && args.transparent_mode == TransparentMode::Redirect && !sys::natlook_available() {
+                log::warn!("pf natlook is not available, falling back to --transparent-mode tun");
+                args.transparent_mode = TransparentMode::Tun;
+            }
+        }
         match self.mode {
             Mode::Server(ref args) => {
-                let back_addr: SocketAddr = args.remote_addr.parse().unwrap();
-                self.back_addr = Some(back_addr);
-                self.dns_cache_duration = Duration::new(args.dns_cache_time, 0);
+                if args.remote_addr.starts_with("unix:") {
+                    if cfg!(not(unix)) {
+                        return Err(Error::Config("unix socket --remote-addr is only supported on unix".to_string()));
+                    }
+                    let path = &args.remote_addr["unix:".len()..];
+                    if path.is_empty() {
+                        return Err(Error::Config(format!("invalid --remote-addr {}:empty path", args.remote_addr)));
+                    }
+                    self.back_path = Some(path.to_string());
+                } else {
+                    let back_addr: SocketAddr = args.remote_addr.parse()
+                        .map_err(|err| Error::Config(format!("invalid --remote-addr {}:{}", args.remote_addr, err)))?;
+                    self.back_addr = Some(back_addr);
+                }
+                self.dns_cache = DnsCache::new(args.dns_cache_size,
+                                               Duration::new(args.dns_min_time, 0),
+                                               Duration::new(args.dns_cache_time, 0),
+                                               Duration::new(args.dns_negative_time, 0));
+                self.replay_cache = ReplayCache::new(Duration::new(args.replay_window, 0), args.replay_cache_size);
+                if let Some(path) = args.hosts_file.as_ref() {
+                    self.hosts_file_time = password::modified_time(path);
+                    self.hosts = Hosts::load(path).map_err(|err| Error::io(format!("load --hosts-file {}", path), err))?;
+                }
+                self.block_ports = args.block_ports.clone();
+                self.allow_ports = args.allow_ports.clone();
+                self.allow_ips = args.allow_ips.clone();
+                if let Some(path) = args.allow_ips_file.as_ref() {
+                    self.allow_ips.extend(cidr::load_list(path).map_err(|err| Error::io(format!("load --allow-ips-file {}", path), err))?);
+                }
+                self.deny_ips = args.deny_ips.clone();
+                if let Some(path) = args.deny_ips_file.as_ref() {
+                    self.deny_ips.extend(cidr::load_list(path).map_err(|err| Error::io(format!("load --deny-ips-file {}", path), err))?);
+                }
+                self.user_routes.clear();
+                for allow in args.user_allow.iter() {
+                    self.user_routes.entry(allow.user.clone()).or_default().allow.extend(allow.ranges.iter().cloned());
+                }
+                for bind in args.user_bind.iter() {
+                    self.user_routes.entry(bind.user.clone()).or_default().bind = Some(bind.addr);
+                }
+                if let Some(token) = args.dashboard_token.as_ref() {
+                    if relay.health_addr.is_none() {
+                        return Err(Error::Config("--dashboard-token needs --health-addr".to_string()));
+                    }
+                    // the token is compared with the query string as it is, without decoding it
+                    if token.is_empty() || !token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                        return Err(Error::Config("--dashboard-token should be letters, digits, - and _".to_string()));
+                    }
+                }
+                self.ss_addr = None;
+                if let Some(ss_addr) = args.ss_addr.as_ref() {
+                    if args.ss_password.as_ref().map_or(true, |password| password.is_empty()) {
+                        return Err(Error::Config("--ss-addr needs --ss-password".to_string()));
+                    }
+                    let addr: SocketAddr = ss_addr.parse().map_err(|err| Error::Config(format!("invalid --ss-addr {}:{}", ss_addr, err)))?;
+                    // the shadowsocks listener takes the token after those of the trojan ones
+                    if self.local_addrs.len() >= MAX_LISTENERS {
+                        return Err(Error::Config(format!("too many listen addresses, at most {} are supported with --ss-addr", MAX_LISTENERS - 1)));
+                    }
+                    self.ss_addr = Some(addr);
+                }
+                if let Some(hook) = args.auth_hook.as_ref() {
+                    if self.sandbox && !http_client::is_url(hook) {
+                        return Err(Error::Config("--auth-hook can not run a command with --sandbox, which forbids running programs, use an http url".to_string()));
+                    }
+                }
             }
             Mode::Proxy(ref args) => {
-                let mut hostname = args.hostname.clone();
-                if !hostname.ends_with(".") {
-                    hostname.push('.');
+                self.udp_addrs.clear();
+                if args.udp_addr.is_empty() {
+                    self.udp_addrs.extend(self.local_addrs.iter().map(|addr| (*addr, relay.marker)));
                 }
-                let resolver = Resolver::from_system_conf().unwrap();
-                let response = resolver.lookup_ip(hostname.as_str()).unwrap();
-                while let Some(ip) = response.iter().next() {
-                    if ip.is_ipv4() {
-                        self.back_addr.replace(SocketAddr::new(ip, 443));
-                        break;
-                    } else if self.back_addr.is_none() {
-                        self.back_addr.replace(SocketAddr::new(ip, 443));
+                for udp_addr in args.udp_addr.iter() {
+                    if !self.udp_addrs.iter().any(|(addr, _)| *addr == udp_addr.addr) {
+                        self.udp_addrs.push((udp_addr.addr, udp_addr.marker.unwrap_or(relay.marker)));
                     }
                 }
-                if self.back_addr.is_none() {
-                    panic!("resolve host {} failed", hostname);
+                if self.udp_addrs.len() > MAX_LISTENERS {
+                    return Err(Error::Config(format!("too many udp listen addresses, at most {} are supported", MAX_LISTENERS)));
                 }
-
-                log::info!("server address is {}", self.back_addr.as_ref().unwrap());
+                if let Some(url) = args.upstream_proxy.as_ref() {
+                    if args.plugin.is_some() {
+                        return Err(Error::Config("--upstream-proxy can not be used with --plugin".to_string()));
+                    }
+                    self.upstream_proxy = Some(UpstreamProxy::parse(url).map_err(Error::Config)?);
+                }
+                for upstream in args.hostname.iter().chain(args.upstream.iter()) {
+                    self.upstreams.push(upstream.parse()?);
+                }
+                if let Some(url) = args.subscription.as_ref() {
+                    match subscription::fetch(url, relay.outbound_marker.unwrap_or(relay.marker), relay.ip_strategy) {
+                        Ok(content) => {
+                            let upstreams = subscription::parse(content.as_str());
+                            log::warn!("{} trojan servers found in subscription", upstreams.len());
+                            for mut upstream in upstreams.into_iter().take(MAX_UPSTREAMS.saturating_sub(self.upstreams.len())) {
+                                upstream.subscribed = true;
+                                self.upstreams.push(upstream);
+                            }
+                        }
+                        Err(err) => log::error!("fetch subscription {} failed:{}", url, err),
+                    }
+                }
+                if self.upstreams.len() > MAX_UPSTREAMS {
+                    return Err(Error::Config(format!("too many trojan servers, at most {} are supported", MAX_UPSTREAMS)));
+                }
+                if args.dns_addr.is_some() {
+                    self.fake_dns = FakeDns::new(args.fake_ip_range)?;
+                }
+                let mut saved = match args.state_file.as_ref().map(|path| state::load(path.as_str())) {
+                    Some(Ok(saved)) => saved,
+                    Some(Err(err)) => {
+                        log::error!("load state file failed:{}", err);
+                        state::State::default()
+                    }
+                    None => state::State::default(),
+                };
+                let count = saved.fake_ips.len();
+                for (ip, domain) in saved.fake_ips.drain(..) {
+                    self.fake_dns.restore(ip, domain);
+                }
+                if let Some(next) = saved.fake_next {
+                    self.fake_dns.set_next(next);
+                }
+                if args.state_file.is_some() {
+                    log::warn!("{} trojan servers and {} fake ips loaded from state file", saved.servers.len(), count);
+                }
+                if let Some(remote_dns) = args.remote_dns.as_ref() {
+                    let remote_dns: SocketAddr = remote_dns.parse()
+                        .map_err(|err| Error::Config(format!("invalid --remote-dns {}:{}", remote_dns, err)))?;
+                    self.remote_dns = Some(remote_dns);
+                }
+                self.router = Router::load(args).map_err(|err| Error::io("load route rules", err))?;
+                self.check_outbounds();
+                self.route_check_duration = Duration::new(args.route_check_time, 0);
+                if args.tunnel_keepalive > 0 && !args.padding {
+                    return Err(Error::Config("--tunnel-keepalive needs --padding, only framed connections carry keepalives".to_string()));
+                }
+                if args.udp_over_tcp && args.udp_keepalive == 0 {
+                    return Err(Error::Config("--udp-keepalive should be positive with --udp-over-tcp".to_string()));
+                }
+                // tproxy and redirect need the socket options of linux and macos
+                if cfg!(windows) && (args.transparent_mode == TransparentMode::Tproxy || args.transparent_mode == TransparentMode::Redirect
+                    || args.transparent_mode == TransparentMode::Tun) {
+                    return Err(Error::Config("--transparent-mode tproxy, redirect and tun are not supported on this platform, use socks5 or http".to_string()));
+                }
+                if args.pac_addr.is_some() && args.transparent_mode != TransparentMode::Socks5 && args.transparent_mode != TransparentMode::Http
+                    && args.transparent_mode != TransparentMode::Tun {
+                    return Err(Error::Config("--pac-addr needs --transparent-mode socks5, http or tun".to_string()));
+                }
+                // ip and tcp headers take at least 60 bytes, and ipv6 needs 1280
+                if args.transparent_mode == TransparentMode::Tun && !(1280..=65535).contains(&args.tun_mtu) {
+                    return Err(Error::Config(format!("invalid --tun-mtu {}, it should be within 1280 and 65535", args.tun_mtu)));
+                }
+                if args.plugin.is_some() {
+                    if self.sandbox {
+                        return Err(Error::Config("--plugin can not be used with --sandbox, which forbids running programs".to_string()));
+                    }
+                    // connections to the local port of the plugin can not leave through the device
+                    if relay.outbound_device.is_some() {
+                        return Err(Error::Config("--plugin can not be used with --outbound-device".to_string()));
+                    }
+                }
+                let resolver = resolver::new_resolver(relay.ip_strategy).map_err(|err| Error::io("read dns configuration", err))?;
+                for upstream in self.upstreams.iter_mut() {
+                    if let Err(err) = upstream.setup(self.password.as_str()) {
+                        if !upstream.subscribed {
+                            return Err(Error::Config(err));
+                        }
+                        log::error!("{}", err);
+                        upstream.set_removed(true);
+                        continue;
+                    }
+                    if args.plugin.is_some() {
+                        upstream.set_plugin_addr(plugin::local_addr().map_err(|err| Error::io("find a local port for plugin", err))?);
+                        continue;
+                    }
+                    if let Some(proxy) = self.upstream_proxy.as_ref() {
+                        upstream.set_proxy_addr(proxy.addr);
+                        continue;
+                    }
+                    if let Some(ip) = upstream.ip() {
+                        upstream.update_addrs(vec![ip], relay.ip_strategy);
+                    }
+                    // saved addresses spare waiting for the resolver on start, they are resolved again in the background
+                    if let Some(pos) = saved.servers.iter().position(|server| server.hostname == upstream.hostname) {
+                        let server = saved.servers.remove(pos);
+                        upstream.restore(server.addrs, relay.ip_strategy, server.latency, server.failures);
+                    }
+                    if upstream.is_available() {
+                        continue;
+                    }
+                    let mut hostname = upstream.hostname.clone();
+                    if !hostname.ends_with(".") {
+                        hostname.push('.');
+                    }
+                    match resolver.lookup_ip(hostname.as_str()) {
+                        Ok(response) => upstream.update_addrs(response.iter().collect(), relay.ip_strategy),
+                        Err(err) => log::error!("resolve host {} failed:{}", hostname, err),
+                    }
+                }
+                self.upstream_index = match self.upstreams.iter().position(|upstream| upstream.is_available()) {
+                    Some(index) => index,
+                    None => {
+                        let names: Vec<&str> = self.upstreams.iter().map(|upstream| upstream.name()).collect();
+                        return Err(Error::Resolve(format!("no trojan server can be resolved:{}", names.join(","))));
+                    }
+                };
             }
+            _ => unreachable!(),
+        }
+        let addr = match self.mode {
+            // udp sockets follow the family of the fallback, ipv4 for a unix socket
+            Mode::Server(_) => self.back_addr.unwrap_or_else(|| SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)),
+            Mode::Proxy(_) => self.upstream().addr().unwrap(),
+            _ => unreachable!(),
+        };
+        self.set_empty_addr(addr);
+        self.idle_duration = Duration::new(relay.idle_timeout, 0);
+        self.udp_duration = Duration::new(relay.udp_timeout, 0);
+        if let Mode::Server(ref args) = self.mode {
+            let idle = |timeout: Option<u64>| Duration::new(timeout.unwrap_or(relay.idle_timeout), 0);
+            self.idle_durations = IdleDurations {
+                client_read: idle(args.client_read_timeout),
+                client_write: idle(args.client_write_timeout),
+                target_read: idle(args.target_read_timeout),
+                target_write: idle(args.target_write_timeout),
+            };
+        }
+        if relay.max_udp_size == 0 || relay.max_udp_size > MAX_UDP_SIZE {
+            return Err(Error::Config(format!("invalid --max-udp-size {}, expected 1 to {}", relay.max_udp_size, MAX_UDP_SIZE)));
         }
-        let empty_addr = if self.back_addr.as_ref().unwrap().is_ipv4() {
+        if relay.max_pending == 0 {
+            return Err(Error::Config("invalid --max-pending 0, expected at least 1".to_string()));
+        }
+        self.pending_limit = relay.max_pending;
+        self.connect_duration = Duration::new(relay.connect_timeout, 0);
+        self.tcp_opts.marker = relay.outbound_marker.unwrap_or(relay.marker);
+        self.tcp_opts.bind_addr = relay.outbound_bind;
+        self.tcp_opts.device = relay.outbound_device.clone();
+        self.tcp_opts.fast_open = relay.fast_open;
+        self.tcp_opts.keepalive = relay.tcp_keepalive;
+        self.tcp_opts.user_timeout = relay.tcp_user_timeout;
+        self.tcp_opts.send_buffer = relay.send_buffer;
+        self.tcp_opts.recv_buffer = relay.recv_buffer;
+        self.tcp_opts.congestion = relay.congestion.clone();
+        self.tcp_opts.dscp = relay.tcp_dscp;
+        self.handshake_duration = Duration::new(relay.tls_handshake_timeout, 0);
+        self.attempt_duration = Duration::from_millis(relay.attempt_delay);
+        Ok(())
+    }
+
+    fn set_empty_addr(&mut self, addr: SocketAddr) {
+        let empty_addr = if addr.is_ipv4() {
             SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)
         } else {
             SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)
         };
         self.empty_addr.replace(empty_addr);
-        self.idle_duration = Duration::new(self.idle_timeout, 0);
-        self.digest_pass();
     }
 
-    fn digest_pass(&mut self) {
-        let mut encoder = Sha224::new();
-        encoder.reset();
-        encoder.input(self.password.as_bytes());
-        let result = encoder.result_str();
-        self.pass_len = result.len();
-        log::info!("sha224({}) = {}, length = {}", self.password, result, self.pass_len);
-        self.sha_pass = result;
+    pub fn upstream(&self) -> &Upstream {
+        &self.upstreams[self.upstream_index]
+    }
+
+    pub fn update_upstream_addrs(&mut self, index: usize, addrs: Vec<IpAddr>) {
+        let strategy = self.relay_args().ip_strategy;
+        self.upstreams[index].update_addrs(addrs, strategy);
+    }
+
+    pub fn update_subscription(&mut self, mut upstreams: Vec<Upstream>) {
+        let default_password = self.password.clone();
+        let strategy = self.relay_args().ip_strategy;
+        let use_plugin = self.proxy_args().plugin.is_some();
+        for upstream in self.upstreams.iter_mut().filter(|upstream| upstream.subscribed) {
+            if let Some(pos) = upstreams.iter().position(|new| new.same_server(upstream)) {
+                upstreams.remove(pos);
+                upstream.set_removed(false);
+            } else {
+                upstream.set_removed(true);
+            }
+        }
+        for mut upstream in upstreams {
+            if self.upstreams.len() >= MAX_UPSTREAMS {
+                log::error!("too many trojan servers, ignore {} from subscription", upstream.name());
+                continue;
+            }
+            if let Err(err) = upstream.setup(default_password.as_str()) {
+                log::error!("{}", err);
+                continue;
+            }
+            if use_plugin {
+                match plugin::local_addr() {
+                    Ok(addr) => upstream.set_plugin_addr(addr),
+                    Err(err) => {
+                        log::error!("find a local port for plugin of {} failed:{}", upstream.name(), err);
+                        continue;
+                    }
+                }
+            } else if let Some(proxy) = self.upstream_proxy.as_ref() {
+                upstream.set_proxy_addr(proxy.addr);
+            } else if let Some(ip) = upstream.ip() {
+                upstream.update_addrs(vec![ip], strategy);
+            }
+            log::warn!("trojan server {} added from subscription", upstream.name());
+            upstream.subscribed = true;
+            self.upstreams.push(upstream);
+        }
+        if !self.upstream().is_available() {
+            if let Some(index) = self.upstreams.iter().position(|upstream| upstream.is_available()) {
+                self.switch_upstream(index);
+            }
+        }
+    }
+
+    pub fn select_upstream(&mut self) -> usize {
+        let healthy: Vec<usize> = (0..self.upstreams.len()).filter(|i| self.upstreams[*i].is_healthy()).collect();
+        if healthy.is_empty() {
+            return self.upstream_index;
+        }
+        let index = match self.proxy_args().balance {
+            Balance::Failover => self.upstream_index,
+            Balance::RoundRobin => {
+                self.next_upstream = self.next_upstream.wrapping_add(1);
+                healthy[self.next_upstream % healthy.len()]
+            }
+            Balance::LeastConnections => *healthy.iter().min_by_key(|i| self.upstreams[**i].connections).unwrap(),
+            // servers without samples are tried first to get measured
+            Balance::Latency => *healthy.iter().min_by_key(|i| self.upstreams[**i].latency.unwrap_or_default()).unwrap(),
+        };
+        index
+    }
+
+    // the request asking the upstream proxy to connect to the server, if there is one
+    pub fn upstream_tunnel(&self, index: usize) -> Option<Tunnel> {
+        let upstream = &self.upstreams[index];
+        self.upstream_proxy.as_ref().map(|proxy| proxy.tunnel(upstream.hostname.as_str(), upstream.port))
+    }
+
+    pub fn upstream_opened(&mut self, index: usize) {
+        self.upstreams[index].connections += 1;
+    }
+
+    pub fn upstream_connected(&mut self, index: usize, latency: Duration) {
+        self.upstreams[index].update_latency(latency);
+    }
+
+    pub fn upstream_failed(&mut self, index: usize) {
+        if !self.upstreams[index].failed() || index != self.upstream_index {
+            return;
+        }
+        let count = self.upstreams.len();
+        for i in 1..count {
+            let next = (index + i) % count;
+            if self.upstreams[next].is_available() {
+                self.switch_upstream(next);
+                return;
+            }
+        }
     }
 
-    pub fn check_pass(&self, pass: &str) -> Option<&String> {
-        if self.sha_pass.eq(pass) {
-            Some(&self.password)
+    pub fn upstream_closed(&mut self, index: usize, failed: bool) {
+        self.upstreams[index].connections -= 1;
+        if failed {
+            self.upstream_failed(index);
         } else {
-            None
+            self.upstreams[index].succeeded();
         }
     }
 
-    pub fn get_pass(&self) -> &String {
-        &self.sha_pass
+    pub fn upstream_abandoned(&mut self, index: usize) {
+        self.upstreams[index].connections -= 1;
     }
 
-    pub fn update_dns(&mut self, domain: String, address: IpAddr) {
-        log::trace!("update dns cache, {} = {}", domain, address);
-        let expired_time = Instant::now() + self.dns_cache_duration;
-        self.dns_cache.insert(domain,
-                              DnsEntry {
-                                  address,
-                                  expired_time,
-                              });
+    pub fn switch_upstream(&mut self, index: usize) {
+        if index == self.upstream_index {
+            return;
+        }
+        log::warn!("switch trojan server from {} to {}", self.upstream().name(), self.upstreams[index].name());
+        self.upstream_index = index;
+        self.upstreams[index].succeeded();
     }
 
-    pub fn query_dns(&mut self, domain: &String) -> Option<IpAddr> {
-        if let Some(entry) = self.dns_cache.get(domain) {
-            log::debug!("found {} = {} in dns cache", domain, entry.address);
-            if entry.expired_time > Instant::now() {
-                return Some(entry.address);
-            } else {
-                log::info!("domain {} expired, remove from cache", domain);
-                let _ = self.dns_cache.remove(domain);
+    pub fn route(&self, domain: Option<&str>, ip: Option<&IpAddr>, port: Option<u16>) -> Action {
+        match ip {
+            // dns queries redirected to the remote dns server always go through the tunnel
+            Some(_) if port == Some(53) && self.remote_dns.is_some() => Action::Proxy,
+            Some(ip) if cidr::is_private(ip) => self.proxy_args().lan_action,
+            _ => self.router.route(domain, ip, port),
+        }
+    }
+
+    // the trojan server a proxy:name rule chooses, none for the balance to choose one
+    pub fn outbound_upstream(&self, action: Action) -> Option<usize> {
+        match action {
+            Action::Outbound(outbound) => {
+                let name = self.router.outbounds()[outbound].as_str();
+                self.upstreams.iter().position(|upstream| upstream.name() == name || upstream.hostname == name)
+            }
+            _ => None,
+        }
+    }
+
+    fn check_outbounds(&self) {
+        for name in self.router.outbounds() {
+            if !self.upstreams.iter().any(|upstream| upstream.name() == name || upstream.hostname == *name) {
+                log::warn!("route rules name unknown trojan server {}, connections routed to it use the balance instead", name);
+            }
+        }
+    }
+
+    pub fn reload_router(&mut self) {
+        match Router::load(self.proxy_args()) {
+            Ok(router) => {
+                log::warn!("route rules reloaded");
+                self.router = router;
+                self.check_outbounds();
+            }
+            Err(err) => {
+                log::error!("reload route rules failed:{}, keep using the old ones", err);
+            }
+        }
+    }
+
+    // denied ranges win over allowed ones
+    pub fn user_route(&self, user: Option<&String>) -> Option<&UserRoute> {
+        user.and_then(|user| self.user_routes.get(user))
+    }
+
+    pub fn is_client_allowed(&self, ip: &IpAddr) -> bool {
+        !self.deny_ips.iter().any(|range| range.contains(ip))
+            && (self.allow_ips.is_empty() || self.allow_ips.iter().any(|range| range.contains(ip)))
+    }
+
+    // interfaces come and go, the list is refreshed with the other files
+    pub fn check_local_addrs(&mut self) {
+        match sys::local_addrs() {
+            Ok(addrs) => self.interface_addrs = addrs,
+            Err(err) => log::debug!("list local addresses failed:{}", err),
+        }
+    }
+
+    pub fn is_local_addr(&self, ip: &IpAddr) -> bool {
+        self.interface_addrs.contains(&cidr::unmap(*ip))
+    }
+
+    pub fn is_port_allowed(&self, port: u16) -> bool {
+        !self.block_ports.contains(&port) && (self.allow_ports.is_empty() || self.allow_ports.contains(&port))
+    }
+
+    fn set_passwords(&mut self, passwords: Vec<Password>) {
+        self.passwords = passwords.into_iter().map(|password| (password.digest.clone(), password)).collect();
+        log::info!("{} passwords loaded", self.passwords.len());
+    }
+
+    // reloads --password-file once it is changed, the old passwords are kept if the new file is invalid
+    pub fn check_password_file(&mut self) {
+        let path = match self.relay_args().password_file.as_ref() {
+            Some(path) if path != "-" => path.clone(),
+            _ => return,
+        };
+        let modified_time = password::modified_time(path.as_str());
+        if modified_time == self.password_file_time {
+            return;
+        }
+        self.password_file_time = modified_time;
+        match read_passwords(self.relay_args()) {
+            Ok(passwords) => {
+                log::warn!("password file {} reloaded", path);
+                self.set_passwords(passwords);
             }
+            Err(err) => log::error!("reload password file failed:{}, keep using the old passwords", err),
         }
-        None
+    }
+
+    // reloads --hosts-file once it is changed, the old domains are kept if it can not be read
+    pub fn check_hosts_file(&mut self) {
+        let path = match self.server_args().hosts_file.as_ref() {
+            Some(path) => path.clone(),
+            None => return,
+        };
+        let modified_time = password::modified_time(path.as_str());
+        if modified_time == self.hosts_file_time {
+            return;
+        }
+        self.hosts_file_time = modified_time;
+        match Hosts::load(path.as_str()) {
+            Ok(hosts) => {
+                log::warn!("hosts file {} reloaded", path);
+                self.hosts = hosts;
+            }
+            Err(err) => log::error!("reload hosts file failed:{}, keep using the old domains", err),
+        }
+    }
+
+    pub fn check_pass(&self, pass: &str) -> Option<&Password> {
+        self.passwords.get(pass)
+    }
+
+    pub fn passwords(&self) -> impl Iterator<Item = &Password> {
+        self.passwords.values()
+    }
+
+    pub fn password_count(&self) -> usize {
+        self.passwords.len()
+    }
+
+    pub fn update_dns(&mut self, domain: String, addresses: Vec<IpAddr>, valid_until: Option<Instant>) {
+        self.dns_cache.update(domain, addresses, valid_until);
+    }
+
+    // lookups are all made with --ip-strategy, which decides the record types asked for
+    pub fn update_dns_negative(&mut self, domain: String, valid_until: Option<Instant>) {
+        let record_types = self.relay_args().ip_strategy.lookup_strategy();
+        self.dns_cache.update_negative(domain, record_types, valid_until);
+    }
+
+    pub fn query_dns(&mut self, domain: &str) -> Vec<IpAddr> {
+        self.dns_cache.query(domain)
+    }
+
+    pub fn update_dns_failed(&mut self, address: IpAddr) {
+        self.dns_cache.update_failed(address);
+    }
+
+    pub fn is_dns_negative(&mut self, domain: &str) -> bool {
+        let record_types = self.relay_args().ip_strategy.lookup_strategy();
+        self.dns_cache.is_negative(domain, record_types)
     }
 }
 
-pub fn setup_logger(logfile: &Option<String>, level: u8) {
-    let level = match level {
-        0x00 => log::LevelFilter::Trace,
-        0x01 => log::LevelFilter::Debug,
-        0x02 => log::LevelFilter::Info,
-        0x03 => log::LevelFilter::Warn,
-        0x04 => log::LevelFilter::Error,
-        _ => log::LevelFilter::Off,
-    };
-    let mut builder = fern::Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "{}[{}:{}][{}]{}",
-                chrono::Local::now().format("[%Y-%m-%d %H:%M:%S%.6f]"),
-                record.file().unwrap_or("unknown"),
-                record.line().unwrap_or(0),
-                record.level(),
-                message
-            ))
-        })
-        .level(level);
-    if logfile.is_some() {
-        cfg_if::cfg_if! {
-            if #[cfg(unix)] {
-                let path = std::path::Path::new(logfile.as_ref().unwrap().as_str());
-                builder = builder.chain(fern::log_reopen(path, Some(libc::SIGUSR2)).unwrap());
-            } else {
-                builder = builder.chain(fern::log_file(logfile.as_ref().unwrap()).unwrap());
+// --password or TROJAN_PASSWORD, followed by the ones in --password-file
+fn read_passwords(relay: &RelayArgs) -> Result<Vec<Password>, String> {
+    let mut passwords = Vec::new();
+    if let Some(password) = relay.password.clone().or_else(|| std::env::var(password::ENV_NAME).ok()) {
+        passwords.push(Password::new(None, password.as_str())?);
+    }
+    if let Some(path) = relay.password_file.as_ref() {
+        passwords.extend(password::load(path)?);
+    }
+    if passwords.is_empty() {
+        return Err(format!("no password given by --password, --password-file or {}", password::ENV_NAME));
+    }
+    Ok(passwords)
+}
+
+// the hex digest of a password, as sent in trojan requests
+pub fn sha224(password: &str) -> String {
+    let mut encoder = Sha224::new();
+    encoder.reset();
+    encoder.input(password.as_bytes());
+    encoder.result_str()
+}
+
+// a password given as sha224:<hex digest> is used as it is, so that the plain one needs not be written anywhere
+pub fn password_digest(password: &str) -> Result<String, String> {
+    if !password.starts_with(SHA224_PREFIX) {
+        return Ok(sha224(password));
+    }
+    let digest = password[SHA224_PREFIX.len()..].to_ascii_lowercase();
+    if digest.len() != 56 || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("invalid sha224 password digest:{}", password));
+    }
+    Ok(digest)
+}
+
+fn only_v6<'a>(addr: &SocketAddr, mut others: impl Iterator<Item = &'a SocketAddr>) -> bool {
+    addr.is_ipv6() && addr.ip().is_unspecified()
+        && others.any(|other| other.is_ipv4() && other.ip().is_unspecified() && other.port() == addr.port())
+}
+
+// ip:port, or ip:first-last for a port range
+fn parse_local_addr(s: &str) -> error::Result<(SocketAddr, u16)> {
+    let invalid = |err: String| Error::Config(format!("invalid --local-addr {}:{}", s, err));
+    let pos = s.rfind(':').unwrap_or(0);
+    let (addr, last_port) = match s[pos..].find('-') {
+        Some(dash) => {
+            let addr: SocketAddr = s[..pos + dash].parse().map_err(|err: std::net::AddrParseError| invalid(err.to_string()))?;
+            let last_port: u16 = s[pos + dash + 1..].parse().map_err(|err: std::num::ParseIntError| invalid(err.to_string()))?;
+            if last_port < addr.port() {
+                return Err(invalid("the last port of the range is smaller than the first".to_string()));
             }
+            (addr, last_port)
         }
-    } else {
-        builder = builder.chain(std::io::stdout());
+        None => {
+            let addr: SocketAddr = s.parse().map_err(|err: std::net::AddrParseError| invalid(err.to_string()))?;
+            (addr, addr.port())
+        }
+    };
+    Ok((addr, last_port))
+}
+
+pub fn setup_logger(opts: &Opts) -> error::Result<()> {
+    let level = log_level::from_option(opts.log_level);
+    let target = opts.log_target();
+    // the level is checked on each record, as it may be changed at runtime
+    let builder = fern::Dispatch::new()
+        .filter(|metadata| metadata.level() <= log_level::get()
+            && metadata.target() != access_log::TARGET && metadata.target() != security_log::TARGET);
+    // syslog and journald keep their own time and level, records are sent to them as they are
+    let builder = match target {
+        LogTarget::Syslog => builder.chain(log_target::syslog()?),
+        LogTarget::Journald => builder.chain(journald_output()?),
+        LogTarget::File | LogTarget::Stdout => builder.chain(stream_logger(opts, target)?),
+    };
+    let mut builder = fern::Dispatch::new().chain(builder);
+    let mut floor = log::LevelFilter::Off;
+    if let Some(access_target) = opts.access_log_target() {
+        builder = builder.chain(access_logger(opts, access_target)?);
+        floor = log::LevelFilter::Info;
+    }
+    if let Some(path) = opts.security_log.as_ref() {
+        floor = std::cmp::max(floor, log::LevelFilter::Warn);
+        let security_logger = fern::Dispatch::new()
+            .level(log::LevelFilter::Warn)
+            .filter(|metadata| metadata.target() == security_log::TARGET)
+            .format(|out, message, _| out.finish(format_args!("{} {}", chrono::Local::now().to_rfc3339(), message)));
+        builder = builder.chain(reopen_file(security_logger, path.as_str())?);
     }
     builder.apply().unwrap();
+    log_level::setup(level, floor);
+    Ok(())
+}
+
+fn journald_output() -> error::Result<fern::Output> {
+    log_target::journald().map_err(|err| Error::io("connect to journald", err))
+}
+
+// one line per finished session, written as formatted by access_log regardless of --log-level
+fn access_logger(opts: &Opts, target: LogTarget) -> error::Result<fern::Dispatch> {
+    access_log::setup(opts.access_log_format.as_str())?;
+    let builder = fern::Dispatch::new()
+        .level(log::LevelFilter::Info)
+        .filter(|metadata| metadata.target() == access_log::TARGET);
+    match target {
+        LogTarget::Syslog => Ok(builder.chain(log_target::syslog()?)),
+        LogTarget::Journald => Ok(builder.chain(journald_output()?)),
+        LogTarget::Stdout => Ok(builder.format(|out, message, _| out.finish(*message)).chain(std::io::stdout())),
+        LogTarget::File => {
+            let builder = builder.format(|out, message, _| out.finish(*message));
+            let path = opts.access_log.as_ref()
+                .ok_or_else(|| Error::Config("--access-log is required by access log target file".to_string()))?;
+            reopen_file(builder, path.as_str())
+        }
+    }
+}
+
+// reopened on SIGUSR2 for logrotate
+fn reopen_file(builder: fern::Dispatch, path: &str) -> error::Result<fern::Dispatch> {
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            let file = fern::log_reopen(std::path::Path::new(path), Some(libc::SIGUSR2));
+        } else {
+            let file = fern::log_file(path);
+        }
+    }
+    let file = file.map_err(|err| Error::io(format!("open log file {}", path), err))?;
+    Ok(builder.chain(file))
+}
+
+fn stream_logger(opts: &Opts, target: LogTarget) -> error::Result<fern::Dispatch> {
+    let builder = match opts.log_format {
+        LogFormat::Json => fern::Dispatch::new().format(log_format::json),
+        LogFormat::Text => fern::Dispatch::new()
+            .format(|out, message, record| {
+                out.finish(format_args!(
+                    "{}[{}:{}][{}]{}",
+                    chrono::Local::now().format("[%Y-%m-%d %H:%M:%S%.6f]"),
+                    record.file().unwrap_or("unknown"),
+                    record.line().unwrap_or(0),
+                    record.level(),
+                    message
+                ))
+            }),
+    };
+    let logfile = match (target, opts.log_file.as_ref()) {
+        (LogTarget::File, Some(logfile)) => logfile,
+        (LogTarget::File, None) => return Err(Error::Config("--log-file is required by log target file".to_string())),
+        _ => return Ok(builder.chain(std::io::stdout())),
+    };
+    if opts.log_max_size > 0 || opts.log_rotate != LogRotate::Never {
+        let file = RotatingFile::new(logfile.as_str(), opts.log_max_size * 1024 * 1024, opts.log_rotate, opts.log_keep, opts.log_compress)
+            .map_err(|err| Error::io(format!("open log file {}", logfile), err))?;
+        Ok(builder.chain(Box::new(file) as Box<dyn std::io::Write + Send>))
+    } else {
+        reopen_file(builder, logfile.as_str())
+    }
 }
 
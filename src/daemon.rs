@@ -0,0 +1,148 @@
+use std::io::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(unix)]
+use std::fs::{File, OpenOptions};
+#[cfg(unix)]
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+#[cfg(target_os = "linux")]
+use std::mem::ManuallyDrop;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::FromRawFd;
+#[cfg(unix)]
+use std::sync::atomic::AtomicI32;
+
+// the locked pid file, which is handed over to the new process during a binary upgrade
+#[cfg(unix)]
+static PID_FD: AtomicI32 = AtomicI32::new(-1);
+static RELEASED: AtomicBool = AtomicBool::new(false);
+
+// a pid file locked for the lifetime of trojan, removed on exit
+pub struct PidFile {
+    path: String,
+    #[cfg(unix)]
+    file: File,
+}
+
+#[cfg(unix)]
+impl PidFile {
+    // fails if another instance holds the lock, the lock is kept by the daemon after forking
+    pub fn lock(path: &str) -> Result<PidFile> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).mode(0o644).open(path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = Error::last_os_error();
+            if err.kind() == ErrorKind::WouldBlock {
+                let mut pid = String::new();
+                let _ = file.read_to_string(&mut pid);
+                return Err(Error::new(ErrorKind::AddrInUse, format!("trojan is already running with pid {}", pid.trim())));
+            }
+            return Err(err);
+        }
+        PID_FD.store(file.as_raw_fd(), Ordering::SeqCst);
+        Ok(PidFile {
+            path: path.to_string(),
+            file,
+        })
+    }
+
+    pub fn write(&mut self) -> Result<()> {
+        write_pid(&mut self.file)
+    }
+}
+
+#[cfg(unix)]
+fn write_pid(file: &mut File) -> Result<()> {
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    writeln!(file, "{}", std::process::id())?;
+    file.sync_all()
+}
+
+// unlocks the pid file for the new process, which writes its own pid
+#[cfg(target_os = "linux")]
+pub fn release_pid_file() {
+    let fd = PID_FD.load(Ordering::SeqCst);
+    if fd >= 0 {
+        unsafe {
+            libc::flock(fd, libc::LOCK_UN);
+        }
+    }
+    RELEASED.store(true, Ordering::SeqCst);
+}
+
+// takes the pid file back when the new process failed to start
+#[cfg(target_os = "linux")]
+pub fn reclaim_pid_file() {
+    let fd = PID_FD.load(Ordering::SeqCst);
+    if fd >= 0 {
+        if unsafe { libc::flock(fd, libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            log::error!("lock pid file again failed:{}", Error::last_os_error());
+            return;
+        }
+        // the file is still owned by PidFile
+        let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+        if let Err(err) = write_pid(&mut file) {
+            log::error!("write pid file failed:{}", err);
+        }
+    }
+    RELEASED.store(false, Ordering::SeqCst);
+}
+
+#[cfg(not(unix))]
+impl PidFile {
+    pub fn lock(_path: &str) -> Result<PidFile> {
+        Err(std::io::Error::new(std::io::ErrorKind::Other, "pid file is not supported on this platform"))
+    }
+
+    pub fn write(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if RELEASED.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Err(err) = std::fs::remove_file(self.path.as_str()) {
+            log::error!("remove pid file {} failed:{}", self.path, err);
+        }
+    }
+}
+
+// forks twice so that the daemon is not a session leader and never gets a controlling terminal again,
+// the working directory is kept as relative paths in options are opened later
+#[cfg(unix)]
+pub fn daemonize() -> Result<()> {
+    fork_and_exit()?;
+    if unsafe { libc::setsid() } < 0 {
+        return Err(Error::last_os_error());
+    }
+    fork_and_exit()?;
+    let null = OpenOptions::new().read(true).write(true).open("/dev/null")?;
+    for fd in &[libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(null.as_raw_fd(), *fd) } < 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// the parent exits without running destructors, which would remove the pid file
+#[cfg(unix)]
+fn fork_and_exit() -> Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => Err(Error::last_os_error()),
+        0 => Ok(()),
+        _ => std::process::exit(0),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn daemonize() -> Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Other, "daemon is not supported on this platform, use service instead"))
+}
@@ -0,0 +1,291 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use trust_dns_resolver::config::LookupIpStrategy;
+
+struct DnsEntry {
+    // empty for a domain without addresses of the record types looked up
+    addresses: Vec<IpAddr>,
+    // the record types a negative entry was looked up with, as a domain may have records of the other types only
+    record_types: Option<LookupIpStrategy>,
+    expired_time: Instant,
+    seq: u64,
+}
+
+#[derive(Default)]
+pub struct DnsCache {
+    entries: HashMap<String, DnsEntry>,
+    lru: BTreeMap<u64, String>,
+    next_seq: u64,
+    capacity: usize,
+    min_ttl: Duration,
+    max_ttl: Duration,
+    negative_ttl: Duration,
+    hits: u64,
+    misses: u64,
+    // addresses a connection failed to, until when they are tried after the other addresses of their domains
+    failed: HashMap<IpAddr, Instant>,
+}
+
+impl DnsCache {
+    pub fn new(capacity: usize, min_ttl: Duration, max_ttl: Duration, negative_ttl: Duration) -> DnsCache {
+        DnsCache {
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            next_seq: 0,
+            capacity,
+            min_ttl,
+            max_ttl,
+            negative_ttl,
+            hits: 0,
+            misses: 0,
+            failed: HashMap::new(),
+        }
+    }
+
+    pub fn update(&mut self, domain: String, addresses: Vec<IpAddr>, valid_until: Option<Instant>) {
+        let now = Instant::now();
+        let ttl = valid_until.map_or(self.max_ttl, |valid_until| valid_until.saturating_duration_since(now));
+        let ttl = self.clamp(ttl);
+        log::trace!("update dns cache, {} = {:?}, ttl:{}s", domain, addresses, ttl.as_secs());
+        self.insert(domain, addresses, None, now + ttl);
+    }
+
+    // nxdomain and an answer without records of the types looked up are told apart by neither the resolver nor the
+    // soa minimum, so the entry holds for lookups of the same record types only
+    pub fn update_negative(&mut self, domain: String, record_types: LookupIpStrategy, valid_until: Option<Instant>) {
+        let now = Instant::now();
+        let ttl = valid_until.map_or(self.negative_ttl, |valid_until| valid_until.saturating_duration_since(now));
+        let ttl = std::cmp::min(ttl, self.negative_ttl);
+        log::trace!("update dns cache, {} has no {:?} records, ttl:{}s", domain, record_types, ttl.as_secs());
+        self.insert(domain, Vec::new(), Some(record_types), now + ttl);
+    }
+
+    // the addresses of the domain, those failed lately last, empty if it is not cached
+    pub fn query(&mut self, domain: &str) -> Vec<IpAddr> {
+        match self.lookup(domain) {
+            Some(mut addresses) if !addresses.is_empty() => {
+                log::debug!("found {} = {:?} in dns cache", domain, addresses);
+                self.hits += 1;
+                self.sort(&mut addresses);
+                addresses
+            }
+            _ => {
+                self.misses += 1;
+                Vec::new()
+            }
+        }
+    }
+
+    pub fn is_negative(&mut self, domain: &str, record_types: LookupIpStrategy) -> bool {
+        if self.lookup(domain).map_or(false, |addresses| addresses.is_empty())
+            && self.entries.get(domain).map_or(false, |entry| entry.record_types == Some(record_types)) {
+            log::debug!("found {} in negative dns cache", domain);
+            true
+        } else {
+            false
+        }
+    }
+
+    // a connection to the address failed, it is tried last for as long as a negative entry lives
+    pub fn update_failed(&mut self, address: IpAddr) {
+        if self.capacity == 0 {
+            return;
+        }
+        let now = Instant::now();
+        if self.failed.len() >= self.capacity {
+            self.failed.retain(|_, expired_time| *expired_time > now);
+            if self.failed.len() >= self.capacity {
+                return;
+            }
+        }
+        log::debug!("address {} failed, try it last for {}s", address, self.negative_ttl.as_secs());
+        self.failed.insert(address, now + self.negative_ttl);
+    }
+
+    // moves the addresses failed lately to the end, keeping the order of the others
+    pub fn sort(&self, addresses: &mut [IpAddr]) {
+        let now = Instant::now();
+        addresses.sort_by_key(|address| self.failed.get(address).map_or(false, |expired_time| *expired_time > now));
+    }
+
+    // the entries, negative ones included, and the hits and misses of queries since start
+    pub fn stats(&self) -> (usize, u64, u64) {
+        (self.entries.len(), self.hits, self.misses)
+    }
+
+    // one line per live entry, the domain, the addresses separated by commas or - for a negative one, and the seconds left, by domain
+    pub fn dump(&self) -> String {
+        let now = Instant::now();
+        let mut entries: Vec<(&String, &DnsEntry)> = self.entries.iter()
+            .filter(|(_, entry)| entry.expired_time > now)
+            .collect();
+        entries.sort_by_key(|(domain, _)| *domain);
+        let mut list = String::new();
+        for (domain, entry) in entries {
+            let addresses = if entry.addresses.is_empty() {
+                "-".to_string()
+            } else {
+                entry.addresses.iter().map(|address| address.to_string()).collect::<Vec<String>>().join(",")
+            };
+            let _ = writeln!(list, "{} {} {}s", domain, addresses, (entry.expired_time - now).as_secs());
+        }
+        list
+    }
+
+    // returns false if the domain is not cached
+    pub fn delete(&mut self, domain: &str) -> bool {
+        let found = self.entries.contains_key(domain);
+        self.remove(domain);
+        found
+    }
+
+    // returns the number of entries removed
+    pub fn flush(&mut self) -> usize {
+        let count = self.entries.len();
+        self.entries.clear();
+        self.lru.clear();
+        self.failed.clear();
+        count
+    }
+
+    fn lookup(&mut self, domain: &str) -> Option<Vec<IpAddr>> {
+        let seq = self.next_seq;
+        if let Some(entry) = self.entries.get_mut(domain) {
+            if entry.expired_time > Instant::now() {
+                self.lru.remove(&entry.seq);
+                self.lru.insert(seq, domain.to_string());
+                entry.seq = seq;
+                self.next_seq += 1;
+                return Some(entry.addresses.clone());
+            }
+        } else {
+            return None;
+        }
+        log::info!("domain {} expired, remove from cache", domain);
+        self.remove(domain);
+        None
+    }
+
+    fn clamp(&self, ttl: Duration) -> Duration {
+        if ttl < self.min_ttl {
+            self.min_ttl
+        } else if ttl > self.max_ttl {
+            self.max_ttl
+        } else {
+            ttl
+        }
+    }
+
+    fn insert(&mut self, domain: String, addresses: Vec<IpAddr>, record_types: Option<LookupIpStrategy>, expired_time: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&domain);
+        while self.entries.len() >= self.capacity {
+            self.evict();
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.lru.insert(seq, domain.clone());
+        self.entries.insert(domain, DnsEntry {
+            addresses,
+            record_types,
+            expired_time,
+            seq,
+        });
+    }
+
+    fn remove(&mut self, domain: &str) {
+        if let Some(entry) = self.entries.remove(domain) {
+            self.lru.remove(&entry.seq);
+        }
+    }
+
+    fn evict(&mut self) {
+        let seq = if let Some(seq) = self.lru.keys().next() {
+            *seq
+        } else {
+            return;
+        };
+        if let Some(domain) = self.lru.remove(&seq) {
+            log::debug!("dns cache is full, evict {}", domain);
+            self.entries.remove(&domain);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(last: u8) -> Vec<IpAddr> {
+        vec![IpAddr::from([10, 0, 0, last])]
+    }
+
+    fn ttl(cache: &DnsCache, domain: &str) -> Duration {
+        cache.entries[domain].expired_time.saturating_duration_since(Instant::now())
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let mut cache = DnsCache::new(2, Duration::from_secs(0), Duration::from_secs(60), Duration::from_secs(60));
+        cache.update("a".to_string(), address(1), None);
+        cache.update("b".to_string(), address(2), None);
+        // a is used after b, so b goes first
+        assert_eq!(cache.query("a"), address(1));
+        cache.update("c".to_string(), address(3), None);
+        assert!(cache.query("b").is_empty());
+        assert_eq!(cache.query("a"), address(1));
+        assert_eq!(cache.query("c"), address(3));
+        // updating an entry makes it the most recent one as well
+        cache.update("a".to_string(), address(4), None);
+        cache.update("d".to_string(), address(5), None);
+        assert!(cache.query("c").is_empty());
+        assert_eq!(cache.query("a"), address(4));
+        assert_eq!(cache.stats(), (2, 4, 2));
+    }
+
+    #[test]
+    fn clamp_ttl() {
+        let mut cache = DnsCache::new(8, Duration::from_secs(30), Duration::from_secs(300), Duration::from_secs(60));
+        let now = Instant::now();
+        cache.update("short".to_string(), address(1), Some(now + Duration::from_secs(1)));
+        cache.update("long".to_string(), address(2), Some(now + Duration::from_secs(3600)));
+        cache.update("middle".to_string(), address(3), Some(now + Duration::from_secs(120)));
+        cache.update("none".to_string(), address(4), None);
+        assert!(ttl(&cache, "short") > Duration::from_secs(29) && ttl(&cache, "short") <= Duration::from_secs(30));
+        assert!(ttl(&cache, "long") > Duration::from_secs(299) && ttl(&cache, "long") <= Duration::from_secs(300));
+        assert!(ttl(&cache, "middle") > Duration::from_secs(119) && ttl(&cache, "middle") <= Duration::from_secs(120));
+        assert!(ttl(&cache, "none") > Duration::from_secs(299) && ttl(&cache, "none") <= Duration::from_secs(300));
+    }
+
+    #[test]
+    fn negative_entry_expires() {
+        let mut cache = DnsCache::new(8, Duration::from_secs(30), Duration::from_secs(300), Duration::from_secs(60));
+        cache.update_negative("long".to_string(), LookupIpStrategy::Ipv4AndIpv6, Some(Instant::now() + Duration::from_secs(3600)));
+        assert!(ttl(&cache, "long") <= Duration::from_secs(60));
+        assert!(cache.is_negative("long", LookupIpStrategy::Ipv4AndIpv6));
+        assert!(cache.query("long").is_empty());
+        // the min ttl does not hold for a negative entry, it is gone at once
+        cache.update_negative("gone".to_string(), LookupIpStrategy::Ipv4AndIpv6, Some(Instant::now()));
+        assert!(!cache.is_negative("gone", LookupIpStrategy::Ipv4AndIpv6));
+        assert!(!cache.entries.contains_key("gone"));
+        assert_eq!(cache.stats().0, 1);
+    }
+
+    #[test]
+    fn negative_entry_keeps_record_types() {
+        let mut cache = DnsCache::new(8, Duration::from_secs(30), Duration::from_secs(300), Duration::from_secs(60));
+        // a domain with aaaa records only has no a records, which tells nothing about a lookup of both
+        cache.update_negative("v6".to_string(), LookupIpStrategy::Ipv4Only, None);
+        assert!(cache.is_negative("v6", LookupIpStrategy::Ipv4Only));
+        assert!(!cache.is_negative("v6", LookupIpStrategy::Ipv4AndIpv6));
+        assert!(!cache.is_negative("v6", LookupIpStrategy::Ipv6Only));
+        // addresses found later replace the negative entry
+        cache.update("v6".to_string(), address(1), None);
+        assert!(!cache.is_negative("v6", LookupIpStrategy::Ipv4Only));
+    }
+}
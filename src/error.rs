@@ -0,0 +1,71 @@
+use std::fmt;
+use std::io;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// errors keeping trojan from starting, reported with a hint instead of a panic
+#[derive(Debug)]
+pub enum Error {
+    // an invalid option value, the message names the option
+    Config(String),
+    // what was being done, and the io error failing it
+    Io(String, io::Error),
+    // host names which could not be resolved
+    Resolve(String),
+}
+
+impl Error {
+    pub fn io<S: Into<String>>(context: S, err: io::Error) -> Error {
+        Error::Io(context.into(), err)
+    }
+
+    // invalid options exit with 2, like usage errors of clap
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Config(_) => 2,
+            _ => 1,
+        }
+    }
+
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            Error::Config(_) => Some("run trojan help <mode> for the valid options"),
+            Error::Io(_, err) => match err.kind() {
+                io::ErrorKind::AddrInUse => Some("another process is listening on the address, stop it or choose another port"),
+                io::ErrorKind::AddrNotAvailable => Some("the address is not assigned to any interface of this host"),
+                io::ErrorKind::PermissionDenied => Some("ports below 1024 need root or CAP_NET_BIND_SERVICE, tproxy and markers need CAP_NET_ADMIN"),
+                io::ErrorKind::NotFound => Some("check that the file exists, paths are relative to the working directory"),
+                _ => None,
+            },
+            Error::Resolve(_) => Some("check the host names and the dns servers in /etc/resolv.conf, or give ip addresses"),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Config(message) => write!(f, "{}", message),
+            Error::Io(context, err) => write!(f, "{} failed:{}", context, err),
+            Error::Resolve(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<String> for Error {
+    fn from(message: String) -> Error {
+        Error::Config(message)
+    }
+}
+
+// logs the error with its hint and exits
+pub fn exit(err: Error) -> ! {
+    log::error!("{}", err);
+    if let Some(hint) = err.hint() {
+        log::error!("{}", hint);
+    }
+    log::logger().flush();
+    std::process::exit(err.exit_code());
+}
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+
+use crate::cidr::Cidr;
+use crate::error::{Error, Result};
+
+#[derive(Default)]
+pub struct FakeDns {
+    range: Option<Cidr>,
+    network: u32,
+    size: u32,
+    next: u32,
+    domain2ip: HashMap<String, Ipv4Addr>,
+    ip2domain: HashMap<Ipv4Addr, String>,
+}
+
+impl FakeDns {
+    pub fn new(range: Cidr) -> Result<FakeDns> {
+        let (network, size) = if let IpAddr::V4(network) = range.addr() {
+            (u32::from(network), 1u32.checked_shl(32 - range.prefix() as u32).unwrap_or(0))
+        } else {
+            return Err(Error::Config(format!("invalid --fake-ip-range {}, it should be ipv4", range)));
+        };
+        if size < 4 {
+            return Err(Error::Config(format!("invalid --fake-ip-range {}, it is too small", range)));
+        }
+        Ok(FakeDns {
+            range: Some(range),
+            network,
+            size,
+            next: 1,
+            domain2ip: HashMap::new(),
+            ip2domain: HashMap::new(),
+        })
+    }
+
+    pub fn is_fake(&self, ip: &IpAddr) -> bool {
+        self.range.map_or(false, |range| range.contains(ip))
+    }
+
+    pub fn lookup(&self, ip: &IpAddr) -> Option<&String> {
+        if let IpAddr::V4(ip) = ip {
+            self.ip2domain.get(ip)
+        } else {
+            None
+        }
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&Ipv4Addr, &String)> {
+        self.ip2domain.iter()
+    }
+
+    // the offset of the address handed out next, none without a range
+    pub fn next(&self) -> Option<u32> {
+        self.range.map(|_| self.next)
+    }
+
+    // a mapping handed out before a restart, false if it is not in the range, which may have changed since
+    pub fn restore(&mut self, ip: Ipv4Addr, domain: String) -> bool {
+        let offset = u32::from(ip).wrapping_sub(self.network);
+        if self.range.is_none() || offset == 0 || offset >= self.size - 1 || self.domain2ip.contains_key(&domain) {
+            return false;
+        }
+        if let Some(old) = self.ip2domain.insert(ip, domain.clone()) {
+            self.domain2ip.remove(&old);
+        }
+        self.domain2ip.insert(domain, ip);
+        true
+    }
+
+    pub fn set_next(&mut self, next: u32) {
+        if self.range.is_some() && next >= 1 && next < self.size - 1 {
+            self.next = next;
+        }
+    }
+
+    pub fn allocate(&mut self, domain: &str) -> Ipv4Addr {
+        if let Some(ip) = self.domain2ip.get(domain) {
+            return *ip;
+        }
+        // addresses are handed out in a ring, the oldest mapping is reused when the range is exhausted
+        let ip = Ipv4Addr::from(self.network + self.next);
+        self.next += 1;
+        if self.next >= self.size - 1 {
+            self.next = 1;
+        }
+        if let Some(old) = self.ip2domain.remove(&ip) {
+            log::debug!("fake ip {} reused, {} is removed", ip, old);
+            self.domain2ip.remove(&old);
+        }
+        log::debug!("fake ip {} allocated for {}", ip, domain);
+        self.domain2ip.insert(domain.to_string(), ip);
+        self.ip2domain.insert(ip, domain.to_string());
+        ip
+    }
+}
@@ -0,0 +1,149 @@
+use std::fmt::Write as FmtWrite;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::process::{Command, ExitStatus, Stdio};
+
+use crate::cidr::{self, Cidr};
+use crate::config::{FirewallArgs, Opts};
+use crate::error::{self, Error};
+
+fn bypass_list(args: &FirewallArgs) -> (Vec<Cidr>, Vec<Cidr>) {
+    cidr::PRIVATE_RANGES.iter().chain(args.bypass.iter()).cloned()
+        .partition(|cidr| cidr.addr().is_ipv4())
+}
+
+fn protocols(args: &FirewallArgs) -> &'static [&'static str] {
+    if args.no_udp {
+        &["tcp"]
+    } else {
+        &["tcp", "udp"]
+    }
+}
+
+fn iptables(script: &mut String, args: &FirewallArgs, port: u16, ipv6: bool) {
+    let (cmd, ip, any) = if ipv6 {
+        ("ip6tables", "ip -6", "::/0")
+    } else {
+        ("iptables", "ip", "0.0.0.0/0")
+    };
+    let (v4, v6) = bypass_list(args);
+    let bypass = if ipv6 { v6 } else { v4 };
+    let marker = args.marker;
+    let outbound_marker = args.outbound_marker.unwrap_or(args.marker);
+
+    writeln!(script, "{} rule add fwmark {} table {}", ip, marker, args.table).unwrap();
+    writeln!(script, "{} route add local {} dev lo table {}", ip, any, args.table).unwrap();
+
+    writeln!(script, "{} -t mangle -N TROJAN_ROUTE", cmd).unwrap();
+    for cidr in &bypass {
+        writeln!(script, "{} -t mangle -A TROJAN_ROUTE -d {} -j RETURN", cmd, cidr).unwrap();
+    }
+    for protocol in protocols(args) {
+        writeln!(script, "{} -t mangle -A TROJAN_ROUTE -p {} -j TPROXY --on-port {} --tproxy-mark {}", cmd, protocol, port, marker).unwrap();
+    }
+    writeln!(script, "{} -t mangle -A PREROUTING -j TROJAN_ROUTE", cmd).unwrap();
+
+    writeln!(script, "{} -t mangle -N TROJAN_LOCAL", cmd).unwrap();
+    for cidr in &bypass {
+        writeln!(script, "{} -t mangle -A TROJAN_LOCAL -d {} -j RETURN", cmd, cidr).unwrap();
+    }
+    writeln!(script, "{} -t mangle -A TROJAN_LOCAL -m mark --mark {} -j RETURN", cmd, outbound_marker).unwrap();
+    for protocol in protocols(args) {
+        writeln!(script, "{} -t mangle -A TROJAN_LOCAL -p {} -j MARK --set-mark {}", cmd, protocol, marker).unwrap();
+    }
+    writeln!(script, "{} -t mangle -A OUTPUT -j TROJAN_LOCAL", cmd).unwrap();
+}
+
+fn nftables(script: &mut String, args: &FirewallArgs, port: u16) {
+    let (v4, v6) = bypass_list(args);
+    let marker = args.marker;
+    let outbound_marker = args.outbound_marker.unwrap_or(args.marker);
+    let join = |list: &Vec<Cidr>| list.iter().map(|cidr| cidr.to_string()).collect::<Vec<_>>().join(", ");
+    let l4proto = format!("{{ {} }}", protocols(args).join(", "));
+
+    writeln!(script, "ip rule add fwmark {} table {}", marker, args.table).unwrap();
+    writeln!(script, "ip route add local 0.0.0.0/0 dev lo table {}", args.table).unwrap();
+    if args.ipv6 {
+        writeln!(script, "ip -6 rule add fwmark {} table {}", marker, args.table).unwrap();
+        writeln!(script, "ip -6 route add local ::/0 dev lo table {}", args.table).unwrap();
+    }
+    writeln!(script, "nft -f - <<'EOF'").unwrap();
+    writeln!(script, "table inet trojan {{").unwrap();
+    writeln!(script, "    chain prerouting {{").unwrap();
+    writeln!(script, "        type filter hook prerouting priority mangle; policy accept;").unwrap();
+    writeln!(script, "        ip daddr {{ {} }} return", join(&v4)).unwrap();
+    if args.ipv6 {
+        writeln!(script, "        ip6 daddr {{ {} }} return", join(&v6)).unwrap();
+    }
+    writeln!(script, "        meta nfproto ipv4 meta l4proto {} tproxy ip to :{} meta mark set {} accept", l4proto, port, marker).unwrap();
+    if args.ipv6 {
+        writeln!(script, "        meta nfproto ipv6 meta l4proto {} tproxy ip6 to :{} meta mark set {} accept", l4proto, port, marker).unwrap();
+    }
+    writeln!(script, "    }}").unwrap();
+    writeln!(script, "    chain output {{").unwrap();
+    writeln!(script, "        type route hook output priority mangle; policy accept;").unwrap();
+    writeln!(script, "        ip daddr {{ {} }} return", join(&v4)).unwrap();
+    if args.ipv6 {
+        writeln!(script, "        ip6 daddr {{ {} }} return", join(&v6)).unwrap();
+    } else {
+        writeln!(script, "        meta nfproto ipv6 return").unwrap();
+    }
+    writeln!(script, "        meta mark {} return", outbound_marker).unwrap();
+    writeln!(script, "        meta l4proto {} meta mark set {}", l4proto, marker).unwrap();
+    writeln!(script, "    }}").unwrap();
+    writeln!(script, "}}").unwrap();
+    writeln!(script, "EOF").unwrap();
+}
+
+pub fn generate(args: &FirewallArgs) -> String {
+    let port = args.local_addr.parse::<SocketAddr>().unwrap().port();
+    let mut script = String::new();
+    writeln!(script, "#!/bin/sh").unwrap();
+    writeln!(script, "# tproxy rules for trojan listening on {}, marker {}", args.local_addr, args.marker).unwrap();
+    if args.outbound_marker.is_none() || args.outbound_marker == Some(args.marker) {
+        // packets of trojan itself carry the tproxy marker, and would be routed back to it
+        writeln!(script, "# WARNING: no distinct --outbound-marker, add the trojan server addresses with --bypass to avoid routing loops").unwrap();
+    }
+    if args.nftables {
+        nftables(&mut script, args, port);
+    } else {
+        iptables(&mut script, args, port, false);
+        if args.ipv6 {
+            iptables(&mut script, args, port, true);
+        }
+    }
+    writeln!(script, "ip route flush cache").unwrap();
+    script
+}
+
+// the other ports of a listen port range are redirected to the first one, where the server listens
+pub fn redirect_rule(addr: &SocketAddr, last_port: u16) -> String {
+    let cmd = if addr.is_ipv4() { "iptables" } else { "ip6tables" };
+    format!("{} -t nat -A PREROUTING -p tcp --dport {}:{} -j REDIRECT --to-ports {}", cmd, addr.port(), last_port, addr.port())
+}
+
+pub fn run(opts: &Opts) {
+    let args = opts.firewall_args();
+    let script = generate(args);
+    if !args.apply {
+        print!("{}", script);
+        return;
+    }
+    let status = match apply(script.as_str()) {
+        Ok(status) => status,
+        Err(err) => error::exit(Error::io("run sh", err)),
+    };
+    if status.success() {
+        log::warn!("firewall rules applied");
+    } else {
+        log::error!("apply firewall rules failed:{}", status);
+        std::process::exit(1);
+    }
+}
+
+// sh reads the script from stdin, which is closed once written
+fn apply(script: &str) -> std::io::Result<ExitStatus> {
+    let mut child = Command::new("sh").arg("-e").stdin(Stdio::piped()).spawn()?;
+    child.stdin.take().unwrap().write_all(script.as_bytes())?;
+    child.wait()
+}
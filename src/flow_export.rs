@@ -0,0 +1,190 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::ptr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::access_log::Entry;
+use crate::error::{Error, Result};
+
+const IPFIX_VERSION: u16 = 10;
+const TEMPLATE_SET_ID: u16 = 2;
+const IPV4_TEMPLATE_ID: u16 = 256;
+const IPV6_TEMPLATE_ID: u16 = 257;
+// collectors over udp learn the templates again from time to time, RFC 7011 section 8.4
+const TEMPLATE_REFRESH: Duration = Duration::from_secs(60);
+// reverse information elements of biflows, RFC 5103
+const REVERSE_PEN: u32 = 29305;
+const VARIABLE_LENGTH: u16 = 0xffff;
+
+const FLOW_START_MILLISECONDS: u16 = 152;
+const FLOW_END_MILLISECONDS: u16 = 153;
+const SOURCE_IPV4_ADDRESS: u16 = 8;
+const DESTINATION_IPV4_ADDRESS: u16 = 12;
+const SOURCE_IPV6_ADDRESS: u16 = 27;
+const DESTINATION_IPV6_ADDRESS: u16 = 28;
+const SOURCE_TRANSPORT_PORT: u16 = 7;
+const DESTINATION_TRANSPORT_PORT: u16 = 11;
+const PROTOCOL_IDENTIFIER: u16 = 4;
+const OCTET_DELTA_COUNT: u16 = 1;
+const USER_NAME: u16 = 371;
+
+const TCP: u8 = 6;
+const UDP: u8 = 17;
+
+// set once at startup, null when flows are not exported
+static EXPORTER: AtomicPtr<Mutex<Exporter>> = AtomicPtr::new(ptr::null_mut());
+
+// sends an ipfix message with a biflow record for each finished session, from the client to the target, the octets
+// sent by the client forward and those sent to it in reverse
+struct Exporter {
+    socket: UdpSocket,
+    collector: SocketAddr,
+    domain: u32,
+    // data records sent so far, the sequence number of the next message
+    sequence: u32,
+    last_template_time: Option<Instant>,
+}
+
+pub fn setup(collector: &str, domain: u32) -> Result<()> {
+    let collector: SocketAddr = collector.parse().map_err(|err| Error::Config(format!("invalid --flow-collector {}:{}", collector, err)))?;
+    let bind_addr: SocketAddr = if collector.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+    let socket = UdpSocket::bind(bind_addr).map_err(|err| Error::io("bind flow export socket", err))?;
+    // a record is dropped rather than blocking the relay
+    socket.set_nonblocking(true).map_err(|err| Error::io("bind flow export socket", err))?;
+    let exporter = Box::new(Mutex::new(Exporter {
+        socket,
+        collector,
+        domain,
+        sequence: 0,
+        last_template_time: None,
+    }));
+    EXPORTER.store(Box::into_raw(exporter), Ordering::Release);
+    log::warn!("exporting ipfix flows to {}", collector);
+    Ok(())
+}
+
+pub fn export(entry: &Entry) {
+    let exporter = match unsafe { EXPORTER.load(Ordering::Acquire).as_ref() } {
+        Some(exporter) => exporter,
+        None => return,
+    };
+    if let Ok(mut exporter) = exporter.lock() {
+        exporter.export(entry);
+    }
+}
+
+// the source and target in the same family, ipv4 ones mapped when the other is ipv6
+fn addresses(source: SocketAddr, target: SocketAddr) -> (u16, Vec<u8>, Vec<u8>) {
+    match (source.ip(), target.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(target)) => (IPV4_TEMPLATE_ID, source.octets().to_vec(), target.octets().to_vec()),
+        (source, target) => (IPV6_TEMPLATE_ID, to_ipv6(source).to_vec(), to_ipv6(target).to_vec()),
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+fn put_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_field(buffer: &mut Vec<u8>, id: u16, length: u16) {
+    put_u16(buffer, id);
+    put_u16(buffer, length);
+}
+
+fn put_reverse_field(buffer: &mut Vec<u8>, id: u16, length: u16) {
+    put_u16(buffer, id | 0x8000);
+    put_u16(buffer, length);
+    buffer.extend_from_slice(&REVERSE_PEN.to_be_bytes());
+}
+
+fn template(buffer: &mut Vec<u8>, id: u16, address_len: u16) {
+    put_u16(buffer, id);
+    put_u16(buffer, 10);
+    put_field(buffer, FLOW_START_MILLISECONDS, 8);
+    put_field(buffer, FLOW_END_MILLISECONDS, 8);
+    put_field(buffer, if address_len == 4 { SOURCE_IPV4_ADDRESS } else { SOURCE_IPV6_ADDRESS }, address_len);
+    put_field(buffer, SOURCE_TRANSPORT_PORT, 2);
+    put_field(buffer, if address_len == 4 { DESTINATION_IPV4_ADDRESS } else { DESTINATION_IPV6_ADDRESS }, address_len);
+    put_field(buffer, DESTINATION_TRANSPORT_PORT, 2);
+    put_field(buffer, PROTOCOL_IDENTIFIER, 1);
+    put_field(buffer, OCTET_DELTA_COUNT, 8);
+    put_reverse_field(buffer, OCTET_DELTA_COUNT, 8);
+    put_field(buffer, USER_NAME, VARIABLE_LENGTH);
+}
+
+// a set with its id and length in front
+fn put_set(message: &mut Vec<u8>, id: u16, content: &[u8]) {
+    put_u16(message, id);
+    put_u16(message, (content.len() + 4) as u16);
+    message.extend_from_slice(content);
+}
+
+impl Exporter {
+    fn export(&mut self, entry: &Entry) {
+        let source = match entry.remote_addr {
+            Some(addr) => addr,
+            None => return,
+        };
+        // domains which were never resolved have no address to export
+        let target = entry.target_addr.unwrap_or_else(|| SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let end = now.as_millis() as u64;
+        let start = end.saturating_sub(entry.start_time.elapsed().as_millis() as u64);
+        let (template_id, source_ip, target_ip) = addresses(source, target);
+
+        let mut record = Vec::new();
+        record.extend_from_slice(&start.to_be_bytes());
+        record.extend_from_slice(&end.to_be_bytes());
+        record.extend_from_slice(source_ip.as_slice());
+        put_u16(&mut record, source.port());
+        record.extend_from_slice(target_ip.as_slice());
+        put_u16(&mut record, target.port());
+        record.push(if entry.protocol == "udp" { UDP } else { TCP });
+        record.extend_from_slice(&(entry.bytes_received as u64).to_be_bytes());
+        record.extend_from_slice(&(entry.bytes_sent as u64).to_be_bytes());
+        let user = entry.user.unwrap_or("").as_bytes();
+        let user = &user[..user.len().min(1024)];
+        if user.len() < 255 {
+            record.push(user.len() as u8);
+        } else {
+            record.push(255);
+            put_u16(&mut record, user.len() as u16);
+        }
+        record.extend_from_slice(user);
+
+        let mut message = Vec::with_capacity(256);
+        put_u16(&mut message, IPFIX_VERSION);
+        put_u16(&mut message, 0);
+        message.extend_from_slice(&(now.as_secs() as u32).to_be_bytes());
+        message.extend_from_slice(&self.sequence.to_be_bytes());
+        message.extend_from_slice(&self.domain.to_be_bytes());
+        let instant = Instant::now();
+        let send_templates = self.last_template_time.map_or(true, |time| instant - time > TEMPLATE_REFRESH);
+        if send_templates {
+            let mut templates = Vec::new();
+            template(&mut templates, IPV4_TEMPLATE_ID, 4);
+            template(&mut templates, IPV6_TEMPLATE_ID, 16);
+            put_set(&mut message, TEMPLATE_SET_ID, templates.as_slice());
+        }
+        put_set(&mut message, template_id, record.as_slice());
+        let length = message.len() as u16;
+        message[2..4].copy_from_slice(&length.to_be_bytes());
+
+        match self.socket.send_to(message.as_slice(), self.collector) {
+            Ok(_) => {
+                self.sequence = self.sequence.wrapping_add(1);
+                if send_templates {
+                    self.last_template_time.replace(instant);
+                }
+            }
+            Err(err) => log::debug!("export flow to {} failed:{}", self.collector, err),
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+
+use crate::pac;
+
+// domain types defined in v2ray's routercommon.proto
+const TYPE_PLAIN: u64 = 0;
+const TYPE_REGEX: u64 = 1;
+const TYPE_DOMAIN: u64 = 2;
+const TYPE_FULL: u64 = 3;
+
+#[derive(Default)]
+pub struct DomainSet {
+    full: HashSet<String>,
+    suffix: HashSet<String>,
+    keyword: Vec<String>,
+}
+
+impl DomainSet {
+    pub fn load(path: &str, code: &str) -> Result<DomainSet> {
+        let data = fs::read(path)?;
+        let mut set = DomainSet::default();
+        let mut found = false;
+        let mut regex = 0;
+        let mut list = Reader::new(data.as_slice());
+        // GeoSiteList { repeated GeoSite entry = 1; }
+        while let Some((field, site)) = list.next_bytes()? {
+            if field != 1 {
+                continue;
+            }
+            let mut site = Reader::new(site);
+            let mut domains = Vec::new();
+            let mut matched = false;
+            // GeoSite { string country_code = 1; repeated Domain domain = 2; }
+            while let Some((field, value)) = site.next_bytes()? {
+                match field {
+                    1 => matched = String::from_utf8_lossy(value).eq_ignore_ascii_case(code),
+                    2 => domains.push(value),
+                    _ => {}
+                }
+            }
+            if !matched {
+                continue;
+            }
+            found = true;
+            for domain in domains {
+                let (typ, value) = parse_domain(domain)?;
+                let value = value.to_lowercase();
+                match typ {
+                    TYPE_PLAIN => set.keyword.push(value),
+                    TYPE_DOMAIN => {
+                        set.suffix.insert(value);
+                    }
+                    TYPE_FULL => {
+                        set.full.insert(value);
+                    }
+                    TYPE_REGEX => regex += 1,
+                    _ => {}
+                }
+            }
+        }
+        if !found {
+            return Err(Error::new(ErrorKind::NotFound, format!("geosite {} not found in {}", code, path)));
+        }
+        if regex > 0 {
+            log::warn!("geosite {} has {} regex domains, which are not supported", code, regex);
+        }
+        log::info!("geosite {} loaded, {} full, {} suffix, {} keyword", code, set.full.len(), set.suffix.len(), set.keyword.len());
+        Ok(set)
+    }
+
+    // hosts format lines block exactly the domains listed, plain domain lines block the subdomains as well
+    pub fn load_list(&mut self, path: &str) -> Result<()> {
+        let content = fs::read_to_string(path)?;
+        let (full, suffix) = (self.full.len(), self.suffix.len());
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap().trim();
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() == 1 {
+                self.suffix.insert(fields[0].trim_start_matches('.').to_lowercase());
+            } else if fields.len() > 1 {
+                for domain in &fields[1..] {
+                    if *domain != "localhost" && !domain.starts_with("localhost.") {
+                        self.full.insert(domain.to_lowercase());
+                    }
+                }
+            }
+        }
+        log::warn!("blocklist {} loaded, {} full, {} suffix", path, self.full.len() - full, self.suffix.len() - suffix);
+        Ok(())
+    }
+
+    // a javascript object for inSet of the proxy auto-config file
+    pub fn write_pac(&self, out: &mut String) {
+        out.push_str("{full:{");
+        for (i, domain) in self.full.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            pac::quote(out, domain);
+            out.push_str(":1");
+        }
+        out.push_str("},suffix:{");
+        for (i, domain) in self.suffix.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            pac::quote(out, domain);
+            out.push_str(":1");
+        }
+        out.push_str("},keyword:[");
+        for (i, keyword) in self.keyword.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            pac::quote(out, keyword);
+        }
+        out.push_str("]}");
+    }
+
+    pub fn contains(&self, domain: &str) -> bool {
+        if self.full.contains(domain) || self.suffix.contains(domain) {
+            return true;
+        }
+        for (i, c) in domain.char_indices() {
+            if c == '.' && self.suffix.contains(&domain[i + 1..]) {
+                return true;
+            }
+        }
+        self.keyword.iter().any(|keyword| domain.contains(keyword.as_str()))
+    }
+}
+
+// Domain { Type type = 1; string value = 2; repeated Attribute attribute = 3; }
+fn parse_domain(data: &[u8]) -> Result<(u64, String)> {
+    let mut reader = Reader::new(data);
+    let mut typ = TYPE_PLAIN;
+    let mut value = String::new();
+    while let Some((field, wire_type)) = reader.next_key()? {
+        match (field, wire_type) {
+            (1, 0) => typ = reader.varint()?,
+            (2, 2) => value = String::from_utf8_lossy(reader.bytes()?).to_string(),
+            _ => reader.skip(wire_type)?,
+        }
+    }
+    Ok((typ, value))
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data }
+    }
+
+    fn invalid() -> Error {
+        Error::new(ErrorKind::InvalidData, "invalid geosite data")
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for i in 0..10 {
+            if self.data.is_empty() {
+                return Err(Reader::invalid());
+            }
+            let byte = self.data[0];
+            self.data = &self.data[1..];
+            value |= ((byte & 0x7f) as u64) << (i * 7);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(Reader::invalid())
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.varint()? as usize;
+        if len > self.data.len() {
+            return Err(Reader::invalid());
+        }
+        let (bytes, data) = self.data.split_at(len);
+        self.data = data;
+        Ok(bytes)
+    }
+
+    fn next_key(&mut self) -> Result<Option<(u64, u64)>> {
+        if self.data.is_empty() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        Ok(Some((key >> 3, key & 0x7)))
+    }
+
+    // returns the next length-delimited field, skipping the others
+    fn next_bytes(&mut self) -> Result<Option<(u64, &'a [u8])>> {
+        while let Some((field, wire_type)) = self.next_key()? {
+            if wire_type == 2 {
+                return Ok(Some((field, self.bytes()?)));
+            }
+            self.skip(wire_type)?;
+        }
+        Ok(None)
+    }
+
+    fn skip(&mut self, wire_type: u64) -> Result<()> {
+        let len = match wire_type {
+            0 => {
+                self.varint()?;
+                0
+            }
+            1 => 8,
+            2 => self.varint()? as usize,
+            5 => 4,
+            _ => return Err(Reader::invalid()),
+        };
+        if len > self.data.len() {
+            return Err(Reader::invalid());
+        }
+        self.data = &self.data[len..];
+        Ok(())
+    }
+}
@@ -0,0 +1,845 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::SocketAddr;
+
+use mio::{Poll, PollOpt, Ready, Token};
+use mio::net::{TcpListener, TcpStream};
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+// the defaults of http/2, none of them is changed by the settings sent to clients
+const MAX_FRAME_SIZE: usize = 16384;
+const INITIAL_WINDOW_SIZE: i64 = 65535;
+const HEADER_TABLE_SIZE: usize = 4096;
+// api calls are small, clients going beyond these are dropped or their calls refused
+const MAX_HEADER_BLOCK: usize = 65536;
+const MAX_MESSAGE_SIZE: usize = 1 << 20;
+const MAX_OUTPUT: usize = 4 << 20;
+const MAX_STREAMS: usize = 100;
+
+const DATA: u8 = 0;
+const HEADERS: u8 = 1;
+const RST_STREAM: u8 = 3;
+const SETTINGS: u8 = 4;
+const PING: u8 = 6;
+const WINDOW_UPDATE: u8 = 8;
+const CONTINUATION: u8 = 9;
+
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY: u8 = 0x20;
+
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 4;
+const REFUSED_STREAM: u32 = 7;
+
+const OK: u32 = 0;
+pub const INVALID_ARGUMENT: u32 = 3;
+pub const NOT_FOUND: u32 = 5;
+const RESOURCE_EXHAUSTED: u32 = 8;
+pub const UNIMPLEMENTED: u32 = 12;
+const INTERNAL: u32 = 13;
+
+// the header fields of the static table of hpack, rfc 7541 appendix a
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""), (":method", "GET"), (":method", "POST"), (":path", "/"), (":path", "/index.html"),
+    (":scheme", "http"), (":scheme", "https"), (":status", "200"), (":status", "204"), (":status", "206"),
+    (":status", "304"), (":status", "400"), (":status", "404"), (":status", "500"), ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"), ("accept-language", ""), ("accept-ranges", ""), ("accept", ""),
+    ("access-control-allow-origin", ""), ("age", ""), ("allow", ""), ("authorization", ""), ("cache-control", ""),
+    ("content-disposition", ""), ("content-encoding", ""), ("content-language", ""), ("content-length", ""),
+    ("content-location", ""), ("content-range", ""), ("content-type", ""), ("cookie", ""), ("date", ""), ("etag", ""),
+    ("expect", ""), ("expires", ""), ("from", ""), ("host", ""), ("if-match", ""), ("if-modified-since", ""),
+    ("if-none-match", ""), ("if-range", ""), ("if-unmodified-since", ""), ("last-modified", ""), ("link", ""),
+    ("location", ""), ("max-forwards", ""), ("proxy-authenticate", ""), ("proxy-authorization", ""), ("range", ""),
+    ("referer", ""), ("refresh", ""), ("retry-after", ""), ("server", ""), ("set-cookie", ""),
+    ("strict-transport-security", ""), ("transfer-encoding", ""), ("user-agent", ""), ("vary", ""), ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// the code lengths of the huffman code of hpack by symbol, rfc 7541 appendix b, the code is canonical so they are all
+// it takes to decode it, the last symbol is the end of string
+const HUFFMAN_LENGTHS: [u8; 257] = [
+    13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28,
+    28, 28, 28, 28, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 28,
+    6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6,
+    5, 5, 5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15, 6, 12, 10,
+    13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7,
+    7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6,
+    15, 5, 6, 5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6, 6, 5,
+    6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28,
+    20, 22, 20, 20, 22, 22, 22, 23, 22, 23, 23, 23, 23, 23, 24, 23,
+    24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23, 24,
+    22, 21, 20, 22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23,
+    21, 21, 22, 21, 23, 22, 23, 23, 20, 22, 22, 22, 23, 22, 22, 23,
+    26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25,
+    19, 21, 26, 27, 27, 26, 27, 24, 21, 21, 26, 26, 28, 27, 27, 27,
+    20, 24, 20, 21, 22, 21, 21, 23, 22, 22, 25, 25, 24, 24, 26, 23,
+    26, 27, 26, 26, 27, 27, 27, 27, 27, 28, 27, 27, 27, 27, 27, 26,
+    30,
+];
+
+// grpc over cleartext http/2 for the stats api, only what grpc clients use of http/2 is spoken, there is no tls and
+// no authentication, so it is meant for a local address
+pub struct GrpcServer {
+    listener: TcpListener,
+    client_token: Token,
+    clients: Vec<Client>,
+}
+
+struct Client {
+    stream: TcpStream,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    writable: bool,
+    preface: bool,
+    decoder: Decoder,
+    // the header block being received in continuation frames, its stream and whether the stream ends with it
+    continuation: Option<(u32, Vec<u8>, bool)>,
+    streams: HashMap<u32, Stream>,
+    last_stream: u32,
+    // what the client lets be sent on the connection and on new streams
+    window: i64,
+    initial_window: i64,
+}
+
+struct Stream {
+    path: String,
+    data: Vec<u8>,
+    headers_sent: bool,
+    // response data waiting for the window, then the trailers ending the stream
+    pending: Vec<u8>,
+    trailers: Option<Vec<u8>>,
+    window: i64,
+}
+
+impl GrpcServer {
+    // clients are all registered with client_token, as there are few of them
+    pub fn new(addr: &SocketAddr, poll: &Poll, token: Token, client_token: Token) -> Result<GrpcServer> {
+        let listener = TcpListener::bind(addr)?;
+        poll.register(&listener, token, Ready::readable(), PollOpt::level())?;
+        log::warn!("grpc api listening on {}", addr);
+        Ok(GrpcServer {
+            listener,
+            client_token,
+            clients: Vec::new(),
+        })
+    }
+
+    pub fn accept(&mut self, poll: &Poll) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, addr)) => {
+                    if let Err(err) = poll.register(&stream, self.client_token, Ready::readable() | Ready::writable(), PollOpt::level()) {
+                        log::error!("register grpc client failed:{}", err);
+                        continue;
+                    }
+                    log::debug!("grpc client {} connected", addr);
+                    let mut client = Client {
+                        stream,
+                        input: Vec::new(),
+                        output: Vec::new(),
+                        writable: true,
+                        preface: false,
+                        decoder: Decoder::new(),
+                        continuation: None,
+                        streams: HashMap::new(),
+                        last_stream: 0,
+                        window: INITIAL_WINDOW_SIZE,
+                        initial_window: INITIAL_WINDOW_SIZE,
+                    };
+                    // the defaults are all kept
+                    write_frame(&mut client.output, SETTINGS, 0, 0, &[]);
+                    self.clients.push(client);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::error!("accept grpc client failed:{}", err);
+                    break;
+                }
+            }
+        }
+    }
+
+    // handles what each client has sent, call answers a request message of a method with the response messages or a
+    // grpc status code and message
+    pub fn ready<C>(&mut self, poll: &Poll, mut call: C)
+        where C: FnMut(&str, &[u8]) -> std::result::Result<Vec<Vec<u8>>, (u32, String)> {
+        let mut i = 0;
+        while i < self.clients.len() {
+            let client = &mut self.clients[i];
+            match client.serve(&mut call) {
+                Ok(()) => {
+                    let writable = !client.output.is_empty();
+                    if writable == client.writable {
+                        i += 1;
+                        continue;
+                    }
+                    let ready = if writable { Ready::readable() | Ready::writable() } else { Ready::readable() };
+                    match poll.reregister(&client.stream, self.client_token, ready, PollOpt::level()) {
+                        Ok(()) => {
+                            client.writable = writable;
+                            i += 1;
+                            continue;
+                        }
+                        Err(err) => log::warn!("register grpc client failed:{}", err),
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => log::debug!("grpc client closed"),
+                Err(err) => log::warn!("grpc client failed:{}", err),
+            }
+            let client = self.clients.swap_remove(i);
+            let _ = poll.deregister(&client.stream);
+        }
+    }
+}
+
+impl Client {
+    fn serve<C>(&mut self, call: &mut C) -> Result<()>
+        where C: FnMut(&str, &[u8]) -> std::result::Result<Vec<Vec<u8>>, (u32, String)> {
+        let mut buffer = [0u8; 16384];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(size) => {
+                    self.input.extend_from_slice(&buffer[..size]);
+                    self.process(call)?;
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+        self.send_pending();
+        while !self.output.is_empty() {
+            match self.stream.write(self.output.as_slice()) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(size) => {
+                    self.output.drain(..size);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+        if self.output.len() > MAX_OUTPUT {
+            return Err(invalid("client does not read"));
+        }
+        Ok(())
+    }
+
+    // handles the complete frames read
+    fn process<C>(&mut self, call: &mut C) -> Result<()>
+        where C: FnMut(&str, &[u8]) -> std::result::Result<Vec<Vec<u8>>, (u32, String)> {
+        if !self.preface {
+            if self.input.len() < PREFACE.len() {
+                return if PREFACE.starts_with(self.input.as_slice()) { Ok(()) } else { Err(invalid("not an http/2 client")) };
+            }
+            if !self.input.starts_with(PREFACE) {
+                return Err(invalid("not an http/2 client"));
+            }
+            self.input.drain(..PREFACE.len());
+            self.preface = true;
+        }
+        let mut offset = 0;
+        while self.input.len() >= offset + 9 {
+            let header = &self.input[offset..offset + 9];
+            let size = (header[0] as usize) << 16 | (header[1] as usize) << 8 | header[2] as usize;
+            if size > MAX_FRAME_SIZE {
+                return Err(invalid("frame too large"));
+            }
+            if self.input.len() < offset + 9 + size {
+                break;
+            }
+            let (kind, flags) = (header[3], header[4]);
+            let stream_id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff;
+            let payload = self.input[offset + 9..offset + 9 + size].to_vec();
+            offset += 9 + size;
+            self.frame(kind, flags, stream_id, payload, call)?;
+        }
+        self.input.drain(..offset);
+        Ok(())
+    }
+
+    fn frame<C>(&mut self, kind: u8, flags: u8, stream_id: u32, payload: Vec<u8>, call: &mut C) -> Result<()>
+        where C: FnMut(&str, &[u8]) -> std::result::Result<Vec<Vec<u8>>, (u32, String)> {
+        if let Some((id, _, _)) = self.continuation {
+            if kind != CONTINUATION || stream_id != id {
+                return Err(invalid("header block interrupted"));
+            }
+        }
+        match kind {
+            DATA => {
+                // the windows are opened again at once, what is received is taken off them when handled
+                let size = payload.len() as u32;
+                if size > 0 {
+                    write_frame(&mut self.output, WINDOW_UPDATE, 0, 0, &size.to_be_bytes());
+                }
+                let end = flags & END_STREAM != 0;
+                let data = unpad(flags, payload.as_slice())?;
+                if let Some(stream) = self.streams.get_mut(&stream_id) {
+                    if size > 0 && !end {
+                        write_frame(&mut self.output, WINDOW_UPDATE, 0, stream_id, &size.to_be_bytes());
+                    }
+                    stream.data.extend_from_slice(data);
+                    self.handle(stream_id, end, call);
+                }
+            }
+            HEADERS => {
+                let end = flags & END_STREAM != 0;
+                let mut block = unpad(flags, payload.as_slice())?.to_vec();
+                if flags & PRIORITY != 0 {
+                    if block.len() < 5 {
+                        return Err(invalid("invalid headers frame"));
+                    }
+                    block.drain(..5);
+                }
+                if flags & END_HEADERS != 0 {
+                    self.headers(stream_id, block, end, call)?;
+                } else {
+                    self.continuation = Some((stream_id, block, end));
+                }
+            }
+            CONTINUATION => {
+                let (id, mut block, end) = self.continuation.take().ok_or_else(|| invalid("unexpected continuation"))?;
+                block.extend_from_slice(payload.as_slice());
+                if block.len() > MAX_HEADER_BLOCK {
+                    return Err(invalid("header block too large"));
+                }
+                if flags & END_HEADERS != 0 {
+                    self.headers(id, block, end, call)?;
+                } else {
+                    self.continuation = Some((id, block, end));
+                }
+            }
+            RST_STREAM => {
+                self.streams.remove(&stream_id);
+            }
+            SETTINGS if flags & ACK == 0 => {
+                for setting in payload.chunks(6).filter(|setting| setting.len() == 6) {
+                    let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+                    // the windows of open streams move by the change, frames are never larger than the least allowed
+                    if u16::from_be_bytes([setting[0], setting[1]]) == SETTINGS_INITIAL_WINDOW_SIZE {
+                        let delta = value as i64 - self.initial_window;
+                        for stream in self.streams.values_mut() {
+                            stream.window += delta;
+                        }
+                        self.initial_window = value as i64;
+                    }
+                }
+                write_frame(&mut self.output, SETTINGS, ACK, 0, &[]);
+            }
+            PING if flags & ACK == 0 => write_frame(&mut self.output, PING, ACK, 0, payload.as_slice()),
+            WINDOW_UPDATE if payload.len() == 4 => {
+                let increment = (u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) & 0x7fff_ffff) as i64;
+                if stream_id == 0 {
+                    self.window += increment;
+                } else if let Some(stream) = self.streams.get_mut(&stream_id) {
+                    stream.window += increment;
+                }
+            }
+            // priorities, goaway and frames of extensions change nothing here
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // a request begins with the headers, the trailers of requests, if any, are only decoded
+    fn headers<C>(&mut self, stream_id: u32, block: Vec<u8>, end: bool, call: &mut C) -> Result<()>
+        where C: FnMut(&str, &[u8]) -> std::result::Result<Vec<Vec<u8>>, (u32, String)> {
+        let headers = self.decoder.decode(block.as_slice()).ok_or_else(|| invalid("invalid header block"))?;
+        if self.streams.contains_key(&stream_id) {
+            self.handle(stream_id, end, call);
+            return Ok(());
+        }
+        if stream_id % 2 == 0 || stream_id <= self.last_stream {
+            return Ok(());
+        }
+        self.last_stream = stream_id;
+        if self.streams.len() >= MAX_STREAMS {
+            write_frame(&mut self.output, RST_STREAM, 0, stream_id, &REFUSED_STREAM.to_be_bytes());
+            return Ok(());
+        }
+        let path = headers.into_iter().find(|(name, _)| name == ":path").map(|(_, value)| value).unwrap_or_default();
+        log::debug!("grpc call:{}", path);
+        self.streams.insert(stream_id, Stream {
+            path,
+            data: Vec::new(),
+            headers_sent: false,
+            pending: Vec::new(),
+            trailers: None,
+            window: self.initial_window,
+        });
+        self.handle(stream_id, end, call);
+        Ok(())
+    }
+
+    // answers the complete messages of the stream, and ends it once the client has or a call fails
+    fn handle<C>(&mut self, stream_id: u32, end: bool, call: &mut C)
+        where C: FnMut(&str, &[u8]) -> std::result::Result<Vec<Vec<u8>>, (u32, String)> {
+        let output = &mut self.output;
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(stream) if stream.trailers.is_none() => stream,
+            _ => return,
+        };
+        let mut status = None;
+        while stream.data.len() >= 5 {
+            let size = u32::from_be_bytes([stream.data[1], stream.data[2], stream.data[3], stream.data[4]]) as usize;
+            if size > MAX_MESSAGE_SIZE {
+                status = Some((RESOURCE_EXHAUSTED, "message too large".to_string()));
+                break;
+            }
+            if stream.data.len() < 5 + size {
+                break;
+            }
+            if stream.data[0] != 0 {
+                status = Some((UNIMPLEMENTED, "compressed messages are not supported".to_string()));
+                break;
+            }
+            let message: Vec<u8> = stream.data.drain(..5 + size).skip(5).collect();
+            match call(stream.path.as_str(), message.as_slice()) {
+                Ok(responses) => {
+                    if !stream.headers_sent {
+                        let mut block = vec![0x88];
+                        encode_header(&mut block, "content-type", "application/grpc");
+                        write_frame(output, HEADERS, END_HEADERS, stream_id, block.as_slice());
+                        stream.headers_sent = true;
+                    }
+                    for response in responses {
+                        stream.pending.push(0);
+                        stream.pending.extend_from_slice(&(response.len() as u32).to_be_bytes());
+                        stream.pending.extend_from_slice(response.as_slice());
+                    }
+                }
+                Err(err) => {
+                    status = Some(err);
+                    break;
+                }
+            }
+        }
+        if status.is_none() && end {
+            status = Some(if stream.data.is_empty() { (OK, String::new()) } else { (INTERNAL, "truncated message".to_string()) });
+        }
+        if let Some((code, message)) = status {
+            if code != OK {
+                log::info!("grpc call:{} failed with status {}:{}", stream.path, code, message);
+            }
+            // a response without messages is only trailers
+            let mut block = Vec::new();
+            if !stream.headers_sent {
+                block.push(0x88);
+                encode_header(&mut block, "content-type", "application/grpc");
+            }
+            encode_header(&mut block, "grpc-status", code.to_string().as_str());
+            if !message.is_empty() {
+                encode_header(&mut block, "grpc-message", percent_encode(message.as_str()).as_str());
+            }
+            stream.trailers = Some(block);
+        }
+    }
+
+    // sends what the windows let of the pending data of the streams, and the trailers once all of it is sent
+    fn send_pending(&mut self) {
+        let mut ended = Vec::new();
+        for (stream_id, stream) in self.streams.iter_mut() {
+            while !stream.pending.is_empty() {
+                let size = std::cmp::min(std::cmp::min(stream.window, self.window), MAX_FRAME_SIZE as i64);
+                if size <= 0 {
+                    break;
+                }
+                let size = std::cmp::min(size as usize, stream.pending.len());
+                write_frame(&mut self.output, DATA, 0, *stream_id, &stream.pending[..size]);
+                stream.pending.drain(..size);
+                stream.window -= size as i64;
+                self.window -= size as i64;
+            }
+            if stream.pending.is_empty() {
+                if let Some(block) = stream.trailers.take() {
+                    write_frame(&mut self.output, HEADERS, END_HEADERS | END_STREAM, *stream_id, block.as_slice());
+                    ended.push(*stream_id);
+                }
+            }
+        }
+        for stream_id in ended {
+            self.streams.remove(&stream_id);
+        }
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn write_frame(output: &mut Vec<u8>, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+    let size = payload.len();
+    output.extend_from_slice(&[(size >> 16) as u8, (size >> 8) as u8, size as u8, kind, flags]);
+    output.extend_from_slice(&stream_id.to_be_bytes());
+    output.extend_from_slice(payload);
+}
+
+fn unpad(flags: u8, payload: &[u8]) -> Result<&[u8]> {
+    if flags & PADDED == 0 {
+        return Ok(payload);
+    }
+    let padding = *payload.first().ok_or_else(|| invalid("invalid padding"))? as usize;
+    if padding + 1 > payload.len() {
+        return Err(invalid("invalid padding"));
+    }
+    let end = payload.len() - padding;
+    Ok(&payload[1..end])
+}
+
+// grpc-message is percent encoded, a name given by the client may be part of it
+fn percent_encode(message: &str) -> String {
+    let mut encoded = String::with_capacity(message.len());
+    for byte in message.bytes() {
+        if (0x20..0x7f).contains(&byte) && byte != b'%' {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(format!("%{:02X}", byte).as_str());
+        }
+    }
+    encoded
+}
+
+// a literal header field without indexing and with a new name, never compressed
+fn encode_header(block: &mut Vec<u8>, name: &str, value: &str) {
+    block.push(0);
+    for string in &[name, value] {
+        encode_int(block, 0, 7, string.len());
+        block.extend_from_slice(string.as_bytes());
+    }
+}
+
+fn encode_int(block: &mut Vec<u8>, first: u8, prefix: u32, mut value: usize) {
+    let max = (1 << prefix) - 1;
+    if value < max {
+        block.push(first | value as u8);
+        return;
+    }
+    block.push(first | max as u8);
+    value -= max;
+    while value >= 0x80 {
+        block.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+// the hpack decoder of a connection, whose dynamic table follows the header blocks of the client
+struct Decoder {
+    table: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+}
+
+impl Decoder {
+    fn new() -> Decoder {
+        Decoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: HEADER_TABLE_SIZE,
+        }
+    }
+
+    fn decode(&mut self, mut block: &[u8]) -> Option<Vec<(String, String)>> {
+        let mut headers = Vec::new();
+        while let Some(first) = block.first().cloned() {
+            if first & 0x80 != 0 {
+                let index = decode_int(&mut block, 7)?;
+                headers.push(self.get(index)?);
+            } else if first & 0xe0 == 0x20 {
+                let size = decode_int(&mut block, 5)?;
+                if size > HEADER_TABLE_SIZE {
+                    return None;
+                }
+                self.max_size = size;
+                self.evict(0);
+            } else {
+                // with incremental indexing, or without indexing and never indexed
+                let indexing = first & 0x40 != 0;
+                let index = decode_int(&mut block, if indexing { 6 } else { 4 })?;
+                let name = if index == 0 { decode_string(&mut block)? } else { self.get(index)?.0 };
+                let value = decode_string(&mut block)?;
+                if indexing {
+                    self.insert(name.clone(), value.clone());
+                }
+                headers.push((name, value));
+            }
+        }
+        Some(headers)
+    }
+
+    fn get(&self, index: usize) -> Option<(String, String)> {
+        if index == 0 {
+            None
+        } else if index <= STATIC_TABLE.len() {
+            let (name, value) = STATIC_TABLE[index - 1];
+            Some((name.to_string(), value.to_string()))
+        } else {
+            self.table.get(index - STATIC_TABLE.len() - 1).cloned()
+        }
+    }
+
+    fn insert(&mut self, name: String, value: String) {
+        let size = name.len() + value.len() + 32;
+        self.evict(size);
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front((name, value));
+        }
+    }
+
+    // drops the oldest entries until there is room for size
+    fn evict(&mut self, size: usize) {
+        while self.size + size > self.max_size {
+            match self.table.pop_back() {
+                Some((name, value)) => self.size -= name.len() + value.len() + 32,
+                None => break,
+            }
+        }
+    }
+}
+
+fn decode_int(block: &mut &[u8], prefix: u32) -> Option<usize> {
+    let max = (1 << prefix) - 1;
+    let (first, rest) = block.split_first()?;
+    *block = rest;
+    let mut value = *first as usize & max;
+    if value < max {
+        return Some(value);
+    }
+    let mut shift = 0;
+    loop {
+        let (byte, rest) = block.split_first()?;
+        *block = rest;
+        if shift > 21 {
+            return None;
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+}
+
+fn decode_string(block: &mut &[u8]) -> Option<String> {
+    let huffman = block.first()? & 0x80 != 0;
+    let size = decode_int(block, 7)?;
+    if block.len() < size {
+        return None;
+    }
+    let (string, rest) = block.split_at(size);
+    *block = rest;
+    let string = if huffman { huffman_decode(string)? } else { string.to_vec() };
+    Some(String::from_utf8_lossy(string.as_slice()).into_owned())
+}
+
+// decodes the canonical code bit by bit, the codes of a length follow those of shorter ones, so a code is found once it
+// is below the first code of its length plus their count
+fn huffman_decode(data: &[u8]) -> Option<Vec<u8>> {
+    let mut counts = [0u32; 31];
+    for length in HUFFMAN_LENGTHS.iter() {
+        counts[*length as usize] += 1;
+    }
+    let mut symbols: Vec<usize> = (0..HUFFMAN_LENGTHS.len()).collect();
+    symbols.sort_by_key(|symbol| HUFFMAN_LENGTHS[*symbol]);
+    let mut decoded = Vec::with_capacity(data.len() * 8 / 5);
+    let (mut code, mut first, mut index, mut length) = (0u32, 0u32, 0usize, 0usize);
+    for byte in data {
+        for bit in (0..8).rev() {
+            code |= (*byte >> bit & 1) as u32;
+            length += 1;
+            let count = counts[length];
+            if code < first + count {
+                let symbol = symbols[index + (code - first) as usize];
+                // the end of string is not sent in strings
+                if symbol == 256 {
+                    return None;
+                }
+                decoded.push(symbol as u8);
+                code = 0;
+                first = 0;
+                index = 0;
+                length = 0;
+            } else {
+                if length == counts.len() - 1 {
+                    return None;
+                }
+                index += count as usize;
+                first = (first + count) << 1;
+                code <<= 1;
+            }
+        }
+    }
+    // the padding is fewer than 8 bits of ones, the start of the end of string
+    if length > 7 || code >> 1 != (1 << length) - 1 {
+        return None;
+    }
+    Some(decoded)
+}
+
+// a field of a protobuf message, fixed width ones are not used by the api and skipped
+pub enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+// the fields of a message with their numbers, none if it is malformed
+pub fn parse_message(mut message: &[u8]) -> Option<Vec<(u32, Field<'_>)>> {
+    let mut fields = Vec::new();
+    while !message.is_empty() {
+        let key = read_varint(&mut message)?;
+        let number = (key >> 3) as u32;
+        match key & 7 {
+            0 => fields.push((number, Field::Varint(read_varint(&mut message)?))),
+            1 | 5 => {
+                let size = if key & 7 == 1 { 8 } else { 4 };
+                if message.len() < size {
+                    return None;
+                }
+                message = &message[size..];
+            }
+            2 => {
+                let size = read_varint(&mut message)? as usize;
+                if message.len() < size {
+                    return None;
+                }
+                let (bytes, rest) = message.split_at(size);
+                fields.push((number, Field::Bytes(bytes)));
+                message = rest;
+            }
+            _ => return None,
+        }
+    }
+    Some(fields)
+}
+
+fn read_varint(message: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = message.split_first()?;
+        *message = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn write_varint(message: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        message.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    message.push(value as u8);
+}
+
+pub fn put_varint(message: &mut Vec<u8>, number: u32, value: u64) {
+    write_varint(message, (number as u64) << 3);
+    write_varint(message, value);
+}
+
+pub fn put_bytes(message: &mut Vec<u8>, number: u32, bytes: &[u8]) {
+    write_varint(message, (number as u64) << 3 | 2);
+    write_varint(message, bytes.len() as u64);
+    message.extend_from_slice(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        let text: String = text.split_whitespace().collect();
+        (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn headers(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    // the requests with huffman coding of RFC 7541 C.4, on one connection
+    #[test]
+    fn huffman_requests() {
+        let mut decoder = Decoder::new();
+        let decoded = decoder.decode(&hex("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff")).unwrap();
+        assert_eq!(decoded, headers(&[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")]));
+        assert_eq!(decoder.size, 57);
+        let decoded = decoder.decode(&hex("8286 84be 5886 a8eb 1064 9cbf")).unwrap();
+        assert_eq!(decoded, headers(&[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com"),
+            ("cache-control", "no-cache")]));
+        assert_eq!(decoder.size, 110);
+        let decoded = decoder.decode(&hex("8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf")).unwrap();
+        assert_eq!(decoded, headers(&[(":method", "GET"), (":scheme", "https"), (":path", "/index.html"),
+            (":authority", "www.example.com"), ("custom-key", "custom-value")]));
+        assert_eq!(decoder.size, 164);
+        assert_eq!(decoder.get(62), Some(("custom-key".to_string(), "custom-value".to_string())));
+    }
+
+    // the responses of RFC 7541 C.6 with the table shrunk to 256 bytes by an update, the second one evicts
+    #[test]
+    fn evict_after_size_update() {
+        let mut decoder = Decoder::new();
+        let mut block = hex("3fe1 01");
+        block.extend(hex("4882 6402 5885 aec3 771a 4b61 96d0 7abe 9410 54d4 44a8 2005 9504 0b81 66e0 82a6 2d1b ff6e 919d 29ad 1718
+            63c7 8f0b 97c8 e9ae 82ae 43d3"));
+        let decoded = decoder.decode(&block).unwrap();
+        assert_eq!(decoded, headers(&[(":status", "302"), ("cache-control", "private"), ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+            ("location", "https://www.example.com")]));
+        assert_eq!((decoder.max_size, decoder.size, decoder.table.len()), (256, 222, 4));
+        let decoded = decoder.decode(&hex("4883 640e ffc1 c0bf")).unwrap();
+        assert_eq!(decoded[0], (":status".to_string(), "307".to_string()));
+        assert_eq!((decoder.size, decoder.table.len()), (222, 4));
+        assert_eq!(decoder.get(62), Some((":status".to_string(), "307".to_string())));
+        assert_eq!(decoder.get(65), Some(("cache-control".to_string(), "private".to_string())));
+        assert_eq!(decoder.get(66), None);
+        // shrinking the table drops the oldest entries, an update above the settings is refused
+        assert_eq!(decoder.decode(&hex("3f13")), Some(Vec::new()));
+        assert_eq!((decoder.size, decoder.table.len()), (42, 1));
+        assert_eq!(decoder.decode(&hex("3fe2 1f")), None);
+    }
+
+    #[test]
+    fn huffman_padding() {
+        // 0 is coded as five zero bits
+        assert_eq!(huffman_decode(&[0x07]), Some(b"0".to_vec()));
+        // padding of zeros, of more than 7 bits, or the end of string itself
+        assert_eq!(huffman_decode(&[0x00]), None);
+        assert_eq!(huffman_decode(&[0x07, 0xff]), None);
+        assert_eq!(huffman_decode(&[0xff, 0xff, 0xff, 0xfc]), None);
+    }
+
+    #[test]
+    fn integers() {
+        // 1337 with a 5 bit prefix, RFC 7541 C.1.2
+        let mut block = Vec::new();
+        encode_int(&mut block, 0, 5, 1337);
+        assert_eq!(block, vec![0x1f, 0x9a, 0x0a]);
+        assert_eq!(decode_int(&mut block.as_slice(), 5), Some(1337));
+        // truncated, and too large to be a size or an index
+        assert_eq!(decode_int(&mut &[0x7f, 0x80][..], 7), None);
+        assert_eq!(decode_int(&mut &[0x7f, 0xff, 0xff, 0xff, 0xff, 0x0f][..], 7), None);
+    }
+
+    #[test]
+    fn protobuf_round_trip() {
+        let mut message = Vec::new();
+        put_varint(&mut message, 1, 150);
+        assert_eq!(message, vec![0x08, 0x96, 0x01]);
+        put_bytes(&mut message, 2, b"alice");
+        // a fixed64 field is skipped
+        message.extend_from_slice(&[0x19, 1, 2, 3, 4, 5, 6, 7, 8]);
+        put_varint(&mut message, 300, u64::MAX);
+        put_bytes(&mut message, 4, &[]);
+        let fields = parse_message(message.as_slice()).unwrap();
+        assert_eq!(fields.len(), 4);
+        match fields.as_slice() {
+            [(1, Field::Varint(150)), (2, Field::Bytes(b"alice")), (300, Field::Varint(u64::MAX)), (4, Field::Bytes(&[]))] => {}
+            _ => panic!("unexpected fields"),
+        }
+        assert!(parse_message(&message[..message.len() - 3]).is_none());
+        assert!(parse_message(&[0x12, 0x05, b'a']).is_none());
+        assert!(parse_message(&[0x0b]).is_none());
+    }
+}
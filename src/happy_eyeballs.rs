@@ -0,0 +1,196 @@
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use mio::{Poll, PollOpt, Ready, Token};
+use crate::net::{self, Stream};
+use crate::sys::TcpOpts;
+use crate::upstream_proxy::Tunnel;
+
+pub enum ConnectResult {
+    Connected(Box<dyn Stream>, SocketAddr),
+    Pending,
+    Failed,
+}
+
+pub struct HappyEyeballs {
+    index: usize,
+    pending: VecDeque<SocketAddr>,
+    attempts: Vec<(SocketAddr, Box<dyn Stream>)>,
+    // addresses refused or unreachable since the last take_failed
+    failed: Vec<SocketAddr>,
+    next_attempt_time: Instant,
+    delay: Duration,
+    start_time: Instant,
+    tcp_opts: TcpOpts,
+    // the connect request to the upstream proxy the addresses belong to, and the connection it is sent on
+    tunnel: Option<Tunnel>,
+    tunneling: Option<(SocketAddr, Box<dyn Stream>)>,
+}
+
+impl HappyEyeballs {
+    pub fn new(index: usize, addrs: &[SocketAddr], delay: Duration, tcp_opts: &TcpOpts) -> HappyEyeballs {
+        // interleave address families as RFC 8305 suggests, starting with the preferred one
+        let mut pending = VecDeque::new();
+        if let Some(first) = addrs.first() {
+            let (mut preferred, mut others): (VecDeque<SocketAddr>, VecDeque<SocketAddr>) =
+                addrs.iter().partition(|addr| addr.is_ipv4() == first.is_ipv4());
+            loop {
+                match (preferred.pop_front(), others.pop_front()) {
+                    (None, None) => break,
+                    (Some(addr), None) | (None, Some(addr)) => pending.push_back(addr),
+                    (Some(addr1), Some(addr2)) => {
+                        pending.push_back(addr1);
+                        pending.push_back(addr2);
+                    }
+                }
+            }
+        }
+        HappyEyeballs {
+            index,
+            pending,
+            attempts: Vec::new(),
+            failed: Vec::new(),
+            next_attempt_time: net::now(),
+            delay,
+            start_time: net::now(),
+            tcp_opts: tcp_opts.clone(),
+            tunnel: None,
+            tunneling: None,
+        }
+    }
+
+    // the addresses are of an upstream proxy, connected only once it has connected to the server
+    pub fn set_tunnel(&mut self, tunnel: Tunnel) {
+        self.tunnel = Some(tunnel);
+    }
+
+    pub fn connect(&mut self, poll: &Poll, token: Token) -> bool {
+        self.start_next(poll, token);
+        !self.attempts.is_empty()
+    }
+
+    pub fn is_racing(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    pub fn check_timeout(&mut self, now: Instant, poll: &Poll, token: Token) -> bool {
+        if now >= self.next_attempt_time && !self.pending.is_empty() {
+            log::debug!("connection:{} attempt delay elapsed, start next attempt", self.index);
+            self.start_next(poll, token);
+        }
+        !self.attempts.is_empty() || self.tunneling.is_some()
+    }
+
+    pub fn take_failed(&mut self) -> Vec<SocketAddr> {
+        std::mem::take(&mut self.failed)
+    }
+
+    pub fn timed_out(&self, now: Instant, timeout: Duration) -> bool {
+        now > self.deadline(timeout)
+    }
+
+    pub fn deadline(&self, timeout: Duration) -> Instant {
+        self.start_time + timeout
+    }
+
+    pub fn ready(&mut self, poll: &Poll, token: Token) -> ConnectResult {
+        if let Some((addr, stream)) = self.tunneling.as_mut() {
+            return match self.tunnel.as_mut().unwrap().handshake(stream) {
+                Ok(false) => ConnectResult::Pending,
+                Ok(true) => {
+                    let (addr, stream) = self.tunneling.take().unwrap();
+                    log::debug!("connection:{} upstream proxy {} connected to server", self.index, addr);
+                    ConnectResult::Connected(stream, addr)
+                }
+                Err(err) => {
+                    log::warn!("connection:{} upstream proxy {} failed:{}", self.index, addr, err);
+                    let _ = poll.deregister(&**stream);
+                    self.tunneling = None;
+                    ConnectResult::Failed
+                }
+            };
+        }
+        let mut i = 0;
+        while i < self.attempts.len() {
+            let (addr, stream) = &self.attempts[i];
+            let failed = match stream.take_error() {
+                Ok(Some(err)) | Err(err) => {
+                    log::warn!("connection:{} connect to {} failed:{}", self.index, addr, err);
+                    true
+                }
+                Ok(None) => match stream.peer_addr() {
+                    Ok(_) => {
+                        let (addr, stream) = self.attempts.swap_remove(i);
+                        log::debug!("connection:{} connect to {} succeeded", self.index, addr);
+                        for (_, other) in self.attempts.drain(..) {
+                            let _ = poll.deregister(&*other);
+                        }
+                        self.pending.clear();
+                        if self.tunnel.is_none() {
+                            return ConnectResult::Connected(stream, addr);
+                        }
+                        if let Err(err) = poll.reregister(&*stream, token, Ready::readable() | Ready::writable(), PollOpt::edge()) {
+                            log::error!("connection:{} register connection to upstream proxy {} failed:{}", self.index, addr, err);
+                            let _ = poll.deregister(&*stream);
+                            return ConnectResult::Failed;
+                        }
+                        self.tunneling = Some((addr, stream));
+                        return self.ready(poll, token);
+                    }
+                    Err(err) if err.kind() == ErrorKind::NotConnected => false,
+                    Err(err) => {
+                        log::warn!("connection:{} connect to {} failed:{}", self.index, addr, err);
+                        true
+                    }
+                },
+            };
+            if failed {
+                let (addr, stream) = self.attempts.swap_remove(i);
+                let _ = poll.deregister(&*stream);
+                self.failed.push(addr);
+            } else {
+                i += 1;
+            }
+        }
+
+        if self.attempts.is_empty() {
+            // the running attempts all failed, no need to wait for the delay
+            self.start_next(poll, token);
+        }
+        if self.attempts.is_empty() {
+            ConnectResult::Failed
+        } else {
+            ConnectResult::Pending
+        }
+    }
+
+    pub fn close(&mut self, poll: &Poll) {
+        for (_, stream) in self.attempts.drain(..).chain(self.tunneling.take()) {
+            let _ = poll.deregister(&*stream);
+        }
+        self.pending.clear();
+    }
+
+    fn start_next(&mut self, poll: &Poll, token: Token) {
+        while let Some(addr) = self.pending.pop_front() {
+            match net::connect(&self.tcp_opts, &addr) {
+                Ok(stream) => {
+                    if let Err(err) = poll.register(&*stream, token, Ready::writable(), PollOpt::edge()) {
+                        log::error!("connection:{} register connection to {} failed:{}", self.index, addr, err);
+                        continue;
+                    }
+                    log::debug!("connection:{} connecting to {}", self.index, addr);
+                    self.attempts.push((addr, stream));
+                    self.next_attempt_time = net::now() + self.delay;
+                    break;
+                }
+                Err(err) => {
+                    log::warn!("connection:{} connect to {} failed:{}", self.index, addr, err);
+                    self.failed.push(addr);
+                }
+            }
+        }
+    }
+}
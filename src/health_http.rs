@@ -0,0 +1,214 @@
+use std::io::{ErrorKind, Read, Result, Write};
+use std::net::SocketAddr;
+
+use mio::{Poll, PollOpt, Ready, Token};
+use mio::net::{TcpListener, TcpStream};
+
+// requests longer than this are not expected, the client is dropped
+const MAX_REQUEST_LEN: usize = 4096;
+const TEXT: &str = "text/plain";
+
+// a small http endpoint for kubernetes and load balancers, /healthz answers as long as the event loop runs,
+// /readyz only while trojan can take connections, e.g. curl http://127.0.0.1:8080/readyz
+pub struct HealthHttp {
+    listener: TcpListener,
+    client_token: Token,
+    clients: Vec<Client>,
+    // paths under /dashboard are served only with ?token= matching it
+    dashboard_token: Option<String>,
+}
+
+struct Client {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    // the part of the response the socket has not taken yet
+    response: Vec<u8>,
+}
+
+impl HealthHttp {
+    // clients are all registered with client_token, as there are few of them
+    pub fn new(addr: &SocketAddr, poll: &Poll, token: Token, client_token: Token) -> Result<HealthHttp> {
+        let http = HealthHttp::bind(addr, poll, token, client_token)?;
+        log::warn!("health endpoint listening on {}", addr);
+        Ok(http)
+    }
+
+    // a listener serving other paths than the health ones with serve
+    pub fn bind(addr: &SocketAddr, poll: &Poll, token: Token, client_token: Token) -> Result<HealthHttp> {
+        let listener = TcpListener::bind(addr)?;
+        poll.register(&listener, token, Ready::readable(), PollOpt::level())?;
+        Ok(HealthHttp {
+            listener,
+            client_token,
+            clients: Vec::new(),
+            dashboard_token: None,
+        })
+    }
+
+    pub fn set_dashboard_token(&mut self, token: String) {
+        self.dashboard_token.replace(token);
+    }
+
+    pub fn accept(&mut self, poll: &Poll) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(err) = poll.register(&stream, self.client_token, Ready::readable(), PollOpt::level()) {
+                        log::error!("register http client failed:{}", err);
+                        continue;
+                    }
+                    self.clients.push(Client {
+                        stream,
+                        buffer: Vec::new(),
+                        response: Vec::new(),
+                    });
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::error!("accept http client failed:{}", err);
+                    break;
+                }
+            }
+        }
+    }
+
+    // answers health and dashboard requests, ready tells why trojan is not ready, if it is not, dashboard gives the
+    // content type and the body of a path under /dashboard
+    pub fn ready<F, D>(&mut self, poll: &Poll, mut ready: F, mut dashboard: D)
+        where F: FnMut() -> std::result::Result<(), String>, D: FnMut(&str) -> Option<(&'static str, String)> {
+        let dashboard_token = self.dashboard_token.clone();
+        self.serve(poll, |path, query| match path {
+            "/healthz" => ("200 OK", TEXT, "ok".to_string()),
+            "/readyz" => match ready() {
+                Ok(()) => ("200 OK", TEXT, "ok".to_string()),
+                Err(reason) => ("503 Service Unavailable", TEXT, reason),
+            },
+            path if path.starts_with("/dashboard") && dashboard_token.is_some() => {
+                if !is_authorized(dashboard_token.as_ref().unwrap(), query) {
+                    ("401 Unauthorized", TEXT, "unauthorized".to_string())
+                } else {
+                    match dashboard(&path["/dashboard".len()..]) {
+                        Some((content_type, body)) => ("200 OK", content_type, body),
+                        None => not_found(),
+                    }
+                }
+            }
+            _ => not_found(),
+        });
+    }
+
+    // answers each client which has sent the whole request with the status, the content type and the body respond
+    // gives for the path and the query
+    pub fn serve<R: FnMut(&str, &str) -> (&'static str, &'static str, String)>(&mut self, poll: &Poll, mut respond: R) {
+        let mut i = 0;
+        while i < self.clients.len() {
+            if !self.clients[i].response.is_empty() {
+                if self.flush(i, poll) {
+                    i += 1;
+                }
+                continue;
+            }
+            match self.clients[i].read_path() {
+                Ok(None) => {
+                    i += 1;
+                    continue;
+                }
+                Ok(Some(target)) => {
+                    let (path, query) = match target.find('?') {
+                        Some(pos) => (&target[..pos], &target[pos + 1..]),
+                        None => (target.as_str(), ""),
+                    };
+                    log::debug!("http request:{}", path);
+                    let (status, content_type, body) = respond(path, query);
+                    let response = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}\n",
+                                           status, content_type, body.len() + 1, body);
+                    self.clients[i].response = response.into_bytes();
+                    if self.flush(i, poll) {
+                        i += 1;
+                    }
+                    continue;
+                }
+                Err(err) => log::debug!("read http request failed:{}", err),
+            }
+            let client = self.clients.swap_remove(i);
+            let _ = poll.deregister(&client.stream);
+        }
+    }
+
+    // writes what the socket takes of the response, returns true if the client waits for the socket to be writable,
+    // otherwise it is removed
+    fn flush(&mut self, i: usize, poll: &Poll) -> bool {
+        let client = &mut self.clients[i];
+        let result = match client.stream.write(client.response.as_slice()) {
+            Ok(size) => {
+                client.response.drain(..size);
+                Ok(())
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(()),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(()) if !client.response.is_empty() => {
+                match poll.reregister(&client.stream, self.client_token, Ready::writable(), PollOpt::level()) {
+                    Ok(()) => return true,
+                    Err(err) => log::warn!("register http client failed:{}", err),
+                }
+            }
+            Ok(()) => {}
+            Err(err) => log::warn!("write http response failed:{}", err),
+        }
+        let client = self.clients.swap_remove(i);
+        let _ = poll.deregister(&client.stream);
+        false
+    }
+}
+
+pub fn not_found() -> (&'static str, &'static str, String) {
+    ("404 Not Found", TEXT, "not found".to_string())
+}
+
+// the token is compared in constant time, it is the only thing between the dashboard and anyone reaching the address
+fn is_authorized(token: &str, query: &str) -> bool {
+    let token = token.as_bytes();
+    query.split('&')
+        .filter(|param| param.starts_with("token="))
+        .any(|param| {
+            let value = &param.as_bytes()["token=".len()..];
+            value.len() == token.len() && value.iter().zip(token.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+        })
+}
+
+impl Client {
+    // the target of the request line, the path with the query, once the headers are all read
+    fn read_path(&mut self) -> Result<Option<String>> {
+        let mut buffer = [0u8; 1024];
+        let mut eof = false;
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => {
+                    eof = true;
+                    break;
+                }
+                Ok(size) => self.buffer.extend_from_slice(&buffer[..size]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+            if self.buffer.len() > MAX_REQUEST_LEN {
+                return Err(ErrorKind::InvalidData.into());
+            }
+        }
+        if !self.buffer.windows(4).any(|window| window == b"\r\n\r\n") {
+            if eof {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            return Ok(None);
+        }
+        let line = self.buffer.split(|c| *c == b'\n').next().unwrap_or(&[]);
+        let line = String::from_utf8_lossy(line);
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next()) {
+            (Some("GET"), Some(target)) => Ok(Some(target.to_string())),
+            _ => Err(ErrorKind::InvalidData.into()),
+        }
+    }
+}
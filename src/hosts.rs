@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Result};
+use std::net::IpAddr;
+
+// domains the server answers by a hosts format file instead of the resolver, each line is an address followed by
+// domains, 0.0.0.0 or :: blocks the domains, and *.example.com takes the subdomains of example.com
+#[derive(Default)]
+pub struct Hosts {
+    // no addresses for blocked domains
+    names: HashMap<String, Vec<IpAddr>>,
+    wildcards: HashMap<String, Vec<IpAddr>>,
+}
+
+impl Hosts {
+    pub fn load(path: &str) -> Result<Hosts> {
+        let file = File::open(path)?;
+        let mut hosts = Hosts::default();
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            let line = match line.find('#') {
+                Some(pos) => &line[..pos],
+                None => line.as_str(),
+            };
+            let mut fields = line.split_whitespace();
+            let ip: IpAddr = match fields.next() {
+                Some(ip) => match ip.parse() {
+                    Ok(ip) => ip,
+                    Err(_) => {
+                        log::warn!("invalid address in hosts file {} line {}:{}", path, i + 1, ip);
+                        continue;
+                    }
+                },
+                None => continue,
+            };
+            for name in fields {
+                let name = name.trim_end_matches('.').to_lowercase();
+                let (map, name) = if let Some(name) = name.strip_prefix("*.") {
+                    (&mut hosts.wildcards, name.to_string())
+                } else {
+                    (&mut hosts.names, name)
+                };
+                // a domain blocked once stays blocked whatever other lines give it
+                let blocked = map.get(&name).map_or(false, |addresses| addresses.is_empty());
+                if ip.is_unspecified() {
+                    map.insert(name, Vec::new());
+                } else if !blocked {
+                    map.entry(name).or_insert_with(Vec::new).push(ip);
+                }
+            }
+        }
+        log::warn!("{} domains loaded from hosts file {}", hosts.count(), path);
+        Ok(hosts)
+    }
+
+    pub fn count(&self) -> usize {
+        self.names.len() + self.wildcards.len()
+    }
+
+    // the addresses of the domain, empty if it is blocked, none if the resolver is asked for it
+    pub fn lookup(&self, domain: &str) -> Option<&Vec<IpAddr>> {
+        if self.names.is_empty() && self.wildcards.is_empty() {
+            return None;
+        }
+        let domain = domain.trim_end_matches('.').to_lowercase();
+        if let Some(addresses) = self.names.get(&domain) {
+            return Some(addresses);
+        }
+        let mut suffix = domain.as_str();
+        while let Some(pos) = suffix.find('.') {
+            suffix = &suffix[pos + 1..];
+            if let Some(addresses) = self.wildcards.get(suffix) {
+                return Some(addresses);
+            }
+        }
+        None
+    }
+}
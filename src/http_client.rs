@@ -0,0 +1,138 @@
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::{ClientConfig, ClientSession, StreamOwned};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use webpki::DNSNameRef;
+
+use crate::config::IpStrategy;
+use crate::resolver;
+use crate::sys;
+
+// http[s]://hostname[:port][/path]
+struct Url<'a> {
+    https: bool,
+    hostname: &'a str,
+    port: u16,
+    path: &'a str,
+}
+
+pub fn is_url(s: &str) -> bool {
+    s.starts_with("https://") || s.starts_with("http://")
+}
+
+fn parse_url(url: &str) -> Result<Url<'_>> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid url:{}", url));
+    let (https, rest) = if let Some(rest) = url.strip_prefix("https://") {
+        (true, rest)
+    } else if let Some(rest) = url.strip_prefix("http://") {
+        (false, rest)
+    } else {
+        return Err(invalid());
+    };
+    let (host, path) = match rest.find('/') {
+        Some(pos) => (&rest[..pos], &rest[pos..]),
+        None => (rest, "/"),
+    };
+    let (hostname, port) = match host.rfind(':') {
+        Some(pos) => (&host[..pos], host[pos + 1..].parse().map_err(|_| invalid())?),
+        None => (host, if https { 443 } else { 80 }),
+    };
+    if hostname.is_empty() {
+        return Err(invalid());
+    }
+    Ok(Url { https, hostname, port, path })
+}
+
+fn connect(url: &Url, marker: u8, strategy: IpStrategy, timeout: Duration) -> Result<std::net::TcpStream> {
+    let addr = match url.hostname.parse() {
+        Ok(ip) => SocketAddr::new(ip, url.port),
+        Err(_) => {
+            let resolver = resolver::new_resolver(strategy)?;
+            let ip = resolver.lookup_ip(url.hostname)
+                .map_err(|err| Error::new(ErrorKind::Other, err.to_string()))?
+                .iter().next()
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("no address found for {}", url.hostname)))?;
+            SocketAddr::new(ip, url.port)
+        }
+    };
+    let domain = if addr.is_ipv4() {
+        Domain::ipv4()
+    } else {
+        Domain::ipv6()
+    };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    sys::set_mark(&socket, marker)?;
+    socket.connect_timeout(&SockAddr::from(addr), timeout)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.set_write_timeout(Some(timeout))?;
+    Ok(socket.into_tcp_stream())
+}
+
+fn request<S: Read + Write>(stream: &mut S, url: &Url, method: &str, body: Option<(&str, &[u8])>, max_size: usize) -> Result<(u16, String)> {
+    // http/1.0 keeps the server from sending chunked responses
+    let mut request = format!("{} {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: trojan-rs\r\nAccept: */*\r\nConnection: close\r\n", method, url.path, url.hostname);
+    if let Some((content_type, body)) = body {
+        request.push_str(format!("Content-Type: {}\r\nContent-Length: {}\r\n", content_type, body.len()).as_str());
+    }
+    request.push_str("\r\n");
+    let mut request = request.into_bytes();
+    if let Some((_, body)) = body {
+        request.extend_from_slice(body);
+    }
+    stream.write_all(request.as_slice())?;
+    let mut response = Vec::new();
+    let mut buffer = [0u8; 4096];
+    loop {
+        match stream.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => response.extend_from_slice(&buffer[..n]),
+            // some servers close the tls connection without close_notify
+            Err(err) if err.kind() == ErrorKind::ConnectionAborted && !response.is_empty() => break,
+            Err(err) => return Err(err),
+        }
+        if response.len() > max_size {
+            return Err(Error::new(ErrorKind::InvalidData, "http response too large"));
+        }
+    }
+    let response = String::from_utf8_lossy(response.as_slice()).to_string();
+    let pos = response.find("\r\n\r\n").ok_or_else(|| Error::new(ErrorKind::InvalidData, "invalid http response"))?;
+    let status = response.lines().next().unwrap_or("");
+    let status = status.split_whitespace().nth(1).and_then(|code| code.parse().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("invalid http status:{}", status)))?;
+    Ok((status, response[pos + 4..].to_string()))
+}
+
+// a blocking http/1.0 request, the status and the body of the response, for the threads fetching subscriptions and
+// calling hooks, not for the event loop
+pub fn send(url: &str, method: &str, body: Option<(&str, &[u8])>, marker: u8, strategy: IpStrategy, timeout: Duration, max_size: usize)
+            -> Result<(u16, String)> {
+    let url = parse_url(url)?;
+    let stream = connect(&url, marker, strategy, timeout)?;
+    if url.https {
+        let mut config = ClientConfig::new();
+        config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        let dns_name = DNSNameRef::try_from_ascii(url.hostname.as_bytes())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("invalid hostname:{}", url.hostname)))?;
+        let session = ClientSession::new(&Arc::new(config), dns_name);
+        request(&mut StreamOwned::new(session, stream), &url, method, body, max_size)
+    } else {
+        let mut stream = stream;
+        request(&mut stream, &url, method, body, max_size)
+    }
+}
+
+// percent encoding of a query value
+pub fn encode_query(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(format!("%{:02X}", byte).as_str());
+        }
+    }
+    encoded
+}
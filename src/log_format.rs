@@ -0,0 +1,97 @@
+use std::fmt::{Arguments, Write};
+
+use log::Record;
+
+// fields of a record, taken from the key:value words the log messages are written with
+#[derive(Default)]
+struct Fields<'a> {
+    conn_id: Option<usize>,
+    peer: Option<&'a str>,
+    target: Option<&'a str>,
+    bytes: Option<usize>,
+}
+
+impl<'a> Fields<'a> {
+    fn parse(message: &'a str) -> Fields<'a> {
+        let mut fields = Fields::default();
+        let mut last = None;
+        for word in message.split_whitespace() {
+            let word = word.trim_end_matches(',');
+            if word.starts_with("connection:") && fields.conn_id.is_none() {
+                fields.conn_id = word["connection:".len()..].parse().ok();
+            } else if word.starts_with("from:") && fields.peer.is_none() {
+                fields.peer = Some(&word["from:".len()..]);
+            } else if word.starts_with("to:") && fields.target.is_none() {
+                fields.target = Some(&word["to:".len()..]);
+            } else if word == "bytes" && fields.bytes.is_none() {
+                fields.bytes = last.and_then(|last: &str| last.parse().ok());
+            }
+            last = Some(word);
+        }
+        fields
+    }
+}
+
+// one json object per line, fields missing from the record are null
+pub fn json(out: fern::FormatCallback, message: &Arguments, record: &Record) {
+    let message = message.to_string();
+    let fields = Fields::parse(message.as_str());
+    let mut line = String::with_capacity(message.len() + 192);
+    line.push('{');
+    add_str(&mut line, "timestamp", Some(chrono::Local::now().to_rfc3339().as_str()));
+    line.push(',');
+    add_str(&mut line, "level", Some(record.level().as_str()));
+    line.push(',');
+    add_num(&mut line, "conn_id", fields.conn_id);
+    line.push(',');
+    add_str(&mut line, "peer", fields.peer);
+    line.push(',');
+    add_str(&mut line, "target", fields.target);
+    line.push(',');
+    add_num(&mut line, "bytes", fields.bytes);
+    line.push(',');
+    add_str(&mut line, "file", record.file());
+    line.push(',');
+    add_num(&mut line, "line", record.line());
+    line.push(',');
+    add_str(&mut line, "message", Some(message.as_str()));
+    line.push('}');
+    out.finish(format_args!("{}", line))
+}
+
+pub fn add_str(line: &mut String, name: &str, value: Option<&str>) {
+    let _ = write!(line, "\"{}\":", name);
+    let value = match value {
+        Some(value) => value,
+        None => {
+            line.push_str("null");
+            return;
+        }
+    };
+    line.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            '\t' => line.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(line, "\\u{:04x}", c as u32);
+            }
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
+
+pub fn add_num<T: std::fmt::Display>(line: &mut String, name: &str, value: Option<T>) {
+    match value {
+        Some(value) => {
+            let _ = write!(line, "\"{}\":{}", name, value);
+        }
+        None => {
+            let _ = write!(line, "\"{}\":null", name);
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use log::LevelFilter;
+
+use crate::access_log;
+
+// the level of the main log, changed at runtime by the admin socket and SIGUSR1, records above it are cut by
+// log::max_level before they are formatted unless the access or the security log takes them
+static LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Info as usize);
+// the least level the access and security logs need, whatever the level of the main log
+static FLOOR: AtomicUsize = AtomicUsize::new(LevelFilter::Off as usize);
+// the level and the access log state before SIGUSR1 turned on tracing, restored by the next one
+static SAVED_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Info as usize);
+static SAVED_ACCESS_LOG: AtomicBool = AtomicBool::new(true);
+static TRACING: AtomicBool = AtomicBool::new(false);
+
+fn from_usize(value: usize) -> LevelFilter {
+    match value {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+// --log-level, 0 for trace up to 5 for off
+pub fn from_option(value: u8) -> LevelFilter {
+    match value {
+        0x00 => LevelFilter::Trace,
+        0x01 => LevelFilter::Debug,
+        0x02 => LevelFilter::Info,
+        0x03 => LevelFilter::Warn,
+        0x04 => LevelFilter::Error,
+        _ => LevelFilter::Off,
+    }
+}
+
+// a name like trace or warn, or a number like --log-level takes
+pub fn parse(value: &str) -> Option<LevelFilter> {
+    match value.parse::<u8>() {
+        Ok(value) if value <= 5 => Some(from_option(value)),
+        Ok(_) => None,
+        Err(_) => LevelFilter::from_str(value).ok(),
+    }
+}
+
+pub fn setup(level: LevelFilter, floor: LevelFilter) {
+    FLOOR.store(floor as usize, Ordering::SeqCst);
+    set(level);
+}
+
+pub fn get() -> LevelFilter {
+    from_usize(LEVEL.load(Ordering::Relaxed))
+}
+
+pub fn set(level: LevelFilter) {
+    LEVEL.store(level as usize, Ordering::SeqCst);
+    log::set_max_level(std::cmp::max(level, from_usize(FLOOR.load(Ordering::SeqCst))));
+}
+
+// SIGUSR1 turns on trace logs and the access log for an incident, and the next one turns them back
+pub fn toggle_trace() {
+    if TRACING.swap(false, Ordering::SeqCst) {
+        log::warn!("tracing is turned off, log level is back to {}", from_usize(SAVED_LEVEL.load(Ordering::SeqCst)));
+        set(from_usize(SAVED_LEVEL.load(Ordering::SeqCst)));
+        access_log::set_enabled(SAVED_ACCESS_LOG.load(Ordering::SeqCst));
+    } else {
+        TRACING.store(true, Ordering::SeqCst);
+        SAVED_LEVEL.store(get() as usize, Ordering::SeqCst);
+        SAVED_ACCESS_LOG.store(access_log::is_enabled(), Ordering::SeqCst);
+        set(LevelFilter::Trace);
+        access_log::set_enabled(true);
+        log::warn!("tracing is turned on, send SIGUSR1 again to turn it off");
+    }
+}
@@ -0,0 +1,127 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Result, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+use crate::config::LogRotate;
+
+// the log file, moved aside into timestamped archives by size or time
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    rotate: LogRotate,
+    period: String,
+    keep: usize,
+    compress: bool,
+}
+
+impl RotatingFile {
+    pub fn new(path: &str, max_size: u64, rotate: LogRotate, keep: usize, compress: bool) -> Result<RotatingFile> {
+        let path = PathBuf::from(path);
+        let file = open(path.as_path())?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            file,
+            size,
+            max_size,
+            rotate,
+            period: rotate.period(),
+            keep,
+            compress,
+        })
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        let archive = PathBuf::from(format!("{}.{}", self.path.display(), chrono::Local::now().format("%Y%m%d-%H%M%S%.3f")));
+        fs::rename(self.path.as_path(), archive.as_path())?;
+        self.file = open(self.path.as_path())?;
+        self.size = 0;
+        let path = self.path.clone();
+        let keep = self.keep;
+        if self.compress {
+            // compressing takes a while, which should not block the relay
+            std::thread::spawn(move || {
+                if let Err(err) = compress(archive.as_path()) {
+                    eprintln!("compress log file {} failed:{}", archive.display(), err);
+                }
+                remove_old(path.as_path(), keep);
+            });
+        } else {
+            remove_old(path.as_path(), keep);
+        }
+        Ok(())
+    }
+}
+
+// fern flushes after each record, so records are never split between files
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let size = self.file.write(buf)?;
+        self.size += size as u64;
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.flush()?;
+        let period = self.rotate.period();
+        if (self.max_size > 0 && self.size >= self.max_size) || period != self.period {
+            self.period = period;
+            if let Err(err) = self.rotate() {
+                eprintln!("rotate log file {} failed:{}", self.path.display(), err);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn compress(archive: &Path) -> Result<()> {
+    let mut input = File::open(archive)?;
+    let output = File::create(format!("{}.gz", archive.display()))?;
+    let mut encoder = GzEncoder::new(output, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(archive)
+}
+
+// archive names sort by time, the oldest ones beyond keep are removed, 0 keeps all
+fn remove_old(path: &Path, keep: usize) {
+    if keep == 0 {
+        return;
+    }
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = match path.file_name() {
+        Some(name) => format!("{}.", name.to_string_lossy()),
+        None => return,
+    };
+    let mut archives: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(prefix.as_str()))
+            .map(|entry| entry.path())
+            .collect(),
+        Err(err) => {
+            eprintln!("list log directory {} failed:{}", dir.display(), err);
+            return;
+        }
+    };
+    if archives.len() <= keep {
+        return;
+    }
+    archives.sort();
+    for archive in &archives[..archives.len() - keep] {
+        if let Err(err) = fs::remove_file(archive) {
+            eprintln!("remove log file {} failed:{}", archive.display(), err);
+        }
+    }
+}
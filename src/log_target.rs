@@ -0,0 +1,83 @@
+#[cfg(unix)]
+use log::{Level, Record};
+
+#[cfg(unix)]
+use std::ffi::CString;
+#[cfg(target_os = "linux")]
+use std::io::Result;
+#[cfg(target_os = "linux")]
+use std::os::unix::net::UnixDatagram;
+
+#[cfg(unix)]
+static IDENT: &[u8] = b"trojan\0";
+#[cfg(target_os = "linux")]
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+// syslog priorities, which journald uses as well
+#[cfg(unix)]
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+#[cfg(unix)]
+pub fn syslog() -> crate::error::Result<fern::Output> {
+    unsafe {
+        libc::openlog(IDENT.as_ptr() as *const libc::c_char, libc::LOG_PID | libc::LOG_NDELAY, libc::LOG_DAEMON);
+    }
+    Ok(fern::Output::call(|record: &Record| {
+        // interior nul bytes can not be passed to syslog
+        let message = format!("{}", record.args()).replace('\0', "\\0");
+        if let Ok(message) = CString::new(message) {
+            unsafe {
+                libc::syslog(priority(record.level()) as libc::c_int, b"%s\0".as_ptr() as *const libc::c_char, message.as_ptr());
+            }
+        }
+    }))
+}
+
+#[cfg(not(unix))]
+pub fn syslog() -> crate::error::Result<fern::Output> {
+    Err(crate::error::Error::Config("syslog is not supported on this platform".to_string()))
+}
+
+// entries in the native protocol of journald, so that fields like the source line are kept
+#[cfg(target_os = "linux")]
+pub fn journald() -> Result<fern::Output> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(JOURNALD_SOCKET)?;
+    Ok(fern::Output::call(move |record: &Record| {
+        let mut entry = Vec::new();
+        add_field(&mut entry, "PRIORITY", priority(record.level()).to_string().as_bytes());
+        add_field(&mut entry, "SYSLOG_IDENTIFIER", b"trojan");
+        add_field(&mut entry, "CODE_FILE", record.file().unwrap_or("unknown").as_bytes());
+        add_field(&mut entry, "CODE_LINE", record.line().unwrap_or(0).to_string().as_bytes());
+        add_field(&mut entry, "MESSAGE", format!("{}", record.args()).as_bytes());
+        if let Err(err) = socket.send(entry.as_slice()) {
+            eprintln!("send log to journald failed:{}", err);
+        }
+    }))
+}
+
+// values with new lines are sent with their length instead
+#[cfg(target_os = "linux")]
+fn add_field(entry: &mut Vec<u8>, name: &str, value: &[u8]) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains(&b'\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value);
+    entry.push(b'\n');
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn journald() -> std::io::Result<fern::Output> {
+    Err(std::io::Error::new(std::io::ErrorKind::Other, "journald is only supported on linux"))
+}
@@ -1,30 +1,228 @@
+// lints asking for std apis newer than the code is written against, and for changes to the layout kept from upstream
+#![allow(clippy::unnecessary_map_or, clippy::io_other_error, clippy::manual_is_multiple_of, clippy::manual_div_ceil,
+         clippy::manual_hash_one, clippy::too_many_arguments, clippy::module_inception, clippy::large_enum_variant,
+         clippy::unnecessary_unwrap)]
+
+use std::io::BufRead;
+
 use clap::{App, AppSettings, FromArgMatches};
 use clap::derive::IntoApp;
 
-use crate::config::{Mode, Opts};
+use crate::config::{CheckArgs, HashArgs, Mode, Opts, StatsArgs};
 
 mod server;
 mod config;
+mod error;
 mod proto;
 mod sys;
 mod proxy;
 mod session;
+mod budget;
+mod dns_cache;
+mod replay_cache;
+mod security_log;
+mod resolver;
+mod happy_eyeballs;
+mod net;
+mod timer_wheel;
+mod cidr;
+mod fake_dns;
+mod hosts;
+mod route;
+mod geosite;
+mod pac;
+mod sniff;
+mod state;
+mod stats_file;
+mod padding;
+mod upstream;
+mod upstream_proxy;
+mod http_client;
+mod subscription;
+mod plugin;
+mod firewall;
+mod service;
+mod systemd;
+mod daemon;
+mod privilege;
+mod sandbox;
+mod upgrade;
+mod log_rotate;
+mod log_target;
+mod log_format;
+mod access_log;
+mod log_level;
+mod flow_export;
+mod webhook;
+mod admin;
+mod health_http;
+mod grpc;
+mod password;
+mod check;
+mod selftest;
+mod bench;
+#[cfg(test)]
+mod replay;
+
+pub fn parse_opts() -> Opts {
+    let app: App = <Opts as IntoApp>::into_app().setting(AppSettings::AllowExternalSubcommands);
+    <Opts as FromArgMatches>::from_arg_matches(&app.get_matches())
+}
 
 fn main() {
-    let mut app: App = <Opts as IntoApp>::into_app();
-    app.set(AppSettings::AllowExternalSubcommands);
-    let mut opts = <Opts as FromArgMatches>::from_arg_matches(&app.get_matches());
+    let mut opts = parse_opts();
+
+    // there is no logger to report a failure to yet
+    if let Err(err) = config::setup_logger(&opts) {
+        eprintln!("{}", err);
+        if let Some(hint) = err.hint() {
+            eprintln!("{}", hint);
+        }
+        std::process::exit(err.exit_code());
+    }
+    if let Mode::SetupFirewall(_) = opts.mode {
+        firewall::run(&opts);
+        return;
+    }
+    if let Mode::Service(_) = opts.mode {
+        service::control(&opts);
+        return;
+    }
+    if let Mode::Hash(ref args) = opts.mode {
+        hash(args);
+        return;
+    }
+    if let Mode::Stats(ref args) = opts.mode {
+        stats(args);
+        return;
+    }
+    if let Mode::CheckConfig(ref args) = opts.mode {
+        check_config(args);
+        return;
+    }
+    if let Mode::Selftest(ref args) = opts.mode {
+        selftest::run(args);
+    }
+    if let Mode::Bench(ref args) = opts.mode {
+        bench::run(args);
+        return;
+    }
+    if opts.check {
+        check::run(&mut opts);
+    }
+    if let Some(name) = opts.service.as_ref() {
+        service::dispatch(name.as_str());
+        return;
+    }
+    // locked before forking, so that a second instance fails in the foreground
+    let mut pid_file = opts.pid_file.as_ref().map(|path| match daemon::PidFile::lock(path.as_str()) {
+        Ok(pid_file) => pid_file,
+        Err(err) => {
+            log::error!("lock pid file {} failed:{}", path, err);
+            std::process::exit(1);
+        }
+    });
+    if opts.daemon && !upgrade::upgraded() {
+        if opts.log_target() == config::LogTarget::Stdout {
+            log::warn!("logs to stdout are discarded in daemon mode");
+        }
+        if let Err(err) = daemon::daemonize() {
+            log::error!("daemonize failed:{}", err);
+            std::process::exit(1);
+        }
+    }
+    if let Some(pid_file) = pid_file.as_mut() {
+        if let Err(err) = pid_file.write() {
+            log::error!("write pid file failed:{}", err);
+        }
+    }
+    if let Err(err) = sys::set_stop_handler(opts.daemon || systemd::is_service()) {
+        log::error!("set stop handler failed:{}", err);
+    }
+    run(opts);
+}
 
-    config::setup_logger(&opts.log_file, opts.log_level);
-    opts.setup();
-    match opts.mode {
+// prints sha224:<hex> lines ready to be given as passwords
+fn hash(args: &HashArgs) {
+    if !args.passwords.is_empty() {
+        for password in args.passwords.iter() {
+            println!("{}{}", config::SHA224_PREFIX, config::sha224(password.as_str()));
+        }
+        return;
+    }
+    for line in std::io::stdin().lock().lines() {
+        let line = line.expect("read stdin failed");
+        let password = line.trim_end_matches('\r');
+        if !password.is_empty() {
+            println!("{}{}", config::SHA224_PREFIX, config::sha224(password));
+        }
+    }
+}
+
+// the file may be read while a server updates it, a record it adds meanwhile is missed or read with zero traffic
+fn stats(args: &StatsArgs) {
+    let records = match stats_file::read(args.file.as_str()) {
+        Ok(records) => records,
+        Err(err) => error::exit(error::Error::io(format!("read stats file {}", args.file), err)),
+    };
+    if args.json {
+        let mut json = String::from("[");
+        for (i, record) in records.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push('{');
+            log_format::add_str(&mut json, "user", Some(record.user.as_str()));
+            json.push_str(format!(",\"connections\":{},\"sent\":{},\"received\":{}}}", record.connections, record.bytes_sent, record.bytes_received).as_str());
+        }
+        json.push(']');
+        println!("{}", json);
+        return;
+    }
+    for record in records.iter() {
+        println!("{} connections={} sent={} received={}", record.user, record.connections, record.bytes_sent, record.bytes_received);
+    }
+}
+
+// global options are kept, and the check-config subcommand is replaced by the mode given after --
+fn check_config(args: &CheckArgs) {
+    let argv: Vec<String> = std::env::args().collect();
+    let pos = argv.iter().position(|arg| arg == "check-config").unwrap();
+    let mut check_argv = argv[..pos].to_vec();
+    check_argv.extend(args.args.iter().cloned());
+    let app: App = <Opts as IntoApp>::into_app().setting(AppSettings::AllowExternalSubcommands);
+    let mut opts = <Opts as FromArgMatches>::from_arg_matches(&app.get_matches_from(check_argv));
+    check::run(&mut opts);
+}
+
+pub fn run(mut opts: Opts) {
+    if let Err(err) = opts.setup() {
+        error::exit(err);
+    }
+    if let Some(collector) = opts.flow_collector.as_ref() {
+        if let Err(err) = flow_export::setup(collector.as_str(), opts.flow_domain) {
+            error::exit(err);
+        }
+    }
+    if let Some(url) = opts.webhook.as_ref() {
+        if let Err(err) = webhook::setup(url.as_str(), opts.webhook_interval, opts.webhook_event.as_slice(), opts.tcp_opts.marker, opts.relay_args().ip_strategy) {
+            error::exit(err);
+        }
+    }
+    let result = match opts.mode {
         Mode::Proxy(_) => {
             log::warn!("trojan started in proxy mode");
-            proxy::run(&mut opts);
+            proxy::run(&mut opts)
         }
         Mode::Server(_) => {
             log::warn!("trojan started in server mode");
-            server::run(&mut opts);
+            server::run(&mut opts)
         }
+        _ => unreachable!(),
+    };
+    if let Err(err) = result {
+        error::exit(err);
     }
+    log::warn!("trojan stopped");
+    log::logger().flush();
 }
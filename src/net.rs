@@ -0,0 +1,73 @@
+use std::io::{Error, Read, Result, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::time::{Duration, Instant};
+
+use mio::Evented;
+use mio::net::TcpStream;
+
+use crate::sys::TcpOpts;
+
+// a tcp stream of the relay loops, a socket, a connection terminated from tun packets, or a peer of a replayed trace
+pub trait Stream: Read + Write + Evented {
+    fn peer_addr(&self) -> Result<SocketAddr>;
+
+    fn local_addr(&self) -> Result<SocketAddr>;
+
+    fn shutdown(&self, how: Shutdown) -> Result<()>;
+
+    // a zero linger resets the connection once closed
+    fn set_linger(&self, dur: Option<Duration>) -> Result<()>;
+
+    fn set_nodelay(&self, nodelay: bool) -> Result<()>;
+
+    // the error of a connect which failed
+    fn take_error(&self) -> Result<Option<Error>>;
+}
+
+impl Stream for TcpStream {
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn set_linger(&self, dur: Option<Duration>) -> Result<()> {
+        TcpStream::set_linger(self, dur)
+    }
+
+    fn set_nodelay(&self, nodelay: bool) -> Result<()> {
+        TcpStream::set_nodelay(self, nodelay)
+    }
+
+    fn take_error(&self) -> Result<Option<Error>> {
+        TcpStream::take_error(self)
+    }
+}
+
+// starts connecting to addr, the tests connect to the peers of a trace instead
+#[cfg(not(test))]
+pub fn connect(tcp_opts: &TcpOpts, addr: &SocketAddr) -> Result<Box<dyn Stream>> {
+    Ok(Box::new(tcp_opts.connect(addr)?))
+}
+
+#[cfg(test)]
+pub fn connect(_tcp_opts: &TcpOpts, addr: &SocketAddr) -> Result<Box<dyn Stream>> {
+    crate::replay::connect(addr)
+}
+
+// the time of the relay loops, which the tests move by hand
+#[cfg(not(test))]
+pub fn now() -> Instant {
+    Instant::now()
+}
+
+#[cfg(test)]
+pub fn now() -> Instant {
+    crate::replay::now()
+}
@@ -0,0 +1,146 @@
+use std::fmt::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::cidr;
+use crate::config::{Opts, TransparentMode};
+use crate::route::Action;
+
+// the rules are data written by Router::write_pac, these decide with them like Router::route does, except that
+// browsers give the host alone, so rules on the addresses of domains can not be checked and the connection goes to
+// the proxy, which routes it with all the rules anyway
+const FUNCTIONS: &str = r#"
+function inSet(set, host) {
+    if (set.full[host] || set.suffix[host]) {
+        return true;
+    }
+    for (var i = host.indexOf("."); i >= 0; i = host.indexOf(".", i + 1)) {
+        if (set.suffix[host.substring(i + 1)]) {
+            return true;
+        }
+    }
+    for (var j = 0; j < set.keyword.length; j++) {
+        if (host.indexOf(set.keyword[j]) >= 0) {
+            return true;
+        }
+    }
+    return false;
+}
+
+function urlPort(url) {
+    var match = /^([a-z0-9+.-]+):\/\/(\[[^\]]*\]|[^\/:]*)(:(\d+))?/i.exec(url);
+    if (!match) {
+        return 0;
+    }
+    if (match[4]) {
+        return parseInt(match[4], 10);
+    }
+    var ports = {http: 80, ws: 80, https: 443, wss: 443, ftp: 21};
+    return ports[match[1].toLowerCase()] || 0;
+}
+
+function FindProxyForURL(url, host) {
+    host = host.toLowerCase().replace(/\.$/, "");
+    if (host.indexOf(":") >= 0) {
+        return PROXY;
+    }
+    var ip = /^\d+\.\d+\.\d+\.\d+$/.test(host);
+    if (ip) {
+        for (var i = 0; i < PRIVATE.length; i++) {
+            if (isInNet(host, PRIVATE[i][0], PRIVATE[i][1])) {
+                return LAN;
+            }
+        }
+    } else if (inSet(BLOCK, host)) {
+        return PROXY;
+    }
+    var port = urlPort(url);
+    for (var j = 0; j < RULES.length; j++) {
+        var rule = RULES[j];
+        var matched = false;
+        switch (rule[0]) {
+        case "domain":
+            matched = !ip && host == rule[1];
+            break;
+        case "domain-suffix":
+            matched = !ip && (host == rule[1] || host.substring(host.length - rule[1].length - 1) == "." + rule[1]);
+            break;
+        case "domain-keyword":
+            matched = !ip && host.indexOf(rule[1]) >= 0;
+            break;
+        case "geosite":
+            matched = !ip && inSet(rule[1], host);
+            break;
+        case "ip-cidr":
+            matched = ip && isInNet(host, rule[1][0], rule[1][1]);
+            break;
+        case "geoip":
+            if (ip) {
+                return PROXY;
+            }
+            break;
+        case "port":
+            matched = port >= rule[1][0] && port <= rule[1][1];
+            break;
+        }
+        if (matched) {
+            return rule[2] ? "DIRECT" : PROXY;
+        }
+    }
+    return DEFAULT ? "DIRECT" : PROXY;
+}
+"#;
+
+// a javascript string literal
+pub fn quote(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 || c == '\u{2028}' || c == '\u{2029}' => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+// an ipv4 range as the pattern and the mask isInNet takes
+pub fn write_net(out: &mut String, addr: Ipv4Addr, prefix: u8) {
+    let mask = if prefix == 0 { 0 } else { !0u32 << (32 - prefix as u32) };
+    let _ = write!(out, "[\"{}\",\"{}\"]", addr, Ipv4Addr::from(mask));
+}
+
+// the proxy auto-config file of the socks5 or http listener, blocked domains go to the proxy as well to be refused there
+pub fn generate(opts: &Opts) -> String {
+    let mut addr = opts.local_addrs[0];
+    if addr.ip().is_unspecified() {
+        let ip = if addr.is_ipv4() { IpAddr::V4(Ipv4Addr::LOCALHOST) } else { IpAddr::V6(Ipv6Addr::LOCALHOST) };
+        addr = SocketAddr::new(ip, addr.port());
+    }
+    let proxy = match opts.proxy_args().transparent_mode {
+        TransparentMode::Http => format!("PROXY {}", addr),
+        _ => format!("SOCKS5 {}; SOCKS {}", addr, addr),
+    };
+    let mut pac = String::with_capacity(4096);
+    pac.push_str("var PROXY = ");
+    quote(&mut pac, proxy.as_str());
+    pac.push_str(";\nvar LAN = ");
+    quote(&mut pac, if opts.proxy_args().lan_action == Action::Direct { "DIRECT" } else { proxy.as_str() });
+    pac.push_str(";\nvar PRIVATE = [");
+    let mut first = true;
+    for range in cidr::PRIVATE_RANGES.iter() {
+        if let IpAddr::V4(ip) = range.addr() {
+            if !first {
+                pac.push(',');
+            }
+            first = false;
+            write_net(&mut pac, ip, range.prefix());
+        }
+    }
+    pac.push_str("];\n");
+    opts.router.write_pac(&mut pac);
+    pac.push_str(FUNCTIONS);
+    pac
+}
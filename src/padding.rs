@@ -0,0 +1,82 @@
+use std::io::{Result, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::proto::{FrameParser, MAX_FRAME_DATA, write_frame, write_keepalive};
+use crate::sys;
+
+// the first packets, like the tls handshake of the tunneled connection, are the ones telling the most by their lengths
+const PADDED_FRAMES: usize = 8;
+const MAX_PADDING: usize = 512;
+// data of the padded frames is split into pieces of random sizes between these
+const MIN_PIECE: usize = 128;
+const MAX_PIECE: usize = 1024;
+
+fn random(bound: usize) -> usize {
+    let mut bytes = [0u8; 4];
+    if sys::fill_random(&mut bytes).is_err() {
+        // the lengths are not secrets, the clock does on windows
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.subsec_nanos()).unwrap_or(0);
+        bytes = nanos.to_le_bytes();
+    }
+    u32::from_le_bytes(bytes) as usize % bound
+}
+
+// a frame of padding alone, appended to the request so that its length varies as well
+pub fn write_padding<B: Extend<u8>>(buffer: &mut B) {
+    write_frame(buffer, &[], random(MAX_PADDING + 1));
+}
+
+// the frames of a CONNECT_PADDED connection, the data written and read by one end
+pub struct Padding {
+    sent_frames: usize,
+    parser: FrameParser,
+}
+
+impl Padding {
+    pub fn new() -> Padding {
+        Padding {
+            sent_frames: 0,
+            parser: FrameParser::new(),
+        }
+    }
+
+    // each of the first frames gets random padding and a write of its own, which makes a tls record of its own,
+    // the rest go unpadded
+    pub fn write<W: Write>(&mut self, mut data: &[u8], writer: &mut W) -> Result<()> {
+        let mut frame = Vec::new();
+        while !data.is_empty() && self.sent_frames < PADDED_FRAMES {
+            let size = data.len().min(MIN_PIECE + random(MAX_PIECE - MIN_PIECE + 1));
+            frame.clear();
+            write_frame(&mut frame, &data[..size], random(MAX_PADDING + 1));
+            writer.write_all(frame.as_slice())?;
+            self.sent_frames += 1;
+            data = &data[size..];
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+        frame.clear();
+        for chunk in data.chunks(MAX_FRAME_DATA) {
+            write_frame(&mut frame, chunk, 0);
+        }
+        writer.write_all(frame.as_slice())
+    }
+
+    pub fn read(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(data.len());
+        self.parser.feed(data, &mut output);
+        output
+    }
+
+    // true if keepalives were read since the last call
+    pub fn take_keepalive(&mut self) -> bool {
+        self.parser.take_keepalives() > 0
+    }
+
+    // keepalives take no part in the padded frames
+    pub fn write_keepalive<W: Write>(&mut self, writer: &mut W) -> Result<()> {
+        let mut frame = Vec::new();
+        write_keepalive(&mut frame);
+        writer.write_all(frame.as_slice())
+    }
+}
@@ -0,0 +1,62 @@
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::time::SystemTime;
+
+use crate::config::{self, SHA224_PREFIX};
+
+// read for --password when it is not given, so that the password stays out of the shell history and ps
+pub const ENV_NAME: &str = "TROJAN_PASSWORD";
+
+// a password accepted by the server, the label tells users apart
+pub struct Password {
+    pub label: Option<String>,
+    pub digest: String,
+}
+
+impl Password {
+    pub fn new(label: Option<String>, password: &str) -> Result<Password, String> {
+        if password.is_empty() {
+            return Err("empty password".to_string());
+        }
+        Ok(Password {
+            label,
+            digest: config::password_digest(password)?,
+        })
+    }
+
+    // label:password or just the password, a password with a colon needs a label, which may be empty
+    fn parse(line: &str) -> Result<Password, String> {
+        if line.starts_with(SHA224_PREFIX) {
+            return Password::new(None, line);
+        }
+        match line.find(':') {
+            Some(pos) if pos > 0 => Password::new(Some(line[..pos].to_string()), &line[pos + 1..]),
+            Some(_) => Password::new(None, &line[1..]),
+            None => Password::new(None, line),
+        }
+    }
+}
+
+// one password per line, empty lines and lines starting with # are skipped, - reads stdin
+pub fn load(path: &str) -> Result<Vec<Password>, String> {
+    let reader: Box<dyn BufRead> = if path == "-" {
+        Box::new(BufReader::new(std::io::stdin()))
+    } else {
+        let file = fs::File::open(path).map_err(|err| format!("open password file {} failed:{}", path, err))?;
+        Box::new(BufReader::new(file))
+    };
+    let mut passwords = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.map_err(|err| format!("read password file {} failed:{}", path, err))?;
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        passwords.push(Password::parse(line).map_err(|err| format!("{} at line {} of {}", err, i + 1, path))?);
+    }
+    Ok(passwords)
+}
+
+pub fn modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::io::Result;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::config::Opts;
+
+// a plugin exiting right after it starts is not restarted in a loop
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+// a free port on the loopback for a plugin to listen on, it is released for the plugin to bind
+pub fn local_addr() -> Result<SocketAddr> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    listener.local_addr()
+}
+
+struct Plugin {
+    child: Option<Child>,
+    start_time: Instant,
+}
+
+// sip003 plugins like v2ray-plugin, one process for each trojan server, the server is given in SS_REMOTE_HOST and SS_REMOTE_PORT,
+// the plugin listens on SS_LOCAL_HOST and SS_LOCAL_PORT and trojan connects there instead
+pub struct Plugins {
+    command: String,
+    options: String,
+    plugins: HashMap<usize, Plugin>,
+}
+
+impl Plugins {
+    pub fn new(command: String, options: String) -> Plugins {
+        Plugins {
+            command,
+            options,
+            plugins: HashMap::new(),
+        }
+    }
+
+    // starts plugins of new servers, restarts those exited and stops those of servers removed from the subscription
+    pub fn check(&mut self, now: Instant, opts: &Opts) {
+        for (index, upstream) in opts.upstreams.iter().enumerate() {
+            let addr = match upstream.plugin_addr() {
+                Some(addr) if upstream.is_available() => addr,
+                _ => {
+                    if let Some(mut plugin) = self.plugins.remove(&index) {
+                        log::warn!("stop plugin of trojan server {}", upstream.name());
+                        plugin.stop();
+                    }
+                    continue;
+                }
+            };
+            if let Some(plugin) = self.plugins.get_mut(&index) {
+                if let Some(child) = plugin.child.as_mut() {
+                    match child.try_wait() {
+                        Ok(None) => continue,
+                        Ok(Some(status)) => log::error!("plugin of trojan server {} exited:{}", upstream.name(), status),
+                        Err(err) => log::error!("wait plugin of trojan server {} failed:{}", upstream.name(), err),
+                    }
+                    plugin.child.take();
+                }
+                if now - plugin.start_time < RESTART_DELAY {
+                    continue;
+                }
+            }
+            let child = self.start(upstream.hostname.as_str(), upstream.port, addr);
+            match child.as_ref() {
+                Ok(child) => log::warn!("plugin {} of trojan server {} started on {}, pid {}", self.command, upstream.name(), addr, child.id()),
+                Err(err) => log::error!("start plugin {} of trojan server {} failed:{}", self.command, upstream.name(), err),
+            }
+            self.plugins.insert(index, Plugin {
+                child: child.ok(),
+                start_time: now,
+            });
+        }
+    }
+
+    fn start(&self, hostname: &str, port: u16, addr: SocketAddr) -> Result<Child> {
+        let mut command = Command::new(self.command.as_str());
+        command.env("SS_REMOTE_HOST", hostname)
+            .env("SS_REMOTE_PORT", port.to_string())
+            .env("SS_LOCAL_HOST", addr.ip().to_string())
+            .env("SS_LOCAL_PORT", addr.port().to_string())
+            .env("SS_PLUGIN_OPTIONS", self.options.as_str())
+            .stdin(Stdio::null());
+        #[cfg(target_os = "linux")]
+        unsafe {
+            use std::os::unix::process::CommandExt;
+            // plugins go away with trojan even if it is killed
+            command.pre_exec(|| {
+                if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        command.spawn()
+    }
+}
+
+impl Plugin {
+    fn stop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+impl Drop for Plugins {
+    fn drop(&mut self) {
+        for plugin in self.plugins.values_mut() {
+            plugin.stop();
+        }
+    }
+}
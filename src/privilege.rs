@@ -0,0 +1,176 @@
+use crate::config::Opts;
+
+#[cfg(unix)]
+use std::ffi::CString;
+#[cfg(unix)]
+use std::io::{Error, ErrorKind, Result};
+
+// CAP_NET_ADMIN for marks and tproxy sockets, CAP_NET_RAW for IP_TRANSPARENT on older kernels
+#[cfg(target_os = "linux")]
+const CAP_NET_ADMIN: u32 = 12;
+#[cfg(target_os = "linux")]
+const CAP_NET_RAW: u32 = 13;
+#[cfg(target_os = "linux")]
+const PR_CAP_AMBIENT: libc::c_int = 47;
+#[cfg(target_os = "linux")]
+const PR_CAP_AMBIENT_RAISE: libc::c_ulong = 2;
+#[cfg(target_os = "linux")]
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+// switches to --user and --group once listeners are bound, exits rather than going on as root
+pub fn drop(opts: &Opts, keep_net_admin: bool) {
+    if opts.user.is_none() && opts.group.is_none() {
+        return;
+    }
+    if !is_root() {
+        // started by an upgrade from a process which has dropped them already
+        log::info!("not running as root, --user and --group are ignored");
+        return;
+    }
+    if let Err(err) = drop_privileges(opts.user.as_ref(), opts.group.as_ref(), keep_net_admin) {
+        log::error!("drop privileges failed:{}", err);
+        std::process::exit(1);
+    }
+    log::warn!("privileges dropped to user:{} group:{}, CAP_NET_ADMIN kept:{}",
+               opts.user.as_ref().map_or("-", |user| user.as_str()),
+               opts.group.as_ref().map_or("-", |group| group.as_str()),
+               keep_net_admin);
+}
+
+#[cfg(unix)]
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+fn is_root() -> bool {
+    true
+}
+
+#[cfg(unix)]
+fn drop_privileges(user: Option<&String>, group: Option<&String>, keep_net_admin: bool) -> Result<()> {
+    let (uid, user_gid) = match user {
+        Some(user) => {
+            let name = CString::new(user.as_str()).map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid user name"))?;
+            let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+            if passwd.is_null() {
+                return Err(Error::new(ErrorKind::NotFound, format!("user {} not found", user)));
+            }
+            unsafe { (Some((*passwd).pw_uid), Some((*passwd).pw_gid)) }
+        }
+        None => (None, None),
+    };
+    let gid = match group {
+        Some(group) => {
+            let name = CString::new(group.as_str()).map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid group name"))?;
+            let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+            if entry.is_null() {
+                return Err(Error::new(ErrorKind::NotFound, format!("group {} not found", group)));
+            }
+            Some(unsafe { (*entry).gr_gid })
+        }
+        None => user_gid,
+    };
+
+    if keep_net_admin {
+        keep_caps()?;
+    }
+    // groups go first, as setuid takes away the right to change them
+    if let Some(gid) = gid {
+        if unsafe { libc::setgroups(1, &gid) } != 0 || unsafe { libc::setgid(gid) } != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    if let Some(uid) = uid {
+        if unsafe { libc::setuid(uid) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(Error::new(ErrorKind::Other, "root privileges could be regained"));
+        }
+    }
+    if keep_net_admin {
+        set_net_admin()?;
+    }
+    Ok(())
+}
+
+// capabilities in the permitted set survive setuid only with PR_SET_KEEPCAPS
+#[cfg(target_os = "linux")]
+fn keep_caps() -> Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) } != 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+// limits the capabilities to the network ones, the effective set is cleared by setuid,
+// and they are made ambient so that a new binary started by an upgrade keeps them
+#[cfg(target_os = "linux")]
+fn set_net_admin() -> Result<()> {
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    let caps = (1 << CAP_NET_ADMIN) | (1 << CAP_NET_RAW);
+    data[0].effective = caps;
+    data[0].permitted = caps;
+    data[0].inheritable = caps;
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header as *mut CapHeader, data.as_mut_ptr()) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    for cap in &[CAP_NET_ADMIN, CAP_NET_RAW] {
+        if unsafe { libc::prctl(PR_CAP_AMBIENT, PR_CAP_AMBIENT_RAISE, *cap as libc::c_ulong, 0, 0) } != 0 {
+            log::warn!("raise ambient capability failed:{}, upgrades lose it", Error::last_os_error());
+            break;
+        }
+    }
+    Ok(())
+}
+
+// whether marks and tproxy sockets can be set, for --check
+#[cfg(target_os = "linux")]
+pub fn has_net_admin() -> bool {
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    if unsafe { libc::syscall(libc::SYS_capget, &mut header as *mut CapHeader, data.as_mut_ptr()) } != 0 {
+        return false;
+    }
+    data[0].effective & (1 << CAP_NET_ADMIN) != 0
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn keep_caps() -> Result<()> {
+    Ok(())
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn set_net_admin() -> Result<()> {
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn drop_privileges(_user: Option<&String>, _group: Option<&String>, _keep_net_admin: bool) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Other, "dropping privileges is not supported on this platform"))
+}
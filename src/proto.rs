@@ -1,140 +1,120 @@
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::net::{IpAddr, SocketAddr};
 
-use bytes::{BufMut, BytesMut};
+use bytes::BytesMut;
+use trojan_proto::{Address, Error, Progress, Request, RequestParser, UdpPacket};
+pub use trojan_proto::{CONNECT, CONNECT_PADDED, FrameParser, MAX_FRAME_DATA, MAX_UDP_SIZE, UDP_ASSOCIATE, UDP_OVER_TCP, write_frame, write_keepalive};
 
 use crate::config::Opts;
 
-pub const CONNECT: u8 = 0x01;
-pub const UDP_ASSOCIATE: u8 = 0x03;
-pub const MAX_UDP_SIZE: usize = 8192;
-const IPV4: u8 = 0x01;
-const DOMAIN: u8 = 0x03;
-const IPV6: u8 = 0x04;
-
 pub enum Sock5Address {
     Socket(SocketAddr),
     Domain(String, u16),
     None,
 }
 
+impl std::fmt::Display for Sock5Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Sock5Address::Socket(addr) => write!(f, "{}", addr),
+            Sock5Address::Domain(domain, port) => write!(f, "{}:{}", domain, port),
+            Sock5Address::None => write!(f, "-"),
+        }
+    }
+}
+
 pub struct TrojanRequest<'a> {
+    pub user: Option<String>,
     pub command: u8,
     pub address: Sock5Address,
     pub payload: &'a [u8],
 }
 
-impl<'a> TrojanRequest<'a> {
-    pub fn parse(mut buffer: &'a [u8], opts: &mut Opts) -> Option<TrojanRequest<'a>> {
-        if buffer.len() < opts.pass_len {
-            log::debug!("data length:{} is too short for a trojan request", buffer.len());
-            return None;
-        }
-
-        let pass = String::from_utf8_lossy(&buffer[..opts.pass_len]);
-        if let Some(orig) = opts.check_pass(&pass) {
-            log::debug!("request using password:{}", &orig);
-        } else {
-            log::debug!("request didn't find matched password");
-            return None;
-        }
-
-        buffer = &buffer[opts.pass_len..];
-        if buffer.len() < 2 || buffer[0] != b'\r' || buffer[1] != b'\n' {
-            log::error!("unknown protocol, expected CRLF, {:#X}{:#X}", buffer[0], buffer[1]);
-            return None;
-        }
+pub enum RequestParseResult<'a> {
+    Request(TrojanRequest<'a>),
+    InvalidProtocol,
+    // the client is authenticated but asks for a port not allowed, it is refused rather than passed through
+    PortNotAllowed,
+    // the request is split across reads, the parser keeps what it needs of it
+    Continued,
+}
 
-        buffer = &buffer[2..];
-        if buffer.len() < 3 {
-            log::error!("unknown protocol, invalid size");
-            return None;
-        }
-        if buffer[0] != CONNECT && buffer[0] != UDP_ASSOCIATE {
-            log::error!("unknown protocol, expected valid command, found:{}", buffer[0]);
-            return None;
+impl<'a> TrojanRequest<'a> {
+    pub fn parse(parser: &mut RequestParser, buffer: &'a [u8], opts: &mut Opts) -> RequestParseResult<'a> {
+        let (header, size) = match parser.feed(buffer) {
+            Ok(Progress::Done(header, size)) => (header, size),
+            Ok(Progress::Pending) => {
+                log::debug!("data length:{} is too short for a trojan request", buffer.len());
+                return RequestParseResult::Continued;
+            }
+            Err(err) => {
+                log::error!("unknown protocol, {}", err);
+                return RequestParseResult::InvalidProtocol;
+            }
+        };
+        let user = match opts.check_pass(&header.password) {
+            Some(password) => password.label.clone(),
+            None => {
+                log::debug!("request didn't find matched password");
+                return RequestParseResult::InvalidProtocol;
+            }
+        };
+        match resolve_address(header.address, opts) {
+            Some(address) => RequestParseResult::Request(TrojanRequest {
+                user,
+                command: header.command,
+                address,
+                payload: &buffer[size..],
+            }),
+            None => RequestParseResult::PortNotAllowed,
         }
+    }
 
-        let command = buffer[0];
-        let atyp = buffer[1];
-        buffer = &buffer[2..];
-        if let Some((size, address)) = parse_address(atyp, buffer, opts) {
-            buffer = &buffer[size..];
-            if buffer[0] != b'\r' || buffer[1] != b'\n' {
-                log::error!("unknown protocol, expected CRLF after address");
-                return None;
+    // shadowsocks sends the target address alone, the user is that of the password the cipher is keyed with
+    pub fn parse_shadowsocks(buffer: &'a [u8], user: Option<String>, opts: &mut Opts) -> RequestParseResult<'a> {
+        let (address, size) = match Address::parse(buffer) {
+            Ok(result) => result,
+            Err(Error::Incomplete) => return RequestParseResult::Continued,
+            Err(err) => {
+                log::error!("invalid shadowsocks request, {}", err);
+                return RequestParseResult::InvalidProtocol;
             }
-            Some(TrojanRequest {
-                command,
+        };
+        match resolve_address(address, opts) {
+            Some(address) => RequestParseResult::Request(TrojanRequest {
+                user,
+                command: CONNECT,
                 address,
-                payload: &buffer[2..],
-            })
-        } else {
-            None
+                payload: &buffer[size..],
+            }),
+            None => RequestParseResult::PortNotAllowed,
         }
     }
 
-    pub fn generate(buffer: &mut BytesMut, cmd: u8, addr: &SocketAddr, opts: &Opts) {
-        buffer.extend_from_slice(opts.get_pass().as_bytes());
-        buffer.put_u8(b'\r');
-        buffer.put_u8(b'\n');
-        buffer.put_u8(cmd);
-        Sock5Address::generate(buffer, addr);
-        buffer.put_u8(b'\r');
-        buffer.put_u8(b'\n');
+    pub fn generate(buffer: &mut BytesMut, cmd: u8, addr: &Sock5Address, pass: &str) {
+        let address = match addr {
+            Sock5Address::Socket(addr) => Address::Socket(*addr),
+            Sock5Address::Domain(domain, port) => Address::Domain(domain.clone(), *port),
+            Sock5Address::None => unreachable!("trojan request without address"),
+        };
+        Request::write(buffer, pass, cmd, &address);
     }
 }
 
-fn parse_address(atyp: u8, buffer: &[u8], opts: &mut Opts) -> Option<(usize, Sock5Address)> {
-    match atyp {
-        IPV4 => {
-            log::debug!("ipv4 address found");
-            if buffer.len() < 6 {
-                log::error!("unknown protocol, invalid ipv4 address");
-                return None;
-            }
-            let port = to_u16(&buffer[4..]);
-            let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(buffer[0], buffer[1], buffer[2], buffer[3]), port));
-            Some((6, Sock5Address::Socket(addr)))
-        }
-        DOMAIN => {
-            log::debug!("domain address found");
-            let length = buffer[0] as usize;
-            if buffer.len() < length + 3 {
-                log::error!("unknown protocol, invalid domain address");
-                return None;
-            }
-            let domain: String = String::from_utf8_lossy(&buffer[1..length + 1]).into();
-            let port = to_u16(&buffer[length + 1..]);
+// domains are left to the connection, which tries each of their addresses, the port is checked against the allowed ones
+fn resolve_address(address: Address, opts: &mut Opts) -> Option<Sock5Address> {
+    if !opts.is_port_allowed(address.port()) {
+        log::error!("destination port {} is not allowed", address.port());
+        return None;
+    }
+    match address {
+        Address::Socket(addr) => Some(Sock5Address::Socket(addr)),
+        Address::Domain(domain, port) => {
             if let Ok(ip) = domain.parse::<IpAddr>() {
-                Some((length + 3, Sock5Address::Socket(SocketAddr::new(ip, port))))
-            } else if let Some(ip) = opts.query_dns(&domain) {
-                Some((length + 3, Sock5Address::Socket(SocketAddr::new(ip, port))))
+                Some(Sock5Address::Socket(SocketAddr::new(ip, port)))
             } else {
                 log::info!("domain found:{}:{}", domain, port);
-                Some((length + 3, Sock5Address::Domain(domain, port)))
-            }
-        }
-        IPV6 => {
-            log::debug!("ipv6 address found");
-            if buffer.len() < 18 {
-                log::error!("unknown protocol, invalid ipv6 address");
-                return None;
+                Some(Sock5Address::Domain(domain, port))
             }
-            let addr = SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::new(
-                to_u16(buffer),
-                to_u16(&buffer[2..]),
-                to_u16(&buffer[4..]),
-                to_u16(&buffer[6..]),
-                to_u16(&buffer[8..]),
-                to_u16(&buffer[10..]),
-                to_u16(&buffer[12..]),
-                to_u16(&buffer[14..]),
-            ), to_u16(&buffer[16..]), 0, 0));
-            Some((18, Sock5Address::Socket(addr)))
-        }
-        _ => {
-            log::warn!("unknown protocol, invalid address type:{}", atyp);
-            return None;
         }
     }
 }
@@ -142,84 +122,85 @@ fn parse_address(atyp: u8, buffer: &[u8], opts: &mut Opts) -> Option<(usize, Soc
 pub struct UdpAssociate<'a> {
     pub address: SocketAddr,
     pub length: usize,
+    // the payload of this packet followed by the remaining data
     pub payload: &'a [u8],
 }
 
 pub enum UdpParseResult<'a> {
     Packet(UdpAssociate<'a>),
+    // an oversized packet or one to a destination not allowed is skipped, parsing goes on with the remaining data
+    Dropped(&'a [u8]),
+    // a keepalive of a UDP_OVER_TCP stream, followed by the remaining data
+    Keepalive(&'a [u8]),
     InvalidProtocol,
     Continued,
 }
 
 impl<'a> UdpAssociate<'a> {
-    pub fn parse(mut buffer: &'a [u8], opts: &mut Opts) -> UdpParseResult<'a> {
-        if buffer.len() < 11 {
-            log::debug!("data is too short for UDP_ASSOCIATE");
-            return UdpParseResult::Continued;
-        }
-        let atyp = buffer[0];
-        buffer = &buffer[1..];
-        if let Some((size, addr)) = parse_address(atyp, buffer, opts) {
-            buffer = &buffer[size..];
-            if buffer.len() < 4 {
+    pub fn parse(buffer: &'a [u8], opts: &mut Opts) -> UdpParseResult<'a> {
+        let (packet, size) = match UdpPacket::parse(buffer) {
+            Ok(result) => result,
+            Err(Error::Incomplete) => {
+                log::debug!("data is too short for UDP_ASSOCIATE");
                 return UdpParseResult::Continued;
             }
-            let length = to_u16(buffer) as usize;
-            if length > MAX_UDP_SIZE {
-                log::warn!("udp packet size:{} is too long", length);
+            Err(err) => {
+                log::warn!("invalid udp packet, {}", err);
                 return UdpParseResult::InvalidProtocol;
             }
-            if buffer.len() < length + 4 {
-                return UdpParseResult::Continued;
-            }
-            if buffer[2] != b'\r' || buffer[3] != b'\n' {
-                log::warn!("udp packet expected CRLF after length");
-                return UdpParseResult::InvalidProtocol;
+        };
+        if packet.is_keepalive() {
+            return UdpParseResult::Keepalive(&buffer[size..]);
+        }
+        let length = packet.payload.len();
+        if length > opts.relay_args().max_udp_size {
+            log::warn!("udp packet size:{} exceeds max udp size:{}, drop it", length, opts.relay_args().max_udp_size);
+            return UdpParseResult::Dropped(&buffer[size..]);
+        }
+        match resolve_address(packet.address, opts) {
+            Some(Sock5Address::Socket(address)) => {
+                UdpParseResult::Packet(UdpAssociate {
+                    address,
+                    length,
+                    payload: &buffer[size - length..],
+                })
             }
-            match addr {
-                Sock5Address::Socket(address) => {
-                    UdpParseResult::Packet(UdpAssociate {
-                        address,
+            // packets to a domain go to an address found in the hosts file or the local cache
+            Some(Sock5Address::Domain(domain, port)) => {
+                let ip = match opts.hosts.lookup(&domain) {
+                    Some(addresses) if addresses.is_empty() => {
+                        log::info!("udp packet to {} is blocked by hosts file, drop it", domain);
+                        return UdpParseResult::Dropped(&buffer[size..]);
+                    }
+                    Some(addresses) => addresses.first().cloned(),
+                    None => opts.query_dns(&domain).first().cloned(),
+                };
+                match ip {
+                    Some(ip) => UdpParseResult::Packet(UdpAssociate {
+                        address: SocketAddr::new(ip, port),
                         length,
-                        payload: &buffer[4..],
-                    })
-                }
-                _ => {
-                    log::warn!("udp packet only accept ip address");
-                    UdpParseResult::InvalidProtocol
+                        payload: &buffer[size - length..],
+                    }),
+                    None => {
+                        log::warn!("udp packet to {} is not in dns cache, drop it", domain);
+                        UdpParseResult::Dropped(&buffer[size..])
+                    }
                 }
             }
-        } else {
-            UdpParseResult::InvalidProtocol
+            Some(Sock5Address::None) => {
+                log::warn!("udp packet only accept ip address");
+                UdpParseResult::InvalidProtocol
+            }
+            // a port not allowed only drops this packet, the association goes on
+            None => UdpParseResult::Dropped(&buffer[size..]),
         }
     }
 
     pub fn generate(buffer: &mut BytesMut, address: &SocketAddr, length: u16) {
-        Sock5Address::generate(buffer, address);
-        buffer.put_u16(length);
-        buffer.put_u8(b'\r');
-        buffer.put_u8(b'\n');
+        UdpPacket::write_header(buffer, &Address::Socket(*address), length);
     }
-}
 
-fn to_u16(buffer: &[u8]) -> u16 {
-    (buffer[0] as u16) << 8 | buffer[1] as u16
-}
-
-impl Sock5Address {
-    pub fn generate(buffer: &mut BytesMut, address: &SocketAddr) {
-        let port = match address {
-            SocketAddr::V4(v4) => {
-                buffer.put_u8(IPV4);
-                buffer.extend_from_slice(&v4.ip().octets()[..]);
-                v4.port()
-            }
-            SocketAddr::V6(v6) => {
-                buffer.put_u8(IPV6);
-                buffer.extend_from_slice(&v6.ip().octets()[..]);
-                v6.port()
-            }
-        };
-        buffer.put_u16(port);
+    pub fn generate_keepalive(buffer: &mut BytesMut) {
+        UdpPacket::write_keepalive(buffer);
     }
 }
@@ -0,0 +1,422 @@
+use std::io::{ErrorKind, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
+use std::time::Instant;
+
+use mio::{Event, Poll, PollOpt, Ready, Token};
+use mio::net::{TcpStream, UdpSocket};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+
+use crate::access_log;
+use crate::net::Stream;
+use crate::proxy::udp_cache::UdpSvrCache;
+use crate::session::TcpSession;
+use crate::sys;
+
+pub struct TcpDirect {
+    index: usize,
+    dst_addr: SocketAddr,
+    client: Box<dyn Stream>,
+    target: TcpStream,
+    client_session: TcpSession,
+    target_session: TcpSession,
+    connected: bool,
+    closing: bool,
+    closed: bool,
+    client_recv: usize,
+    client_sent: usize,
+    start_time: Instant,
+    last_active_time: Instant,
+    client_eof: bool,
+    target_eof: bool,
+    client_shutdown: bool,
+    target_shutdown: bool,
+    client_readiness: Ready,
+    target_readiness: Ready,
+}
+
+pub struct UdpDirect {
+    index: usize,
+    src_addr: SocketAddr,
+    socket: UdpSocket,
+    last_active_time: Instant,
+    closed: bool,
+}
+
+pub fn new_direct_stream(addr: &SocketAddr, marker: u8) -> std::io::Result<TcpStream> {
+    let domain = if addr.is_ipv4() {
+        Domain::ipv4()
+    } else {
+        Domain::ipv6()
+    };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    // mark must be set before connecting, or the syn packet is redirected to us again
+    sys::set_mark(&socket, marker)?;
+    TcpStream::connect_stream(socket.into_tcp_stream(), addr)
+}
+
+pub fn new_direct_socket(addr: &SocketAddr, marker: u8) -> std::io::Result<UdpSocket> {
+    let bind_addr = if addr.is_ipv4() {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)
+    } else {
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)
+    };
+    let domain = if addr.is_ipv4() {
+        Domain::ipv4()
+    } else {
+        Domain::ipv6()
+    };
+    let socket = Socket::new(domain, Type::dgram(), Some(Protocol::udp()))?;
+    sys::set_mark(&socket, marker)?;
+    socket.bind(&SockAddr::from(bind_addr))?;
+    UdpSocket::from_socket(socket.into_udp_socket())
+}
+
+impl TcpDirect {
+    pub fn new(index: usize, dst_addr: SocketAddr, client: Box<dyn Stream>, target: TcpStream) -> TcpDirect {
+        TcpDirect {
+            index,
+            dst_addr,
+            client,
+            target,
+            client_session: TcpSession::new(index),
+            target_session: TcpSession::new(index),
+            connected: false,
+            closing: false,
+            closed: false,
+            client_recv: 0,
+            client_sent: 0,
+            start_time: Instant::now(),
+            last_active_time: Instant::now(),
+            client_eof: false,
+            target_eof: false,
+            client_shutdown: false,
+            target_shutdown: false,
+            client_readiness: Ready::readable() | Ready::writable(),
+            target_readiness: Ready::readable() | Ready::writable(),
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn closed(&self) -> bool {
+        self.closed
+    }
+
+    pub fn last_active_time(&self) -> Instant {
+        self.last_active_time
+    }
+
+    // bytes waiting to be written to either side
+    pub fn queued(&self) -> usize {
+        self.client_session.pending() + self.target_session.pending()
+    }
+
+    fn client_token(&self) -> Token {
+        Token(self.index * 3 + 1)
+    }
+
+    fn target_token(&self) -> Token {
+        Token(self.index * 3 + 2)
+    }
+
+    pub fn setup(&mut self, poll: &Poll) -> bool {
+        if let Err(err) = poll.register(&*self.client, self.client_token(), self.client_readiness, PollOpt::edge()) {
+            log::warn!("connection:{} register client failed:{}", self.index, err);
+            false
+        } else if let Err(err) = poll.register(&self.target, self.target_token(), self.target_readiness, PollOpt::edge()) {
+            log::warn!("connection:{} register target failed:{}", self.index, err);
+            false
+        } else {
+            true
+        }
+    }
+
+    pub fn ready(&mut self, event: &Event, poll: &Poll, max_pending: usize) {
+        self.last_active_time = Instant::now();
+        match event.token().0 % 3 {
+            1 => {
+                if event.readiness().is_readable() {
+                    self.try_read_client();
+                }
+                if event.readiness().is_writable() {
+                    self.try_send_client();
+                }
+            }
+            2 => {
+                if !self.connected {
+                    self.try_connect_target();
+                }
+                if self.connected && event.readiness().is_readable() {
+                    self.try_read_target();
+                }
+                if self.connected && event.readiness().is_writable() {
+                    self.try_send_target();
+                }
+            }
+            _ => {
+                log::error!("invalid token found in direct connection");
+                self.closing = true;
+            }
+        }
+        self.check_shutdown();
+        self.reregister(poll, max_pending);
+        if self.closing {
+            self.close_now(poll);
+        }
+    }
+
+    // a side is not read while the data for the other piles up, reregistering reports what arrived meanwhile
+    fn reregister(&mut self, poll: &Poll, max_pending: usize) {
+        if self.closing {
+            return;
+        }
+        let client_readable = self.target_session.pending() < max_pending;
+        if client_readable != self.client_readiness.is_readable() {
+            if client_readable {
+                self.client_readiness.insert(Ready::readable());
+            } else {
+                self.client_readiness.remove(Ready::readable());
+            }
+            if let Err(err) = poll.reregister(&*self.client, self.client_token(), self.client_readiness, PollOpt::edge()) {
+                log::error!("connection:{} reregister client failed:{}", self.index, err);
+                self.closing = true;
+                return;
+            }
+        }
+        let target_readable = self.client_session.pending() < max_pending;
+        if target_readable != self.target_readiness.is_readable() {
+            if target_readable {
+                self.target_readiness.insert(Ready::readable());
+            } else {
+                self.target_readiness.remove(Ready::readable());
+            }
+            if let Err(err) = poll.reregister(&self.target, self.target_token(), self.target_readiness, PollOpt::edge()) {
+                log::error!("connection:{} reregister target failed:{}", self.index, err);
+                self.closing = true;
+            }
+        }
+    }
+
+    // a side which has finished sending gets a shutdown of the other once the data for it is all written
+    fn check_shutdown(&mut self) {
+        if self.closing {
+            return;
+        }
+        if self.client_eof && self.connected && !self.target_shutdown && !self.target_session.wants_write() {
+            if let Err(err) = self.target.shutdown(Shutdown::Write) {
+                log::warn!("connection:{} shutdown target failed:{}", self.index, err);
+                self.closing = true;
+                return;
+            }
+            self.target_shutdown = true;
+        }
+        if self.target_eof && !self.client_shutdown && !self.client_session.wants_write() {
+            if let Err(err) = self.client.shutdown(Shutdown::Write) {
+                log::warn!("connection:{} shutdown client failed:{}", self.index, err);
+                self.closing = true;
+                return;
+            }
+            self.client_shutdown = true;
+        }
+        if self.client_shutdown && self.target_shutdown {
+            log::info!("connection:{} both sides finished", self.index);
+            self.closing = true;
+        }
+    }
+
+    pub fn close_now(&mut self, poll: &Poll) {
+        access_log::write(&access_log::Entry {
+            conn_id: self.index,
+            remote_addr: self.client.peer_addr().ok(),
+            user: None,
+            target: &self.dst_addr,
+            target_addr: Some(self.dst_addr),
+            protocol: "tcp-direct",
+            bytes_sent: self.client_recv,
+            bytes_received: self.client_sent,
+            start_time: self.start_time,
+        });
+        let _ = poll.deregister(&*self.client);
+        let _ = poll.deregister(&self.target);
+        let _ = self.client.shutdown(Shutdown::Both);
+        let _ = self.target.shutdown(Shutdown::Both);
+        self.closed = true;
+        log::warn!("connection:{} closed, direct target address {}, {} byte read, {} byte sent", self.index, self.dst_addr, self.client_recv, self.client_sent);
+    }
+
+    fn try_connect_target(&mut self) {
+        if self.closing {
+            return;
+        }
+        match self.target.take_error() {
+            Ok(Some(err)) | Err(err) => {
+                log::warn!("connection:{} connect to {} failed:{}", self.index, self.dst_addr, err);
+                self.closing = true;
+            }
+            Ok(None) => match self.target.peer_addr() {
+                Ok(_) => {
+                    log::info!("connection:{} connected to {} directly", self.index, self.dst_addr);
+                    self.connected = true;
+                    if let Err(err) = self.target.set_nodelay(true) {
+                        log::error!("connection:{} set nodelay failed:{}", self.index, err);
+                        self.closing = true;
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::NotConnected => {}
+                Err(err) => {
+                    log::warn!("connection:{} connect to {} failed:{}", self.index, self.dst_addr, err);
+                    self.closing = true;
+                }
+            },
+        }
+    }
+
+    fn try_read_client(&mut self) {
+        if self.closing || self.client_eof {
+            return;
+        }
+        match self.client_session.read_backend(&mut self.client) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                log::info!("connection:{} client finished sending", self.index);
+                self.client_eof = true;
+            }
+            Err(err) => {
+                log::warn!("connection:{} read from client failed:{}", self.index, err);
+                self.closing = true;
+                return;
+            }
+            Ok(_) => {}
+        }
+        let data = self.client_session.read_all();
+        if data.is_empty() {
+            return;
+        }
+        self.client_sent += data.len();
+        let _ = self.target_session.write_all(data.as_ref());
+        self.try_send_target();
+    }
+
+    // data read from the client before the connection is set up, sent once the target is connected
+    pub fn send_payload(&mut self, data: &[u8]) {
+        self.client_sent += data.len();
+        let _ = self.target_session.write_all(data);
+        self.try_send_target();
+    }
+
+    fn try_read_target(&mut self) {
+        if self.closing || self.target_eof {
+            return;
+        }
+        match self.target_session.read_backend(&mut self.target) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                log::info!("connection:{} target finished sending", self.index);
+                self.target_eof = true;
+            }
+            Err(err) => {
+                log::warn!("connection:{} read from target failed:{}", self.index, err);
+                self.closing = true;
+                return;
+            }
+            Ok(_) => {}
+        }
+        let data = self.target_session.read_all();
+        if data.is_empty() {
+            return;
+        }
+        self.client_recv += data.len();
+        let _ = self.client_session.write_all(data.as_ref());
+        self.try_send_client();
+    }
+
+    fn try_send_client(&mut self) {
+        if self.closing {
+            return;
+        }
+        if let Err(err) = self.client_session.write_backend(&mut self.client) {
+            log::warn!("connection:{} write to client failed:{}", self.index, err);
+            self.closing = true;
+        }
+    }
+
+    fn try_send_target(&mut self) {
+        if self.closing || !self.connected {
+            return;
+        }
+        if let Err(err) = self.target_session.write_backend(&mut self.target) {
+            log::warn!("connection:{} write to target failed:{}", self.index, err);
+            self.closing = true;
+        }
+    }
+}
+
+impl UdpDirect {
+    pub fn new(index: usize, src_addr: SocketAddr, socket: UdpSocket) -> UdpDirect {
+        UdpDirect {
+            index,
+            src_addr,
+            socket,
+            last_active_time: Instant::now(),
+            closed: false,
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn closed(&self) -> bool {
+        self.closed
+    }
+
+    pub fn last_active_time(&self) -> Instant {
+        self.last_active_time
+    }
+
+    pub fn setup(&mut self, poll: &Poll) -> bool {
+        if let Err(err) = poll.register(&self.socket, Token(self.index * 3), Ready::readable(), PollOpt::edge()) {
+            log::warn!("connection:{} register direct udp socket failed:{}", self.index, err);
+            false
+        } else {
+            true
+        }
+    }
+
+    pub fn send_request(&mut self, payload: &[u8], dst_addr: &SocketAddr) {
+        self.last_active_time = Instant::now();
+        if let Err(err) = self.socket.send_to(payload, dst_addr) {
+            log::error!("connection:{} send udp data to {} directly failed:{}", self.index, dst_addr, err);
+        }
+    }
+
+    pub fn ready(&mut self, poll: &Poll, udp_cache: &mut UdpSvrCache, max_udp_size: usize) {
+        let mut buffer = vec![0u8; max_udp_size + 1];
+        loop {
+            match self.socket.recv_from(buffer.as_mut_slice()) {
+                Ok((size, addr)) => {
+                    self.last_active_time = Instant::now();
+                    if size > max_udp_size {
+                        log::warn!("connection:{} udp packet from {} exceeds max udp size:{}, drop it", self.index, addr, max_udp_size);
+                        continue;
+                    }
+                    udp_cache.send_to(self.index, self.src_addr, addr, &buffer[..size]);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(err) => {
+                    log::warn!("connection:{} receive from direct udp socket failed:{}", self.index, err);
+                    self.close_now(poll);
+                    break;
+                }
+            }
+        }
+    }
+
+    pub fn close_now(&mut self, poll: &Poll) {
+        let _ = poll.deregister(&self.socket);
+        self.closed = true;
+        log::warn!("connection:{} direct udp socket for {} closed", self.index, self.src_addr);
+    }
+}
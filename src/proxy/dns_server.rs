@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+
+use mio::net::UdpSocket;
+use trust_dns_resolver::proto::op::{Message, MessageType, ResponseCode};
+use trust_dns_resolver::proto::rr::{RData, Record, RecordType};
+
+use crate::config::Opts;
+use crate::proto::MAX_UDP_SIZE;
+use crate::route::Action;
+
+const FAKE_TTL: u32 = 10;
+
+pub struct DnsServer {
+    socket: UdpSocket,
+    upstream: UdpSocket,
+    upstream_addr: SocketAddr,
+    pending: HashMap<u16, (SocketAddr, u16)>,
+    next_id: u16,
+    recv_buffer: Vec<u8>,
+}
+
+impl DnsServer {
+    pub fn new(socket: UdpSocket, upstream: UdpSocket, upstream_addr: SocketAddr) -> DnsServer {
+        DnsServer {
+            socket,
+            upstream,
+            upstream_addr,
+            pending: HashMap::new(),
+            next_id: 0,
+            recv_buffer: vec![0u8; MAX_UDP_SIZE],
+        }
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    pub fn upstream(&self) -> &UdpSocket {
+        &self.upstream
+    }
+
+    pub fn upstream_ready(&mut self) {
+        loop {
+            match self.upstream.recv_from(self.recv_buffer.as_mut_slice()) {
+                Ok((size, addr)) => {
+                    if addr != self.upstream_addr || size < 2 {
+                        log::warn!("dns server got unexpected response from {}", addr);
+                        continue;
+                    }
+                    let id = u16::from_be_bytes([self.recv_buffer[0], self.recv_buffer[1]]);
+                    if let Some((src_addr, origin_id)) = self.pending.remove(&id) {
+                        self.recv_buffer[..2].copy_from_slice(&origin_id.to_be_bytes());
+                        if let Err(err) = self.socket.send_to(&self.recv_buffer[..size], &src_addr) {
+                            log::error!("dns server send response to {} failed:{}", src_addr, err);
+                        }
+                    } else {
+                        log::warn!("dns server got response with unknown id:{}", id);
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(err) => {
+                    log::error!("dns server receive from upstream failed:{}", err);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn forward(&mut self, size: usize, src_addr: SocketAddr) {
+        let origin_id = u16::from_be_bytes([self.recv_buffer[0], self.recv_buffer[1]]);
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.recv_buffer[..2].copy_from_slice(&id.to_be_bytes());
+        if let Err(err) = self.upstream.send_to(&self.recv_buffer[..size], &self.upstream_addr) {
+            log::error!("dns server forward request to {} failed:{}", self.upstream_addr, err);
+            return;
+        }
+        // ids are reused in a ring, a lost response is overwritten when its id comes round again
+        self.pending.insert(id, (src_addr, origin_id));
+    }
+
+    pub fn ready(&mut self, opts: &mut Opts) {
+        loop {
+            match self.socket.recv_from(self.recv_buffer.as_mut_slice()) {
+                Ok((size, src_addr)) => {
+                    log::debug!("dns server received {} bytes from {}", size, src_addr);
+                    match self.handle(&self.recv_buffer[..size], opts) {
+                        Ok(Some(response)) => {
+                            if let Err(err) = self.socket.send_to(response.as_slice(), &src_addr) {
+                                log::error!("dns server send response to {} failed:{}", src_addr, err);
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(_) => self.forward(size, src_addr),
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(err) => {
+                    log::error!("dns server receive failed:{}", err);
+                    break;
+                }
+            }
+        }
+    }
+
+    // Err means the request should be forwarded to the upstream server
+    fn handle(&self, data: &[u8], opts: &mut Opts) -> Result<Option<Vec<u8>>, ()> {
+        let request = match Message::from_vec(data) {
+            Ok(request) => request,
+            Err(err) => {
+                log::warn!("dns server got invalid request:{}", err);
+                return Ok(None);
+            }
+        };
+        if request.queries().len() == 1 {
+            let domain = request.queries()[0].name().to_ascii();
+            match opts.route(Some(domain.as_str()), None, None) {
+                Action::Direct => {
+                    log::info!("dns query {} is forwarded to upstream", domain);
+                    return Err(());
+                }
+                Action::Block => {
+                    log::info!("dns query {} is blocked", domain);
+                    let mut response = Message::new();
+                    response.set_id(request.id())
+                        .set_message_type(MessageType::Response)
+                        .set_op_code(request.op_code())
+                        .set_recursion_desired(request.recursion_desired())
+                        .set_recursion_available(true)
+                        .set_response_code(ResponseCode::NXDomain)
+                        .add_query(request.queries()[0].clone());
+                    return Ok(response.to_vec().ok());
+                }
+                Action::Proxy | Action::Outbound(_) => {}
+            }
+        }
+        let mut response = Message::new();
+        response.set_id(request.id())
+            .set_message_type(MessageType::Response)
+            .set_op_code(request.op_code())
+            .set_recursion_desired(request.recursion_desired())
+            .set_recursion_available(true);
+        for query in request.queries() {
+            response.add_query(query.clone());
+            // only A queries get an answer, others are answered empty so that clients fall back to ipv4
+            if query.query_type() == RecordType::A {
+                let domain = query.name().to_ascii();
+                let domain = domain.trim_end_matches('.');
+                let ip = opts.fake_dns.allocate(domain);
+                log::info!("dns query {} = {}", domain, ip);
+                response.add_answer(Record::from_rdata(query.name().clone(), FAKE_TTL, RData::A(ip)));
+            }
+        }
+        match response.to_vec() {
+            Ok(data) => Ok(Some(data)),
+            Err(err) => {
+                log::error!("dns server encode response failed:{}", err);
+                Ok(None)
+            }
+        }
+    }
+}
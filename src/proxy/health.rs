@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use mio::{Poll, PollOpt, Ready, Token};
+use rustls::ClientConfig;
+
+use crate::config::Opts;
+use crate::proxy::HEALTH_CHECK;
+use crate::proxy::tls::TlsConnect;
+use crate::upstream::MAX_UPSTREAMS;
+
+pub struct HealthChecker {
+    config: Arc<ClientConfig>,
+    checks: HashMap<usize, TlsConnect>,
+    interval: Duration,
+    timeout: Duration,
+    last_check_time: Instant,
+}
+
+impl HealthChecker {
+    pub fn new(config: Arc<ClientConfig>, interval: Duration, timeout: Duration) -> HealthChecker {
+        HealthChecker {
+            config,
+            checks: HashMap::new(),
+            interval,
+            timeout,
+            last_check_time: Instant::now(),
+        }
+    }
+
+    pub fn is_check_token(token: Token) -> bool {
+        token.0 >= HEALTH_CHECK && token.0 < HEALTH_CHECK + MAX_UPSTREAMS
+    }
+
+    pub fn check(&mut self, now: Instant, opts: &mut Opts, poll: &Poll) {
+        let expired: Vec<usize> = self.checks.iter()
+            .filter(|(_, check)| now - check.start_time() > self.timeout)
+            .map(|(index, _)| *index)
+            .collect();
+        for index in expired {
+            log::warn!("health check of trojan server {} timeout", opts.upstreams[index].name());
+            self.finish(index, false, opts, poll);
+        }
+
+        // unreachable servers are retried in the background, connections to them fail fast meanwhile
+        for index in 0..opts.upstreams.len() {
+            if opts.upstreams[index].needs_retry(now) && !self.checks.contains_key(&index) {
+                log::info!("retrying trojan server {}", opts.upstreams[index].name());
+                self.start(index, opts, poll);
+            }
+        }
+
+        if self.interval.as_secs() == 0 || now - self.last_check_time < self.interval {
+            return;
+        }
+        self.last_check_time = now;
+        for index in 0..opts.upstreams.len() {
+            if !self.checks.contains_key(&index) && opts.upstreams[index].is_available() {
+                self.start(index, opts, poll);
+            }
+        }
+    }
+
+    fn start(&mut self, index: usize, opts: &Opts, poll: &Poll) {
+        let upstream = &opts.upstreams[index];
+        let addr = match upstream.addr() {
+            Some(addr) => addr,
+            None => return,
+        };
+        let check = match TlsConnect::new(&addr, &opts.tcp_opts, &self.config, upstream.dns_name(), opts.upstream_tunnel(index)) {
+            Ok(check) => check,
+            Err(err) => {
+                log::warn!("health check connect to {} failed:{}", addr, err);
+                return;
+            }
+        };
+        if let Err(err) = poll.register(check.stream(), Token(HEALTH_CHECK + index), Ready::readable() | Ready::writable(), PollOpt::edge()) {
+            log::error!("register health check failed:{}", err);
+            return;
+        }
+        self.checks.insert(index, check);
+    }
+
+    pub fn ready(&mut self, token: Token, opts: &mut Opts, poll: &Poll) {
+        let index = token.0 - HEALTH_CHECK;
+        let result = if let Some(check) = self.checks.get_mut(&index) {
+            check.handshake()
+        } else {
+            return;
+        };
+        match result {
+            Ok(true) => self.finish(index, true, opts, poll),
+            Ok(false) => {}
+            Err(err) => {
+                log::warn!("health check of trojan server {} failed:{}", opts.upstreams[index].name(), err);
+                self.finish(index, false, opts, poll);
+            }
+        }
+    }
+
+    fn finish(&mut self, index: usize, healthy: bool, opts: &mut Opts, poll: &Poll) {
+        if let Some(check) = self.checks.remove(&index) {
+            let _ = poll.deregister(check.stream());
+            if healthy {
+                let latency = check.start_time().elapsed();
+                log::info!("health check of trojan server {} done in {}ms", opts.upstreams[index].name(), latency.as_millis());
+                opts.upstream_connected(index, latency);
+                opts.upstreams[index].succeeded();
+            } else {
+                opts.upstream_failed(index);
+            }
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
+use std::time::{Duration, Instant};
+
+use mio::{Event, Poll, PollOpt, Ready, Token};
+use mio::net::TcpStream;
+
+use crate::config::{IpStrategy, TransparentMode};
+use crate::proto::Sock5Address;
+use crate::resolver::EventedResolver;
+
+const MAX_HANDSHAKE_SIZE: usize = 8192;
+const SOCKS5: u8 = 0x05;
+const NO_AUTH: u8 = 0x00;
+const NO_ACCEPTABLE: u8 = 0xff;
+const CMD_CONNECT: u8 = 0x01;
+const SUCCEEDED: u8 = 0x00;
+const CMD_NOT_SUPPORTED: u8 = 0x07;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+pub enum HandshakeResult {
+    Pending,
+    Done(Sock5Address),
+    Resolved(Option<SocketAddr>),
+    Failed,
+}
+
+// socks5 and http proxy handshakes of clients, before their connections are routed
+pub struct Handshake {
+    index: usize,
+    src_addr: SocketAddr,
+    client: TcpStream,
+    mode: TransparentMode,
+    buffer: Vec<u8>,
+    greeted: bool,
+    resolver: Option<(u16, EventedResolver)>,
+    accept_time: Instant,
+}
+
+impl Handshake {
+    pub fn new(index: usize, src_addr: SocketAddr, client: TcpStream, mode: TransparentMode) -> Handshake {
+        Handshake {
+            index,
+            src_addr,
+            client,
+            mode,
+            buffer: Vec::new(),
+            greeted: false,
+            resolver: None,
+            accept_time: Instant::now(),
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn src_addr(&self) -> SocketAddr {
+        self.src_addr
+    }
+
+    fn client_token(&self) -> Token {
+        Token(self.index * 3 + 1)
+    }
+
+    fn resolver_token(&self) -> Token {
+        Token(self.index * 3 + 2)
+    }
+
+    pub fn setup(&mut self, poll: &Poll) -> bool {
+        if let Err(err) = poll.register(&self.client, self.client_token(), Ready::readable(), PollOpt::edge()) {
+            log::warn!("connection:{} register client failed:{}", self.index, err);
+            false
+        } else {
+            true
+        }
+    }
+
+    pub fn timed_out(&self, now: Instant, timeout: Duration) -> bool {
+        now - self.accept_time > timeout
+    }
+
+    pub fn ready(&mut self, event: &Event, poll: &Poll) -> HandshakeResult {
+        if event.token() == self.resolver_token() {
+            if let Some((port, resolver)) = self.resolver.take() {
+                let _ = poll.deregister(&resolver);
+                return HandshakeResult::Resolved(resolver.address().map(|ip| SocketAddr::new(ip, port)));
+            }
+            return HandshakeResult::Pending;
+        }
+        if !self.read_client() {
+            return HandshakeResult::Failed;
+        }
+        if self.resolver.is_some() {
+            // the handshake is done, keep the data for the target
+            return HandshakeResult::Pending;
+        }
+        match self.mode {
+            // the listeners of tun mode serve programs configured to use a proxy
+            TransparentMode::Socks5 | TransparentMode::Tun => self.socks5(),
+            TransparentMode::Http => self.http(),
+            _ => HandshakeResult::Failed,
+        }
+    }
+
+    // resolves domains of direct connections without blocking the loop
+    pub fn resolve(&mut self, domain: String, port: u16, strategy: IpStrategy, poll: &Poll) -> bool {
+        let resolver = EventedResolver::new(domain, strategy);
+        if let Err(err) = poll.register(&resolver, self.resolver_token(), Ready::readable(), PollOpt::level()) {
+            log::error!("connection:{} register resolver failed:{}", self.index, err);
+            false
+        } else {
+            self.resolver.replace((port, resolver));
+            true
+        }
+    }
+
+    // hands the client over to the relay along with the data sent after the handshake
+    pub fn into_parts(mut self, poll: &Poll) -> (TcpStream, Vec<u8>) {
+        let _ = poll.deregister(&self.client);
+        if let Some((_, resolver)) = self.resolver.take() {
+            let _ = poll.deregister(&resolver);
+        }
+        (self.client, self.buffer)
+    }
+
+    pub fn close_now(&mut self, poll: &Poll) {
+        let _ = poll.deregister(&self.client);
+        let _ = self.client.shutdown(Shutdown::Both);
+        if let Some((_, resolver)) = self.resolver.take() {
+            let _ = poll.deregister(&resolver);
+        }
+        log::info!("connection:{} handshake from {} closed", self.index, self.src_addr);
+    }
+
+    fn read_client(&mut self) -> bool {
+        let mut buffer = [0u8; 1024];
+        loop {
+            match self.client.read(&mut buffer) {
+                Ok(0) => {
+                    log::warn!("connection:{} client closed during handshake", self.index);
+                    return false;
+                }
+                Ok(size) => {
+                    self.buffer.extend_from_slice(&buffer[..size]);
+                    if self.buffer.len() > MAX_HANDSHAKE_SIZE {
+                        log::warn!("connection:{} handshake is too long", self.index);
+                        return false;
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return true,
+                Err(err) => {
+                    log::warn!("connection:{} read from client failed:{}", self.index, err);
+                    return false;
+                }
+            }
+        }
+    }
+
+    fn reply(&mut self, data: &[u8]) -> bool {
+        if let Err(err) = self.client.write_all(data) {
+            log::warn!("connection:{} write handshake to client failed:{}", self.index, err);
+            false
+        } else {
+            true
+        }
+    }
+
+    fn socks5(&mut self) -> HandshakeResult {
+        if !self.greeted {
+            if self.buffer.len() < 2 {
+                return HandshakeResult::Pending;
+            }
+            if self.buffer[0] != SOCKS5 {
+                log::warn!("connection:{} invalid socks version:{}", self.index, self.buffer[0]);
+                return HandshakeResult::Failed;
+            }
+            let size = 2 + self.buffer[1] as usize;
+            if self.buffer.len() < size {
+                return HandshakeResult::Pending;
+            }
+            if !self.buffer[2..size].contains(&NO_AUTH) {
+                log::warn!("connection:{} socks client does not support no authentication", self.index);
+                self.reply(&[SOCKS5, NO_ACCEPTABLE]);
+                return HandshakeResult::Failed;
+            }
+            if !self.reply(&[SOCKS5, NO_AUTH]) {
+                return HandshakeResult::Failed;
+            }
+            self.buffer.drain(..size);
+            self.greeted = true;
+        }
+
+        if self.buffer.len() < 5 {
+            return HandshakeResult::Pending;
+        }
+        let (size, address) = match self.buffer[3] {
+            ATYP_IPV4 => {
+                if self.buffer.len() < 10 {
+                    return HandshakeResult::Pending;
+                }
+                let ip = Ipv4Addr::new(self.buffer[4], self.buffer[5], self.buffer[6], self.buffer[7]);
+                (10, Sock5Address::Socket(SocketAddr::new(IpAddr::V4(ip), to_u16(&self.buffer[8..]))))
+            }
+            ATYP_DOMAIN => {
+                let size = 7 + self.buffer[4] as usize;
+                if self.buffer.len() < size {
+                    return HandshakeResult::Pending;
+                }
+                let domain = String::from_utf8_lossy(&self.buffer[5..size - 2]).to_string();
+                (size, Sock5Address::Domain(domain, to_u16(&self.buffer[size - 2..])))
+            }
+            ATYP_IPV6 => {
+                if self.buffer.len() < 22 {
+                    return HandshakeResult::Pending;
+                }
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&self.buffer[4..20]);
+                (22, Sock5Address::Socket(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), to_u16(&self.buffer[20..]))))
+            }
+            atyp => {
+                log::warn!("connection:{} invalid socks address type:{}", self.index, atyp);
+                return HandshakeResult::Failed;
+            }
+        };
+        if self.buffer[0] != SOCKS5 || self.buffer[1] != CMD_CONNECT {
+            log::warn!("connection:{} socks command {} is not supported", self.index, self.buffer[1]);
+            self.reply(&[SOCKS5, CMD_NOT_SUPPORTED, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0]);
+            return HandshakeResult::Failed;
+        }
+        // the relay is not connected yet, clients learn about failures from the connection being closed
+        if !self.reply(&[SOCKS5, SUCCEEDED, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0]) {
+            return HandshakeResult::Failed;
+        }
+        self.buffer.drain(..size);
+        HandshakeResult::Done(address)
+    }
+
+    fn http(&mut self) -> HandshakeResult {
+        let end = match self.buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            Some(pos) => pos + 4,
+            None => return HandshakeResult::Pending,
+        };
+        let line_end = self.buffer.windows(2).position(|window| window == b"\r\n").unwrap();
+        let line = String::from_utf8_lossy(&self.buffer[..line_end]).to_string();
+        let mut parts = line.split_whitespace();
+        let (method, uri, version) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(uri), Some(version)) => (method, uri, version),
+            _ => {
+                log::warn!("connection:{} invalid http request:{}", self.index, line);
+                return HandshakeResult::Failed;
+            }
+        };
+        if method == "CONNECT" {
+            let address = match parse_host(uri, 443) {
+                Some(address) => address,
+                None => {
+                    log::warn!("connection:{} invalid http connect target:{}", self.index, uri);
+                    return HandshakeResult::Failed;
+                }
+            };
+            if !self.reply(b"HTTP/1.1 200 Connection established\r\n\r\n") {
+                return HandshakeResult::Failed;
+            }
+            self.buffer.drain(..end);
+            return HandshakeResult::Done(address);
+        }
+
+        // plain http proxy requests carry absolute uris, which are sent as origin form to the target
+        if !uri.starts_with("http://") {
+            log::warn!("connection:{} unsupported http proxy request:{}", self.index, line);
+            self.reply(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n");
+            return HandshakeResult::Failed;
+        }
+        let uri = &uri[7..];
+        let (host, path) = match uri.find('/') {
+            Some(pos) => (&uri[..pos], &uri[pos..]),
+            None => (uri, "/"),
+        };
+        let address = match parse_host(host, 80) {
+            Some(address) => address,
+            None => {
+                log::warn!("connection:{} invalid http proxy target:{}", self.index, host);
+                return HandshakeResult::Failed;
+            }
+        };
+        let mut request = format!("{} {} {}", method, path, version).into_bytes();
+        request.extend_from_slice(&self.buffer[line_end..]);
+        self.buffer = request;
+        HandshakeResult::Done(address)
+    }
+}
+
+fn to_u16(buffer: &[u8]) -> u16 {
+    (buffer[0] as u16) << 8 | buffer[1] as u16
+}
+
+// host[:port] or [ipv6][:port]
+fn parse_host(host: &str, default_port: u16) -> Option<Sock5Address> {
+    let (name, port) = if host.starts_with('[') {
+        let end = host.find(']')?;
+        let port = &host[end + 1..];
+        let port = if port.is_empty() {
+            default_port
+        } else if let Some(port) = port.strip_prefix(':') {
+            port.parse().ok()?
+        } else {
+            return None;
+        };
+        (&host[1..end], port)
+    } else if let Some(pos) = host.rfind(':') {
+        (&host[..pos], host[pos + 1..].parse().ok()?)
+    } else {
+        (host, default_port)
+    };
+    if name.is_empty() {
+        return None;
+    }
+    if let Ok(ip) = name.parse::<IpAddr>() {
+        Some(Sock5Address::Socket(SocketAddr::new(ip, port)))
+    } else {
+        Some(Sock5Address::Domain(name.to_string(), port))
+    }
+}
@@ -1,30 +1,73 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
 
 use mio::{Events, Poll, PollOpt, Ready, Token};
-use mio::net::TcpListener;
+use mio::net::{TcpListener, TcpStream};
 use mio::net::UdpSocket;
 use rustls::ClientConfig;
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
-use webpki::DNSNameRef;
 
-use crate::config::Opts;
+use crate::budget::MemoryBudget;
+use crate::config::{MAX_LISTENERS, Opts, TransparentMode};
+use crate::error::{Error, Result};
+use crate::health_http::{self, HealthHttp};
+use crate::plugin::Plugins;
+use crate::proxy::dns_server::DnsServer;
+use crate::proxy::health::HealthChecker;
+use crate::proxy::network::NetworkWatch;
 use crate::proxy::tcp_server::TcpServer;
+use crate::proxy::tun::Tun;
 use crate::proxy::udp_cache::UdpSvrCache;
 use crate::proxy::udp_server::UdpServer;
-use crate::sys;
+use crate::resolver::EventedResolver;
+use crate::subscription::EventedSubscription;
+use crate::upstream::MAX_UPSTREAMS;
+use crate::{log_level, pac, privilege, sandbox, state, sys, systemd, upgrade};
 
 mod tcp_server;
 mod udp_server;
 mod udp_cache;
+mod inbound;
+mod dns_server;
+mod direct;
+mod health;
+mod network;
+mod tls;
+mod tun;
 
-pub const MIN_INDEX: usize = 2;
-pub const MAX_INDEX: usize = std::usize::MAX / 3;
-pub const TCP_LISTENER: usize = 1;
-pub const UDP_LISTENER: usize = 2;
+pub const RESOLVER: usize = 3;
+pub const DNS_LISTENER: usize = 4;
+pub const DNS_UPSTREAM: usize = 5;
+pub const PROBE: usize = 6;
+pub const SUBSCRIPTION: usize = 7;
+pub const HEALTH_HTTP: usize = 8;
+pub const HEALTH_HTTP_CLIENT: usize = 9;
+pub const PAC_HTTP: usize = 10;
+pub const PAC_HTTP_CLIENT: usize = 11;
+pub const TUN: usize = 12;
+pub const HEALTH_CHECK: usize = 16;
+pub const TCP_LISTENER: usize = HEALTH_CHECK + MAX_UPSTREAMS;
+pub const UDP_LISTENER: usize = TCP_LISTENER + MAX_LISTENERS;
+// tokens of sessions start after those of the listeners
+pub const MIN_INDEX: usize = (UDP_LISTENER + MAX_LISTENERS) / 3 + 1;
+pub const MAX_INDEX: usize = usize::MAX / 3;
 
-pub fn new_socket(addr: SocketAddr, is_udp: bool) -> Socket {
+// ids of tcp and udp sessions, shared so that each session is told apart in logs by its id alone
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(MIN_INDEX);
+
+pub fn next_index() -> usize {
+    let index = NEXT_INDEX.fetch_add(1, Ordering::Relaxed);
+    if index >= MAX_INDEX {
+        NEXT_INDEX.store(MIN_INDEX + 1, Ordering::Relaxed);
+        MIN_INDEX
+    } else {
+        index
+    }
+}
+
+pub fn new_socket(addr: SocketAddr, is_udp: bool, transparent: bool, only_v6: bool) -> std::io::Result<Socket> {
     let domain = if addr.is_ipv4() {
         Domain::ipv4()
     } else {
@@ -35,65 +78,447 @@ pub fn new_socket(addr: SocketAddr, is_udp: bool) -> Socket {
     } else {
         (Type::stream(), Protocol::tcp())
     };
-    let socket = Socket::new(domain, typ, Some(protocol)).unwrap();
-    sys::set_socket_opts(addr.is_ipv4(), is_udp, &socket).unwrap();
-    socket.set_nonblocking(true).unwrap();
-    socket.set_reuse_address(true).unwrap();
-    socket.bind(&SockAddr::from(addr)).unwrap();
+    let socket = Socket::new(domain, typ, Some(protocol))?;
+    if addr.ip().is_unspecified() && addr.is_ipv6() {
+        // listen on [::] for both ipv4 and ipv6
+        socket.set_only_v6(only_v6)?;
+    }
+    if transparent {
+        sys::set_socket_opts(addr.is_ipv4(), is_udp, &socket)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SockAddr::from(addr))?;
     if !is_udp {
-        socket.listen(1024).unwrap();
+        socket.listen(1024)?;
     }
-    socket
+    Ok(socket)
 }
 
-pub fn run(opts: &mut Opts) {
-    let addr: SocketAddr = opts.local_addr.parse().unwrap();
-    let tcp_listener = TcpListener::from_std(new_socket(addr, false).into_tcp_listener()).unwrap();
-    let udp_listener = UdpSocket::from_socket(new_socket(addr, true).into_udp_socket()).unwrap();
-    if let Err(err) = sys::set_mark(&udp_listener, opts.marker) {
-        log::error!("udp socket set mark failed:{}", err);
-        return;
+pub fn run(opts: &mut Opts) -> Result<()> {
+    let transparent = opts.proxy_args().transparent_mode == TransparentMode::Tproxy;
+    let mut tcp_listeners = Vec::new();
+    let inherited = systemd::tcp_listeners();
+    if !inherited.is_empty() {
+        log::warn!("using {} inherited tcp listeners, {} is ignored", inherited.len(), opts.relay_args().local_addr.join(","));
+        for listener in inherited.into_iter().take(MAX_LISTENERS) {
+            if transparent {
+                // the socket unit binds the listener, but the tproxy options are still needed
+                let v4 = listener.local_addr().map_or(true, |addr| addr.is_ipv4());
+                if let Err(err) = sys::set_socket_opts(v4, false, &listener) {
+                    log::error!("set transparent options on tcp listener failed:{}, Transparent=yes or CAP_NET_ADMIN is required", err);
+                }
+            }
+            tcp_listeners.push(TcpListener::from_std(listener).map_err(|err| Error::io("use inherited tcp listener", err))?);
+        }
+    } else {
+        for addr in opts.local_addrs.iter() {
+            let listener = new_socket(*addr, false, transparent, opts.only_v6(addr))
+                .and_then(|socket| TcpListener::from_std(socket.into_tcp_listener()))
+                .map_err(|err| Error::io(format!("listen on tcp {}", addr), err))?;
+            tcp_listeners.push(listener);
+        }
+    }
+    // the device is made before privileges are dropped
+    let tun_mode = opts.proxy_args().transparent_mode == TransparentMode::Tun;
+    let mut tun = if tun_mode {
+        let name = opts.proxy_args().tun_name.clone().unwrap_or_else(|| sys::TUN_NAME.to_string());
+        let device = sys::TunDevice::open(name.as_str()).map_err(|err| Error::io(format!("open tun device {}", name), err))?;
+        log::warn!("relaying packets routed to tun device {}", device.name());
+        Some(Tun::new(device, opts.proxy_args().tun_mtu))
+    } else {
+        None
+    };
+    // the original destination of redirected udp packets is lost
+    let udp_transparent = transparent && sys::UDP_TRANSPARENT;
+    if !udp_transparent && !tun_mode && !opts.relay_args().no_udp {
+        log::warn!("udp is not supported in this transparent mode or on this platform");
+    }
+    let mut udp_listeners = Vec::new();
+    let mut udp_markers = Vec::new();
+    if udp_transparent && !opts.relay_args().no_udp {
+        let inherited = systemd::udp_sockets();
+        if !inherited.is_empty() {
+            log::warn!("using {} inherited udp sockets", inherited.len());
+            for socket in inherited.into_iter().take(MAX_LISTENERS) {
+                let v4 = socket.local_addr().map_or(true, |addr| addr.is_ipv4());
+                if let Err(err) = sys::set_socket_opts(v4, true, &socket) {
+                    log::error!("set transparent options on udp socket failed:{}, CAP_NET_ADMIN is required", err);
+                }
+                // the marker of the udp address the socket is bound to, if it is one of them
+                let marker = socket.local_addr().ok()
+                    .and_then(|local_addr| opts.udp_addrs.iter().find(|(addr, _)| *addr == local_addr))
+                    .map_or(opts.relay_args().marker, |(_, marker)| *marker);
+                udp_listeners.push(UdpSocket::from_socket(socket).map_err(|err| Error::io("use inherited udp socket", err))?);
+                udp_markers.push(marker);
+            }
+        } else {
+            for (addr, marker) in opts.udp_addrs.iter() {
+                let socket = new_socket(*addr, true, transparent, opts.udp_only_v6(addr))
+                    .and_then(|socket| UdpSocket::from_socket(socket.into_udp_socket()))
+                    .map_err(|err| Error::io(format!("listen on udp {}", addr), err))?;
+                udp_listeners.push(socket);
+                udp_markers.push(*marker);
+            }
+        }
+        for (udp_listener, marker) in udp_listeners.iter().zip(udp_markers.iter()) {
+            sys::set_mark(udp_listener, *marker).map_err(|err| Error::io("set mark on udp socket", err))?;
+        }
     }
     let mut udp_cache = UdpSvrCache::new();
-    let poll = Poll::new().unwrap();
-    poll.register(&tcp_listener, Token(TCP_LISTENER), Ready::readable(), PollOpt::edge()).unwrap();
-    poll.register(&udp_listener, Token(UDP_LISTENER), Ready::readable(), PollOpt::edge()).unwrap();
+    if let Some(tun) = tun.as_ref() {
+        udp_cache.set_tun(tun.writer());
+    }
+    let poll = Poll::new().map_err(|err| Error::io("create poll", err))?;
+    for (i, tcp_listener) in tcp_listeners.iter().enumerate() {
+        poll.register(tcp_listener, Token(TCP_LISTENER + i), Ready::readable(), PollOpt::edge()).map_err(|err| Error::io("register tcp listener", err))?;
+    }
+    for (i, udp_listener) in udp_listeners.iter().enumerate() {
+        poll.register(udp_listener, Token(UDP_LISTENER + i), Ready::readable(), PollOpt::edge()).map_err(|err| Error::io("register udp listener", err))?;
+    }
+    if let Some(tun) = tun.as_ref() {
+        poll.register(tun.device(), Token(TUN), Ready::readable(), PollOpt::edge()).map_err(|err| Error::io("register tun device", err))?;
+    }
 
 
-    let hostname = DNSNameRef::try_from_ascii(opts.proxy_args().hostname.as_bytes()).unwrap().to_owned();
     let mut config = ClientConfig::new();
     config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
     let config = Arc::new(config);
 
-    let mut tcp_server = TcpServer::new(tcp_listener, config.clone(), hostname.clone());
-    let mut udp_server = UdpServer::new(udp_listener, config, hostname);
+    let mut dns_server = if let Some(dns_addr) = opts.proxy_args().dns_addr.as_ref() {
+        let dns_addr: SocketAddr = dns_addr.parse().map_err(|err| Error::Config(format!("invalid --dns-addr {}:{}", dns_addr, err)))?;
+        let direct_dns = opts.proxy_args().direct_dns.as_str();
+        let upstream_addr: SocketAddr = direct_dns.parse().map_err(|err| Error::Config(format!("invalid --direct-dns {}:{}", direct_dns, err)))?;
+        let bind_addr = if upstream_addr.is_ipv4() {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)
+        } else {
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)
+        };
+        let upstream = UdpSocket::bind(&bind_addr).map_err(|err| Error::io("bind dns upstream socket", err))?;
+        sys::set_mark(&upstream, opts.relay_args().marker).map_err(|err| Error::io("set mark on dns upstream socket", err))?;
+        let socket = UdpSocket::bind(&dns_addr).map_err(|err| Error::io(format!("listen on dns {}", dns_addr), err))?;
+        let dns_server = DnsServer::new(socket, upstream, upstream_addr);
+        poll.register(dns_server.socket(), Token(DNS_LISTENER), Ready::readable(), PollOpt::edge()).map_err(|err| Error::io("register dns listener", err))?;
+        poll.register(dns_server.upstream(), Token(DNS_UPSTREAM), Ready::readable(), PollOpt::edge()).map_err(|err| Error::io("register dns upstream", err))?;
+        log::warn!("fake ip dns server listening on {}", dns_addr);
+        Some(dns_server)
+    } else {
+        None
+    };
+
+    let mut health_checker = HealthChecker::new(config.clone(),
+                                                Duration::new(opts.proxy_args().health_check_time, 0),
+                                                Duration::new(opts.proxy_args().health_check_timeout, 0));
+    let mut health_http = match opts.relay_args().health_addr.as_ref() {
+        Some(addr) => {
+            let addr: SocketAddr = addr.parse().map_err(|err| Error::Config(format!("invalid --health-addr {}:{}", addr, err)))?;
+            Some(HealthHttp::new(&addr, &poll, Token(HEALTH_HTTP), Token(HEALTH_HTTP_CLIENT))
+                .map_err(|err| Error::io(format!("listen on health address {}", addr), err))?)
+        }
+        None => None,
+    };
+    let mut pac_http = match opts.proxy_args().pac_addr.as_ref() {
+        Some(addr) => {
+            let addr: SocketAddr = addr.parse().map_err(|err| Error::Config(format!("invalid --pac-addr {}:{}", addr, err)))?;
+            let pac_http = HealthHttp::bind(&addr, &poll, Token(PAC_HTTP), Token(PAC_HTTP_CLIENT))
+                .map_err(|err| Error::io(format!("listen on pac address {}", addr), err))?;
+            log::warn!("proxy auto-config served on http://{}/proxy.pac", addr);
+            Some(pac_http)
+        }
+        None => None,
+    };
+    let mut tcp_server = TcpServer::new(tcp_listeners, config.clone());
+    let max_udp_size = opts.relay_args().max_udp_size;
+    let tun_udp = tun_mode && !opts.relay_args().no_udp;
+    let mut udp_server = if udp_listeners.is_empty() && !tun_udp {
+        None
+    } else {
+        Some(UdpServer::new(udp_listeners, udp_markers, config, max_udp_size))
+    };
 
     let mut events = Events::with_capacity(1024);
     let mut last_check_time = Instant::now();
     let check_duration = Duration::new(1, 0);
+    let mut resolver: Option<(usize, EventedResolver)> = None;
+    let mut probe: Option<TcpStream> = None;
+    let mut last_probe_time = Instant::now();
+    let probe_duration = Duration::new(opts.proxy_args().failback_time, 0);
+    let resolve_duration = Duration::new(opts.proxy_args().dns_refresh_time, 0);
+    let hop_duration = Duration::new(opts.proxy_args().hop_interval, 0);
+    let racing_duration = Duration::from_millis(10);
+    let mut last_route_check_time = Instant::now();
+    let mut network_watch = NetworkWatch::new();
+    let mut last_network_check_time = Instant::now();
+    let network_duration = Duration::new(opts.proxy_args().network_check_interval, 0);
+    let mut subscription: Option<EventedSubscription> = None;
+    let mut last_subscription_time = Instant::now();
+    let subscription_duration = Duration::new(opts.proxy_args().subscription_time, 0);
+    let drain_duration = Duration::new(opts.relay_args().drain_timeout, 0);
+    let mut stop_time: Option<Instant> = None;
+    let mut upgrade: Option<upgrade::Upgrade> = None;
+    let mut budget = MemoryBudget::new();
+    // udp replies are sent from the original destinations, which are not local addresses
+    privilege::drop(opts, transparent || opts.relay_args().marker != 0 || opts.tcp_opts.marker != 0);
+    sandbox::apply(opts);
+    // plugins run as the user trojan drops to
+    let mut plugins = opts.proxy_args().plugin.clone().map(|command| Plugins::new(command, opts.proxy_args().plugin_opts.clone().unwrap_or_default()));
+    if let Some(plugins) = plugins.as_mut() {
+        plugins.check(Instant::now(), opts);
+    }
+    let mut watchdog = systemd::Watchdog::new();
+    systemd::notify("READY=1");
     loop {
-        let nevent = poll.poll(&mut events, Some(check_duration)).unwrap();
+        if sys::trace_toggle_requested() {
+            log_level::toggle_trace();
+        }
+        if sys::upgrade_requested() && upgrade.is_none() && stop_time.is_none() {
+            if tun_mode {
+                log::error!("binary upgrade is not possible in tun mode, the device is held by this process");
+            } else {
+                upgrade = upgrade::start(opts, tcp_server.listeners(), udp_server.as_ref().map_or(&[][..], |udp_server| udp_server.listeners()));
+            }
+        }
+        if stop_time.is_none() && sys::stopping() {
+            log::warn!("trojan is stopping, draining {} tcp connections", tcp_server.conn_count());
+            systemd::notify("STOPPING=1");
+            tcp_server.stop_accept(&poll);
+            if let Some(udp_server) = udp_server.as_mut() {
+                udp_server.stop_accept();
+            }
+            stop_time.replace(Instant::now());
+        }
+        if let Some(stop_time) = stop_time {
+            let count = tcp_server.conn_count() + udp_server.as_ref().map_or(0, |udp_server| udp_server.conn_count());
+            if count == 0 {
+                log::warn!("all connections drained");
+                break;
+            }
+            if stop_time.elapsed() >= drain_duration {
+                log::warn!("drain timeout, close {} connections", count);
+                break;
+            }
+        }
+        let udp_racing = udp_server.as_ref().map_or(false, |udp_server| udp_server.is_racing());
+        let timeout = if tcp_server.is_racing() || udp_racing {
+            racing_duration
+        } else {
+            check_duration
+        };
+        // the stack of the device has timers of its own, retransmissions and delayed acks
+        let timeout = tun.as_mut().and_then(|tun| tun.poll_delay()).map_or(timeout, |delay| delay.min(timeout));
+        let nevent = poll.poll(&mut events, Some(timeout)).unwrap();
         log::trace!("poll got {} events", nevent);
         for event in &events {
             match event.token() {
-                Token(TCP_LISTENER) => {
-                    tcp_server.accept(&event, opts, &poll);
+                Token(i) if (TCP_LISTENER..TCP_LISTENER + MAX_LISTENERS).contains(&i) => {
+                    tcp_server.accept(i - TCP_LISTENER, opts, &poll);
+                }
+                Token(i) if (UDP_LISTENER..UDP_LISTENER + MAX_LISTENERS).contains(&i) => {
+                    if let Some(udp_server) = udp_server.as_mut() {
+                        udp_server.accept(i - UDP_LISTENER, &event, opts, &poll);
+                    }
+                }
+                Token(TUN) => {
+                    if let Some(tun) = tun.as_mut() {
+                        tun.ready(opts, &poll, &tcp_server, udp_server.as_mut());
+                    }
+                }
+                Token(HEALTH_HTTP) => {
+                    if let Some(health_http) = health_http.as_mut() {
+                        health_http.accept(&poll);
+                    }
+                }
+                Token(HEALTH_HTTP_CLIENT) => {
+                    if let Some(health_http) = health_http.as_mut() {
+                        health_http.ready(&poll, || {
+                            let now = Instant::now();
+                            if stop_time.is_some() {
+                                Err("stopping".to_string())
+                            } else if !opts.upstreams.iter().any(|upstream| upstream.is_healthy() && !upstream.is_backing_off(now)) {
+                                Err("no trojan server is reachable".to_string())
+                            } else {
+                                Ok(())
+                            }
+                        }, |_| None);
+                    }
+                }
+                Token(PAC_HTTP) => {
+                    if let Some(pac_http) = pac_http.as_mut() {
+                        pac_http.accept(&poll);
+                    }
+                }
+                Token(PAC_HTTP_CLIENT) => {
+                    if let Some(pac_http) = pac_http.as_mut() {
+                        // generated on each request, so that reloaded rules are served
+                        pac_http.serve(&poll, |path, _| match path {
+                            "/proxy.pac" => ("200 OK", "application/x-ns-proxy-autoconfig", pac::generate(opts)),
+                            _ => health_http::not_found(),
+                        });
+                    }
+                }
+                Token(DNS_LISTENER) => {
+                    if let Some(dns_server) = dns_server.as_mut() {
+                        dns_server.ready(opts);
+                    }
+                }
+                Token(DNS_UPSTREAM) => {
+                    if let Some(dns_server) = dns_server.as_mut() {
+                        dns_server.upstream_ready();
+                    }
+                }
+                Token(RESOLVER) => {
+                    if let Some((index, resolver)) = resolver.take() {
+                        let _ = poll.deregister(&resolver);
+                        opts.update_upstream_addrs(index, resolver.addresses());
+                    }
                 }
-                Token(UDP_LISTENER) => {
-                    udp_server.accept(&event, opts, &poll);
+                Token(SUBSCRIPTION) => {
+                    if let Some(subscription) = subscription.take() {
+                        let _ = poll.deregister(&subscription);
+                        match subscription.upstreams() {
+                            Ok(upstreams) => opts.update_subscription(upstreams),
+                            Err(err) => log::error!("fetch subscription failed:{}", err),
+                        }
+                    }
+                }
+                Token(PROBE) => {
+                    if let Some(stream) = probe.take() {
+                        let _ = poll.deregister(&stream);
+                        match (stream.take_error(), stream.peer_addr()) {
+                            (Ok(None), Ok(addr)) => {
+                                log::warn!("preferred trojan server {} is reachable again", addr);
+                                opts.switch_upstream(0);
+                            }
+                            _ => {
+                                log::info!("preferred trojan server is still unreachable");
+                            }
+                        }
+                    }
+                }
+                token if HealthChecker::is_check_token(token) => {
+                    health_checker.ready(token, opts, &poll);
                 }
                 Token(i) if i % 3 == 0 => {
-                    udp_server.ready(&event, opts, &poll, &mut udp_cache);
+                    if let Some(udp_server) = udp_server.as_mut() {
+                        udp_server.ready(&event, opts, &poll, &mut udp_cache);
+                    }
                 }
                 _ => {
-                    tcp_server.ready(&event, &poll);
+                    tcp_server.ready(&event, opts, &poll);
                 }
             }
         }
+        if let Some(tun) = tun.as_mut() {
+            tun.poll(opts, &poll, &mut tcp_server);
+        }
+        if tcp_server.is_racing() {
+            tcp_server.check_racing(opts, &poll);
+        }
+        if let Some(udp_server) = udp_server.as_mut() {
+            if udp_server.is_racing() {
+                udp_server.check_racing(opts, &poll);
+            }
+        }
         let now = Instant::now();
+        watchdog.check(now);
         if now - last_check_time > check_duration {
-            udp_cache.check_timeout(now - opts.idle_duration);
+            udp_cache.check_timeout(now - opts.udp_duration);
+            if let Some(udp_server) = udp_server.as_mut() {
+                udp_server.check_timeout(now - opts.udp_duration, &poll);
+                udp_server.check_keepalive(now, opts, &poll);
+            }
+            if let Some(plugins) = plugins.as_mut() {
+                plugins.check(now, opts);
+            }
+            for upstream in opts.upstreams.iter_mut() {
+                upstream.hop(now, hop_duration);
+            }
+            health_checker.check(now, opts, &poll);
+            if stop_time.is_none() {
+                tcp_server.check_pool(now, opts, &poll);
+                tcp_server.check_budget(now, &mut budget, opts, &poll);
+            }
+            tcp_server.check_timeout(now, opts, &poll);
+            if upgrade::check(&mut upgrade, now) {
+                if let Some(udp_server) = udp_server.as_mut() {
+                    udp_server.hand_over(&poll);
+                }
+                sys::stop();
+            }
             last_check_time = now;
         }
+        if opts.route_check_duration.as_secs() > 0 && now - last_route_check_time > opts.route_check_duration {
+            if opts.router.changed() {
+                opts.reload_router();
+            }
+            last_route_check_time = now;
+        }
+        if network_duration.as_secs() > 0 && now - last_network_check_time > network_duration {
+            // the connections to the servers are made again over the new network, the pool is filled on the next check
+            if network_watch.check(opts) {
+                log::warn!("network changed, reconnecting to trojan servers");
+                for upstream in opts.upstreams.iter_mut() {
+                    upstream.network_changed();
+                }
+                tcp_server.network_changed(opts, &poll);
+                if let Some(udp_server) = udp_server.as_mut() {
+                    udp_server.network_changed(opts, &poll);
+                }
+            }
+            last_network_check_time = now;
+        }
+        if resolver.is_none() {
+            if let Some(index) = opts.upstreams.iter().position(|upstream| upstream.needs_resolve(now, resolve_duration)) {
+                let upstream = &mut opts.upstreams[index];
+                log::info!("resolving trojan server {} again", upstream.hostname);
+                upstream.start_resolve();
+                let new_resolver = EventedResolver::new(upstream.hostname.clone(), opts.relay_args().ip_strategy);
+                if let Err(err) = poll.register(&new_resolver, Token(RESOLVER), Ready::readable(), PollOpt::level()) {
+                    log::error!("register resolver failed:{}", err);
+                } else {
+                    resolver.replace((index, new_resolver));
+                }
+            }
+        }
+        if subscription.is_none() && now - last_subscription_time > subscription_duration {
+            if let Some(url) = opts.proxy_args().subscription.clone() {
+                last_subscription_time = now;
+                log::info!("fetching subscription {} again", url);
+                let new_subscription = EventedSubscription::new(url, opts.tcp_opts.marker, opts.relay_args().ip_strategy);
+                if let Err(err) = poll.register(&new_subscription, Token(SUBSCRIPTION), Ready::readable(), PollOpt::level()) {
+                    log::error!("register subscription failed:{}", err);
+                } else {
+                    subscription.replace(new_subscription);
+                }
+            }
+        }
+        if opts.upstream_index != 0 && now - last_probe_time > probe_duration {
+            last_probe_time = now;
+            if let Some(stream) = probe.take() {
+                // the last probe got no answer in time
+                let _ = poll.deregister(&stream);
+            }
+            if let Some(addr) = opts.upstreams[0].addr() {
+                log::info!("probing preferred trojan server {}", addr);
+                match opts.tcp_opts.connect(&addr) {
+                    Ok(stream) => {
+                        if let Err(err) = poll.register(&stream, Token(PROBE), Ready::writable(), PollOpt::edge()) {
+                            log::error!("register probe failed:{}", err);
+                        } else {
+                            probe.replace(stream);
+                        }
+                    }
+                    Err(err) => {
+                        log::warn!("probe trojan server {} failed:{}", addr, err);
+                    }
+                }
+            }
+        }
+    }
+    if let Some(path) = opts.proxy_args().state_file.as_ref() {
+        if let Err(err) = state::save(path.as_str(), opts) {
+            log::error!("save state file {} failed:{}", path, err);
+        }
     }
+    Ok(())
 }
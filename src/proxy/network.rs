@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+
+use crate::config::Opts;
+use crate::sys;
+
+// the local address the kernel picks to reach each trojan server, which changes with the network, e.g. from wifi to
+// ethernet or with another dhcp lease. connecting a udp socket tells it without sending anything
+pub struct NetworkWatch {
+    sources: HashMap<SocketAddr, Option<IpAddr>>,
+}
+
+impl NetworkWatch {
+    pub fn new() -> NetworkWatch {
+        NetworkWatch {
+            sources: HashMap::new(),
+        }
+    }
+
+    // true if the local address to a server changed since the last check, or the server got reachable or unreachable,
+    // servers seen for the first time are only learnt
+    pub fn check(&mut self, opts: &Opts) -> bool {
+        let mut sources = HashMap::new();
+        for addr in opts.upstreams.iter().filter(|upstream| upstream.is_available()).filter_map(|upstream| upstream.addr()) {
+            sources.insert(addr, source_ip(addr, opts.tcp_opts.marker));
+        }
+        let mut changed = false;
+        for (addr, source) in sources.iter() {
+            match self.sources.get(addr) {
+                Some(old) if old != source => {
+                    log::warn!("local address to trojan server {} changed from {} to {}", addr, display(old), display(source));
+                    changed = true;
+                }
+                _ => {}
+            }
+        }
+        self.sources = sources;
+        changed
+    }
+}
+
+fn display(ip: &Option<IpAddr>) -> String {
+    ip.map_or("none".to_string(), |ip| ip.to_string())
+}
+
+fn source_ip(addr: SocketAddr, marker: u8) -> Option<IpAddr> {
+    let bind_ip = if addr.is_ipv4() {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    } else {
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(bind_ip, 0)).ok()?;
+    // marked packets may take another route
+    sys::set_mark(&socket, marker).ok()?;
+    socket.connect(addr).ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
+
+// connections from an address the host no longer has are dead, it can not be bound any more
+pub fn is_gone(addr: SocketAddr) -> bool {
+    match UdpSocket::bind(SocketAddr::new(addr.ip(), 0)) {
+        Err(err) => err.kind() == ErrorKind::AddrNotAvailable,
+        Ok(_) => false,
+    }
+}
@@ -1,97 +1,135 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::{ErrorKind, Read, Write};
-use std::net::Shutdown;
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use bytes::BytesMut;
 use mio::{Event, Poll, PollOpt, Ready, Token};
-use mio::net::{TcpListener, TcpStream};
+use mio::net::TcpListener;
 use rustls::{ClientConfig, ClientSession, Session};
-use webpki::DNSName;
 
-use crate::config::Opts;
-use crate::proto::{CONNECT, TrojanRequest};
-use crate::proxy::{MAX_INDEX, MIN_INDEX};
+use crate::access_log;
+use crate::budget::MemoryBudget;
+use crate::config::{Opts, TransparentMode};
+use crate::happy_eyeballs::{ConnectResult, HappyEyeballs};
+use crate::net::{self, Stream};
+use crate::padding::{self, Padding};
+use crate::proto::{CONNECT, CONNECT_PADDED, Sock5Address, TrojanRequest};
+use crate::proxy::{next_index, TCP_LISTENER};
+use crate::proxy::direct::{new_direct_stream, TcpDirect};
+use crate::proxy::inbound::{Handshake, HandshakeResult};
+use crate::proxy::network;
+use crate::proxy::tls::TlsConnect;
+use crate::route::Action;
 use crate::session::TcpSession;
+use crate::sniff;
 use crate::sys;
 
 pub struct TcpServer {
-    tcp_listener: TcpListener,
+    tcp_listeners: Vec<TcpListener>,
     conns: HashMap<usize, Connection>,
+    direct_conns: HashMap<usize, TcpDirect>,
     config: Arc<ClientConfig>,
-    hostname: DNSName,
-    next_id: usize,
+    racing: HashSet<usize>,
+    pool: HashMap<usize, PooledConnection>,
+    handshakes: HashMap<usize, Handshake>,
+    accept_paused: bool,
+}
+
+// handshaked connections waiting for new clients, the index is taken over by the connection using it
+struct PooledConnection {
+    upstream: usize,
+    conn: TlsConnect,
+    ready: bool,
 }
 
 struct Connection {
     index: usize,
     dst_addr: SocketAddr,
-    client: TcpStream,
+    upstream: usize,
+    connect_time: Instant,
+    connected_time: Option<Instant>,
+    last_active_time: Instant,
+    // of the last data read from or written to the server, keepalives included
+    server_active_time: Instant,
+    target: Sock5Address,
+    client: Box<dyn Stream>,
     client_session: TcpSession,
-    server: TcpStream,
+    server: Option<Box<dyn Stream>>,
+    connector: Option<HappyEyeballs>,
     server_session: ClientSession,
     client_readiness: Ready,
     server_readiness: Ready,
     closed: bool,
     closing: bool,
+    sniffing: bool,
     client_recv: usize,
     client_sent: usize,
+    // each side may finish sending before the other, which is told with a shutdown once its data is written
+    client_eof: bool,
+    server_eof: bool,
+    client_shutdown: bool,
+    server_shutdown: bool,
+    close_notified: bool,
+    // plain bytes written to the server session since it was last drained
+    server_pending: usize,
+    padding: Option<Padding>,
+    // the server failed the connection, which counts against it, a client going away meanwhile does not
+    server_failed: bool,
 }
 
 impl TcpServer {
-    pub fn new(tcp_listener: TcpListener, config: Arc<ClientConfig>, hostname: DNSName) -> TcpServer {
+    pub fn new(tcp_listeners: Vec<TcpListener>, config: Arc<ClientConfig>) -> TcpServer {
         TcpServer {
-            tcp_listener,
+            tcp_listeners,
             config,
-            hostname,
             conns: HashMap::new(),
-            next_id: MIN_INDEX,
+            direct_conns: HashMap::new(),
+            racing: HashSet::new(),
+            pool: HashMap::new(),
+            handshakes: HashMap::new(),
+            accept_paused: false,
         }
     }
 
 
-    pub fn accept(&mut self, _event: &Event, opts: &mut Opts, poll: &Poll) {
+    pub fn accept(&mut self, listener: usize, opts: &mut Opts, poll: &Poll) {
         loop {
-            match self.tcp_listener.accept() {
+            match self.tcp_listeners[listener].accept() {
                 Ok((client, src_addr)) => {
-                    if let Err(err) = sys::set_mark(&client, opts.marker) {
-                        log::error!("set mark failed:{}", err);
+                    let index = next_index();
+                    log::debug!("connection:{} accepted from:{}", index, src_addr);
+                    if let Err(err) = sys::set_mark(&client, opts.relay_args().marker) {
+                        log::error!("connection:{} set mark failed:{}", index, err);
                         continue;
                     } else if let Err(err) = client.set_nodelay(true) {
-                        log::error!("set nodelay failed:{}", err);
+                        log::error!("connection:{} set nodelay failed:{}", index, err);
+                        continue;
+                    } else if let Err(err) = opts.tcp_opts.apply(&client) {
+                        log::error!("connection:{} set tcp options failed:{}", index, err);
                         continue;
                     }
-                    match sys::get_oridst_addr(&client) {
-                        Ok(dst_addr) => {
-                            log::info!("got new connection from:{} to:{}", src_addr, dst_addr);
-                            match TcpStream::connect(opts.back_addr.as_ref().unwrap()) {
-                                Ok(server) => {
-                                    if let Err(err) = sys::set_mark(&server, opts.marker) {
-                                        log::error!("set mark failed:{}", err);
-                                        continue;
-                                    } else if let Err(err) = server.set_nodelay(true) {
-                                        log::error!("set nodelay:{}", err);
-                                        continue;
-                                    } else {
-                                        let session = ClientSession::new(&self.config, self.hostname.as_ref());
-                                        let mut conn = Connection::new(self.next_index(), dst_addr, session, client, server);
-                                        if conn.setup(opts, poll) {
-                                            self.conns.insert(conn.index(), conn);
-                                        } else {
-                                            conn.close_now(poll);
-                                        }
-                                    }
-                                }
-                                Err(err) => {
-                                    //FIXME should refresh dns now?
-                                    log::error!("connection to server failed:{}", err);
-                                    continue;
-                                }
+                    let mode = opts.proxy_args().transparent_mode;
+                    let dst_addr = match mode {
+                        TransparentMode::Socks5 | TransparentMode::Http | TransparentMode::Tun => {
+                            let mut handshake = Handshake::new(index, src_addr, client, mode);
+                            if handshake.setup(poll) {
+                                self.handshakes.insert(handshake.index(), handshake);
+                            } else {
+                                handshake.close_now(poll);
                             }
+                            continue;
+                        }
+                        TransparentMode::Redirect => sys::get_original_dst(&client),
+                        TransparentMode::Tproxy => sys::get_oridst_addr(&client),
+                    };
+                    match dst_addr {
+                        Ok(dst_addr) => {
+                            self.accept_client(index, Box::new(client), src_addr, dst_addr, &[], opts, poll);
                         }
                         Err(err) => {
-                            log::error!("get original destination address failed:{}", err);
+                            log::error!("connection:{} get original destination address failed:{}", index, err);
                             continue;
                         }
                     }
@@ -107,42 +145,428 @@ impl TcpServer {
         }
     }
 
-    pub fn ready(&mut self, event: &Event, poll: &Poll) {
+    // connections of tun mode, terminated by the stack of the device
+    pub fn accept_tun(&mut self, client: Box<dyn Stream>, src_addr: SocketAddr, dst_addr: SocketAddr, opts: &mut Opts, poll: &Poll) {
+        let index = next_index();
+        log::debug!("connection:{} accepted from tun device:{}", index, src_addr);
+        self.accept_client(index, client, src_addr, dst_addr, &[], opts, poll);
+    }
+
+    // new connections of tun mode wait as those in the backlog of the listeners do
+    pub fn accepting(&self) -> bool {
+        !self.accept_paused
+    }
+
+    // routes the client by its destination, payload is the data read from the client before
+    fn accept_client(&mut self, index: usize, client: Box<dyn Stream>, src_addr: SocketAddr, dst_addr: SocketAddr, payload: &[u8], opts: &mut Opts, poll: &Poll) {
+        log::info!("connection:{} got new connection from:{} to:{}", index, src_addr, dst_addr);
+        let domain = opts.fake_dns.lookup(&dst_addr.ip()).cloned();
+        if domain.is_none() && opts.fake_dns.is_fake(&dst_addr.ip()) {
+            log::error!("connection:{} fake ip {} not found, drop it", index, dst_addr.ip());
+            return;
+        }
+        let ip = if domain.is_none() { Some(dst_addr.ip()) } else { None };
+        let action = opts.route(domain.as_deref(), ip.as_ref(), Some(dst_addr.port()));
+        match action {
+            Action::Block => {
+                log::info!("connection:{} from:{} to:{} is blocked", index, src_addr, dst_addr);
+                // reset the connection so that clients give up immediately
+                let _ = client.set_linger(Some(Duration::new(0, 0)));
+            }
+            Action::Direct => {
+                if domain.is_some() {
+                    log::error!("connection:{} fake ip {} can't be connected directly, drop it", index, dst_addr.ip());
+                    return;
+                }
+                self.accept_direct(index, client, dst_addr, payload, opts, poll);
+            }
+            Action::Proxy | Action::Outbound(_) => {
+                let target = if let Some(domain) = domain {
+                    log::info!("connection:{} fake ip {} is mapped to {}", index, dst_addr.ip(), domain);
+                    Sock5Address::Domain(domain, dst_addr.port())
+                } else if let (53, Some(remote_dns)) = (dst_addr.port(), opts.remote_dns) {
+                    log::info!("connection:{} dns query to {} is redirected to {}", index, dst_addr, remote_dns);
+                    Sock5Address::Socket(remote_dns)
+                } else {
+                    Sock5Address::Socket(dst_addr)
+                };
+                self.accept_proxy(index, client, dst_addr, target, action, payload, opts, poll);
+            }
+        }
+    }
+
+    fn accept_proxy(&mut self, index: usize, client: Box<dyn Stream>, dst_addr: SocketAddr, target: Sock5Address, action: Action, payload: &[u8], opts: &mut Opts, poll: &Poll) {
+        let upstream = opts.outbound_upstream(action).unwrap_or_else(|| opts.select_upstream());
+        if opts.upstreams[upstream].is_backing_off(net::now()) {
+            log::debug!("connection:{} trojan server {} is unreachable, reject connection to {}", index, opts.upstreams[upstream].name(), dst_addr);
+            let _ = client.set_linger(Some(Duration::new(0, 0)));
+            return;
+        }
+        let pooled = self.pool.iter().find(|(_, pooled)| pooled.ready && pooled.upstream == upstream).map(|(index, _)| *index);
+        // the pooled server stream is registered again with the token of this connection in setup
+        let mut conn = if let Some(pooled) = pooled {
+            let (server, session) = self.pool.remove(&pooled).unwrap().conn.into_parts();
+            log::info!("connection:{} uses pooled connection:{} to server", index, pooled);
+            self.fill_pool(opts, poll);
+            Connection::new(index, dst_addr, upstream, target, session, client, None, Some(server))
+        } else {
+            let mut connector = HappyEyeballs::new(index, opts.upstreams[upstream].addrs().as_slice(), opts.attempt_duration, &opts.tcp_opts);
+            if let Some(tunnel) = opts.upstream_tunnel(upstream) {
+                connector.set_tunnel(tunnel);
+            }
+            let session = ClientSession::new(&self.config, opts.upstreams[upstream].dns_name());
+            Connection::new(index, dst_addr, upstream, target, session, client, Some(connector), None)
+        };
+        if conn.setup(opts, poll) {
+            opts.upstream_opened(upstream);
+            if !payload.is_empty() {
+                conn.send_client_data(payload, opts);
+            }
+            self.racing.insert(conn.index());
+            self.conns.insert(conn.index(), conn);
+        } else {
+            conn.close_now(poll);
+        }
+    }
+
+    fn accept_direct(&mut self, index: usize, client: Box<dyn Stream>, dst_addr: SocketAddr, payload: &[u8], opts: &mut Opts, poll: &Poll) {
+        let target = match new_direct_stream(&dst_addr, opts.relay_args().marker) {
+            Ok(target) => target,
+            Err(err) => {
+                log::warn!("connection:{} connect to {} directly failed:{}", index, dst_addr, err);
+                return;
+            }
+        };
+        if let Err(err) = opts.tcp_opts.apply(&target) {
+            log::error!("connection:{} set tcp options failed:{}", index, err);
+            return;
+        }
+        let mut conn = TcpDirect::new(index, dst_addr, client, target);
+        if conn.setup(poll) {
+            log::info!("connection:{} goes to {} directly", conn.index(), dst_addr);
+            if !payload.is_empty() {
+                conn.send_payload(payload);
+            }
+            self.direct_conns.insert(conn.index(), conn);
+        } else {
+            conn.close_now(poll);
+        }
+    }
+
+    fn handshake_ready(&mut self, index: usize, event: &Event, opts: &mut Opts, poll: &Poll) {
+        let result = self.handshakes.get_mut(&index).unwrap().ready(event, poll);
+        match result {
+            HandshakeResult::Pending => {}
+            HandshakeResult::Failed | HandshakeResult::Resolved(None) => {
+                self.handshakes.remove(&index).unwrap().close_now(poll);
+            }
+            HandshakeResult::Resolved(Some(dst_addr)) => {
+                let (client, payload) = self.handshakes.remove(&index).unwrap().into_parts(poll);
+                self.accept_direct(index, Box::new(client), dst_addr, payload.as_slice(), opts, poll);
+            }
+            HandshakeResult::Done(Sock5Address::Domain(domain, port)) => {
+                let src_addr = self.handshakes[&index].src_addr();
+                let action = opts.route(Some(domain.as_str()), None, Some(port));
+                match action {
+                    Action::Block => {
+                        log::info!("connection:{} from:{} to:{}:{} is blocked", index, src_addr, domain, port);
+                        self.handshakes.remove(&index).unwrap().close_now(poll);
+                    }
+                    Action::Direct => {
+                        log::info!("connection:{} got new connection from:{} to:{}:{}, resolving", index, src_addr, domain, port);
+                        let handshake = self.handshakes.get_mut(&index).unwrap();
+                        if !handshake.resolve(domain, port, opts.relay_args().ip_strategy, poll) {
+                            self.handshakes.remove(&index).unwrap().close_now(poll);
+                        }
+                    }
+                    Action::Proxy | Action::Outbound(_) => {
+                        log::info!("connection:{} got new connection from:{} to:{}:{}", index, src_addr, domain, port);
+                        let (client, payload) = self.handshakes.remove(&index).unwrap().into_parts(poll);
+                        let dst_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
+                        self.accept_proxy(index, Box::new(client), dst_addr, Sock5Address::Domain(domain, port), action, payload.as_slice(), opts, poll);
+                    }
+                }
+            }
+            HandshakeResult::Done(Sock5Address::Socket(dst_addr)) => {
+                let src_addr = self.handshakes[&index].src_addr();
+                let (client, payload) = self.handshakes.remove(&index).unwrap().into_parts(poll);
+                self.accept_client(index, Box::new(client), src_addr, dst_addr, payload.as_slice(), opts, poll);
+            }
+            HandshakeResult::Done(Sock5Address::None) => unreachable!(),
+        }
+    }
+
+    pub fn ready(&mut self, event: &Event, opts: &mut Opts, poll: &Poll) {
         let index = Connection::token2index(event.token());
+        if self.handshakes.contains_key(&index) {
+            self.handshake_ready(index, event, opts, poll);
+            return;
+        }
+        if self.pool.contains_key(&index) {
+            self.pool_ready(index, opts, poll);
+            return;
+        }
+        if let Some(conn) = self.direct_conns.get_mut(&index) {
+            conn.ready(event, poll, opts.pending_limit);
+            if conn.closed() {
+                self.direct_conns.remove(&index);
+            }
+            return;
+        }
         if let Some(conn) = self.conns.get_mut(&index) {
-            conn.ready(event, poll);
+            conn.ready(event, opts, poll);
             if !conn.closed() {
+                if conn.is_racing() {
+                    self.racing.insert(index);
+                }
                 return;
             }
+            conn.report_upstream(opts);
         }
         self.conns.remove(&index);
     }
 
-    pub fn next_index(&mut self) -> usize {
-        let index = self.next_id;
-        self.next_id += 1;
-        if self.next_id >= MAX_INDEX {
-            self.next_id = MIN_INDEX;
+    // no more clients once stopping, the existing ones are drained
+    pub fn stop_accept(&mut self, poll: &Poll) {
+        if self.accept_paused {
+            return;
+        }
+        self.accept_paused = true;
+        for tcp_listener in self.tcp_listeners.iter() {
+            if let Err(err) = poll.deregister(tcp_listener) {
+                log::error!("deregister tcp listener failed:{}", err);
+            }
+        }
+    }
+
+    // the clients waiting in the backlog are reported once registered again
+    fn resume_accept(&mut self, poll: &Poll) {
+        self.accept_paused = false;
+        for (i, tcp_listener) in self.tcp_listeners.iter().enumerate() {
+            if let Err(err) = poll.register(tcp_listener, Token(TCP_LISTENER + i), Ready::readable(), PollOpt::edge()) {
+                log::error!("register tcp listener failed:{}", err);
+            }
+        }
+    }
+
+    // not called once stopping, so that accepting is not resumed
+    pub fn check_budget(&mut self, now: Instant, budget: &mut MemoryBudget, opts: &mut Opts, poll: &Poll) {
+        let queued = self.conns.values().map(|conn| conn.queued()).sum::<usize>()
+            + self.direct_conns.values().map(|conn| conn.queued()).sum::<usize>();
+        let shedding = budget.check(queued, opts);
+        if shedding && !self.accept_paused {
+            log::warn!("pause accepting new connections");
+            self.stop_accept(poll);
+        } else if !shedding && self.accept_paused {
+            log::warn!("resume accepting new connections");
+            self.resume_accept(poll);
+        }
+        let conns = self.conns.values().map(|conn| (conn.queued(), conn.last_active_time, conn.index()))
+            .chain(self.direct_conns.values().map(|conn| (conn.queued(), conn.last_active_time(), conn.index())))
+            .collect();
+        for index in budget.victims(conns, now, opts) {
+            if let Some(mut conn) = self.conns.remove(&index) {
+                log::warn!("connection:{} queues {} bytes while over the memory budget, close now", index, conn.queued());
+                conn.close_now(poll);
+                opts.upstream_closed(conn.upstream, false);
+            } else if let Some(mut conn) = self.direct_conns.remove(&index) {
+                log::warn!("connection:{} queues {} bytes while over the memory budget, close now", index, conn.queued());
+                conn.close_now(poll);
+            }
+        }
+    }
+
+    pub fn listeners(&self) -> &[TcpListener] {
+        self.tcp_listeners.as_slice()
+    }
+
+    pub fn conn_count(&self) -> usize {
+        self.conns.len() + self.direct_conns.len() + self.handshakes.len()
+    }
+
+    pub fn is_racing(&self) -> bool {
+        !self.racing.is_empty()
+    }
+
+    pub fn check_timeout(&mut self, now: Instant, opts: &mut Opts, poll: &Poll) {
+        let expired: Vec<usize> = self.handshakes.iter()
+            .filter(|(_, handshake)| handshake.timed_out(now, opts.handshake_duration))
+            .map(|(index, _)| *index)
+            .collect();
+        for index in expired {
+            log::warn!("connection:{} handshake timeout, close now", index);
+            self.handshakes.remove(&index).unwrap().close_now(poll);
+        }
+        let keepalive = Duration::new(opts.proxy_args().tunnel_keepalive, 0);
+        let mut list = Vec::new();
+        for (index, conn) in &mut self.conns {
+            if keepalive.as_secs() > 0 {
+                conn.check_keepalive(now, keepalive);
+            }
+            // only the server is waited for, to connect or to answer the handshake
+            if conn.timeout(now, opts) {
+                log::warn!("connection:{} timeout, close now", index);
+                conn.server_failed = true;
+                conn.close_now(poll);
+                list.push(*index);
+            }
+        }
+        for index in list {
+            if let Some(conn) = self.conns.remove(&index) {
+                conn.report_upstream(opts);
+            }
+        }
+    }
+
+    pub fn check_racing(&mut self, opts: &mut Opts, poll: &Poll) {
+        let now = net::now();
+        let conns = &mut self.conns;
+        self.racing.retain(|index| {
+            if let Some(conn) = conns.get_mut(index) {
+                conn.check_racing(now, poll);
+                if conn.closed() {
+                    conn.report_upstream(opts);
+                    conns.remove(index);
+                    false
+                } else {
+                    conn.is_racing()
+                }
+            } else {
+                false
+            }
+        });
+    }
+
+    fn pool_ready(&mut self, index: usize, opts: &mut Opts, poll: &Poll) {
+        let pooled = self.pool.get_mut(&index).unwrap();
+        let result = if pooled.ready {
+            pooled.conn.check_idle().map(|_| true)
+        } else {
+            pooled.conn.handshake()
+        };
+        match result {
+            Ok(true) if !pooled.ready => {
+                pooled.ready = true;
+                log::debug!("pooled connection:{} is ready", index);
+                if let Err(err) = poll.reregister(pooled.conn.stream(), Token(index * 3 + 2), Ready::readable(), PollOpt::edge()) {
+                    log::error!("pooled connection:{} reregister failed:{}", index, err);
+                    self.pool.remove(&index);
+                }
+            }
+            Ok(_) => {}
+            Err(err) => {
+                log::warn!("pooled connection:{} failed:{}", index, err);
+                let _ = poll.deregister(pooled.conn.stream());
+                if !pooled.ready {
+                    opts.upstream_failed(pooled.upstream);
+                }
+                self.pool.remove(&index);
+            }
+        }
+    }
+
+    // drops idle connections the server may have given up, and makes new ones to the current server
+    pub fn check_pool(&mut self, now: Instant, opts: &mut Opts, poll: &Poll) {
+        let idle_duration = Duration::new(opts.proxy_args().pool_idle_time, 0);
+        let handshake_duration = opts.connect_duration + opts.handshake_duration;
+        let expired: Vec<usize> = self.pool.iter()
+            .filter(|(_, pooled)| {
+                let elapsed = now - pooled.conn.start_time();
+                elapsed > idle_duration || (!pooled.ready && elapsed > handshake_duration)
+            })
+            .map(|(index, _)| *index)
+            .collect();
+        for index in expired {
+            let pooled = self.pool.remove(&index).unwrap();
+            let _ = poll.deregister(pooled.conn.stream());
+            log::debug!("pooled connection:{} expired", index);
+        }
+        self.fill_pool(opts, poll);
+    }
+
+    // pooled connections are made again over the new network, and those relaying from a local address which is gone
+    // are closed, so that their clients connect again at once instead of waiting for the timeout
+    pub fn network_changed(&mut self, opts: &mut Opts, poll: &Poll) {
+        for (index, pooled) in self.pool.drain() {
+            let _ = poll.deregister(pooled.conn.stream());
+            log::debug!("pooled connection:{} dropped", index);
+        }
+        let gone: Vec<usize> = self.conns.iter()
+            .filter(|(_, conn)| conn.server.as_ref().and_then(|server| server.local_addr().ok()).map_or(false, network::is_gone))
+            .map(|(index, _)| *index)
+            .collect();
+        for index in gone {
+            let mut conn = self.conns.remove(&index).unwrap();
+            log::warn!("connection:{} local address is gone, close now", index);
+            conn.close_now(poll);
+            opts.upstream_closed(conn.upstream, false);
+        }
+    }
+
+    fn fill_pool(&mut self, opts: &mut Opts, poll: &Poll) {
+        let upstream = opts.upstream_index;
+        let count = self.pool.values().filter(|pooled| pooled.upstream == upstream).count();
+        for _ in count..opts.proxy_args().pool_size {
+            let addr = match opts.upstreams[upstream].addr() {
+                Some(addr) => addr,
+                None => return,
+            };
+            let conn = match TlsConnect::new(&addr, &opts.tcp_opts, &self.config, opts.upstreams[upstream].dns_name(), opts.upstream_tunnel(upstream)) {
+                Ok(conn) => conn,
+                Err(err) => {
+                    log::warn!("connect to server {} for pool failed:{}", addr, err);
+                    return;
+                }
+            };
+            let index = next_index();
+            if let Err(err) = conn.stream().set_nodelay(true) {
+                log::error!("pooled connection:{} set nodelay failed:{}", index, err);
+                return;
+            } else if let Err(err) = poll.register(conn.stream(), Token(index * 3 + 2), Ready::readable() | Ready::writable(), PollOpt::edge()) {
+                log::error!("pooled connection:{} register failed:{}", index, err);
+                return;
+            }
+            self.pool.insert(index, PooledConnection {
+                upstream,
+                conn,
+                ready: false,
+            });
         }
-        index
     }
 }
 
 impl Connection {
-    fn new(index: usize, dst_addr: SocketAddr, session: ClientSession, client: TcpStream, server: TcpStream) -> Connection {
+    fn new(index: usize, dst_addr: SocketAddr, upstream: usize, target: Sock5Address, session: ClientSession, client: Box<dyn Stream>,
+           connector: Option<HappyEyeballs>, server: Option<Box<dyn Stream>>) -> Connection {
         Connection {
             index,
             dst_addr,
+            upstream,
+            connect_time: net::now(),
+            connected_time: None,
+            last_active_time: net::now(),
+            server_active_time: net::now(),
+            target,
             client,
             server,
+            connector,
             server_session: session,
             client_readiness: Ready::readable(),
             server_readiness: Ready::readable() | Ready::writable(),
             closed: false,
             closing: false,
-            client_session: TcpSession::new(),
+            sniffing: false,
+            client_session: TcpSession::new(index),
             client_recv: 0,
             client_sent: 0,
+            client_eof: false,
+            server_eof: false,
+            client_shutdown: false,
+            server_shutdown: false,
+            close_notified: false,
+            server_pending: 0,
+            padding: None,
+            server_failed: false,
         }
     }
 
@@ -150,23 +574,153 @@ impl Connection {
         self.closed
     }
 
+    // bytes waiting to be written to either side
+    fn queued(&self) -> usize {
+        self.server_pending + self.client_session.pending()
+    }
+
+    // a client going away before the server answered tells nothing about the server
+    fn report_upstream(&self, opts: &mut Opts) {
+        if self.server_failed {
+            opts.upstream_closed(self.upstream, true);
+        } else if self.server_session.is_handshaking() {
+            opts.upstream_abandoned(self.upstream);
+        } else {
+            opts.upstream_closed(self.upstream, false);
+        }
+    }
+
+    fn timeout(&self, now: Instant, opts: &Opts) -> bool {
+        if let Some(connector) = self.connector.as_ref() {
+            connector.timed_out(now, opts.connect_duration)
+        } else if let Some(connected_time) = self.connected_time {
+            // the handshake goes on only after the client sends something
+            self.server_session.is_handshaking() && self.client_sent > 0 && now - connected_time > opts.handshake_duration
+        } else {
+            false
+        }
+    }
+
+    // an empty frame on a tunnel quiet for the interval, so that nat mappings and firewalls along the path do not drop
+    // it, the server answers it
+    fn check_keepalive(&mut self, now: Instant, interval: Duration) {
+        if self.closing || self.sniffing || self.client_eof || self.server.is_none() || self.server_session.is_handshaking()
+            || now - self.server_active_time < interval {
+            return;
+        }
+        if let Some(padding) = self.padding.as_mut() {
+            log::debug!("connection:{} send keepalive", self.index);
+            if let Err(err) = padding.write_keepalive(&mut self.server_session) {
+                log::warn!("connection:{} write keepalive failed:{}", self.index, err);
+                self.closing = true;
+                return;
+            }
+            self.server_active_time = now;
+            self.try_send_server();
+        }
+    }
+
     fn setup(&mut self, opts: &mut Opts, poll: &Poll) -> bool {
-        let mut request = BytesMut::new();
-        TrojanRequest::generate(&mut request, CONNECT, &self.dst_addr, opts);
-        if let Err(err) = self.server_session.write_all(request.as_ref()) {
-            log::warn!("connection:{} write handshake to server session failed:{}", self.index(), err);
+        let token = self.server_token();
+        if opts.proxy_args().padding {
+            self.padding = Some(Padding::new());
+        }
+        if let Sock5Address::Socket(addr) = &self.target {
+            // the request is sent with the first client data after sniffing the domain
+            self.sniffing = opts.proxy_args().sniff && (addr.port() == 80 || addr.port() == 443);
+        }
+        if !self.sniffing && !self.send_request(opts) {
             false
-        } else if let Err(err) = poll.register(&self.client, self.client_token(), self.client_readiness, PollOpt::edge()) {
+        } else if let Err(err) = poll.register(&*self.client, self.client_token(), self.client_readiness, PollOpt::edge()) {
             log::warn!("connection:{} register client failed:{}", self.index(), err);
             false
-        } else if let Err(err) = poll.register(&self.server, self.server_token(), self.server_readiness, PollOpt::level()) {
-            log::warn!("connection:{} register server failed:{}", self.index(), err);
+        } else if let Some(server) = self.server.as_ref() {
+            // pooled connections are connected and handshaked already
+            self.connected_time.replace(net::now());
+            if let Err(err) = poll.reregister(&**server, token, self.server_readiness, PollOpt::level()) {
+                log::warn!("connection:{} register server failed:{}", self.index(), err);
+                false
+            } else {
+                self.try_send_server();
+                true
+            }
+        } else if !self.connector.as_mut().unwrap().connect(poll, token) {
+            log::warn!("connection:{} connect to server failed", self.index());
+            opts.upstream_failed(self.upstream);
             false
         } else {
             true
         }
     }
 
+    fn send_request(&mut self, opts: &Opts) -> bool {
+        let mut request = BytesMut::new();
+        if self.padding.is_some() {
+            TrojanRequest::generate(&mut request, CONNECT_PADDED, &self.target, opts.upstreams[self.upstream].pass());
+            padding::write_padding(&mut request);
+        } else {
+            TrojanRequest::generate(&mut request, CONNECT, &self.target, opts.upstreams[self.upstream].pass());
+        }
+        if let Err(err) = self.server_session.write_all(request.as_ref()) {
+            log::warn!("connection:{} write handshake to server session failed:{}", self.index(), err);
+            false
+        } else {
+            true
+        }
+    }
+
+    fn is_racing(&self) -> bool {
+        self.connector.as_ref().map_or(false, |connector| connector.is_racing())
+    }
+
+    fn check_racing(&mut self, now: Instant, poll: &Poll) {
+        let token = self.server_token();
+        if let Some(connector) = self.connector.as_mut() {
+            if !connector.check_timeout(now, poll, token) {
+                log::warn!("connection:{} connect to server failed", self.index());
+                self.server_failed = true;
+                self.closing = true;
+            }
+        }
+        if self.closing {
+            self.close_now(poll);
+        }
+    }
+
+    fn try_connect_server(&mut self, opts: &mut Opts, poll: &Poll) {
+        if self.closing {
+            return;
+        }
+        let token = self.server_token();
+        match self.connector.as_mut().unwrap().ready(poll, token) {
+            ConnectResult::Connected(server, addr) => {
+                opts.upstream_connected(self.upstream, net::now() - self.connect_time);
+                log::info!("connection:{} connected to server {}", self.index(), addr);
+                self.connector.take();
+                self.connected_time.replace(net::now());
+                if let Err(err) = server.set_nodelay(true) {
+                    log::error!("connection:{} set nodelay failed:{}", self.index(), err);
+                    self.closing = true;
+                    return;
+                } else if let Err(err) = poll.reregister(&*server, token, self.server_readiness, PollOpt::level()) {
+                    log::warn!("connection:{} register server failed:{}", self.index(), err);
+                    self.closing = true;
+                    return;
+                }
+                self.server.replace(server);
+                self.try_send_server();
+            }
+            ConnectResult::Pending => {
+                log::debug!("connection:{} still connecting to server", self.index());
+            }
+            ConnectResult::Failed => {
+                log::warn!("connection:{} connect to server failed", self.index());
+                self.server_failed = true;
+                self.closing = true;
+            }
+        }
+    }
+
     fn index(&self) -> usize {
         self.index
     }
@@ -175,11 +729,12 @@ impl Connection {
         token.0 / 3
     }
 
-    fn ready(&mut self, event: &Event, poll: &Poll) {
+    fn ready(&mut self, event: &Event, opts: &mut Opts, poll: &Poll) {
+        self.last_active_time = net::now();
         match event.token().0 % 3 {
             1 => {
                 if event.readiness().is_readable() {
-                    self.try_read_client();
+                    self.try_read_client(opts);
                 }
 
                 if event.readiness().is_writable() {
@@ -187,12 +742,16 @@ impl Connection {
                 }
             }
             2 => {
-                if event.readiness().is_readable() {
-                    self.try_read_server();
-                }
+                if self.server.is_none() {
+                    self.try_connect_server(opts, poll);
+                } else {
+                    if event.readiness().is_readable() {
+                        self.try_read_server();
+                    }
 
-                if event.readiness().is_writable() {
-                    self.try_send_server();
+                    if event.readiness().is_writable() {
+                        self.try_send_server();
+                    }
                 }
             }
             _ => {
@@ -202,27 +761,97 @@ impl Connection {
             }
         }
 
-
-        self.reregister(poll);
+        self.check_shutdown();
+        self.reregister(poll, opts.pending_limit);
         if self.closing {
             self.close_now(poll);
         }
     }
 
+    // a side which has finished sending gets a shutdown of the other once the data for it is all written
+    fn check_shutdown(&mut self) {
+        if self.closing {
+            return;
+        }
+        // close notify is only sent after the handshake, or it would be mixed into it
+        if self.client_eof && !self.server_shutdown && self.server.is_some() && !self.server_session.is_handshaking() {
+            if !self.close_notified {
+                self.close_notified = true;
+                self.server_session.send_close_notify();
+                self.try_send_server();
+            }
+            if !self.closing && !self.server_session.wants_write() {
+                log::debug!("connection:{} shutdown server write", self.index());
+                if let Err(err) = self.server.as_ref().unwrap().shutdown(Shutdown::Write) {
+                    log::warn!("connection:{} shutdown server failed:{}", self.index(), err);
+                    self.closing = true;
+                    return;
+                }
+                self.server_shutdown = true;
+            }
+        }
+        if self.server_eof && !self.client_shutdown && !self.client_session.wants_write() {
+            log::debug!("connection:{} shutdown client write", self.index());
+            if let Err(err) = self.client.shutdown(Shutdown::Write) {
+                log::warn!("connection:{} shutdown client failed:{}", self.index(), err);
+                self.closing = true;
+                return;
+            }
+            self.client_shutdown = true;
+        }
+        if self.client_shutdown && self.server_shutdown {
+            log::info!("connection:{} both sides finished", self.index());
+            self.closing = true;
+        }
+    }
+
     fn close_now(&mut self, poll: &Poll) {
-        let _ = poll.deregister(&self.client);
-        let _ = poll.deregister(&self.server);
-        let _ = self.server.shutdown(Shutdown::Both);
+        // the sniffed domain is more telling than the address the client connected to
+        let target: &dyn std::fmt::Display = match self.target {
+            Sock5Address::None => &self.dst_addr,
+            _ => &self.target,
+        };
+        access_log::write(&access_log::Entry {
+            conn_id: self.index,
+            remote_addr: self.client.peer_addr().ok(),
+            user: None,
+            target,
+            target_addr: Some(self.dst_addr),
+            protocol: "tcp",
+            bytes_sent: self.client_recv,
+            bytes_received: self.client_sent,
+            start_time: self.connect_time,
+        });
+        let _ = poll.deregister(&*self.client);
+        if let Some(mut connector) = self.connector.take() {
+            connector.close(poll);
+        }
+        if let Some(server) = self.server.as_ref() {
+            let _ = poll.deregister(&**server);
+            let _ = server.shutdown(Shutdown::Both);
+        }
         let _ = self.client.shutdown(Shutdown::Both);
         self.closed = true;
         log::warn!("connection:{} closed, target address {}, {} byte read, {} byte sent", self.index(), self.dst_addr, self.client_recv, self.client_sent);
     }
 
-    fn reregister(&mut self, poll: &Poll) {
+    // the side whose data is not taken fast enough by the other is not read until the queue drains
+    fn reregister(&mut self, poll: &Poll, max_pending: usize) {
         if self.closing {
             return;
         }
         let mut changed = false;
+        // edge triggered, reregistering reports the data which arrived while paused
+        let client_readable = self.server_pending < max_pending;
+        if client_readable != self.client_readiness.is_readable() {
+            if client_readable {
+                self.client_readiness.insert(Ready::readable());
+            } else {
+                log::debug!("connection:{} pause reading client", self.index());
+                self.client_readiness.remove(Ready::readable());
+            }
+            changed = true;
+        }
         if self.client_session.wants_write() && !self.client_readiness.is_writable() {
             self.client_readiness.insert(Ready::writable());
             changed = true;
@@ -233,7 +862,7 @@ impl Connection {
         }
 
         if changed {
-            if let Err(err) = poll.reregister(&self.client, self.client_token(), self.client_readiness, PollOpt::edge()) {
+            if let Err(err) = poll.reregister(&*self.client, self.client_token(), self.client_readiness, PollOpt::edge()) {
                 log::error!("connection:{} reregister client failed:{}", self.index(), err);
                 self.closing = true;
                 return;
@@ -241,6 +870,17 @@ impl Connection {
         }
 
         changed = false;
+        // the server is level triggered, and stays readable after eof
+        let server_readable = !self.server_eof && self.client_session.pending() < max_pending;
+        if server_readable != self.server_readiness.is_readable() {
+            if server_readable {
+                self.server_readiness.insert(Ready::readable());
+            } else {
+                log::debug!("connection:{} pause reading server", self.index());
+                self.server_readiness.remove(Ready::readable());
+            }
+            changed = true;
+        }
         if self.server_session.wants_write() && self.client_sent > 0 && !self.server_readiness.is_writable() {
             self.server_readiness.insert(Ready::writable());
             changed = true;
@@ -249,11 +889,10 @@ impl Connection {
             self.server_readiness.remove(Ready::writable());
             changed = true;
         }
-        if changed {
-            if let Err(err) = poll.reregister(&self.server, self.server_token(), self.server_readiness, PollOpt::level()) {
+        if changed && self.server.is_some() {
+            if let Err(err) = poll.reregister(&**self.server.as_ref().unwrap(), self.server_token(), self.server_readiness, PollOpt::level()) {
                 log::error!("connection:{} reregister server failed:{}", self.index(), err);
                 self.closing = true;
-                return;
             }
         }
     }
@@ -266,21 +905,53 @@ impl Connection {
         Token(self.index * 3 + 2)
     }
 
-    fn try_read_client(&mut self) {
-        if self.closing {
+    fn try_read_client(&mut self, opts: &mut Opts) {
+        if self.closing || self.client_eof {
             return;
         }
-        if let Err(err) = self.client_session.read_backend(&mut self.client) {
-            log::warn!("connection:{} read from client failed:{}", self.index(), err);
-            self.closing = true;
-            return;
+        match self.client_session.read_backend(&mut self.client) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                log::info!("connection:{} client finished sending", self.index());
+                self.client_eof = true;
+            }
+            Err(err) => {
+                log::warn!("connection:{} read from client failed:{}", self.index(), err);
+                self.closing = true;
+                return;
+            }
+            Ok(_) => {}
         }
         let data = self.client_session.read_all();
-        if data.is_empty() {
-            return;
+        if !data.is_empty() {
+            self.send_client_data(data.as_ref(), opts);
+        }
+        if self.client_eof && self.sniffing {
+            // nothing was sent, so there is no request to finish
+            log::info!("connection:{} client closed before sending anything", self.index());
+            self.closing = true;
         }
+    }
+
+    fn send_client_data(&mut self, data: &[u8], opts: &mut Opts) {
         self.client_sent += data.len();
-        if let Err(err) = self.server_session.write_all(data.as_ref()) {
+        if self.sniffing {
+            self.sniffing = false;
+            if let Some(domain) = sniff::sniff(data) {
+                log::info!("connection:{} sniffed domain {} for {}", self.index(), domain, self.dst_addr);
+                self.target = Sock5Address::Domain(domain, self.dst_addr.port());
+            }
+            if !self.send_request(opts) {
+                self.closing = true;
+                return;
+            }
+        }
+        self.server_pending += data.len();
+        self.server_active_time = net::now();
+        let result = match self.padding.as_mut() {
+            Some(padding) => padding.write(data, &mut self.server_session),
+            None => self.server_session.write_all(data),
+        };
+        if let Err(err) = result {
             log::warn!("connection:{} write to server failed:{}", self.index(), err);
             self.closing = true;
             return;
@@ -296,11 +967,9 @@ impl Connection {
             if let Err(err) = self.client_session.write_all(buffer) {
                 log::error!("connection:{} write to client session failed:{}", self.index(), err);
                 self.closing = true;
-                return;
             } else if let Err(err) = self.client_session.write_backend(&mut self.client) {
                 log::warn!("connection:{} write to client failed:{}", self.index(), err);
                 self.closing = true;
-                return;
             } else {
                 log::info!("connection:{} write to client done", self.index());
             }
@@ -311,7 +980,7 @@ impl Connection {
 
     fn do_send_client(&mut self, mut buffer: &[u8]) {
         loop {
-            if buffer.len() == 0 {
+            if buffer.is_empty() {
                 break;
             }
             match self.client.write(buffer) {
@@ -336,17 +1005,18 @@ impl Connection {
     }
 
     fn try_read_server(&mut self) {
-        if self.closing {
+        if self.closing || self.server_eof {
             return;
         }
+        let mut eof = false;
         loop {
-            match self.server_session.read_tls(&mut self.server) {
+            match self.server_session.read_tls(self.server.as_mut().unwrap()) {
                 Ok(size) => {
                     if size == 0 {
-                        log::warn!("connection:{} read from server failed with eof", self.index());
-                        self.closing = true;
-                        return;
+                        eof = true;
+                        break;
                     }
+                    self.server_active_time = net::now();
                     log::info!("connection:{} read {} bytes from server", self.index(), size);
                 }
                 Err(err) if err.kind() == ErrorKind::WouldBlock => {
@@ -355,6 +1025,7 @@ impl Connection {
                 }
                 Err(err) => {
                     log::warn!("connection:{} read from server failed:{}", self.index(), err);
+                    self.server_failed = true;
                     self.closing = true;
                     return;
                 }
@@ -363,32 +1034,58 @@ impl Connection {
 
         if let Err(err) = self.server_session.process_new_packets() {
             log::error!("connection:{} process new packets failed:{}", self.index(), err);
+            self.server_failed = true;
             self.closing = true;
             return;
         }
 
         let mut buffer = Vec::new();
-        if let Err(err) = self.server_session.read_to_end(&mut buffer) {
-            log::error!("connection:{} read from session failed:{}", self.index(), err);
-            self.closing = true;
-            return;
+        match self.server_session.read_to_end(&mut buffer) {
+            Ok(_) => {}
+            // close notify, the data before it is still relayed
+            Err(err) if err.kind() == ErrorKind::ConnectionAborted => eof = true,
+            Err(err) => {
+                log::error!("connection:{} read from session failed:{}", self.index(), err);
+                self.closing = true;
+                return;
+            }
         }
 
+        if let Some(padding) = self.padding.as_mut() {
+            buffer = padding.read(buffer.as_slice());
+            if padding.take_keepalive() {
+                log::debug!("connection:{} got keepalive answer", self.index());
+            }
+        }
         if !buffer.is_empty() {
             self.client_recv += buffer.len();
             self.try_send_client(buffer.as_slice());
         }
+        if eof {
+            if self.server_session.is_handshaking() {
+                log::warn!("connection:{} read from server failed with eof", self.index());
+                self.server_failed = true;
+                self.closing = true;
+            } else {
+                log::info!("connection:{} server finished sending", self.index());
+                self.server_eof = true;
+            }
+        }
     }
 
     fn try_send_server(&mut self) {
-        if self.closing {
+        if self.closing || self.server.is_none() {
             return;
         }
         loop {
             if !self.server_session.wants_write() {
+                // data written during the handshake is kept in the session until it is done
+                if !self.server_session.is_handshaking() {
+                    self.server_pending = 0;
+                }
                 return;
             }
-            match self.server_session.write_tls(&mut self.server) {
+            match self.server_session.write_tls(self.server.as_mut().unwrap()) {
                 Ok(size) => {
                     log::debug!("connection:{} write {} bytes to server", self.index(), size);
                 }
@@ -397,10 +1094,60 @@ impl Connection {
                 }
                 Err(err) => {
                     log::warn!("connection:{} write to server failed:{}", self.index(), err);
+                    self.server_failed = true;
                     self.closing = true;
                     return;
                 }
             }
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use rustls::ServerSession;
+
+    use crate::replay::{self, Machine, Replay};
+
+    use super::*;
+
+    impl Machine for TcpServer {
+        fn ready(&mut self, event: &Event, opts: &mut Opts, poll: &Poll) {
+            TcpServer::ready(self, event, opts, poll);
+        }
+
+        fn timeout(&mut self, now: Instant, opts: &mut Opts, poll: &Poll) {
+            self.check_racing(opts, poll);
+            self.check_timeout(now, opts, poll);
+        }
+    }
+
+    // the harness is the trojan server the client is relayed to
+    fn play(trace: &str, args: &[&str]) {
+        let (server_config, client_config) = replay::tls_configs();
+        let hostname = format!("trojan://secret@192.0.2.10:443?sni={}", replay::SERVER_NAME);
+        let mut argv = vec!["proxy", "-a", "127.0.0.1:1080", "-p", "secret", "-H", hostname.as_str(), "--transparent-mode", "socks5"];
+        argv.extend_from_slice(args);
+        let mut opts = replay::opts(argv.as_slice());
+        let mut replay = Replay::new();
+        replay.tls("server", Box::new(ServerSession::new(&server_config)));
+        let client = replay.accept("client", "127.0.0.1:50000".parse().unwrap(), "192.0.2.20:80".parse().unwrap());
+        let mut tcp_server = TcpServer::new(Vec::new(), client_config);
+        tcp_server.accept_tun(client, "127.0.0.1:50000".parse().unwrap(), "192.0.2.20:80".parse().unwrap(), &mut opts, replay.poll());
+        replay.play(trace, &mut tcp_server, &mut opts);
+    }
+
+    #[test]
+    fn test_half_close() {
+        play(include_str!("../../traces/proxy_half_close.trace"), &[]);
+    }
+
+    #[test]
+    fn test_backpressure() {
+        play(include_str!("../../traces/proxy_backpressure.trace"), &["--max-pending", "64"]);
+    }
+
+    #[test]
+    fn test_timers() {
+        play(include_str!("../../traces/proxy_connect_timeout.trace"), &[]);
+    }
+}
@@ -0,0 +1,104 @@
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use rustls::{ClientConfig, ClientSession, Session};
+use webpki::DNSNameRef;
+
+use crate::net::{self, Stream};
+use crate::sys::TcpOpts;
+use crate::upstream_proxy::Tunnel;
+
+// a tls connection to a trojan server driven until the handshake is done
+pub struct TlsConnect {
+    stream: Box<dyn Stream>,
+    session: ClientSession,
+    start_time: Instant,
+    connected: bool,
+    // the connect request to the upstream proxy addr belongs to, none once it is done
+    tunnel: Option<Tunnel>,
+}
+
+impl TlsConnect {
+    pub fn new(addr: &SocketAddr, tcp_opts: &TcpOpts, config: &Arc<ClientConfig>, dns_name: DNSNameRef, tunnel: Option<Tunnel>) -> Result<TlsConnect> {
+        let stream = net::connect(tcp_opts, addr)?;
+        Ok(TlsConnect {
+            stream,
+            session: ClientSession::new(config, dns_name),
+            start_time: net::now(),
+            connected: false,
+            tunnel,
+        })
+    }
+
+    pub fn stream(&self) -> &dyn Stream {
+        &*self.stream
+    }
+
+    pub fn start_time(&self) -> Instant {
+        self.start_time
+    }
+
+    pub fn into_parts(self) -> (Box<dyn Stream>, ClientSession) {
+        (self.stream, self.session)
+    }
+
+    // returns true when the tls handshake is done
+    pub fn handshake(&mut self) -> Result<bool> {
+        if !self.connected {
+            if let Some(err) = self.stream.take_error()? {
+                return Err(err);
+            }
+            match self.stream.peer_addr() {
+                Ok(_) => self.connected = true,
+                Err(err) if err.kind() == ErrorKind::NotConnected => return Ok(false),
+                Err(err) => return Err(err),
+            }
+        }
+        if let Some(tunnel) = self.tunnel.as_mut() {
+            if !tunnel.handshake(&mut self.stream)? {
+                return Ok(false);
+            }
+            self.tunnel = None;
+        }
+        loop {
+            while self.session.wants_write() {
+                match self.session.write_tls(&mut self.stream) {
+                    Ok(_) => {}
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                    Err(err) => return Err(err),
+                }
+            }
+            if !self.session.is_handshaking() {
+                return Ok(true);
+            }
+            match self.session.read_tls(&mut self.stream) {
+                Ok(0) => return Err(Error::from(ErrorKind::UnexpectedEof)),
+                Ok(_) => {
+                    if let Err(err) = self.session.process_new_packets() {
+                        return Err(Error::new(ErrorKind::InvalidData, err));
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    // reads what the server sends to an idle connection, e.g. session tickets, fails if it is closed
+    pub fn check_idle(&mut self) -> Result<()> {
+        loop {
+            match self.session.read_tls(&mut self.stream) {
+                Ok(0) => return Err(Error::from(ErrorKind::UnexpectedEof)),
+                Ok(_) => {
+                    if let Err(err) = self.session.process_new_packets() {
+                        return Err(Error::new(ErrorKind::InvalidData, err));
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
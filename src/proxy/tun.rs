@@ -0,0 +1,650 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use mio::{Evented, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{ChecksumCapabilities, Device, DeviceCapabilities, Medium, RxToken, TxToken};
+use smoltcp::socket::tcp;
+use smoltcp::wire::{HardwareAddress, Icmpv4Packet, Icmpv4Repr, Icmpv6Packet, Icmpv6Repr, IpAddress, IpCidr, IpListenEndpoint, IpProtocol,
+                   IpRepr, Ipv4Packet, Ipv6Packet, TcpPacket, UdpPacket, UdpRepr};
+
+use crate::config::Opts;
+use crate::net::Stream;
+use crate::proxy::tcp_server::TcpServer;
+use crate::proxy::udp_server::UdpServer;
+use crate::sys::TunDevice;
+
+// the default routes of the stack go through these, so that it takes packets to any address, they are never seen outside
+const GATEWAY_V4: Ipv4Addr = Ipv4Addr::new(169, 254, 0, 1);
+const GATEWAY_V6: Ipv6Addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+const SOCKET_BUFFER: usize = 65536;
+
+// terminates the tcp connections routed to the tun device with a userspace stack, and takes their udp packets
+pub struct Tun {
+    device: TunDevice,
+    stack: Rc<RefCell<Stack>>,
+    buffer: Vec<u8>,
+    // sockets taking the handshakes of new connections, by the time the first syn came
+    pending: HashMap<SocketHandle, Instant>,
+}
+
+struct Stack {
+    iface: Interface,
+    sockets: SocketSet<'static>,
+    queues: Queues,
+    // every socket by the client and the destination of its connection
+    flows: HashMap<(SocketAddr, SocketAddr), SocketHandle>,
+    streams: HashMap<SocketHandle, StreamState>,
+    // sockets whose streams are dropped, removed once closed or after the idle timeout
+    released: HashMap<SocketHandle, Instant>,
+}
+
+struct StreamState {
+    set_readiness: SetReadiness,
+    // the socket as last reported, the stream is told again only once it changes
+    last: (tcp::State, usize, usize),
+}
+
+// packets between the device and the stack, which is polled over these instead of the device
+struct Queues {
+    rx: VecDeque<Vec<u8>>,
+    tx: VecDeque<Vec<u8>>,
+    mtu: usize,
+}
+
+struct QueueRx(Vec<u8>);
+
+struct QueueTx<'a>(&'a mut VecDeque<Vec<u8>>);
+
+// a tcp connection of the stack, relayed like the clients accepted by listeners
+pub struct TunStream {
+    handle: SocketHandle,
+    peer_addr: SocketAddr,
+    // the destination the client connected to
+    local_addr: SocketAddr,
+    stack: Rc<RefCell<Stack>>,
+    registration: Registration,
+}
+
+// writes the udp replies of targets to the device
+pub struct TunWriter {
+    stack: Rc<RefCell<Stack>>,
+}
+
+impl Tun {
+    pub fn new(device: TunDevice, mtu: usize) -> Tun {
+        Tun {
+            device,
+            stack: Rc::new(RefCell::new(Stack::new(mtu))),
+            buffer: vec![0u8; mtu],
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn device(&self) -> &TunDevice {
+        &self.device
+    }
+
+    pub fn writer(&self) -> TunWriter {
+        TunWriter {
+            stack: self.stack.clone(),
+        }
+    }
+
+    // tcp packets are queued for the stack, polled once the events are handled, udp packets go to the udp server
+    pub fn ready(&mut self, opts: &mut Opts, poll: &Poll, tcp_server: &TcpServer, mut udp_server: Option<&mut UdpServer>) {
+        loop {
+            let size = match self.device.recv(self.buffer.as_mut_slice()) {
+                Ok(size) => size,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::error!("read from tun device failed:{}", err);
+                    break;
+                }
+            };
+            let (protocol, src_addr, dst_addr, payload) = match parse_packet(&self.buffer[..size]) {
+                Some(packet) => packet,
+                None => {
+                    log::trace!("tun device got a packet not relayed, drop it");
+                    continue;
+                }
+            };
+            match protocol {
+                IpProtocol::Tcp => {
+                    let packet = match TcpPacket::new_checked(payload) {
+                        Ok(packet) => packet,
+                        Err(_) => continue,
+                    };
+                    let src_addr = SocketAddr::new(src_addr, packet.src_port());
+                    let dst_addr = SocketAddr::new(dst_addr, packet.dst_port());
+                    if packet.syn() && !packet.ack() && !self.listen(src_addr, dst_addr, tcp_server) {
+                        continue;
+                    }
+                    self.stack.borrow_mut().queues.rx.push_back(self.buffer[..size].to_vec());
+                }
+                IpProtocol::Udp => {
+                    let packet = match UdpPacket::new_checked(payload) {
+                        Ok(packet) => packet,
+                        Err(_) => continue,
+                    };
+                    let src_addr = SocketAddr::new(src_addr, packet.src_port());
+                    let dst_addr = SocketAddr::new(dst_addr, packet.dst_port());
+                    match udp_server.as_mut() {
+                        Some(udp_server) => udp_server.accept_tun(src_addr, dst_addr, packet.payload(), opts, poll),
+                        None => log::debug!("udp packet from {} to {} is dropped with --no-udp", src_addr, dst_addr),
+                    }
+                }
+                IpProtocol::Icmp | IpProtocol::Icmpv6 => match echo_reply(src_addr, dst_addr, payload) {
+                    Some(reply) => self.stack.borrow_mut().queues.tx.push_back(reply),
+                    None => log::trace!("tun device got an {} packet other than echo request, drop it", protocol),
+                },
+                _ => {
+                    log::trace!("tun device got a packet of {} not relayed, drop it", protocol);
+                }
+            }
+        }
+    }
+
+    // a socket listening on the destination takes the handshake, new connections wait while the tcp server does not
+    // accept, their syn is dropped and sent again by the client
+    fn listen(&mut self, src_addr: SocketAddr, dst_addr: SocketAddr, tcp_server: &TcpServer) -> bool {
+        let mut stack = self.stack.borrow_mut();
+        if stack.flows.contains_key(&(src_addr, dst_addr)) {
+            return true;
+        }
+        if !tcp_server.accepting() {
+            log::debug!("tcp server is not accepting, drop syn from {} to {}", src_addr, dst_addr);
+            return false;
+        }
+        let mut socket = tcp::Socket::new(tcp::SocketBuffer::new(vec![0u8; SOCKET_BUFFER]), tcp::SocketBuffer::new(vec![0u8; SOCKET_BUFFER]));
+        socket.set_nagle_enabled(false);
+        if let Err(err) = socket.listen(IpListenEndpoint { addr: Some(dst_addr.ip().into()), port: dst_addr.port() }) {
+            log::error!("listen on {} for {} failed:{}", dst_addr, src_addr, err);
+            return false;
+        }
+        let handle = stack.sockets.add(socket);
+        stack.flows.insert((src_addr, dst_addr), handle);
+        self.pending.insert(handle, Instant::now());
+        true
+    }
+
+    pub fn poll(&mut self, opts: &mut Opts, poll: &Poll, tcp_server: &mut TcpServer) {
+        let now = Instant::now();
+        {
+            let stack = &mut *self.stack.borrow_mut();
+            stack.iface.poll(timestamp(), &mut stack.queues, &mut stack.sockets);
+        }
+        self.accept(now, opts, poll, tcp_server);
+        let mut stack = self.stack.borrow_mut();
+        stack.update_readiness();
+        stack.reap(now, opts.idle_duration);
+        while let Some(packet) = stack.queues.tx.pop_front() {
+            // lost like on any link, tcp sends it again
+            if let Err(err) = self.device.send(packet.as_slice()) {
+                log::debug!("write {} bytes to tun device failed:{}", packet.len(), err);
+            }
+        }
+    }
+
+    // the time before the stack has to be polled again for its timers
+    pub fn poll_delay(&mut self) -> Option<Duration> {
+        let stack = &mut *self.stack.borrow_mut();
+        stack.iface.poll_delay(timestamp(), &stack.sockets).map(|delay| Duration::from_micros(delay.total_micros()))
+    }
+
+    // connections done with the handshake go to the tcp server, those not done in the connect timeout are dropped
+    fn accept(&mut self, now: Instant, opts: &mut Opts, poll: &Poll, tcp_server: &mut TcpServer) {
+        let mut accepted = Vec::new();
+        {
+            let stack = &mut *self.stack.borrow_mut();
+            let connect_duration = opts.connect_duration;
+            self.pending.retain(|handle, start_time| {
+                let socket = stack.sockets.get_mut::<tcp::Socket>(*handle);
+                match socket.state() {
+                    tcp::State::Listen | tcp::State::SynReceived if now - *start_time <= connect_duration => true,
+                    tcp::State::Listen | tcp::State::SynReceived | tcp::State::Closed => {
+                        stack.remove(*handle);
+                        false
+                    }
+                    _ => {
+                        accepted.push(*handle);
+                        false
+                    }
+                }
+            });
+        }
+        for handle in accepted {
+            let (stream, src_addr, dst_addr) = {
+                let stack = &mut *self.stack.borrow_mut();
+                let socket = stack.sockets.get_mut::<tcp::Socket>(handle);
+                let (src_addr, dst_addr) = match (socket.remote_endpoint(), socket.local_endpoint()) {
+                    (Some(remote), Some(local)) => (SocketAddr::new(remote.addr.into(), remote.port), SocketAddr::new(local.addr.into(), local.port)),
+                    _ => {
+                        stack.remove(handle);
+                        continue;
+                    }
+                };
+                socket.set_timeout(Some(smoltcp::time::Duration::from_secs(opts.idle_duration.as_secs())));
+                let (registration, set_readiness) = Registration::new2();
+                stack.streams.insert(handle, StreamState {
+                    set_readiness,
+                    last: (tcp::State::Closed, 0, 0),
+                });
+                let stream = TunStream {
+                    handle,
+                    peer_addr: src_addr,
+                    local_addr: dst_addr,
+                    stack: self.stack.clone(),
+                    registration,
+                };
+                (stream, src_addr, dst_addr)
+            };
+            tcp_server.accept_tun(Box::new(stream), src_addr, dst_addr, opts, poll);
+        }
+    }
+}
+
+impl Stack {
+    fn new(mtu: usize) -> Stack {
+        let mut queues = Queues {
+            rx: VecDeque::new(),
+            tx: VecDeque::new(),
+            mtu,
+        };
+        let mut iface = Interface::new(Config::new(HardwareAddress::Ip), &mut queues, timestamp());
+        iface.update_ip_addrs(|addrs| {
+            let _ = addrs.push(IpCidr::new(IpAddress::Ipv4(GATEWAY_V4), 32));
+            let _ = addrs.push(IpCidr::new(IpAddress::Ipv6(GATEWAY_V6), 128));
+        });
+        let _ = iface.routes_mut().add_default_ipv4_route(GATEWAY_V4);
+        let _ = iface.routes_mut().add_default_ipv6_route(GATEWAY_V6);
+        iface.set_any_ip(true);
+        Stack {
+            iface,
+            sockets: SocketSet::new(Vec::new()),
+            queues,
+            flows: HashMap::new(),
+            streams: HashMap::new(),
+            released: HashMap::new(),
+        }
+    }
+
+    fn remove(&mut self, handle: SocketHandle) {
+        self.sockets.remove(handle);
+        self.flows.retain(|_, flow| *flow != handle);
+        self.streams.remove(&handle);
+        self.released.remove(&handle);
+    }
+
+    fn update_readiness(&mut self) {
+        let sockets = &mut self.sockets;
+        for (handle, state) in self.streams.iter_mut() {
+            let socket = sockets.get::<tcp::Socket>(*handle);
+            let last = (socket.state(), socket.recv_queue(), socket.send_queue());
+            if last == state.last {
+                continue;
+            }
+            state.last = last;
+            // eof and errors are reported by reading and writing
+            let mut readiness = Ready::empty();
+            if socket.can_recv() || !socket.may_recv() {
+                readiness.insert(Ready::readable());
+            }
+            if socket.can_send() || !socket.may_send() {
+                readiness.insert(Ready::writable());
+            }
+            if let Err(err) = state.set_readiness.set_readiness(readiness) {
+                log::error!("set readiness of tun connection failed:{}", err);
+            }
+        }
+    }
+
+    fn reap(&mut self, now: Instant, idle_duration: Duration) {
+        let closed: Vec<SocketHandle> = self.released.iter()
+            .filter(|(handle, release_time)| {
+                let socket = self.sockets.get::<tcp::Socket>(**handle);
+                !socket.is_open() || now - **release_time > idle_duration
+            })
+            .map(|(handle, _)| *handle)
+            .collect();
+        for handle in closed {
+            self.sockets.get_mut::<tcp::Socket>(handle).abort();
+            self.remove(handle);
+        }
+    }
+}
+
+impl Device for Queues {
+    type RxToken<'a> = QueueRx;
+    type TxToken<'a> = QueueTx<'a>;
+
+    fn receive(&mut self, _timestamp: smoltcp::time::Instant) -> Option<(QueueRx, QueueTx<'_>)> {
+        let packet = self.rx.pop_front()?;
+        Some((QueueRx(packet), QueueTx(&mut self.tx)))
+    }
+
+    fn transmit(&mut self, _timestamp: smoltcp::time::Instant) -> Option<QueueTx<'_>> {
+        Some(QueueTx(&mut self.tx))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.medium = Medium::Ip;
+        capabilities.max_transmission_unit = self.mtu;
+        capabilities
+    }
+}
+
+impl RxToken for QueueRx {
+    fn consume<R, F>(self, f: F) -> R where F: FnOnce(&[u8]) -> R {
+        f(self.0.as_slice())
+    }
+}
+
+impl TxToken for QueueTx<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R where F: FnOnce(&mut [u8]) -> R {
+        let mut packet = vec![0u8; len];
+        let result = f(packet.as_mut_slice());
+        self.0.push_back(packet);
+        result
+    }
+}
+
+impl TunStream {
+    fn with_socket<R, F: FnOnce(&mut tcp::Socket<'static>) -> R>(&self, f: F) -> R {
+        let mut stack = self.stack.borrow_mut();
+        f(stack.sockets.get_mut::<tcp::Socket>(self.handle))
+    }
+}
+
+impl Read for TunStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.with_socket(|socket| match socket.recv_slice(buf) {
+            Ok(0) if !buf.is_empty() => Err(Error::from(ErrorKind::WouldBlock)),
+            Ok(size) => Ok(size),
+            Err(tcp::RecvError::Finished) => Ok(0),
+            Err(tcp::RecvError::InvalidState) => Err(Error::from(ErrorKind::ConnectionReset)),
+        })
+    }
+}
+
+impl Write for TunStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.with_socket(|socket| match socket.send_slice(buf) {
+            Ok(0) if !buf.is_empty() => Err(Error::from(ErrorKind::WouldBlock)),
+            Ok(size) => Ok(size),
+            Err(tcp::SendError::InvalidState) => Err(Error::from(ErrorKind::BrokenPipe)),
+        })
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Evented for TunStream {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<()> {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<()> {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    #[allow(deprecated)]
+    fn deregister(&self, poll: &Poll) -> Result<()> {
+        self.registration.deregister(poll)
+    }
+}
+
+impl Stream for TunStream {
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        Ok(self.peer_addr)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    // the receive half is closed only by the client
+    fn shutdown(&self, _how: Shutdown) -> Result<()> {
+        self.with_socket(|socket| socket.close());
+        Ok(())
+    }
+
+    fn set_linger(&self, dur: Option<Duration>) -> Result<()> {
+        if dur == Some(Duration::new(0, 0)) {
+            self.with_socket(|socket| socket.abort());
+        }
+        Ok(())
+    }
+
+    // segments go out as the stack polls, there is no delay to turn off
+    fn set_nodelay(&self, _nodelay: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn take_error(&self) -> Result<Option<Error>> {
+        Ok(None)
+    }
+}
+
+// the data written is still sent before the fin, like a closed kernel socket
+impl Drop for TunStream {
+    fn drop(&mut self) {
+        let mut stack = self.stack.borrow_mut();
+        stack.sockets.get_mut::<tcp::Socket>(self.handle).close();
+        stack.streams.remove(&self.handle);
+        stack.released.insert(self.handle, Instant::now());
+    }
+}
+
+impl TunWriter {
+    // replies too large for the device are dropped, like the ones the kernel can not send
+    pub fn send_udp(&self, src_addr: SocketAddr, dst_addr: SocketAddr, payload: &[u8]) -> Result<()> {
+        let (src_ip, dst_ip) = match (src_addr.ip(), dst_addr.ip()) {
+            (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => (IpAddress::Ipv4(src_ip), IpAddress::Ipv4(dst_ip)),
+            (IpAddr::V6(src_ip), IpAddr::V4(dst_ip)) if src_ip.to_ipv4_mapped().is_some() => {
+                (IpAddress::Ipv4(src_ip.to_ipv4_mapped().unwrap()), IpAddress::Ipv4(dst_ip))
+            }
+            (IpAddr::V6(src_ip), IpAddr::V6(dst_ip)) => (IpAddress::Ipv6(src_ip), IpAddress::Ipv6(dst_ip)),
+            _ => return Err(Error::new(ErrorKind::InvalidInput, "address family of the reply does not match the client")),
+        };
+        let udp_repr = UdpRepr {
+            src_port: src_addr.port(),
+            dst_port: dst_addr.port(),
+        };
+        let ip_repr = IpRepr::new(src_ip, dst_ip, IpProtocol::Udp, udp_repr.header_len() + payload.len(), 64);
+        let mut stack = self.stack.borrow_mut();
+        if ip_repr.buffer_len() > stack.queues.mtu {
+            return Err(Error::new(ErrorKind::InvalidInput, "reply exceeds the mtu of the tun device"));
+        }
+        let mut packet = vec![0u8; ip_repr.buffer_len()];
+        let checksum = ChecksumCapabilities::default();
+        ip_repr.emit(packet.as_mut_slice(), &checksum);
+        let header_len = ip_repr.header_len();
+        udp_repr.emit(&mut UdpPacket::new_unchecked(&mut packet[header_len..]), &src_ip, &dst_ip, payload.len(),
+                      |buf| buf.copy_from_slice(payload), &checksum);
+        stack.queues.tx.push_back(packet);
+        Ok(())
+    }
+}
+
+// the protocol, addresses and payload of an ip packet, fragments and ipv6 extension headers are not taken
+fn parse_packet(packet: &[u8]) -> Option<(IpProtocol, IpAddr, IpAddr, &[u8])> {
+    match packet.first().map(|first| first >> 4) {
+        Some(4) => {
+            let packet = Ipv4Packet::new_checked(packet).ok()?;
+            if packet.more_frags() || packet.frag_offset() != 0 {
+                return None;
+            }
+            Some((packet.next_header(), IpAddr::V4(packet.src_addr()), IpAddr::V4(packet.dst_addr()), packet.payload()))
+        }
+        Some(6) => {
+            let packet = Ipv6Packet::new_checked(packet).ok()?;
+            Some((packet.next_header(), IpAddr::V6(packet.src_addr()), IpAddr::V6(packet.dst_addr()), packet.payload()))
+        }
+        _ => None,
+    }
+}
+
+// pings are answered by the proxy itself, icmp can not be relayed through the trojan server, and a device getting no
+// reply at all takes the proxy for down
+fn echo_reply(src_addr: IpAddr, dst_addr: IpAddr, payload: &[u8]) -> Option<Vec<u8>> {
+    let checksum = ChecksumCapabilities::default();
+    match (src_addr, dst_addr) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            let packet = Icmpv4Packet::new_checked(payload).ok()?;
+            let (ident, seq_no, data) = match Icmpv4Repr::parse(&packet, &checksum).ok()? {
+                Icmpv4Repr::EchoRequest { ident, seq_no, data } => (ident, seq_no, data),
+                _ => return None,
+            };
+            let icmp_repr = Icmpv4Repr::EchoReply { ident, seq_no, data };
+            let ip_repr = IpRepr::new(IpAddress::Ipv4(dst_ip), IpAddress::Ipv4(src_ip), IpProtocol::Icmp, icmp_repr.buffer_len(), 64);
+            let mut reply = vec![0u8; ip_repr.buffer_len()];
+            ip_repr.emit(reply.as_mut_slice(), &checksum);
+            let header_len = ip_repr.header_len();
+            icmp_repr.emit(&mut Icmpv4Packet::new_unchecked(&mut reply[header_len..]), &checksum);
+            Some(reply)
+        }
+        (IpAddr::V6(src_ip), IpAddr::V6(dst_ip)) => {
+            let packet = Icmpv6Packet::new_checked(payload).ok()?;
+            let (ident, seq_no, data) = match Icmpv6Repr::parse(&src_ip, &dst_ip, &packet, &checksum).ok()? {
+                Icmpv6Repr::EchoRequest { ident, seq_no, data } => (ident, seq_no, data),
+                _ => return None,
+            };
+            let icmp_repr = Icmpv6Repr::EchoReply { ident, seq_no, data };
+            let ip_repr = IpRepr::new(IpAddress::Ipv6(dst_ip), IpAddress::Ipv6(src_ip), IpProtocol::Icmpv6, icmp_repr.buffer_len(), 64);
+            let mut reply = vec![0u8; ip_repr.buffer_len()];
+            ip_repr.emit(reply.as_mut_slice(), &checksum);
+            let header_len = ip_repr.header_len();
+            icmp_repr.emit(&dst_ip, &src_ip, &mut Icmpv6Packet::new_unchecked(&mut reply[header_len..]), &checksum);
+            Some(reply)
+        }
+        _ => None,
+    }
+}
+
+fn timestamp() -> smoltcp::time::Instant {
+    smoltcp::time::Instant::now()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn writer(mtu: usize) -> TunWriter {
+        TunWriter {
+            stack: Rc::new(RefCell::new(Stack::new(mtu))),
+        }
+    }
+
+    #[test]
+    fn test_udp_reply() {
+        let writer = writer(1500);
+        let target: SocketAddr = "[::ffff:8.8.8.8]:53".parse().unwrap();
+        let client: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+        writer.send_udp(target, client, b"reply").unwrap();
+        let packet = writer.stack.borrow_mut().queues.tx.pop_front().unwrap();
+        let (protocol, src_ip, dst_ip, payload) = parse_packet(packet.as_slice()).unwrap();
+        assert_eq!(protocol, IpProtocol::Udp);
+        assert_eq!(src_ip, "8.8.8.8".parse::<IpAddr>().unwrap());
+        assert_eq!(dst_ip, client.ip());
+        let udp = UdpPacket::new_checked(payload).unwrap();
+        assert_eq!(udp.src_port(), 53);
+        assert_eq!(udp.dst_port(), 40000);
+        assert!(udp.verify_checksum(&src_ip.into(), &dst_ip.into()));
+        assert_eq!(udp.payload(), b"reply");
+
+        let client: SocketAddr = "[fd00::2]:40000".parse().unwrap();
+        assert!(writer.send_udp("8.8.8.8:53".parse().unwrap(), client, b"reply").is_err());
+        assert!(writer.send_udp("[2001:db8::1]:53".parse().unwrap(), client, &[0u8; 1500]).is_err());
+        writer.send_udp("[2001:db8::1]:53".parse().unwrap(), client, b"reply").unwrap();
+        let packet = writer.stack.borrow_mut().queues.tx.pop_front().unwrap();
+        let (_, src_ip, dst_ip, _) = parse_packet(packet.as_slice()).unwrap();
+        assert_eq!(src_ip, "2001:db8::1".parse::<IpAddr>().unwrap());
+        assert_eq!(dst_ip, client.ip());
+    }
+
+    #[test]
+    fn test_echo_reply() {
+        let checksum = ChecksumCapabilities::default();
+        let request = Icmpv4Repr::EchoRequest { ident: 7, seq_no: 3, data: b"ping" };
+        let mut payload = vec![0u8; request.buffer_len()];
+        request.emit(&mut Icmpv4Packet::new_unchecked(payload.as_mut_slice()), &checksum);
+        let client: IpAddr = "10.0.0.2".parse().unwrap();
+        let target: IpAddr = "1.1.1.1".parse().unwrap();
+        let reply = echo_reply(client, target, payload.as_slice()).unwrap();
+        let (protocol, src_ip, dst_ip, payload) = parse_packet(reply.as_slice()).unwrap();
+        assert_eq!(protocol, IpProtocol::Icmp);
+        assert_eq!((src_ip, dst_ip), (target, client));
+        let packet = Icmpv4Packet::new_checked(payload).unwrap();
+        assert_eq!(Icmpv4Repr::parse(&packet, &checksum).unwrap(), Icmpv4Repr::EchoReply { ident: 7, seq_no: 3, data: b"ping" });
+
+        let client: Ipv6Addr = "fd00::2".parse().unwrap();
+        let target: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let request = Icmpv6Repr::EchoRequest { ident: 7, seq_no: 3, data: b"ping" };
+        let mut payload = vec![0u8; request.buffer_len()];
+        request.emit(&client, &target, &mut Icmpv6Packet::new_unchecked(payload.as_mut_slice()), &checksum);
+        let reply = echo_reply(client.into(), target.into(), payload.as_slice()).unwrap();
+        let (protocol, src_ip, dst_ip, payload) = parse_packet(reply.as_slice()).unwrap();
+        assert_eq!(protocol, IpProtocol::Icmpv6);
+        assert_eq!((src_ip, dst_ip), (target.into(), client.into()));
+        let packet = Icmpv6Packet::new_checked(payload).unwrap();
+        assert_eq!(Icmpv6Repr::parse(&target, &client, &packet, &checksum).unwrap(), Icmpv6Repr::EchoReply { ident: 7, seq_no: 3, data: b"ping" });
+
+        let reply = Icmpv4Repr::EchoReply { ident: 7, seq_no: 3, data: b"ping" };
+        let mut payload = vec![0u8; reply.buffer_len()];
+        reply.emit(&mut Icmpv4Packet::new_unchecked(payload.as_mut_slice()), &checksum);
+        assert!(echo_reply("10.0.0.2".parse().unwrap(), "1.1.1.1".parse().unwrap(), payload.as_slice()).is_none());
+    }
+
+    #[test]
+    fn test_tcp_handshake() {
+        use smoltcp::wire::{IpEndpoint, TcpControl, TcpRepr, TcpSeqNumber};
+
+        let mut stack = Stack::new(1500);
+        let client = IpEndpoint::new(IpAddress::v4(10, 0, 0, 2), 40000);
+        let target = IpEndpoint::new(IpAddress::v4(1, 1, 1, 1), 443);
+        let mut socket = tcp::Socket::new(tcp::SocketBuffer::new(vec![0u8; 1024]), tcp::SocketBuffer::new(vec![0u8; 1024]));
+        socket.listen(IpListenEndpoint { addr: Some(target.addr), port: target.port }).unwrap();
+        let handle = stack.sockets.add(socket);
+
+        let syn = TcpRepr {
+            src_port: client.port,
+            dst_port: target.port,
+            control: TcpControl::Syn,
+            seq_number: TcpSeqNumber(1000),
+            ack_number: None,
+            window_len: 1024,
+            window_scale: None,
+            max_seg_size: Some(1460),
+            sack_permitted: false,
+            sack_ranges: [None, None, None],
+            timestamp: None,
+            payload: &[],
+        };
+        let ip_repr = IpRepr::new(client.addr, target.addr, IpProtocol::Tcp, syn.buffer_len(), 64);
+        let mut packet = vec![0u8; ip_repr.buffer_len()];
+        let checksum = ChecksumCapabilities::default();
+        ip_repr.emit(packet.as_mut_slice(), &checksum);
+        let header_len = ip_repr.header_len();
+        syn.emit(&mut TcpPacket::new_unchecked(&mut packet[header_len..]), &client.addr, &target.addr, &checksum);
+        stack.queues.rx.push_back(packet);
+        stack.iface.poll(timestamp(), &mut stack.queues, &mut stack.sockets);
+
+        assert_eq!(stack.sockets.get::<tcp::Socket>(handle).state(), tcp::State::SynReceived);
+        let packet = stack.queues.tx.pop_front().unwrap();
+        let (protocol, src_ip, dst_ip, payload) = parse_packet(packet.as_slice()).unwrap();
+        assert_eq!(protocol, IpProtocol::Tcp);
+        assert_eq!(src_ip, "1.1.1.1".parse::<IpAddr>().unwrap());
+        assert_eq!(dst_ip, "10.0.0.2".parse::<IpAddr>().unwrap());
+        let tcp = TcpPacket::new_checked(payload).unwrap();
+        assert!(tcp.syn() && tcp.ack());
+        assert_eq!(tcp.ack_number(), TcpSeqNumber(1001));
+    }
+}
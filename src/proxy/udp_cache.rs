@@ -1,3 +1,4 @@
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::time::Instant;
@@ -5,9 +6,12 @@ use std::time::Instant;
 use mio::net::UdpSocket;
 
 use crate::proxy::new_socket;
+use crate::proxy::tun::TunWriter;
 
 pub struct UdpSvrCache {
     conns: HashMap<SocketAddr, CacheEntry>,
+    // replies go back as packets of the device in tun mode
+    tun: Option<TunWriter>,
 }
 
 struct CacheEntry {
@@ -19,24 +23,41 @@ impl UdpSvrCache {
     pub fn new() -> UdpSvrCache {
         UdpSvrCache {
             conns: HashMap::new(),
+            tun: None,
         }
     }
 
-    pub fn send_to(&mut self, src_addr: SocketAddr, dst_addr: SocketAddr, payload: &[u8]) {
+    pub fn set_tun(&mut self, tun: TunWriter) {
+        self.tun.replace(tun);
+    }
+
+    // index is the session the response belongs to, sockets are shared by sessions
+    pub fn send_to(&mut self, index: usize, src_addr: SocketAddr, dst_addr: SocketAddr, payload: &[u8]) {
+        if let Some(tun) = self.tun.as_ref() {
+            log::info!("connection:{} sending {} bytes from {} to {} through tun device", index, payload.len(), dst_addr, src_addr);
+            if let Err(err) = tun.send_udp(dst_addr, src_addr, payload) {
+                log::error!("connection:{} send udp data from {} to {} failed {}", index, dst_addr, src_addr, err);
+            }
+            return;
+        }
         let last_active_time = Instant::now();
-        if !self.conns.contains_key(&dst_addr) {
-            log::info!("socket:{} not found, create a new one", dst_addr);
-            let socket = new_socket(dst_addr, true);
-            let socket = UdpSocket::from_socket(socket.into_udp_socket()).unwrap();
-            self.conns.insert(dst_addr, CacheEntry { socket, last_active_time });
+        if let Entry::Vacant(vacant) = self.conns.entry(dst_addr) {
+            log::info!("connection:{} socket:{} not found, create a new one", index, dst_addr);
+            let socket = match new_socket(dst_addr, true, true, false).and_then(|socket| UdpSocket::from_socket(socket.into_udp_socket())) {
+                Ok(socket) => socket,
+                Err(err) => {
+                    log::error!("connection:{} create socket:{} failed:{}", index, dst_addr, err);
+                    return;
+                }
+            };
+            vacant.insert(CacheEntry { socket, last_active_time });
         }
 
-        log::info!("socket is ready, sending {} bytes from {} to {}", payload.len(), dst_addr, src_addr);
+        log::info!("connection:{} socket is ready, sending {} bytes from {} to {}", index, payload.len(), dst_addr, src_addr);
         let entry = self.conns.get_mut(&dst_addr).unwrap();
         entry.last_active_time = last_active_time;
         if let Err(err) = entry.socket.send_to(payload, &src_addr) {
-            log::error!("send udp data from {} to {} failed {}", dst_addr, src_addr, err);
-            return;
+            log::error!("connection:{} send udp data from {} to {} failed {}", index, dst_addr, src_addr, err);
         }
     }
 
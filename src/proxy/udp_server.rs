@@ -1,38 +1,51 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::{ErrorKind, Read, Write};
 use std::net::Shutdown;
 use std::net::SocketAddr;
-use std::rc::Rc;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use bytes::BytesMut;
 use mio::{Event, Poll, PollOpt, Ready, Token};
-use mio::net::TcpStream;
 use mio::net::UdpSocket;
 use rustls::{ClientConfig, ClientSession, Session};
-use webpki::DNSName;
 
 use crate::config::Opts;
-use crate::proto::{MAX_UDP_SIZE, TrojanRequest, UDP_ASSOCIATE, UdpAssociate, UdpParseResult};
-use crate::proxy::{MAX_INDEX, MIN_INDEX};
+use crate::happy_eyeballs::{ConnectResult, HappyEyeballs};
+use crate::net::Stream;
+use crate::proto::{Sock5Address, TrojanRequest, UDP_ASSOCIATE, UDP_OVER_TCP, UdpAssociate, UdpParseResult};
+use crate::proxy::{network, next_index};
+use crate::proxy::direct::{new_direct_socket, UdpDirect};
 use crate::proxy::udp_cache::UdpSvrCache;
+use crate::route::Action;
 use crate::sys;
 
 pub struct UdpServer {
-    udp_listener: Rc<UdpSocket>,
+    udp_listeners: Vec<UdpSocket>,
+    // of the packets relayed directly from each listener
+    markers: Vec<u8>,
     conns: HashMap<usize, Connection>,
-    src_map: HashMap<SocketAddr, usize>,
-    next_id: usize,
+    // a client has a session for the servers the balance chooses and one for each server route rules choose
+    src_map: HashMap<(SocketAddr, Option<usize>), usize>,
+    direct_conns: HashMap<usize, UdpDirect>,
+    direct_map: HashMap<(SocketAddr, bool), usize>,
     recv_buffer: Vec<u8>,
     config: Arc<ClientConfig>,
-    hostname: DNSName,
+    racing: HashSet<usize>,
+    draining: bool,
 }
 
 struct Connection {
     index: usize,
     src_addr: SocketAddr,
+    upstream: usize,
+    // the server chosen by a route rule
+    outbound: Option<usize>,
+    connect_time: Instant,
+    dns_addr: Option<SocketAddr>,
     server_session: ClientSession,
-    server: TcpStream,
+    server: Option<Box<dyn Stream>>,
+    connector: Option<HappyEyeballs>,
     send_buffer: BytesMut,
     recv_buffer: BytesMut,
     server_readiness: Ready,
@@ -40,67 +53,35 @@ struct Connection {
     closed: bool,
     client_recv: usize,
     client_sent: usize,
+    // of the last packet from the client, the last data sent to the server and the last received from it
+    last_client_time: Instant,
+    last_sent_time: Instant,
+    last_server_time: Instant,
 }
 
 impl UdpServer {
-    pub fn new(udp_listener: UdpSocket, config: Arc<ClientConfig>, hostname: DNSName) -> UdpServer {
+    pub fn new(udp_listeners: Vec<UdpSocket>, markers: Vec<u8>, config: Arc<ClientConfig>, max_udp_size: usize) -> UdpServer {
         UdpServer {
-            udp_listener: Rc::new(udp_listener),
+            udp_listeners,
+            markers,
             config,
-            hostname,
             conns: HashMap::new(),
             src_map: HashMap::new(),
-            next_id: MIN_INDEX,
-            recv_buffer: vec![0u8; MAX_UDP_SIZE],
+            direct_conns: HashMap::new(),
+            direct_map: HashMap::new(),
+            // one more byte to tell oversized datagrams, which are truncated by the kernel
+            recv_buffer: vec![0u8; max_udp_size + 1],
+            racing: HashSet::new(),
+            draining: false,
         }
     }
 
-    pub fn accept(&mut self, event: &Event, opts: &mut Opts, poll: &Poll) {
+    pub fn accept(&mut self, listener: usize, event: &Event, opts: &mut Opts, poll: &Poll) {
         if event.readiness().is_readable() {
             loop {
-                match sys::recv_from_with_destination(self.udp_listener.as_ref(), self.recv_buffer.as_mut_slice()) {
+                match sys::recv_from_with_destination(&self.udp_listeners[listener], self.recv_buffer.as_mut_slice()) {
                     Ok((size, src_addr, dst_addr)) => {
-                        log::info!("udp received {} byte from {} to {}", size, src_addr, dst_addr);
-                        let index = if let Some(index) = self.src_map.get(&src_addr) {
-                            log::debug!("connection:{} already exists for address{}", index, src_addr);
-                            *index
-                        } else {
-                            log::debug!("address:{} not found, connecting to {}", src_addr, opts.back_addr.as_ref().unwrap());
-                            match TcpStream::connect(opts.back_addr.as_ref().unwrap()) {
-                                Ok(stream) => {
-                                    if let Err(err) = sys::set_mark(&stream, opts.marker) {
-                                        log::error!("set mark failed:{}", err);
-                                        continue;
-                                    } else if let Err(err) = stream.set_nodelay(true) {
-                                        log::error!("set nodelay failed:{}", err);
-                                        continue;
-                                    } else {
-                                        let session = ClientSession::new(&self.config, self.hostname.as_ref());
-                                        let mut conn = Connection::new(self.next_index(), src_addr, session, stream);
-                                        if conn.setup(opts, poll) {
-                                            let index = conn.index();
-                                            let _ = self.conns.insert(index, conn);
-                                            self.src_map.insert(src_addr, index);
-                                            log::info!("connection:{} is ready", index);
-                                            index
-                                        } else {
-                                            continue;
-                                        }
-                                    }
-                                }
-                                Err(err) => {
-                                    log::error!("connection to back server failed:{}", err);
-                                    //FIXME should update dns now?
-                                    continue;
-                                }
-                            }
-                        };
-                        if let Some(conn) = self.conns.get_mut(&index) {
-                            let payload = &self.recv_buffer.as_slice()[..size];
-                            conn.send_request(payload, &dst_addr);
-                        } else {
-                            log::error!("impossible, connection should be found now");
-                        }
+                        self.relay(self.markers[listener], size, src_addr, dst_addr, opts, poll);
                     }
                     Err(err) if err.kind() == ErrorKind::WouldBlock => {
                         log::debug!("udp server got no more data");
@@ -115,38 +96,256 @@ impl UdpServer {
         }
     }
 
+    // packets of tun mode, read from the device instead of a listener
+    pub fn accept_tun(&mut self, src_addr: SocketAddr, dst_addr: SocketAddr, payload: &[u8], opts: &mut Opts, poll: &Poll) {
+        // truncated like the kernel does, so that oversized ones are told and dropped
+        let size = payload.len().min(self.recv_buffer.len());
+        self.recv_buffer[..size].copy_from_slice(&payload[..size]);
+        self.relay(opts.relay_args().marker, size, src_addr, dst_addr, opts, poll);
+    }
+
+    // relays the packet in the receive buffer, marker is of the packets relayed directly
+    fn relay(&mut self, marker: u8, size: usize, src_addr: SocketAddr, dst_addr: SocketAddr, opts: &mut Opts, poll: &Poll) {
+        log::info!("udp received {} byte from {} to {}", size, src_addr, dst_addr);
+        if size > opts.relay_args().max_udp_size {
+            log::warn!("udp packet from {} to {} exceeds max udp size:{}, drop it", src_addr, dst_addr, opts.relay_args().max_udp_size);
+            return;
+        }
+        if opts.fake_dns.is_fake(&dst_addr.ip()) {
+            log::warn!("udp packet to fake ip {} is not supported, drop it", dst_addr);
+            return;
+        }
+        let action = opts.route(None, Some(&dst_addr.ip()), Some(dst_addr.port()));
+        match action {
+            Action::Block => {
+                log::info!("udp packet from {} to {} is blocked", src_addr, dst_addr);
+                return;
+            }
+            Action::Direct if self.draining && !self.direct_map.contains_key(&(src_addr, dst_addr.is_ipv4())) => {
+                log::debug!("udp server is stopping, drop packet from {}", src_addr);
+                return;
+            }
+            Action::Direct => {
+                self.send_direct(marker, size, src_addr, dst_addr, opts, poll);
+                return;
+            }
+            Action::Proxy | Action::Outbound(_) => {}
+        }
+        let outbound = opts.outbound_upstream(action);
+        let index = if let Some(index) = self.src_map.get(&(src_addr, outbound)) {
+            log::debug!("connection:{} already exists for address{}", index, src_addr);
+            *index
+        } else if self.draining {
+            log::debug!("udp server is stopping, drop packet from {}", src_addr);
+            return;
+        } else {
+            let upstream = outbound.unwrap_or_else(|| opts.select_upstream());
+            if opts.upstreams[upstream].is_backing_off(Instant::now()) {
+                log::debug!("trojan server {} is unreachable, drop packet from {}", opts.upstreams[upstream].name(), src_addr);
+                return;
+            }
+            let index = next_index();
+            log::debug!("connection:{} created for address:{}, connecting to {}", index, src_addr, opts.upstreams[upstream].hostname);
+            let mut connector = HappyEyeballs::new(index, opts.upstreams[upstream].addrs().as_slice(), opts.attempt_duration, &opts.tcp_opts);
+            if let Some(tunnel) = opts.upstream_tunnel(upstream) {
+                connector.set_tunnel(tunnel);
+            }
+            let session = ClientSession::new(&self.config, opts.upstreams[upstream].dns_name());
+            let mut conn = Connection::new(index, src_addr, upstream, outbound, session, connector);
+            if conn.setup(opts, poll) {
+                opts.upstream_opened(upstream);
+                let index = conn.index();
+                let _ = self.conns.insert(index, conn);
+                self.src_map.insert((src_addr, outbound), index);
+                self.racing.insert(index);
+                log::info!("connection:{} is ready", index);
+                index
+            } else {
+                conn.close_now(poll);
+                return;
+            }
+        };
+        if let Some(conn) = self.conns.get_mut(&index) {
+            let payload = &self.recv_buffer.as_slice()[..size];
+            if let (53, Some(remote_dns)) = (dst_addr.port(), opts.remote_dns) {
+                log::info!("connection:{} dns query to {} is redirected to {}", index, dst_addr, remote_dns);
+                conn.dns_addr.replace(dst_addr);
+                conn.send_request(payload, &remote_dns);
+            } else {
+                conn.send_request(payload, &dst_addr);
+            }
+        } else {
+            log::error!("impossible, connection should be found now");
+        }
+    }
+
+    fn send_direct(&mut self, marker: u8, size: usize, src_addr: SocketAddr, dst_addr: SocketAddr, opts: &mut Opts, poll: &Poll) {
+        let key = (src_addr, dst_addr.is_ipv4());
+        let index = if let Some(index) = self.direct_map.get(&key) {
+            *index
+        } else {
+            let index = next_index();
+            let socket = match new_direct_socket(&dst_addr, marker) {
+                Ok(socket) => socket,
+                Err(err) => {
+                    log::error!("connection:{} create direct udp socket for {} failed:{}", index, src_addr, err);
+                    return;
+                }
+            };
+            if let Some(dscp) = opts.relay_args().udp_dscp {
+                if let Err(err) = sys::set_dscp(&socket, dst_addr.is_ipv4(), dscp) {
+                    log::error!("connection:{} set dscp of direct udp socket for {} failed:{}", index, src_addr, err);
+                    return;
+                }
+            }
+            let mut conn = UdpDirect::new(index, src_addr, socket);
+            if !conn.setup(poll) {
+                return;
+            }
+            let index = conn.index();
+            self.direct_conns.insert(index, conn);
+            self.direct_map.insert(key, index);
+            index
+        };
+        if let Some(conn) = self.direct_conns.get_mut(&index) {
+            conn.send_request(&self.recv_buffer.as_slice()[..size], &dst_addr);
+        }
+    }
+
+    pub fn check_timeout(&mut self, recent_active_time: Instant, poll: &Poll) {
+        let direct_conns = &mut self.direct_conns;
+        self.direct_map.retain(|_, index| {
+            if let Some(conn) = direct_conns.get_mut(index) {
+                if conn.last_active_time() >= recent_active_time && !conn.closed() {
+                    return true;
+                }
+                if !conn.closed() {
+                    conn.close_now(poll);
+                }
+                direct_conns.remove(index);
+            }
+            false
+        });
+    }
+
     pub fn ready(&mut self, event: &Event, opts: &mut Opts, poll: &Poll, udp_cache: &mut UdpSvrCache) {
         let index = Connection::token2index(event.token());
-        let src_addr = if let Some(conn) = self.conns.get_mut(&index) {
+        if let Some(conn) = self.direct_conns.get_mut(&index) {
+            conn.ready(poll, udp_cache, opts.relay_args().max_udp_size);
+            return;
+        }
+        let key = if let Some(conn) = self.conns.get_mut(&index) {
             conn.ready(event, opts, poll, udp_cache);
             if !conn.is_closed() {
+                if conn.is_racing() {
+                    self.racing.insert(index);
+                }
                 return;
             }
-            conn.src_addr
+            opts.upstream_closed(conn.upstream, conn.server_failed());
+            conn.key()
         } else {
             return;
         };
         self.conns.remove(&index);
-        self.src_map.remove(&src_addr);
+        self.src_map.remove(&key);
     }
 
-    pub fn next_index(&mut self) -> usize {
-        let index = self.next_id;
-        self.next_id += 1;
-        if self.next_id >= MAX_INDEX {
-            self.next_id = MIN_INDEX;
+    // the listener still carries packets of existing sessions, so only new sessions are refused
+    pub fn stop_accept(&mut self) {
+        self.draining = true;
+    }
+
+    pub fn listeners(&self) -> &[UdpSocket] {
+        self.udp_listeners.as_slice()
+    }
+
+    // packets of both old and new sessions come to the listener, which is left to the new process after an upgrade
+    pub fn hand_over(&mut self, poll: &Poll) {
+        for udp_listener in self.udp_listeners.iter() {
+            if let Err(err) = poll.deregister(udp_listener) {
+                log::error!("deregister udp listener failed:{}", err);
+            }
         }
-        index
+        self.draining = true;
+    }
+
+    pub fn conn_count(&self) -> usize {
+        self.conns.len() + self.direct_conns.len()
+    }
+
+    pub fn is_racing(&self) -> bool {
+        !self.racing.is_empty()
+    }
+
+    // with --udp-over-tcp the server keeps associations open, they are closed here once idle or dead
+    pub fn check_keepalive(&mut self, now: Instant, opts: &mut Opts, poll: &Poll) {
+        if !opts.proxy_args().udp_over_tcp {
+            return;
+        }
+        let interval = Duration::new(opts.proxy_args().udp_keepalive, 0);
+        let idle_duration = opts.idle_duration;
+        let src_map = &mut self.src_map;
+        self.conns.retain(|_, conn| {
+            conn.keepalive(now, interval, idle_duration, poll);
+            if !conn.is_closed() {
+                return true;
+            }
+            opts.upstream_closed(conn.upstream, conn.server_failed());
+            src_map.remove(&conn.key());
+            false
+        });
+    }
+
+    // streams from a local address which is gone are closed, the next packet of their clients opens another
+    pub fn network_changed(&mut self, opts: &mut Opts, poll: &Poll) {
+        let src_map = &mut self.src_map;
+        self.conns.retain(|index, conn| {
+            if !conn.server.as_ref().and_then(|server| server.local_addr().ok()).map_or(false, network::is_gone) {
+                return true;
+            }
+            log::warn!("connection:{} local address is gone, close now", index);
+            conn.close_now(poll);
+            opts.upstream_closed(conn.upstream, false);
+            src_map.remove(&conn.key());
+            false
+        });
+    }
+
+    pub fn check_racing(&mut self, opts: &mut Opts, poll: &Poll) {
+        let now = Instant::now();
+        let conns = &mut self.conns;
+        let src_map = &mut self.src_map;
+        self.racing.retain(|index| {
+            if let Some(conn) = conns.get_mut(index) {
+                conn.check_racing(now, poll);
+                if conn.is_closed() {
+                    opts.upstream_closed(conn.upstream, conn.server_failed());
+                    src_map.remove(&conn.key());
+                    conns.remove(index);
+                    false
+                } else {
+                    conn.is_racing()
+                }
+            } else {
+                false
+            }
+        });
     }
 }
 
 impl Connection {
-    fn new(index: usize, src_addr: SocketAddr, session: ClientSession, stream: TcpStream) -> Connection {
+    fn new(index: usize, src_addr: SocketAddr, upstream: usize, outbound: Option<usize>, session: ClientSession, connector: HappyEyeballs) -> Connection {
         Connection {
             index,
             src_addr,
+            upstream,
+            outbound,
+            connect_time: Instant::now(),
+            dns_addr: None,
             server_session: session,
-            server: stream,
+            server: None,
+            connector: Some(connector),
             send_buffer: BytesMut::new(),
             recv_buffer: BytesMut::new(),
             server_readiness: Ready::readable() | Ready::writable(),
@@ -154,9 +353,16 @@ impl Connection {
             closed: false,
             client_recv: 0,
             client_sent: 0,
+            last_client_time: Instant::now(),
+            last_sent_time: Instant::now(),
+            last_server_time: Instant::now(),
         }
     }
 
+    fn key(&self) -> (SocketAddr, Option<usize>) {
+        (self.src_addr, self.outbound)
+    }
+
     fn server_token(&self) -> Token {
         Token(self.index * 3)
     }
@@ -166,29 +372,89 @@ impl Connection {
     }
 
     fn setup(&mut self, opts: &mut Opts, poll: &Poll) -> bool {
+        let token = self.server_token();
         self.recv_buffer.clear();
-        TrojanRequest::generate(&mut self.recv_buffer, UDP_ASSOCIATE, opts.empty_addr.as_ref().unwrap(), opts);
+        let empty_addr = Sock5Address::Socket(*opts.empty_addr.as_ref().unwrap());
+        let command = if opts.proxy_args().udp_over_tcp { UDP_OVER_TCP } else { UDP_ASSOCIATE };
+        TrojanRequest::generate(&mut self.recv_buffer, command, &empty_addr, opts.upstreams[self.upstream].pass());
         if let Err(err) = self.server_session.write_all(self.recv_buffer.as_ref()) {
             log::warn!("connection:{} write handshake to server session failed:{}", self.index(), err);
             false
-        } else if let Err(err) = poll.register(&self.server, self.server_token(), self.server_readiness, PollOpt::level()) {
-            log::warn!("connection:{} register failed:{}", self.index(), err);
+        } else if !self.connector.as_mut().unwrap().connect(poll, token) {
+            log::warn!("connection:{} connect to server failed", self.index());
+            opts.upstream_failed(self.upstream);
             false
         } else {
             true
         }
     }
 
+    fn is_racing(&self) -> bool {
+        self.connector.as_ref().map_or(false, |connector| connector.is_racing())
+    }
+
+    fn check_racing(&mut self, now: Instant, poll: &Poll) {
+        let token = self.server_token();
+        if let Some(connector) = self.connector.as_mut() {
+            if !connector.check_timeout(now, poll, token) {
+                log::warn!("connection:{} connect to server failed", self.index());
+                self.closing = true;
+            }
+        }
+        if self.closing {
+            self.close_now(poll);
+        }
+    }
+
+    fn try_connect_server(&mut self, opts: &mut Opts, poll: &Poll) {
+        if self.closing {
+            return;
+        }
+        let token = self.server_token();
+        match self.connector.as_mut().unwrap().ready(poll, token) {
+            ConnectResult::Connected(server, addr) => {
+                opts.upstream_connected(self.upstream, self.connect_time.elapsed());
+                log::info!("connection:{} connected to server {}", self.index(), addr);
+                self.connector.take();
+                if let Err(err) = server.set_nodelay(true) {
+                    log::error!("connection:{} set nodelay failed:{}", self.index(), err);
+                    self.closing = true;
+                    return;
+                } else if let Err(err) = poll.reregister(&*server, token, self.server_readiness, PollOpt::level()) {
+                    log::warn!("connection:{} register failed:{}", self.index(), err);
+                    self.closing = true;
+                    return;
+                }
+                self.server.replace(server);
+                self.last_server_time = Instant::now();
+                self.try_send_server();
+            }
+            ConnectResult::Pending => {
+                log::debug!("connection:{} still connecting to server", self.index());
+            }
+            ConnectResult::Failed => {
+                log::warn!("connection:{} connect to server failed", self.index());
+                self.closing = true;
+            }
+        }
+    }
+
     fn is_closed(&self) -> bool {
         self.closed
     }
 
+    fn server_failed(&self) -> bool {
+        self.server_session.is_handshaking()
+    }
+
     fn index(&self) -> usize {
         self.index
     }
 
     fn send_request(&mut self, payload: &[u8], dst_addr: &SocketAddr) {
         self.client_sent += payload.len();
+        self.last_client_time = Instant::now();
+        self.last_sent_time = self.last_client_time;
         self.recv_buffer.clear();
         UdpAssociate::generate(&mut self.recv_buffer, dst_addr, payload.len() as u16);
         if let Err(err) = self.server_session.write_all(self.recv_buffer.as_ref()) {
@@ -202,13 +468,47 @@ impl Connection {
         }
     }
 
-    fn ready(&mut self, event: &Event, opts: &mut Opts, poll: &Poll, udp_cache: &mut UdpSvrCache) {
-        if event.readiness().is_readable() {
-            self.try_read_server(opts, udp_cache);
+    // sends a keepalive once nothing was sent for the interval, and closes the association once its client has been
+    // idle for the idle timeout, or the server has not answered for three intervals
+    fn keepalive(&mut self, now: Instant, interval: Duration, idle_duration: Duration, poll: &Poll) {
+        if self.closing || self.closed || self.server.is_none() {
+            return;
+        }
+        if now - self.last_client_time > idle_duration {
+            log::info!("connection:{} udp client is idle, close it", self.index());
+            self.closing = true;
+        } else if now - self.last_server_time > interval * 3 {
+            log::warn!("connection:{} server does not answer udp keepalives, close it", self.index());
+            self.closing = true;
+        } else if now - self.last_sent_time >= interval {
+            log::debug!("connection:{} send udp keepalive", self.index());
+            self.recv_buffer.clear();
+            UdpAssociate::generate_keepalive(&mut self.recv_buffer);
+            if let Err(err) = self.server_session.write_all(self.recv_buffer.as_ref()) {
+                log::error!("connection:{} write keepalive to server failed:{}", self.index(), err);
+                self.closing = true;
+            } else {
+                self.last_sent_time = now;
+                self.try_send_server();
+            }
         }
+        self.reregister(poll);
+        if self.closing {
+            self.close_now(poll);
+        }
+    }
 
-        if event.readiness().is_writable() {
-            self.try_send_server();
+    fn ready(&mut self, event: &Event, opts: &mut Opts, poll: &Poll, udp_cache: &mut UdpSvrCache) {
+        if self.server.is_none() {
+            self.try_connect_server(opts, poll);
+        } else {
+            if event.readiness().is_readable() {
+                self.try_read_server(opts, udp_cache);
+            }
+
+            if event.readiness().is_writable() {
+                self.try_send_server();
+            }
         }
 
         self.reregister(poll);
@@ -218,8 +518,13 @@ impl Connection {
     }
 
     fn close_now(&mut self, poll: &Poll) {
-        let _ = poll.deregister(&self.server);
-        let _ = self.server.shutdown(Shutdown::Both);
+        if let Some(mut connector) = self.connector.take() {
+            connector.close(poll);
+        }
+        if let Some(server) = self.server.as_ref() {
+            let _ = poll.deregister(&**server);
+            let _ = server.shutdown(Shutdown::Both);
+        }
         self.closed = true;
         log::warn!("connection:{} closed, {} bytes read, {} bytes sent", self.index(), self.client_recv, self.client_sent);
     }
@@ -238,8 +543,8 @@ impl Connection {
             changed = true;
         }
 
-        if changed {
-            if let Err(err) = poll.reregister(&self.server, self.server_token(), self.server_readiness, PollOpt::level()) {
+        if changed && self.server.is_some() {
+            if let Err(err) = poll.reregister(&**self.server.as_ref().unwrap(), self.server_token(), self.server_readiness, PollOpt::level()) {
                 self.closing = true;
                 log::error!("connection:{} reregister failed:{}", self.index(), err);
             }
@@ -247,7 +552,7 @@ impl Connection {
     }
 
     fn try_send_server(&mut self) {
-        if self.closing {
+        if self.closing || self.server.is_none() {
             return;
         }
         log::info!("connection:{} trying to send udp bytes to server", self.index());
@@ -255,7 +560,7 @@ impl Connection {
             if !self.server_session.wants_write() {
                 break;
             }
-            match self.server_session.write_tls(&mut self.server) {
+            match self.server_session.write_tls(self.server.as_mut().unwrap()) {
                 Ok(size) => {
                     log::info!("connection:{} write {} bytes to server", self.index(), size);
                 }
@@ -278,7 +583,7 @@ impl Connection {
             return;
         }
         loop {
-            match self.server_session.read_tls(&mut self.server) {
+            match self.server_session.read_tls(self.server.as_mut().unwrap()) {
                 Ok(size) => {
                     if size == 0 {
                         log::warn!("connection:{} read from server failed with eof", self.index());
@@ -313,6 +618,7 @@ impl Connection {
         }
 
         if !buffer.is_empty() {
+            self.last_server_time = Instant::now();
             self.client_recv += buffer.len();
             self.try_send_client(buffer.as_slice(), opts, udp_cache);
         }
@@ -337,9 +643,21 @@ impl Connection {
                 }
                 UdpParseResult::Packet(packet) => {
                     let payload = &packet.payload[..packet.length];
-                    udp_cache.send_to(self.src_addr, packet.address, payload);
+                    // responses of redirected dns queries should come from the address the client asked
+                    let address = match (opts.remote_dns, self.dns_addr) {
+                        (Some(remote_dns), Some(dns_addr)) if remote_dns == packet.address => dns_addr,
+                        _ => packet.address,
+                    };
+                    udp_cache.send_to(self.index(), self.src_addr, address, payload);
                     buffer = &packet.payload[packet.length..];
                 }
+                UdpParseResult::Dropped(remaining) => {
+                    buffer = remaining;
+                }
+                UdpParseResult::Keepalive(remaining) => {
+                    log::debug!("connection:{} got udp keepalive", self.index());
+                    buffer = remaining;
+                }
                 UdpParseResult::InvalidProtocol => {
                     log::error!("connection:{} got invalid protocol", self.index());
                     self.closing = true;
@@ -0,0 +1,525 @@
+// plays scripted traces through the relay loops, with streams, lookups and time all made up by the trace, so that a
+// regression of the event handling, like a half close lost or a paused side never read again, is kept as a test.
+//
+// a trace has a line per step, the time in milliseconds since the start, then what happens. a peer is a stream of the
+// code under test named by the trace, the client it accepted, or a target or server it connected to
+//
+//   <ms>                          only lets the time pass, so that timers fire
+//   <ms> <peer> > <hex>           the peer sends data, over tls if the peer speaks it
+//   <ms> <peer> < [<hex>]         the peer got exactly this since the last check, nothing if there is no data
+//   <ms> <peer> fin               the peer shuts down writing
+//   <ms> <peer> < fin             the code shut down writing to the peer
+//   <ms> <peer> closed            the code closed the stream of the peer
+//   <ms> <peer> open              the stream of the peer is still open
+//   <ms> <peer> window <n|none>   the code may write n more bytes to the peer before it blocks
+//   <ms> <peer> stalled           data the peer sent is left unread
+//   <ms> <peer> accept <addr>     the connect to addr succeeds, the stream is the peer from then on
+//   <ms> <peer> refuse <addr>     the connect to addr is refused
+//   <ms> dns <domain> <ip>...     answers the lookup of domain, none for no records
+//
+// the event loop is run after each step until nothing moves, as the loop of the server and the proxy would, and the
+// timers of the machine are run whenever the time changes. lines starting with # are comments
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use clap::{App, AppSettings, FromArgMatches};
+use clap::derive::IntoApp;
+use mio::{Event, Events, Evented, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use rustls::{Certificate, ClientConfig, NoClientAuth, PrivateKey, ServerConfig, Session};
+
+use crate::config::{IpStrategy, Opts};
+use crate::net::Stream;
+use crate::resolver::Answer;
+
+pub const SERVER_NAME: &str = "localhost";
+// rounds of the event loop a step may take, more means it spins
+const MAX_ROUNDS: usize = 1000;
+
+thread_local! {
+    static NETWORK: RefCell<Network> = RefCell::new(Network::new());
+}
+
+// the clock, and the connects and lookups the code started which the trace has not answered yet
+struct Network {
+    start: Instant,
+    elapsed: Duration,
+    connects: Vec<Rc<RefCell<Pipe>>>,
+    lookups: Vec<(String, IpStrategy, Answer)>,
+    next_port: u16,
+}
+
+impl Network {
+    fn new() -> Network {
+        Network {
+            start: Instant::now(),
+            elapsed: Duration::default(),
+            connects: Vec::new(),
+            lookups: Vec::new(),
+            next_port: 40000,
+        }
+    }
+}
+
+pub fn now() -> Instant {
+    NETWORK.with(|network| {
+        let network = network.borrow();
+        network.start + network.elapsed
+    })
+}
+
+pub fn connect(addr: &SocketAddr) -> Result<Box<dyn Stream>> {
+    NETWORK.with(|network| {
+        let mut network = network.borrow_mut();
+        let local_addr = SocketAddr::new("198.51.100.1".parse().unwrap(), network.next_port);
+        network.next_port += 1;
+        let (stream, pipe) = ReplayStream::new(*addr, local_addr, false);
+        network.connects.push(pipe);
+        Ok(Box::new(stream) as Box<dyn Stream>)
+    })
+}
+
+pub fn lookup(domain: String, strategy: IpStrategy, answer: Answer) {
+    NETWORK.with(|network| network.borrow_mut().lookups.push((domain, strategy, answer)));
+}
+
+// a self-signed certificate of SERVER_NAME, the server side, and the client side trusting it
+pub fn tls_configs() -> (Arc<ServerConfig>, Arc<ClientConfig>) {
+    let cert = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()]).unwrap();
+    let cert_der = Certificate(cert.serialize_der().unwrap());
+    let mut server_config = ServerConfig::new(NoClientAuth::new());
+    server_config.set_single_cert(vec![cert_der.clone()], PrivateKey(cert.serialize_private_key_der())).unwrap();
+    let mut client_config = ClientConfig::new();
+    client_config.root_store.add(&cert_der).unwrap();
+    (Arc::new(server_config), Arc::new(client_config))
+}
+
+// the options of a mode as parsed from the command line
+pub fn opts(args: &[&str]) -> Opts {
+    let argv: Vec<&str> = std::iter::once("trojan").chain(args.iter().cloned()).collect();
+    let app: App = <Opts as IntoApp>::into_app().setting(AppSettings::AllowExternalSubcommands);
+    let mut opts = <Opts as FromArgMatches>::from_arg_matches(&app.get_matches_from(argv));
+    opts.setup().unwrap();
+    opts
+}
+
+#[derive(PartialEq)]
+enum State {
+    Connecting,
+    Connected,
+    Refused,
+}
+
+// both directions of a stream, in is read by the code, out is written by it
+struct Pipe {
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+    state: State,
+    input: Vec<u8>,
+    input_fin: bool,
+    output: Vec<u8>,
+    output_fin: bool,
+    closed: bool,
+    // what the code may write before it blocks, no limit if none
+    window: Option<usize>,
+    readiness: Ready,
+    set_readiness: SetReadiness,
+}
+
+impl Pipe {
+    // the code only sees a change of readiness, the peer triggers an edge with anything it does
+    fn update(&mut self, edge: bool) {
+        let mut readiness = Ready::empty();
+        if self.state == State::Refused {
+            readiness = Ready::readable() | Ready::writable();
+        } else if self.state == State::Connected {
+            if !self.input.is_empty() || self.input_fin {
+                readiness.insert(Ready::readable());
+            }
+            if !self.output_fin && self.window != Some(0) {
+                readiness.insert(Ready::writable());
+            }
+        }
+        if edge || readiness != self.readiness {
+            self.readiness = readiness;
+            self.set_readiness.set_readiness(readiness).unwrap();
+        }
+    }
+}
+
+pub struct ReplayStream {
+    pipe: Rc<RefCell<Pipe>>,
+    registration: Registration,
+}
+
+impl ReplayStream {
+    fn new(peer_addr: SocketAddr, local_addr: SocketAddr, connected: bool) -> (ReplayStream, Rc<RefCell<Pipe>>) {
+        let (registration, set_readiness) = Registration::new2();
+        let pipe = Rc::new(RefCell::new(Pipe {
+            peer_addr,
+            local_addr,
+            state: if connected { State::Connected } else { State::Connecting },
+            input: Vec::new(),
+            input_fin: false,
+            output: Vec::new(),
+            output_fin: false,
+            closed: false,
+            window: None,
+            readiness: Ready::empty(),
+            set_readiness,
+        }));
+        pipe.borrow_mut().update(true);
+        (ReplayStream { pipe: pipe.clone(), registration }, pipe)
+    }
+}
+
+impl Read for ReplayStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut pipe = self.pipe.borrow_mut();
+        let result = if pipe.state != State::Connected || (pipe.input.is_empty() && !pipe.input_fin) {
+            Err(Error::from(ErrorKind::WouldBlock))
+        } else {
+            let size = std::cmp::min(buf.len(), pipe.input.len());
+            buf[..size].copy_from_slice(&pipe.input[..size]);
+            pipe.input.drain(..size);
+            Ok(size)
+        };
+        pipe.update(false);
+        result
+    }
+}
+
+impl Write for ReplayStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut pipe = self.pipe.borrow_mut();
+        if pipe.output_fin {
+            return Err(Error::from(ErrorKind::BrokenPipe));
+        }
+        let size = std::cmp::min(buf.len(), pipe.window.unwrap_or(buf.len()));
+        if pipe.state != State::Connected || (size == 0 && !buf.is_empty()) {
+            return Err(Error::from(ErrorKind::WouldBlock));
+        }
+        pipe.output.extend_from_slice(&buf[..size]);
+        if let Some(window) = pipe.window.as_mut() {
+            *window -= size;
+        }
+        pipe.update(false);
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Evented for ReplayStream {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<()> {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<()> {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    #[allow(deprecated)]
+    fn deregister(&self, poll: &Poll) -> Result<()> {
+        self.registration.deregister(poll)
+    }
+}
+
+impl Stream for ReplayStream {
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        let pipe = self.pipe.borrow();
+        match pipe.state {
+            State::Connected => Ok(pipe.peer_addr),
+            _ => Err(Error::from(ErrorKind::NotConnected)),
+        }
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.pipe.borrow().local_addr)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> Result<()> {
+        let mut pipe = self.pipe.borrow_mut();
+        if how != Shutdown::Read {
+            pipe.output_fin = true;
+        }
+        if how == Shutdown::Both {
+            pipe.closed = true;
+        }
+        pipe.update(false);
+        Ok(())
+    }
+
+    fn set_linger(&self, dur: Option<Duration>) -> Result<()> {
+        if dur == Some(Duration::new(0, 0)) {
+            self.pipe.borrow_mut().closed = true;
+        }
+        Ok(())
+    }
+
+    fn set_nodelay(&self, _nodelay: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn take_error(&self) -> Result<Option<Error>> {
+        match self.pipe.borrow().state {
+            State::Refused => Ok(Some(Error::from(ErrorKind::ConnectionRefused))),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl Drop for ReplayStream {
+    fn drop(&mut self) {
+        self.pipe.borrow_mut().closed = true;
+    }
+}
+
+// what runs the streams, the events of their tokens are passed to it
+pub trait Machine {
+    fn ready(&mut self, event: &Event, opts: &mut Opts, poll: &Poll);
+
+    // the timers of the event loop, run as the time moves
+    fn timeout(&mut self, now: Instant, opts: &mut Opts, poll: &Poll);
+}
+
+struct Peer {
+    pipe: Rc<RefCell<Pipe>>,
+    tls: Option<Box<dyn Session>>,
+    received: Vec<u8>,
+    // a fin waiting for the tls data before it to be sent
+    fin: bool,
+}
+
+impl Peer {
+    // moves the data between the pipe and the tls session of the peer, true if anything moved
+    fn pump(&mut self) -> bool {
+        let mut pipe = self.pipe.borrow_mut();
+        let output = std::mem::take(&mut pipe.output);
+        let mut moved = !output.is_empty();
+        match self.tls.as_mut() {
+            Some(tls) => {
+                let mut data = output.as_slice();
+                while !data.is_empty() {
+                    tls.read_tls(&mut data).unwrap();
+                    tls.process_new_packets().unwrap();
+                }
+                match tls.read_to_end(&mut self.received) {
+                    Ok(_) => {}
+                    // close notify
+                    Err(err) if err.kind() == ErrorKind::ConnectionAborted => {}
+                    Err(err) => panic!("read tls failed:{}", err),
+                }
+                let mut input = Vec::new();
+                while tls.wants_write() {
+                    tls.write_tls(&mut input).unwrap();
+                }
+                if !input.is_empty() {
+                    pipe.input.extend_from_slice(input.as_slice());
+                    moved = true;
+                }
+            }
+            None => self.received.extend_from_slice(output.as_slice()),
+        }
+        if self.fin && !self.tls.as_ref().map_or(false, |tls| tls.wants_write()) {
+            self.fin = false;
+            pipe.input_fin = true;
+            moved = true;
+        }
+        if moved {
+            pipe.update(true);
+        }
+        moved
+    }
+}
+
+pub struct Replay {
+    poll: Poll,
+    peers: HashMap<String, Peer>,
+    // the sessions of peers speaking tls, taken once they are named
+    sessions: HashMap<String, Box<dyn Session>>,
+}
+
+impl Replay {
+    // each replay starts on a fresh network and clock of the thread
+    pub fn new() -> Replay {
+        NETWORK.with(|network| *network.borrow_mut() = Network::new());
+        Replay {
+            poll: Poll::new().unwrap(),
+            peers: HashMap::new(),
+            sessions: HashMap::new(),
+        }
+    }
+
+    pub fn poll(&self) -> &Poll {
+        &self.poll
+    }
+
+    // the peer speaks tls with the session
+    pub fn tls(&mut self, peer: &str, session: Box<dyn Session>) {
+        self.sessions.insert(peer.to_string(), session);
+    }
+
+    // a connected stream of a client, for the code to accept
+    pub fn accept(&mut self, peer: &str, peer_addr: SocketAddr, local_addr: SocketAddr) -> Box<dyn Stream> {
+        let (stream, pipe) = ReplayStream::new(peer_addr, local_addr, true);
+        self.add_peer(peer, pipe);
+        Box::new(stream)
+    }
+
+    fn add_peer(&mut self, peer: &str, pipe: Rc<RefCell<Pipe>>) {
+        let tls = self.sessions.remove(peer);
+        self.peers.insert(peer.to_string(), Peer {
+            pipe,
+            tls,
+            received: Vec::new(),
+            fin: false,
+        });
+    }
+
+    // runs the event loop until nothing moves
+    fn settle(&mut self, machine: &mut dyn Machine, opts: &mut Opts) {
+        let mut events = Events::with_capacity(64);
+        for _ in 0..MAX_ROUNDS {
+            let mut moved = false;
+            for peer in self.peers.values_mut() {
+                moved |= peer.pump();
+            }
+            self.poll.poll(&mut events, Some(Duration::new(0, 0))).unwrap();
+            if events.is_empty() && !moved {
+                return;
+            }
+            for event in events.iter() {
+                machine.ready(&event, opts, &self.poll);
+            }
+        }
+        panic!("the event loop does not settle");
+    }
+
+    pub fn play(&mut self, trace: &str, machine: &mut dyn Machine, opts: &mut Opts) {
+        self.settle(machine, opts);
+        for (i, line) in trace.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let time = Duration::from_millis(fields[0].parse().unwrap_or_else(|_| panic!("line {}:invalid time", i + 1)));
+            let moved = NETWORK.with(|network| {
+                let mut network = network.borrow_mut();
+                assert!(time >= network.elapsed, "line {}:time goes backwards", i + 1);
+                std::mem::replace(&mut network.elapsed, time) != time
+            });
+            if moved {
+                machine.timeout(now(), opts, &self.poll);
+                self.settle(machine, opts);
+            }
+            if fields.len() > 1 {
+                if let Err(err) = self.step(&fields[1..]) {
+                    panic!("line {}:{}:{}", i + 1, line, err);
+                }
+                self.settle(machine, opts);
+            }
+        }
+    }
+
+    fn step(&mut self, fields: &[&str]) -> std::result::Result<(), String> {
+        if fields[0] == "dns" {
+            return answer(fields.get(1).ok_or("no domain")?, &fields[2..]);
+        }
+        let name = fields[0];
+        let action = *fields.get(1).ok_or("no action")?;
+        if action == "accept" || action == "refuse" {
+            let addr: SocketAddr = fields.get(2).ok_or("no address")?.parse().map_err(|_| "invalid address")?;
+            let pipe = NETWORK.with(|network| {
+                let mut network = network.borrow_mut();
+                let pos = network.connects.iter().position(|pipe| pipe.borrow().peer_addr == addr);
+                pos.map(|pos| network.connects.remove(pos))
+            }).ok_or(format!("no connect to {}", addr))?;
+            pipe.borrow_mut().state = if action == "accept" { State::Connected } else { State::Refused };
+            pipe.borrow_mut().update(true);
+            if action == "accept" {
+                self.add_peer(name, pipe);
+            }
+            return Ok(());
+        }
+        let peer = self.peers.get_mut(name).ok_or(format!("no peer {}", name))?;
+        match (action, fields.get(2).cloned()) {
+            (">", _) => {
+                let data = parse_hex(&fields[2..])?;
+                match peer.tls.as_mut() {
+                    Some(tls) => tls.write_all(data.as_slice()).unwrap(),
+                    None => peer.pipe.borrow_mut().input.extend_from_slice(data.as_slice()),
+                }
+                peer.pump();
+                peer.pipe.borrow_mut().update(true);
+            }
+            ("<", Some("fin")) => {
+                if !peer.pipe.borrow().output_fin {
+                    return Err("not shut down".to_string());
+                }
+            }
+            ("<", _) => {
+                let data = parse_hex(&fields[2..])?;
+                let received = std::mem::take(&mut peer.received);
+                if received != data {
+                    return Err(format!("received {}", hex(received.as_slice())));
+                }
+            }
+            ("fin", None) => {
+                if let Some(tls) = peer.tls.as_mut() {
+                    tls.send_close_notify();
+                }
+                peer.fin = true;
+                peer.pump();
+            }
+            ("closed", None) if !peer.pipe.borrow().closed => return Err("still open".to_string()),
+            ("open", None) if peer.pipe.borrow().closed => return Err("closed".to_string()),
+            ("closed", None) | ("open", None) => {}
+            ("window", Some(window)) => {
+                let mut pipe = peer.pipe.borrow_mut();
+                pipe.window = if window == "none" { None } else { Some(window.parse().map_err(|_| "invalid window")?) };
+                pipe.update(true);
+            }
+            ("stalled", None) if peer.pipe.borrow().input.is_empty() => return Err("all read".to_string()),
+            ("stalled", None) => {}
+            _ => return Err("unknown step".to_string()),
+        }
+        Ok(())
+    }
+}
+
+fn answer(domain: &str, ips: &[&str]) -> std::result::Result<(), String> {
+    let lookup = NETWORK.with(|network| {
+        let mut network = network.borrow_mut();
+        let pos = network.lookups.iter().position(|(name, _, _)| name.trim_end_matches('.') == domain);
+        pos.map(|pos| network.lookups.remove(pos))
+    });
+    let (_, strategy, answer) = lookup.ok_or(format!("no lookup of {}", domain))?;
+    if ips == ["none"] {
+        answer.send(Vec::new(), Some(now() + Duration::new(60, 0)), true);
+    } else {
+        let mut addresses: Vec<IpAddr> = ips.iter().map(|ip| ip.parse().map_err(|_| format!("invalid ip {}", ip))).collect::<std::result::Result<_, _>>()?;
+        strategy.sort(&mut addresses);
+        answer.send(addresses, Some(now() + Duration::new(60, 0)), false);
+    }
+    Ok(())
+}
+
+fn parse_hex(fields: &[&str]) -> std::result::Result<Vec<u8>, String> {
+    let text: String = fields.concat();
+    if text.len() % 2 != 0 {
+        return Err("odd number of hex digits".to_string());
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).map_err(|_| "invalid hex digit".to_string())).collect()
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
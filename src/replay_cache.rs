@@ -0,0 +1,128 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{HashSet, VecDeque};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::time::{Duration, Instant};
+
+// bytes of the first packet taken into account, enough for the request and the random of a tls client hello
+const MAX_KEY_LEN: usize = 512;
+
+// first packets seen within the window, a captured one sent again is a replay. only packets carrying a tls
+// client hello are remembered, as its 32 random bytes make real clients never send the same one twice
+#[derive(Default)]
+pub struct ReplayCache {
+    window: Duration,
+    capacity: usize,
+    state: RandomState,
+    seen: HashSet<u64>,
+    queue: VecDeque<(Instant, u64)>,
+}
+
+impl ReplayCache {
+    pub fn new(window: Duration, capacity: usize) -> ReplayCache {
+        ReplayCache {
+            window,
+            capacity,
+            state: RandomState::new(),
+            seen: HashSet::new(),
+            queue: VecDeque::new(),
+        }
+    }
+
+    // data is the whole first packet with the password hash, payload the part after the request
+    pub fn check(&mut self, data: &[u8], payload: &[u8], now: Instant) -> bool {
+        if self.window.as_secs() == 0 || self.capacity == 0 || !is_client_hello(payload) {
+            return false;
+        }
+        while let Some((time, key)) = self.queue.front() {
+            if now - *time <= self.window && self.queue.len() < self.capacity {
+                break;
+            }
+            self.seen.remove(key);
+            self.queue.pop_front();
+        }
+        let mut hasher = self.state.build_hasher();
+        data[..data.len().min(MAX_KEY_LEN)].hash(&mut hasher);
+        let key = hasher.finish();
+        if !self.seen.insert(key) {
+            return true;
+        }
+        self.queue.push_back((now, key));
+        false
+    }
+}
+
+// a handshake record with a client hello, long enough for the random
+fn is_client_hello(payload: &[u8]) -> bool {
+    payload.len() >= 43 && payload[0] == 0x16 && payload[1] == 0x03 && payload[5] == 0x01
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(random: u8) -> Vec<u8> {
+        let mut payload = vec![0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00, 0x01, 0xfc, 0x03, 0x03];
+        payload.resize(43, random);
+        payload
+    }
+
+    fn packet(payload: &[u8]) -> Vec<u8> {
+        let mut data = b"hash\r\n\x01\x01\x7f\x00\x00\x01\x01\xbb\r\n".to_vec();
+        data.extend_from_slice(payload);
+        data
+    }
+
+    #[test]
+    fn replay_within_window() {
+        let mut cache = ReplayCache::new(Duration::from_secs(60), 16);
+        let now = Instant::now();
+        let first = hello(1);
+        assert!(!cache.check(&packet(&first), &first, now));
+        assert!(cache.check(&packet(&first), &first, now + Duration::from_secs(59)));
+        let second = hello(2);
+        assert!(!cache.check(&packet(&second), &second, now + Duration::from_secs(59)));
+    }
+
+    #[test]
+    fn ignore_other_payloads() {
+        let mut cache = ReplayCache::new(Duration::from_secs(60), 16);
+        let now = Instant::now();
+        let mut short = hello(1);
+        short.truncate(42);
+        let mut server_hello = hello(1);
+        server_hello[5] = 0x02;
+        let mut application_data = hello(1);
+        application_data[0] = 0x17;
+        for payload in [short, server_hello, application_data, b"GET / HTTP/1.1\r\n\r\n".to_vec()].iter() {
+            assert!(!cache.check(&packet(payload), payload, now));
+            assert!(!cache.check(&packet(payload), payload, now));
+        }
+        assert!(cache.seen.is_empty());
+        // a cache turned off remembers nothing either
+        let mut cache = ReplayCache::new(Duration::from_secs(0), 16);
+        let payload = hello(1);
+        assert!(!cache.check(&packet(&payload), &payload, now));
+        assert!(!cache.check(&packet(&payload), &payload, now));
+    }
+
+    #[test]
+    fn expire_by_window_and_capacity() {
+        let mut cache = ReplayCache::new(Duration::from_secs(60), 2);
+        let now = Instant::now();
+        let first = hello(1);
+        assert!(!cache.check(&packet(&first), &first, now));
+        // still a replay at the end of the window, forgotten after it
+        assert!(cache.check(&packet(&first), &first, now + Duration::from_secs(60)));
+        assert!(!cache.check(&packet(&first), &first, now + Duration::from_secs(61)));
+        let later = now + Duration::from_secs(61);
+        let second = hello(2);
+        let third = hello(3);
+        assert!(!cache.check(&packet(&second), &second, later));
+        // the cache is full, so the oldest packet makes room for the third
+        assert!(!cache.check(&packet(&third), &third, later));
+        assert_eq!(cache.queue.len(), 2);
+        assert!(cache.check(&packet(&third), &third, later));
+        // the check made room again, the second is gone
+        assert!(!cache.check(&packet(&second), &second, later));
+    }
+}
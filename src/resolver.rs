@@ -0,0 +1,142 @@
+use std::io::{Error, Result as IoResult};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use mio::{Evented, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+#[cfg(not(test))]
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::Resolver;
+use trust_dns_resolver::system_conf::read_system_conf;
+
+use crate::config::IpStrategy;
+
+#[derive(Default)]
+struct ResolveResult {
+    addresses: Vec<IpAddr>,
+    valid_until: Option<Instant>,
+    // nxdomain, or no records of the types asked for
+    no_records: bool,
+}
+
+// where the resolving thread puts the result, the tests answer lookups from a trace instead
+pub struct Answer {
+    result: Arc<Mutex<ResolveResult>>,
+    set_readiness: SetReadiness,
+}
+
+impl Answer {
+    pub fn send(self, addresses: Vec<IpAddr>, valid_until: Option<Instant>, no_records: bool) {
+        {
+            let mut result = self.result.lock().unwrap();
+            result.addresses = addresses;
+            result.valid_until = valid_until;
+            result.no_records = no_records;
+        }
+        if let Err(err) = self.set_readiness.set_readiness(Ready::readable()) {
+            log::error!("set readiness failed:{}", err);
+        }
+    }
+}
+
+pub struct EventedResolver {
+    registration: Registration,
+    result: Arc<Mutex<ResolveResult>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+pub fn new_resolver(strategy: IpStrategy) -> IoResult<Resolver> {
+    let (config, mut opts) = read_system_conf()?;
+    opts.ip_strategy = strategy.lookup_strategy();
+    Resolver::new(config, opts)
+}
+
+impl EventedResolver {
+    pub fn new(mut domain: String, strategy: IpStrategy) -> EventedResolver {
+        if !domain.ends_with(".") {
+            domain.push('.');
+        }
+        let (registration, set_readiness) = Registration::new2();
+        let result = Arc::new(Mutex::new(ResolveResult::default()));
+        let answer = Answer {
+            result: result.clone(),
+            set_readiness,
+        };
+        #[cfg(not(test))]
+        let handle = Some(std::thread::spawn(move || lookup(domain, strategy, answer)));
+        #[cfg(test)]
+        let handle = {
+            crate::replay::lookup(domain, strategy, answer);
+            None
+        };
+        EventedResolver {
+            registration,
+            result,
+            handle,
+        }
+    }
+
+    pub fn address(&self) -> Option<IpAddr> {
+        self.result.lock().unwrap().addresses.first().cloned()
+    }
+
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        self.result.lock().unwrap().addresses.clone()
+    }
+
+    pub fn valid_until(&self) -> Option<Instant> {
+        self.result.lock().unwrap().valid_until
+    }
+
+    pub fn no_records(&self) -> bool {
+        self.result.lock().unwrap().no_records
+    }
+}
+
+#[cfg(not(test))]
+fn lookup(domain: String, strategy: IpStrategy, answer: Answer) {
+    let mut addresses = Vec::new();
+    let mut valid_until = None;
+    let mut no_records = false;
+    if let Ok(resolver) = new_resolver(strategy) {
+        match resolver.lookup_ip(domain.as_str()) {
+            Ok(response) => {
+                valid_until.replace(response.valid_until());
+                addresses.extend(response.iter());
+                strategy.sort(&mut addresses);
+            }
+            Err(err) => {
+                if let ResolveErrorKind::NoRecordsFound { valid_until: until, .. } = err.kind() {
+                    no_records = true;
+                    valid_until = *until;
+                }
+            }
+        }
+    }
+    answer.send(addresses, valid_until, no_records);
+}
+
+impl Evented for EventedResolver {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<(), Error> {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<(), Error> {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    #[allow(deprecated)]
+    fn deregister(&self, poll: &Poll) -> Result<(), Error> {
+        self.registration.deregister(poll)
+    }
+}
+
+impl Drop for EventedResolver {
+    fn drop(&mut self) {
+        //FIXME is this necessary?
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
\ No newline at end of file
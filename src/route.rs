@@ -0,0 +1,241 @@
+use std::fmt::Write;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use maxminddb::geoip2::Country;
+use maxminddb::Reader;
+
+use crate::cidr::Cidr;
+use crate::config::ProxyArgs;
+use crate::geosite::DomainSet;
+use crate::pac;
+
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub enum Action {
+    #[default]
+    Proxy,
+    Direct,
+    Block,
+    // through the trojan server named by the outbound of the router at this index, proxy:name in route files
+    Outbound(usize),
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "proxy" => Ok(Action::Proxy),
+            "direct" => Ok(Action::Direct),
+            "block" => Ok(Action::Block),
+            _ => Err(format!("invalid route action:{}", s)),
+        }
+    }
+}
+
+enum Rule {
+    DomainFull(String),
+    DomainSuffix(String),
+    DomainKeyword(String),
+    GeoSite(DomainSet),
+    IpCidr(Cidr),
+    GeoIp(String),
+    Port(u16, u16),
+}
+
+impl Rule {
+    fn parse(kind: &str, value: &str) -> std::result::Result<Rule, String> {
+        let rule = match kind {
+            "domain" => Rule::DomainFull(value.to_lowercase()),
+            "domain-suffix" => Rule::DomainSuffix(value.trim_start_matches('.').to_lowercase()),
+            "domain-keyword" => Rule::DomainKeyword(value.to_lowercase()),
+            "ip-cidr" => Rule::IpCidr(value.parse()?),
+            "geoip" => Rule::GeoIp(value.to_uppercase()),
+            "port" => {
+                let (start, end) = if let Some(pos) = value.find('-') {
+                    (&value[..pos], &value[pos + 1..])
+                } else {
+                    (value, value)
+                };
+                let start = start.parse::<u16>().map_err(|_| format!("invalid port:{}", value))?;
+                let end = end.parse::<u16>().map_err(|_| format!("invalid port:{}", value))?;
+                Rule::Port(start, end)
+            }
+            _ => return Err(format!("invalid rule type:{}", kind)),
+        };
+        Ok(rule)
+    }
+
+    fn matches(&self, domain: Option<&str>, ip: Option<&IpAddr>, port: Option<u16>, geoip: Option<&Reader<Vec<u8>>>) -> bool {
+        match (self, domain, ip, port) {
+            (Rule::DomainFull(full), Some(domain), _, _) => full == domain,
+            (Rule::DomainSuffix(suffix), Some(domain), _, _) => {
+                domain.ends_with(suffix.as_str()) && (domain.len() == suffix.len() || domain[..domain.len() - suffix.len()].ends_with('.'))
+            }
+            (Rule::DomainKeyword(keyword), Some(domain), _, _) => domain.contains(keyword.as_str()),
+            (Rule::GeoSite(set), Some(domain), _, _) => set.contains(domain),
+            (Rule::IpCidr(cidr), _, Some(ip), _) => cidr.contains(ip),
+            (Rule::GeoIp(code), _, Some(ip), _) => match geoip.map(|geoip| geoip.lookup::<Country>(*ip)) {
+                Some(Ok(country)) => country.country.and_then(|country| country.iso_code).map_or(false, |iso_code| iso_code == code.as_str()),
+                _ => false,
+            },
+            (Rule::Port(start, end), _, _, Some(port)) => port >= *start && port <= *end,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Router {
+    rules: Vec<(Rule, Action)>,
+    default_action: Action,
+    // labels or hostnames of trojan servers chosen by rules
+    outbounds: Vec<String>,
+    geoip: Option<Reader<Vec<u8>>>,
+    blocklist: DomainSet,
+    files: Vec<(String, Option<SystemTime>)>,
+}
+
+impl Router {
+    pub fn load(args: &ProxyArgs) -> Result<Router> {
+        let mut router = Router::default();
+        let files = args.route_file.iter().chain(args.geoip_file.iter()).chain(args.geosite_file.iter()).chain(args.block_list.iter());
+        for file in files {
+            router.files.push((file.clone(), modified_time(file)));
+        }
+        if let Some(geoip_path) = args.geoip_file.as_ref() {
+            let geoip = Reader::open_readfile(geoip_path)
+                .map_err(|err| Error::new(ErrorKind::InvalidData, format!("load geoip database {} failed:{}", geoip_path, err)))?;
+            router.geoip.replace(geoip);
+        }
+        if let Some(route_file) = args.route_file.as_ref() {
+            router.load_rules(route_file, args.geosite_file.as_ref())?;
+        }
+        for path in &args.block_list {
+            router.blocklist.load_list(path)?;
+        }
+        Ok(router)
+    }
+
+    fn load_rules(&mut self, path: &str, geosite_path: Option<&String>) -> Result<()> {
+        let file = File::open(path)?;
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+            let result = match fields.as_slice() {
+                ["final", action] => self.action(action).map(|action| self.default_action = action),
+                ["geoip", _, _] if self.geoip.is_none() => Err("geoip rule requires a geoip database".to_string()),
+                ["geosite", code, action] => match geosite_path {
+                    Some(geosite_path) => DomainSet::load(geosite_path, code)
+                        .map_err(|err| err.to_string())
+                        .and_then(|set| self.action(action).map(|action| self.rules.push((Rule::GeoSite(set), action)))),
+                    None => Err("geosite rule requires a geosite file".to_string()),
+                },
+                [kind, value, action] => Rule::parse(kind, value)
+                    .and_then(|rule| self.action(action).map(|action| self.rules.push((rule, action)))),
+                _ => Err("invalid rule format".to_string()),
+            };
+            if let Err(err) = result {
+                return Err(Error::new(ErrorKind::InvalidData, format!("{} line {}:{}", path, i + 1, err)));
+            }
+        }
+        log::warn!("{} route rules loaded from {}", self.rules.len(), path);
+        Ok(())
+    }
+
+    // proxy:name sends to the trojan server with the label or hostname name instead of the one the balance chooses
+    fn action(&mut self, action: &str) -> std::result::Result<Action, String> {
+        if !action.starts_with("proxy:") {
+            return action.parse();
+        }
+        let name = &action["proxy:".len()..];
+        if name.is_empty() {
+            return Err(format!("invalid route action:{}", action));
+        }
+        let index = match self.outbounds.iter().position(|outbound| outbound == name) {
+            Some(index) => index,
+            None => {
+                self.outbounds.push(name.to_string());
+                self.outbounds.len() - 1
+            }
+        };
+        Ok(Action::Outbound(index))
+    }
+
+    pub fn outbounds(&self) -> &[String] {
+        self.outbounds.as_slice()
+    }
+
+    pub fn route(&self, domain: Option<&str>, ip: Option<&IpAddr>, port: Option<u16>) -> Action {
+        let domain = domain.map(|domain| domain.trim_end_matches('.').to_lowercase());
+        if domain.as_ref().map_or(false, |domain| self.blocklist.contains(domain)) {
+            return Action::Block;
+        }
+        for (rule, action) in &self.rules {
+            if rule.matches(domain.as_deref(), ip, port, self.geoip.as_ref()) {
+                return *action;
+            }
+        }
+        self.default_action
+    }
+
+    // the rules as javascript data for the proxy auto-config file, ipv6 ranges are left out, as ipv6 hosts always go
+    // to the proxy there, and block is sent to the proxy like proxy
+    pub fn write_pac(&self, out: &mut String) {
+        out.push_str("var DEFAULT = ");
+        out.push_str(if self.default_action == Action::Direct { "true" } else { "false" });
+        out.push_str(";\nvar BLOCK = ");
+        self.blocklist.write_pac(out);
+        out.push_str(";\nvar RULES = [");
+        let mut first = true;
+        for (rule, action) in &self.rules {
+            let (kind, value) = match rule {
+                Rule::DomainFull(domain) => ("domain", quoted(domain)),
+                Rule::DomainSuffix(suffix) => ("domain-suffix", quoted(suffix)),
+                Rule::DomainKeyword(keyword) => ("domain-keyword", quoted(keyword)),
+                Rule::GeoSite(set) => {
+                    let mut value = String::new();
+                    set.write_pac(&mut value);
+                    ("geosite", value)
+                }
+                Rule::IpCidr(cidr) => match cidr.addr() {
+                    IpAddr::V4(ip) => {
+                        let mut value = String::new();
+                        pac::write_net(&mut value, ip, cidr.prefix());
+                        ("ip-cidr", value)
+                    }
+                    IpAddr::V6(_) => continue,
+                },
+                Rule::GeoIp(_) => ("geoip", "null".to_string()),
+                Rule::Port(start, end) => ("port", format!("[{},{}]", start, end)),
+            };
+            if !first {
+                out.push(',');
+            }
+            first = false;
+            let _ = write!(out, "\n[\"{}\",{},{}]", kind, value, *action == Action::Direct);
+        }
+        out.push_str("];\n");
+    }
+
+    pub fn changed(&self) -> bool {
+        self.files.iter().any(|(path, time)| modified_time(path) != *time)
+    }
+}
+
+fn quoted(value: &str) -> String {
+    let mut out = String::new();
+    pac::quote(&mut out, value);
+    out
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
@@ -0,0 +1,267 @@
+use crate::config::Opts;
+#[cfg(target_os = "linux")]
+use crate::config::{LogRotate, Mode};
+
+#[cfg(target_os = "linux")]
+use std::fs::OpenOptions;
+#[cfg(target_os = "linux")]
+use std::io::{Error, Result};
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::OpenOptionsExt;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+#[cfg(target_os = "linux")]
+use std::path::Path;
+
+// landlock syscalls share the numbers on all architectures
+#[cfg(target_os = "linux")]
+const SYS_LANDLOCK_CREATE_RULESET: libc::c_long = 444;
+#[cfg(target_os = "linux")]
+const SYS_LANDLOCK_ADD_RULE: libc::c_long = 445;
+#[cfg(target_os = "linux")]
+const SYS_LANDLOCK_RESTRICT_SELF: libc::c_long = 446;
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+const SYS_CLONE3: libc::c_long = 435;
+#[cfg(target_os = "linux")]
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+#[cfg(target_os = "linux")]
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+#[cfg(target_os = "linux")]
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+#[cfg(target_os = "linux")]
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+#[cfg(target_os = "linux")]
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+#[cfg(target_os = "linux")]
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+// every right of landlock abi v1, from EXECUTE to MAKE_SYM
+#[cfg(target_os = "linux")]
+const ACCESS_FS_ALL: u64 = (1 << 13) - 1;
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+const SECCOMP_SET_MODE_FILTER: libc::c_long = 1;
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+const SECCOMP_FILTER_FLAG_TSYNC: libc::c_long = 1;
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+const BPF_LD_W_ABS: u16 = 0x20;
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+const BPF_JEQ_K: u16 = 0x15;
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+const BPF_RET_K: u16 = 0x06;
+// offsets of nr and arch in struct seccomp_data
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+const SECCOMP_DATA_NR: u32 = 0;
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+const SECCOMP_DATA_ARCH: u32 = 4;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+#[repr(C)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+#[repr(C)]
+struct SockFprog {
+    len: u16,
+    filter: *const SockFilter,
+}
+
+// what the relay loops, resolver threads, logging and file reloading call after startup
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_read, libc::SYS_write, libc::SYS_readv, libc::SYS_writev, libc::SYS_close, libc::SYS_openat,
+    libc::SYS_lseek, libc::SYS_fstat, libc::SYS_newfstatat, libc::SYS_statx, libc::SYS_getdents64,
+    libc::SYS_unlinkat, libc::SYS_renameat, libc::SYS_renameat2, libc::SYS_fsync, libc::SYS_msync, libc::SYS_ftruncate, libc::SYS_fcntl, libc::SYS_ioctl,
+    libc::SYS_mmap, libc::SYS_munmap, libc::SYS_mprotect, libc::SYS_mremap, libc::SYS_madvise, libc::SYS_brk,
+    libc::SYS_futex, libc::SYS_clone, SYS_CLONE3, libc::SYS_set_robust_list, libc::SYS_exit, libc::SYS_exit_group,
+    libc::SYS_sched_yield, libc::SYS_sched_getaffinity, libc::SYS_getpid, libc::SYS_gettid, libc::SYS_tgkill,
+    libc::SYS_rt_sigaction, libc::SYS_rt_sigprocmask, libc::SYS_rt_sigreturn, libc::SYS_sigaltstack,
+    libc::SYS_nanosleep, libc::SYS_clock_nanosleep, libc::SYS_clock_gettime, libc::SYS_gettimeofday,
+    libc::SYS_getrandom, libc::SYS_prctl, libc::SYS_uname, libc::SYS_restart_syscall,
+    libc::SYS_epoll_create1, libc::SYS_epoll_ctl, libc::SYS_epoll_pwait, libc::SYS_eventfd2, libc::SYS_pipe2,
+    libc::SYS_ppoll, libc::SYS_socket, libc::SYS_socketpair, libc::SYS_connect, libc::SYS_accept4, libc::SYS_bind,
+    libc::SYS_listen, libc::SYS_getsockname, libc::SYS_getpeername, libc::SYS_setsockopt, libc::SYS_getsockopt,
+    libc::SYS_sendto, libc::SYS_recvfrom, libc::SYS_sendmsg, libc::SYS_recvmsg, libc::SYS_sendmmsg,
+    libc::SYS_recvmmsg, libc::SYS_shutdown,
+    #[cfg(target_arch = "x86_64")] libc::SYS_open,
+    #[cfg(target_arch = "x86_64")] libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")] libc::SYS_lstat,
+    #[cfg(target_arch = "x86_64")] libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")] libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")] libc::SYS_accept,
+    #[cfg(target_arch = "x86_64")] libc::SYS_pipe,
+    #[cfg(target_arch = "x86_64")] libc::SYS_unlink,
+    #[cfg(target_arch = "x86_64")] libc::SYS_rename,
+    #[cfg(target_arch = "x86_64")] libc::SYS_arch_prctl,
+];
+
+// confines trojan once it is set up, so that an exploited relay can not run programs or write files
+pub fn apply(opts: &Opts) {
+    if !opts.sandbox {
+        return;
+    }
+    if let Err(err) = apply_landlock(opts) {
+        log::error!("landlock sandbox failed:{}", err);
+        std::process::exit(1);
+    }
+    if let Err(err) = apply_seccomp() {
+        log::error!("seccomp sandbox failed:{}", err);
+        std::process::exit(1);
+    }
+    log::warn!("sandbox applied");
+}
+
+// the whole file system is readable, only the log files are writable and the pid file removable, nothing is executable
+#[cfg(target_os = "linux")]
+fn apply_landlock(opts: &Opts) -> Result<()> {
+    let attr = RulesetAttr {
+        handled_access_fs: ACCESS_FS_ALL,
+    };
+    let fd = unsafe { libc::syscall(SYS_LANDLOCK_CREATE_RULESET, &attr as *const RulesetAttr, std::mem::size_of::<RulesetAttr>(), 0) };
+    if fd < 0 {
+        let err = Error::last_os_error();
+        let errno = err.raw_os_error();
+        if errno == Some(libc::ENOSYS) || errno == Some(libc::EOPNOTSUPP) {
+            log::warn!("landlock is not supported by the kernel, file system is not restricted");
+            return Ok(());
+        }
+        return Err(err);
+    }
+    let ruleset = unsafe { std::fs::File::from_raw_fd(fd as RawFd) };
+    add_path_rule(&ruleset, Path::new("/"), ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR)?;
+    if let Some(log_file) = opts.log_file.as_ref() {
+        if opts.log_max_size > 0 || opts.log_rotate != LogRotate::Never {
+            // rotation renames, creates and removes files next to the log file
+            add_path_rule(&ruleset, parent_dir(log_file), ACCESS_FS_WRITE_FILE | ACCESS_FS_MAKE_REG | ACCESS_FS_REMOVE_FILE)?;
+        } else {
+            add_path_rule(&ruleset, Path::new(log_file), ACCESS_FS_WRITE_FILE)?;
+        }
+    }
+    if let Some(access_log) = opts.access_log.as_ref() {
+        add_path_rule(&ruleset, Path::new(access_log), ACCESS_FS_WRITE_FILE)?;
+    }
+    if let Some(security_log) = opts.security_log.as_ref() {
+        add_path_rule(&ruleset, Path::new(security_log), ACCESS_FS_WRITE_FILE)?;
+    }
+    if let Mode::Server(ref args) = opts.mode {
+        if let Some(blocklist_file) = args.blocklist_file.as_ref() {
+            // replaced by renaming a new file
+            add_path_rule(&ruleset, parent_dir(blocklist_file), ACCESS_FS_WRITE_FILE | ACCESS_FS_MAKE_REG | ACCESS_FS_REMOVE_FILE)?;
+        }
+        if let Some(stats_file) = args.stats_file.as_ref() {
+            add_path_rule(&ruleset, Path::new(stats_file), ACCESS_FS_WRITE_FILE)?;
+        }
+        if let Some(admin_socket) = args.admin_socket.as_ref() {
+            add_path_rule(&ruleset, parent_dir(admin_socket), ACCESS_FS_REMOVE_FILE)?;
+        }
+    }
+    if let Mode::Proxy(ref args) = opts.mode {
+        if let Some(state_file) = args.state_file.as_ref() {
+            // replaced by renaming a new file
+            add_path_rule(&ruleset, parent_dir(state_file), ACCESS_FS_WRITE_FILE | ACCESS_FS_MAKE_REG | ACCESS_FS_REMOVE_FILE)?;
+        }
+    }
+    if let Some(pid_file) = opts.pid_file.as_ref() {
+        add_path_rule(&ruleset, parent_dir(pid_file), ACCESS_FS_REMOVE_FILE)?;
+    }
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    if unsafe { libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset.as_raw_fd(), 0) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn parent_dir(path: &str) -> &Path {
+    match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn add_path_rule(ruleset: &std::fs::File, path: &Path, access: u64) -> Result<()> {
+    let parent = OpenOptions::new().read(true).custom_flags(libc::O_PATH | libc::O_CLOEXEC).open(path)?;
+    let attr = PathBeneathAttr {
+        allowed_access: access,
+        parent_fd: parent.as_raw_fd(),
+    };
+    if unsafe { libc::syscall(SYS_LANDLOCK_ADD_RULE, ruleset.as_raw_fd(), LANDLOCK_RULE_PATH_BENEATH, &attr as *const PathBeneathAttr, 0) } != 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+// syscalls out of the list fail with EPERM, and those of other abis like x32 kill the process
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn apply_seccomp() -> Result<()> {
+    let mut filter = vec![
+        SockFilter { code: BPF_LD_W_ABS, jt: 0, jf: 0, k: SECCOMP_DATA_ARCH },
+        SockFilter { code: BPF_JEQ_K, jt: 1, jf: 0, k: AUDIT_ARCH },
+        SockFilter { code: BPF_RET_K, jt: 0, jf: 0, k: SECCOMP_RET_KILL_PROCESS },
+        SockFilter { code: BPF_LD_W_ABS, jt: 0, jf: 0, k: SECCOMP_DATA_NR },
+    ];
+    for nr in ALLOWED_SYSCALLS {
+        filter.push(SockFilter { code: BPF_JEQ_K, jt: 0, jf: 1, k: *nr as u32 });
+        filter.push(SockFilter { code: BPF_RET_K, jt: 0, jf: 0, k: SECCOMP_RET_ALLOW });
+    }
+    filter.push(SockFilter { code: BPF_RET_K, jt: 0, jf: 0, k: SECCOMP_RET_ERRNO | libc::EPERM as u32 });
+    let prog = SockFprog {
+        len: filter.len() as u16,
+        filter: filter.as_ptr(),
+    };
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    // tsync puts threads started during setup under the filter as well
+    if unsafe { libc::syscall(libc::SYS_seccomp, SECCOMP_SET_MODE_FILTER, SECCOMP_FILTER_FLAG_TSYNC, &prog as *const SockFprog) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(all(target_os = "linux", not(any(target_arch = "x86_64", target_arch = "aarch64"))))]
+fn apply_seccomp() -> Result<()> {
+    log::warn!("seccomp is not supported on this architecture, syscalls are not restricted");
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn apply_landlock(_opts: &Opts) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Other, "sandbox is only supported on linux"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn apply_seccomp() -> std::io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,34 @@
+use std::net::IpAddr;
+
+use crate::webhook;
+
+// records of security events go through the logger with this target, which routes them to --security-log
+pub const TARGET: &str = "security";
+
+// events are logged as "<time> <event> ip=<ip> <details>", so that fail2ban filters match them by a fixed prefix
+pub enum Event {
+    AuthFailure,
+    AuthDenied,
+    Replay,
+    MalformedHandshake,
+    Ban,
+    Unban,
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Event::AuthFailure => "auth_failure",
+            Event::AuthDenied => "auth_denied",
+            Event::Replay => "replay",
+            Event::MalformedHandshake => "malformed_handshake",
+            Event::Ban => "ban",
+            Event::Unban => "unban",
+        }
+    }
+}
+
+pub fn write(event: Event, ip: IpAddr, details: &str) {
+    log::warn!(target: TARGET, "{} ip={} {}", event.name(), ip, details);
+    webhook::security_event(event.name(), ip, details);
+}
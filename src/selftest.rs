@@ -0,0 +1,199 @@
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use clap::{App, AppSettings, FromArgMatches};
+use clap::derive::IntoApp;
+use rustls::{Certificate, ClientConfig, ClientSession, StreamOwned};
+use trojan_proto::{Address, Request, UdpPacket};
+use webpki::DNSNameRef;
+
+use crate::config::{self, Opts, SelftestArgs};
+use crate::proto::{CONNECT, UDP_ASSOCIATE};
+use crate::server;
+
+const SERVER_NAME: &str = "localhost";
+const PASSWORD: &str = "selftest";
+
+// a server with a temporary self-signed certificate runs in a thread, traffic is relayed through it to local echo servers
+pub fn run(args: &SelftestArgs) -> ! {
+    let dir = std::env::temp_dir().join(format!("trojan-selftest-{}", std::process::id()));
+    let ok = match prepare(&dir) {
+        Ok(cert) => run_tests(args, &dir, cert),
+        Err(err) => {
+            println!("generate certificate failed:{}", err);
+            false
+        }
+    };
+    let _ = std::fs::remove_dir_all(&dir);
+    if ok {
+        println!("selftest passed");
+        std::process::exit(0);
+    }
+    println!("selftest failed");
+    std::process::exit(1);
+}
+
+// returns the certificate in der, the pem files are written to dir
+fn prepare(dir: &Path) -> Result<Certificate> {
+    let cert = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])
+        .map_err(|err| Error::new(ErrorKind::Other, err.to_string()))?;
+    let cert_pem = cert.serialize_pem().map_err(|err| Error::new(ErrorKind::Other, err.to_string()))?;
+    let cert_der = cert.serialize_der().map_err(|err| Error::new(ErrorKind::Other, err.to_string()))?;
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(dir.join("cert.pem"), cert_pem)?;
+    std::fs::write(dir.join("key.pem"), cert.serialize_private_key_pem())?;
+    Ok(Certificate(cert_der))
+}
+
+fn run_tests(args: &SelftestArgs, dir: &Path, cert: Certificate) -> bool {
+    let timeout = Duration::new(args.timeout, 0);
+    let server_addr = match start_server(dir) {
+        Ok(addr) => addr,
+        Err(err) => {
+            println!("start server failed:{}", err);
+            return false;
+        }
+    };
+    if let Err(err) = wait_server(server_addr, timeout) {
+        println!("server is not listening on {}:{}", server_addr, err);
+        return false;
+    }
+    let mut config = ClientConfig::new();
+    if let Err(err) = config.root_store.add(&cert) {
+        println!("add certificate failed:{:?}", err);
+        return false;
+    }
+    let config = Arc::new(config);
+    let mut ok = true;
+    match test_tcp(server_addr, &config, args.size, timeout) {
+        Ok(duration) => println!("tcp: pass, {} bytes echoed in {}ms", args.size, duration.as_millis()),
+        Err(err) => {
+            println!("tcp: fail, {}", err);
+            ok = false;
+        }
+    }
+    match test_udp(server_addr, &config, timeout) {
+        Ok(duration) => println!("udp: pass, {} packets echoed in {}ms", UDP_PACKETS.len(), duration.as_millis()),
+        Err(err) => {
+            println!("udp: fail, {}", err);
+            ok = false;
+        }
+    }
+    ok
+}
+
+fn start_server(dir: &Path) -> Result<SocketAddr> {
+    // the port is released right before the server binds it
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let argv = vec![
+        "trojan".to_string(),
+        "server".to_string(),
+        "-a".to_string(), addr.to_string(),
+        "-p".to_string(), PASSWORD.to_string(),
+        "-c".to_string(), dir.join("cert.pem").to_string_lossy().into_owned(),
+        "-k".to_string(), dir.join("key.pem").to_string_lossy().into_owned(),
+        "--allow-private".to_string(),
+    ];
+    let app: App = <Opts as IntoApp>::into_app().setting(AppSettings::AllowExternalSubcommands);
+    let mut opts = <Opts as FromArgMatches>::from_arg_matches(&app.get_matches_from(argv));
+    std::thread::spawn(move || {
+        if let Err(err) = opts.setup().and_then(|_| server::run(&mut opts)) {
+            println!("server failed:{}", err);
+        }
+    });
+    Ok(addr)
+}
+
+fn wait_server(addr: SocketAddr, timeout: Duration) -> Result<()> {
+    let start = Instant::now();
+    loop {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(_) => return Ok(()),
+            Err(err) if start.elapsed() >= timeout => return Err(err),
+            Err(_) => std::thread::sleep(Duration::from_millis(50)),
+        }
+    }
+}
+
+fn connect(server_addr: SocketAddr, config: &Arc<ClientConfig>, timeout: Duration) -> Result<StreamOwned<ClientSession, TcpStream>> {
+    let stream = TcpStream::connect_timeout(&server_addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let session = ClientSession::new(config, DNSNameRef::try_from_ascii_str(SERVER_NAME).unwrap());
+    Ok(StreamOwned::new(session, stream))
+}
+
+fn test_tcp(server_addr: SocketAddr, config: &Arc<ClientConfig>, size: usize, timeout: Duration) -> Result<Duration> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let echo_addr = listener.local_addr()?;
+    std::thread::spawn(move || {
+        if let Ok((mut stream, _)) = listener.accept() {
+            let mut reader = stream.try_clone().unwrap();
+            let _ = std::io::copy(&mut reader, &mut stream);
+        }
+    });
+    let start = Instant::now();
+    let mut stream = connect(server_addr, config, timeout)?;
+    let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+    let mut request = Vec::new();
+    Request::write(&mut request, config::sha224(PASSWORD).as_str(), CONNECT, &Address::Socket(echo_addr));
+    stream.write_all(request.as_slice())?;
+    stream.write_all(data.as_slice())?;
+    stream.flush()?;
+    let mut echoed = vec![0u8; size];
+    stream.read_exact(echoed.as_mut_slice())?;
+    if echoed != data {
+        return Err(Error::new(ErrorKind::InvalidData, "echoed data mismatch"));
+    }
+    Ok(start.elapsed())
+}
+
+const UDP_PACKETS: [&[u8]; 3] = [b"trojan", b"selftest", &[0u8; 1400]];
+
+fn test_udp(server_addr: SocketAddr, config: &Arc<ClientConfig>, timeout: Duration) -> Result<Duration> {
+    let socket = UdpSocket::bind("127.0.0.1:0")?;
+    let echo_addr = socket.local_addr()?;
+    std::thread::spawn(move || {
+        let mut buffer = [0u8; 2048];
+        while let Ok((size, addr)) = socket.recv_from(&mut buffer) {
+            let _ = socket.send_to(&buffer[..size], addr);
+        }
+    });
+    let start = Instant::now();
+    let mut stream = connect(server_addr, config, timeout)?;
+    let address = Address::Socket(echo_addr);
+    let mut request = Vec::new();
+    Request::write(&mut request, config::sha224(PASSWORD).as_str(), UDP_ASSOCIATE, &address);
+    for packet in UDP_PACKETS.iter() {
+        UdpPacket::write_header(&mut request, &address, packet.len() as u16);
+        request.extend_from_slice(packet);
+    }
+    stream.write_all(request.as_slice())?;
+    stream.flush()?;
+    let mut received = Vec::new();
+    let mut buffer = [0u8; 4096];
+    for packet in UDP_PACKETS.iter() {
+        loop {
+            match UdpPacket::parse(received.as_slice()) {
+                Ok((echoed, size)) => {
+                    if echoed.address != address || echoed.payload != *packet {
+                        return Err(Error::new(ErrorKind::InvalidData, "echoed packet mismatch"));
+                    }
+                    received.drain(..size);
+                    break;
+                }
+                Err(trojan_proto::Error::Incomplete) => {}
+                Err(err) => return Err(Error::new(ErrorKind::InvalidData, err.to_string())),
+            }
+            let size = stream.read(&mut buffer)?;
+            if size == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed by server"));
+            }
+            received.extend_from_slice(&buffer[..size]);
+        }
+    }
+    Ok(start.elapsed())
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::config::{self, Opts};
+use crate::grpc::{self, Field};
+use crate::password::Password;
+use crate::server::connection::Connection;
+use crate::server::users::Users;
+
+// the stats service of v2ray, also under the package of xray, and the user service of trojan-go, so that the panels
+// speaking either count the traffic of the labeled users
+const STATS_SERVICES: [&str; 2] = ["/v2ray.core.app.stats.command.StatsService/", "/xray.app.stats.command.StatsService/"];
+const TROJAN_GO_SERVICE: &str = "/trojan.api.TrojanServerService/";
+
+// a stat of v2ray, named user>>>label>>>traffic>>>uplink or downlink
+struct Stat {
+    name: String,
+    user: String,
+    uplink: bool,
+    value: u64,
+}
+
+// the response messages of a request message of the method at path
+pub fn call(path: &str, message: &[u8], users: &mut Users, conns: &HashMap<usize, Connection>, opts: &Opts, uptime: Duration)
+            -> Result<Vec<Vec<u8>>, (u32, String)> {
+    let fields = grpc::parse_message(message).ok_or_else(|| (grpc::INVALID_ARGUMENT, "invalid message".to_string()))?;
+    if let Some(service) = STATS_SERVICES.iter().find(|service| path.starts_with(*service)) {
+        return match &path[service.len()..] {
+            "GetStats" => get_stats(&fields, users, conns).map(|response| vec![response]),
+            "QueryStats" => Ok(vec![query_stats(&fields, users, conns)]),
+            "GetSysStats" => {
+                let mut response = Vec::new();
+                grpc::put_varint(&mut response, 10, uptime.as_secs());
+                Ok(vec![response])
+            }
+            _ => Err((grpc::UNIMPLEMENTED, format!("unknown method {}", path))),
+        };
+    }
+    if let Some(method) = path.strip_prefix(TROJAN_GO_SERVICE) {
+        return match method {
+            "ListUsers" => Ok(list_users(users, conns, opts)),
+            "GetUsers" => Ok(vec![get_users(&fields, users, conns, opts)]),
+            "SetUsers" => {
+                let mut response = Vec::new();
+                grpc::put_varint(&mut response, 1, 0);
+                grpc::put_bytes(&mut response, 2, b"users are set by --password-file");
+                Ok(vec![response])
+            }
+            _ => Err((grpc::UNIMPLEMENTED, format!("unknown method {}", path))),
+        };
+    }
+    Err((grpc::UNIMPLEMENTED, format!("unknown method {}", path)))
+}
+
+// the last value of a field wins, as in protobuf
+fn string_field(fields: &[(u32, Field)], number: u32) -> String {
+    fields.iter().rev().find_map(|field| match field {
+        (n, Field::Bytes(bytes)) if *n == number => Some(String::from_utf8_lossy(bytes).into_owned()),
+        _ => None,
+    }).unwrap_or_default()
+}
+
+fn varint_field(fields: &[(u32, Field)], number: u32) -> u64 {
+    fields.iter().rev().find_map(|field| match field {
+        (n, Field::Varint(value)) if *n == number => Some(*value),
+        _ => None,
+    }).unwrap_or(0)
+}
+
+fn stats(users: &Users, conns: &HashMap<usize, Connection>) -> Vec<Stat> {
+    let mut stats = Vec::new();
+    for (user, uplink, downlink) in users.traffic(conns.values()) {
+        for (direction, value) in &[("uplink", uplink), ("downlink", downlink)] {
+            stats.push(Stat {
+                name: format!("user>>>{}>>>traffic>>>{}", user, direction),
+                user: user.to_string(),
+                uplink: *direction == "uplink",
+                value: *value,
+            });
+        }
+    }
+    stats
+}
+
+// the stat as a message, and its value counted from zero again if reset
+fn encode_stat(stat: &Stat, reset: bool, users: &mut Users) -> Vec<u8> {
+    if reset {
+        if stat.uplink {
+            users.reset_traffic(stat.user.as_str(), stat.value, 0);
+        } else {
+            users.reset_traffic(stat.user.as_str(), 0, stat.value);
+        }
+    }
+    let mut message = Vec::new();
+    grpc::put_bytes(&mut message, 1, stat.name.as_bytes());
+    grpc::put_varint(&mut message, 2, stat.value);
+    message
+}
+
+// name is 1 and reset 2 in the request, the stat is 1 in the response
+fn get_stats(fields: &[(u32, Field)], users: &mut Users, conns: &HashMap<usize, Connection>) -> Result<Vec<u8>, (u32, String)> {
+    let name = string_field(fields, 1);
+    let stat = stats(users, conns).into_iter().find(|stat| stat.name == name)
+        .ok_or_else(|| (grpc::NOT_FOUND, format!("{} not found", name)))?;
+    let mut response = Vec::new();
+    grpc::put_bytes(&mut response, 1, encode_stat(&stat, varint_field(fields, 2) != 0, users).as_slice());
+    Ok(response)
+}
+
+// the pattern 1 and the patterns 3 are matched as substrings, the stats of all users are taken without any
+fn query_stats(fields: &[(u32, Field)], users: &mut Users, conns: &HashMap<usize, Connection>) -> Vec<u8> {
+    let patterns: Vec<String> = fields.iter().filter_map(|field| match field {
+        (1, Field::Bytes(bytes)) | (3, Field::Bytes(bytes)) if !bytes.is_empty() => Some(String::from_utf8_lossy(bytes).into_owned()),
+        _ => None,
+    }).collect();
+    let reset = varint_field(fields, 2) != 0;
+    let mut response = Vec::new();
+    for stat in stats(users, conns) {
+        if patterns.is_empty() || patterns.iter().any(|pattern| stat.name.contains(pattern.as_str())) {
+            grpc::put_bytes(&mut response, 1, encode_stat(&stat, reset, users).as_slice());
+        }
+    }
+    response
+}
+
+// the user status of trojan-go, the user with the hash of its password and the traffic of its label, upload being
+// what the client sent
+fn user_status(password: &Password, traffic: &HashMap<String, (u64, u64)>) -> Vec<u8> {
+    let mut user = Vec::new();
+    grpc::put_bytes(&mut user, 2, password.digest.as_bytes());
+    let (upload, download) = password.label.as_ref().and_then(|label| traffic.get(label)).cloned().unwrap_or((0, 0));
+    let mut total = Vec::new();
+    grpc::put_varint(&mut total, 1, upload);
+    grpc::put_varint(&mut total, 2, download);
+    let mut status = Vec::new();
+    grpc::put_bytes(&mut status, 1, user.as_slice());
+    grpc::put_bytes(&mut status, 2, total.as_slice());
+    status
+}
+
+fn traffic(users: &Users, conns: &HashMap<usize, Connection>) -> HashMap<String, (u64, u64)> {
+    users.traffic(conns.values()).into_iter().map(|(user, uplink, downlink)| (user.to_string(), (uplink, downlink))).collect()
+}
+
+// a response for each password, sorted by label
+fn list_users(users: &Users, conns: &HashMap<usize, Connection>, opts: &Opts) -> Vec<Vec<u8>> {
+    let traffic = traffic(users, conns);
+    let mut passwords: Vec<&Password> = opts.passwords().collect();
+    passwords.sort_by(|a, b| (&a.label, &a.digest).cmp(&(&b.label, &b.digest)));
+    passwords.into_iter().map(|password| {
+        let mut response = Vec::new();
+        grpc::put_bytes(&mut response, 1, user_status(password, &traffic).as_slice());
+        response
+    }).collect()
+}
+
+// the user 1 of the request is found by its hash 2 or its password 1, the response has success 1, info 2 and status 3
+fn get_users(fields: &[(u32, Field)], users: &Users, conns: &HashMap<usize, Connection>, opts: &Opts) -> Vec<u8> {
+    let user = fields.iter().rev().find_map(|field| match field {
+        (1, Field::Bytes(bytes)) => grpc::parse_message(bytes),
+        _ => None,
+    }).unwrap_or_default();
+    let mut hash = string_field(user.as_slice(), 2).to_ascii_lowercase();
+    if hash.is_empty() {
+        let password = string_field(user.as_slice(), 1);
+        if !password.is_empty() {
+            hash = config::password_digest(password.as_str()).unwrap_or_default();
+        }
+    }
+    let mut response = Vec::new();
+    match opts.check_pass(hash.as_str()) {
+        Some(password) => {
+            grpc::put_varint(&mut response, 1, 1);
+            grpc::put_bytes(&mut response, 3, user_status(password, &traffic(users, conns)).as_slice());
+        }
+        None => {
+            grpc::put_varint(&mut response, 1, 0);
+            grpc::put_bytes(&mut response, 2, b"user not found");
+        }
+    }
+    response
+}
@@ -0,0 +1,86 @@
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use mio::{Evented, Poll, PollOpt, Ready, Registration, Token};
+
+use crate::config::IpStrategy;
+use crate::http_client;
+
+const MAX_BODY_SIZE: usize = 64 * 1024;
+const WAIT_INTERVAL: Duration = Duration::from_millis(10);
+
+// asks --auth-hook whether an authenticated user may go on from an ip, on a thread of its own, readable once answered.
+// a command is run with the user and the ip as arguments and allows with exit status 0, a url gets a GET request with
+// them in the query and allows with a 2xx status
+pub struct EventedAuthHook {
+    registration: Registration,
+    result: Arc<Mutex<Option<Result<bool>>>>,
+}
+
+impl EventedAuthHook {
+    pub fn new(hook: String, user: String, ip: IpAddr, timeout: Duration, marker: u8, strategy: IpStrategy) -> EventedAuthHook {
+        let (registration, set_readiness) = Registration::new2();
+        let result = Arc::new(Mutex::new(None));
+        let result2 = result.clone();
+        // the thread is not joined, a connection closed in the meantime does not wait for the hook
+        std::thread::spawn(move || {
+            let allowed = if http_client::is_url(hook.as_str()) {
+                call_url(hook.as_str(), user.as_str(), ip, timeout, marker, strategy)
+            } else {
+                run_command(hook.as_str(), user.as_str(), ip, timeout)
+            };
+            result2.lock().unwrap().replace(allowed);
+            let _ = set_readiness.set_readiness(Ready::readable());
+        });
+        EventedAuthHook {
+            registration,
+            result,
+        }
+    }
+
+    pub fn allowed(&self) -> Result<bool> {
+        self.result.lock().unwrap().take().unwrap_or_else(|| Err(Error::new(ErrorKind::Other, "auth hook not answered")))
+    }
+}
+
+fn run_command(command: &str, user: &str, ip: IpAddr, timeout: Duration) -> Result<bool> {
+    let mut child = Command::new(command).arg(user).arg(ip.to_string())
+        .stdin(Stdio::null()).stdout(Stdio::null()).spawn()?;
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status.success());
+        }
+        if start.elapsed() > timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(Error::new(ErrorKind::TimedOut, "auth hook timed out"));
+        }
+        std::thread::sleep(WAIT_INTERVAL);
+    }
+}
+
+fn call_url(url: &str, user: &str, ip: IpAddr, timeout: Duration, marker: u8, strategy: IpStrategy) -> Result<bool> {
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let url = format!("{}{}user={}&ip={}", url, separator, http_client::encode_query(user), http_client::encode_query(ip.to_string().as_str()));
+    let (status, _) = http_client::send(url.as_str(), "GET", None, marker, strategy, timeout, MAX_BODY_SIZE)?;
+    Ok((200..300).contains(&status))
+}
+
+impl Evented for EventedAuthHook {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<()> {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<()> {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    #[allow(deprecated)]
+    fn deregister(&self, poll: &Poll) -> Result<()> {
+        self.registration.deregister(poll)
+    }
+}
@@ -0,0 +1,83 @@
+use std::io::{Read, Result, Write};
+use std::net::Shutdown;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+#[cfg(unix)]
+use mio::unix::EventedFd;
+
+use crate::net::Stream;
+
+// a stream to the target, or to the fallback, which may listen on a unix socket
+pub enum Backend {
+    Tcp(Box<dyn Stream>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Backend {
+    pub fn shutdown(&self, how: Shutdown) -> Result<()> {
+        match self {
+            Backend::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Backend::Unix(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+impl Read for Backend {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            Backend::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Backend::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Backend {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            Backend::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Backend::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Backend::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Backend::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Evented for Backend {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<()> {
+        match self {
+            Backend::Tcp(stream) => stream.register(poll, token, interest, opts),
+            #[cfg(unix)]
+            Backend::Unix(stream) => EventedFd(&stream.as_raw_fd()).register(poll, token, interest, opts),
+        }
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<()> {
+        match self {
+            Backend::Tcp(stream) => stream.reregister(poll, token, interest, opts),
+            #[cfg(unix)]
+            Backend::Unix(stream) => EventedFd(&stream.as_raw_fd()).reregister(poll, token, interest, opts),
+        }
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<()> {
+        match self {
+            Backend::Tcp(stream) => stream.deregister(poll),
+            #[cfg(unix)]
+            Backend::Unix(stream) => EventedFd(&stream.as_raw_fd()).deregister(poll),
+        }
+    }
+}
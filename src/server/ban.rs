@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::security_log::{self, Event};
+
+// clients failing the trojan handshake too often within the window are banned for a while
+pub struct BanList {
+    threshold: usize,
+    window: Duration,
+    ban_duration: Duration,
+    // the start of the window and the failures in it
+    failures: HashMap<IpAddr, (Instant, usize)>,
+    // when the ban ends, and the failures leading to it
+    bans: HashMap<IpAddr, (Instant, usize)>,
+}
+
+impl BanList {
+    pub fn new(threshold: usize, window: Duration, ban_duration: Duration) -> BanList {
+        BanList {
+            threshold,
+            window,
+            ban_duration,
+            failures: HashMap::new(),
+            bans: HashMap::new(),
+        }
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr, now: Instant) -> bool {
+        self.bans.get(ip).map_or(false, |(end_time, _)| *end_time > now)
+    }
+
+    // returns true if the client is banned by this failure
+    pub fn auth_failed(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if self.threshold == 0 || self.is_banned(&ip, now) {
+            return false;
+        }
+        let window = self.window;
+        let entry = self.failures.entry(ip).or_insert((now, 0));
+        if now - entry.0 > window {
+            *entry = (now, 0);
+        }
+        entry.1 += 1;
+        if entry.1 < self.threshold {
+            return false;
+        }
+        let count = entry.1;
+        self.failures.remove(&ip);
+        self.bans.insert(ip, (now + self.ban_duration, count));
+        security_log::write(Event::Ban, ip, format!("failures={} time={}", count, self.ban_duration.as_secs()).as_str());
+        true
+    }
+
+    pub fn unban(&mut self, ip: &IpAddr) -> bool {
+        self.failures.remove(ip);
+        if self.bans.remove(ip).is_none() {
+            return false;
+        }
+        security_log::write(Event::Unban, *ip, "reason=admin");
+        true
+    }
+
+    // returns true if any ban expired
+    pub fn check_timeout(&mut self, now: Instant) -> bool {
+        let window = self.window;
+        self.failures.retain(|_, (start_time, _)| now - *start_time <= window);
+        let count = self.bans.len();
+        self.bans.retain(|ip, (end_time, _)| {
+            if *end_time > now {
+                return true;
+            }
+            log::warn!("ban of {} expired", ip);
+            security_log::write(Event::Unban, *ip, "reason=expired");
+            false
+        });
+        count != self.bans.len()
+    }
+
+    // the banned ips one per line, replaced at once so that readers never see a partial list
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        let mut list = String::new();
+        for ip in self.bans.keys() {
+            let _ = writeln!(list, "{}", ip);
+        }
+        let tmp_path = format!("{}.tmp", path);
+        std::fs::write(tmp_path.as_str(), list)?;
+        std::fs::rename(tmp_path.as_str(), path)
+    }
+
+    pub fn count(&self, now: Instant) -> usize {
+        self.bans.values().filter(|(end_time, _)| *end_time > now).count()
+    }
+
+    // one line per banned client, with the seconds left and the failures
+    pub fn list(&self, now: Instant) -> String {
+        let mut bans: Vec<(&IpAddr, &(Instant, usize))> = self.bans.iter()
+            .filter(|(_, (end_time, _))| *end_time > now)
+            .collect();
+        bans.sort_by_key(|(_, (end_time, _))| *end_time);
+        let mut list = String::new();
+        for (ip, (end_time, count)) in bans {
+            let _ = writeln!(list, "{} {}s {} failures", ip, (*end_time - now).as_secs(), count);
+        }
+        list
+    }
+}
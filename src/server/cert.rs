@@ -0,0 +1,55 @@
+use std::fs::File;
+use std::io::BufReader;
+
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use rustls::internal::pemfile::certs;
+
+// the expiry time of the first certificate in the file, which is the one of the server
+pub fn expiry(path: &str) -> Option<DateTime<Utc>> {
+    let file = File::open(path).ok()?;
+    let chain = certs(&mut BufReader::new(file)).ok()?;
+    not_after(chain.first()?.0.as_slice())
+}
+
+// walks the der encoded certificate down to tbsCertificate.validity.notAfter
+fn not_after(der: &[u8]) -> Option<DateTime<Utc>> {
+    let (_, cert, _) = read_der(der)?;
+    let (_, tbs, _) = read_der(cert)?;
+    // the version is optional and explicitly tagged, the serial number follows
+    let (tag, _, rest) = read_der(tbs)?;
+    let rest = if tag == 0xa0 { read_der(rest)?.2 } else { rest };
+    // signature algorithm and issuer
+    let rest = read_der(rest)?.2;
+    let rest = read_der(rest)?.2;
+    let (_, validity, _) = read_der(rest)?;
+    let rest = read_der(validity)?.2;
+    let (tag, time, _) = read_der(rest)?;
+    let format = match tag {
+        0x17 => "%y%m%d%H%M%SZ",
+        0x18 => "%Y%m%d%H%M%SZ",
+        _ => return None,
+    };
+    let time = NaiveDateTime::parse_from_str(std::str::from_utf8(time).ok()?, format).ok()?;
+    Some(Utc.from_utc_datetime(&time))
+}
+
+// returns the tag, the content and what follows
+fn read_der(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    if data.len() < 2 {
+        return None;
+    }
+    let (len, start) = if data[1] < 0x80 {
+        (data[1] as usize, 2)
+    } else {
+        let count = (data[1] & 0x7f) as usize;
+        if count == 0 || count > 4 || data.len() < 2 + count {
+            return None;
+        }
+        let len = data[2..2 + count].iter().fold(0usize, |len, c| len << 8 | *c as usize);
+        (len, 2 + count)
+    };
+    if data.len() - start < len {
+        return None;
+    }
+    Some((data[0], &data[start..start + len], &data[start + len..]))
+}
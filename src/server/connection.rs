@@ -1,84 +1,326 @@
 use std::io::{Read, Write};
-use std::net::Shutdown;
-use std::net::SocketAddr;
-use std::time::Instant;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
 
 use bytes::{Buf, BytesMut};
 use mio::{Event, Poll, PollOpt, Ready, Token};
-use mio::net::{TcpStream, UdpSocket};
-use rustls::{ServerSession, Session};
+use mio::net::UdpSocket;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use trojan_proto::RequestParser;
 
-use crate::config::Opts;
-use crate::proto::{CONNECT, MAX_UDP_SIZE, Sock5Address, TrojanRequest, UdpAssociate, UdpParseResult};
-use crate::server::resolver::EventedResolver;
+use crate::access_log;
+use crate::cidr;
+use crate::config::{FirstPacketAction, Opts, PortRange};
+use crate::happy_eyeballs::{ConnectResult, HappyEyeballs};
+use crate::net::{self, Stream};
+use crate::padding::Padding;
+use crate::proto::{CONNECT, CONNECT_PADDED, RequestParseResult, Sock5Address, TrojanRequest, UDP_ASSOCIATE, UDP_OVER_TCP, UdpAssociate, UdpParseResult};
+use crate::resolver::EventedResolver;
+use crate::security_log::{self, Event as SecurityEvent};
+use crate::server::auth_hook::EventedAuthHook;
+use crate::server::backend::Backend;
+use crate::server::session::ProxySession;
 use crate::session::TcpSession;
-use crate::sys;
+use crate::{sys, webhook};
 
 enum Status {
     HandShake,
+    AuthWait,
     DnsWait,
+    TCPConnect,
     TCPForward,
     UDPForward,
 }
 
+// where the connection stands with --target-max-connections
+enum TargetSlot {
+    None,
+    // since when it waits for the server to count it, or for a connection to the same target to close
+    Asking(Instant),
+    Queued(Instant),
+    Granted,
+}
+
 pub struct Connection {
     index: usize,
-    proxy: TcpStream,
-    proxy_session: ServerSession,
+    proxy: Box<dyn Stream>,
+    proxy_session: ProxySession,
     target_addr: Option<SocketAddr>,
-    tcp_target: Option<TcpStream>,
+    target_addrs: Vec<SocketAddr>,
+    connector: Option<HappyEyeballs>,
+    tcp_target: Option<Backend>,
     udp_target: Option<UdpSocket>,
+    udp_v6: bool,
     udp_send_buffer: BytesMut,
     udp_recv_head: BytesMut,
     udp_recv_body: Vec<u8>,
     resolver: Option<EventedResolver>,
+    auth_hook: Option<EventedAuthHook>,
+    // the data read while --auth-hook decides, relayed once it allows
+    auth_pending: Vec<u8>,
     target_session: TcpSession,
     closing: bool,
     closed: bool,
+    // each side may finish sending before the other, which is told with a shutdown once its data is written
+    proxy_eof: bool,
+    target_eof: bool,
+    proxy_shutdown: bool,
+    target_shutdown: bool,
+    // plain bytes written to the proxy session since it was last drained
+    proxy_pending: usize,
     proxy_readiness: Ready,
     target_readiness: Ready,
     status: Status,
     sock5_addr: Sock5Address,
     command: u8,
     last_active_time: Instant,
+    // of the last data read from and written to each side, for the idle timeouts of each direction
+    client_read_time: Instant,
+    client_write_time: Instant,
+    target_read_time: Instant,
+    target_write_time: Instant,
+    accept_time: Instant,
+    // when the tls handshake was done, the request is waited for from then on
+    tls_done_time: Option<Instant>,
+    // no request came within --first-packet-timeout, what was read goes to the fallback
+    first_packet_late: bool,
+    peer_ip: Option<IpAddr>,
+    bytes_sent: usize,
+    bytes_received: usize,
+    banned: bool,
+    auth_failed: bool,
+    user: Option<String>,
+    authenticated: bool,
+    // authenticated and allowed by --auth-hook, for the session events of --webhook
+    opened: bool,
+    request_parser: RequestParser,
+    // the reads of a request split across several, for replay detection and the fallback
+    request_data: Vec<u8>,
+    // the frames of a CONNECT_PADDED request
+    padding: Option<Padding>,
+    // a UDP_OVER_TCP request, the client keeps the stream alive, so it is closed by the idle timeout of tcp
+    udp_keepalive: bool,
+    target_slot: TargetSlot,
 }
 
 impl Connection {
-    pub fn new(index: usize, stream: TcpStream, session: ServerSession) -> Connection {
+    pub fn new(index: usize, stream: Box<dyn Stream>, session: ProxySession, banned: bool) -> Connection {
+        let peer_ip = stream.peer_addr().ok().map(|addr| addr.ip());
         Connection {
             index,
             proxy: stream,
             proxy_session: session,
             target_addr: None,
+            target_addrs: Vec::new(),
+            connector: None,
             tcp_target: None,
             udp_target: None,
+            udp_v6: false,
             udp_send_buffer: BytesMut::new(),
-            udp_recv_body: vec![0u8; MAX_UDP_SIZE],
+            udp_recv_body: Vec::new(),
             udp_recv_head: BytesMut::new(),
             resolver: None,
+            auth_hook: None,
+            auth_pending: Vec::new(),
             closing: false,
             closed: false,
+            proxy_eof: false,
+            target_eof: false,
+            proxy_shutdown: false,
+            target_shutdown: false,
+            proxy_pending: 0,
             proxy_readiness: Ready::readable(),
             target_readiness: Ready::readable(),
             status: Status::HandShake,
-            target_session: TcpSession::new(),
+            target_session: TcpSession::new(index),
             command: 0,
             sock5_addr: Sock5Address::None,
-            last_active_time: Instant::now(),
+            last_active_time: net::now(),
+            client_read_time: net::now(),
+            client_write_time: net::now(),
+            target_read_time: net::now(),
+            target_write_time: net::now(),
+            accept_time: net::now(),
+            tls_done_time: None,
+            first_packet_late: false,
+            peer_ip,
+            bytes_sent: 0,
+            bytes_received: 0,
+            banned,
+            auth_failed: false,
+            user: None,
+            authenticated: false,
+            opened: false,
+            request_parser: RequestParser::new(),
+            request_data: Vec::new(),
+            padding: None,
+            udp_keepalive: false,
+            target_slot: TargetSlot::None,
+        }
+    }
+
+    // a connection sending no request in time goes to the fallback with --first-packet-action fallback, otherwise it is
+    // closed
+    pub fn timeout(&mut self, now: Instant, opts: &mut Opts, poll: &Poll) -> bool {
+        if now <= self.deadline(opts) {
+            return false;
+        }
+        if !self.waits_request() || self.proxy_session.is_shadowsocks() || opts.server_args().first_packet_action == FirstPacketAction::Drop {
+            return true;
+        }
+        log::info!("connection:{} sent no request in {}s, pass through", self.index, opts.server_args().first_packet_timeout);
+        self.first_packet_late = true;
+        self.dispatch(&[], opts, poll);
+        self.closing
+    }
+
+    // the tls handshake is done and nothing of the request is read yet
+    fn waits_request(&self) -> bool {
+        if let Status::HandShake = self.status {
+            self.tls_done_time.is_some() && self.request_data.is_empty()
+        } else {
+            false
+        }
+    }
+
+    // when the connection times out unless something moves before, it only gets later while the status stays
+    pub fn deadline(&self, opts: &Opts) -> Instant {
+        if self.proxy_session.is_handshaking() || !self.request_data.is_empty() {
+            // do not let slow handshakes or requests hold the connection for the whole idle timeout
+            self.accept_time + opts.handshake_duration
+        } else if self.waits_request() && opts.server_args().first_packet_timeout > 0 {
+            self.tls_done_time.unwrap() + Duration::new(opts.server_args().first_packet_timeout, 0)
+        } else if let Some(connector) = self.connector.as_ref() {
+            connector.deadline(opts.connect_duration)
+        } else if let TargetSlot::Asking(time) | TargetSlot::Queued(time) = self.target_slot {
+            // waiting for a slot counts against the connect timeout
+            time + opts.connect_duration
+        } else if self.is_udp() && !self.udp_keepalive {
+            self.last_active_time + opts.udp_duration
+        } else {
+            // a direction quiet for long is fine as long as another one moves, like the target of a long poll
+            let idle = &opts.idle_durations;
+            std::cmp::max(std::cmp::max(self.client_read_time + idle.client_read, self.client_write_time + idle.client_write),
+                          std::cmp::max(self.target_read_time + idle.target_read, self.target_write_time + idle.target_write))
+        }
+    }
+
+    pub fn is_udp(&self) -> bool {
+        matches!(self.status, Status::UDPForward)
+    }
+
+    pub fn peer_ip(&self) -> Option<IpAddr> {
+        self.peer_ip
+    }
+
+    fn security_event(&self, event: SecurityEvent, details: &str) {
+        if let Some(ip) = self.peer_ip {
+            security_log::write(event, ip, format!("conn={} {}", self.index, details).trim_end());
         }
     }
 
-    pub fn timeout(&self, recent_active_time: Instant) -> bool {
-        self.last_active_time < recent_active_time
+    // true once after the trojan handshake failed
+    pub fn take_auth_failed(&mut self) -> bool {
+        std::mem::replace(&mut self.auth_failed, false)
+    }
+
+    // true once after the trojan handshake succeeded
+    pub fn take_authenticated(&mut self) -> bool {
+        std::mem::replace(&mut self.authenticated, false)
+    }
+
+    // the label of the password used
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    pub fn bytes_sent(&self) -> usize {
+        self.bytes_sent
+    }
+
+    pub fn bytes_received(&self) -> usize {
+        self.bytes_received
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn last_active_time(&self) -> Instant {
+        self.last_active_time
+    }
+
+    // bytes waiting to be written to either side
+    pub fn queued(&self) -> usize {
+        self.proxy_pending + self.target_session.pending() + self.udp_send_buffer.len()
+    }
+
+    // the target to count against --target-max-connections, once the request is in
+    pub fn asks_target_slot(&self) -> Option<String> {
+        if let TargetSlot::Asking(_) = self.target_slot {
+            Some(self.sock5_addr.to_string())
+        } else {
+            None
+        }
+    }
+
+    // the target the server counts the connection for, connected or waiting
+    pub fn target_slot(&self) -> Option<String> {
+        match self.target_slot {
+            TargetSlot::Queued(_) | TargetSlot::Granted => Some(self.sock5_addr.to_string()),
+            _ => None,
+        }
+    }
+
+    pub fn queue_target(&mut self) {
+        if let TargetSlot::Asking(time) = self.target_slot {
+            log::info!("connection:{} target {} is at the connection limit, wait for a slot", self.index, self.sock5_addr);
+            self.target_slot = TargetSlot::Queued(time);
+        }
+    }
+
+    // connects to the target with the request data buffered so far
+    pub fn grant_target(&mut self, opts: &mut Opts, poll: &Poll) {
+        self.target_slot = TargetSlot::Granted;
+        self.dispatch(&[], opts, poll);
+        if self.closing {
+            self.close_now(poll);
+        }
+    }
+
+    pub fn is_racing(&self) -> bool {
+        self.connector.as_ref().map_or(false, |connector| connector.is_racing())
+    }
+
+    pub fn check_racing(&mut self, now: Instant, poll: &Poll) {
+        let token = self.target_token();
+        if let Some(connector) = self.connector.as_mut() {
+            if !connector.check_timeout(now, poll, token) {
+                log::warn!("connection:{} connect to target failed", self.index);
+                self.closing = true;
+            }
+        }
+        if self.closing {
+            self.close_now(poll);
+        }
     }
 
     pub fn close_now(&mut self, poll: &Poll) {
         log::info!("connection:{} is closing", self.index);
+        if !self.closed {
+            self.write_access_log();
+        }
         self.closed = true;
 
-        let _ = poll.deregister(&self.proxy);
+        let _ = poll.deregister(&*self.proxy);
         let _ = self.proxy.shutdown(Shutdown::Both);
 
+        if let Some(mut connector) = self.connector.take() {
+            connector.close(poll);
+        }
+
         if self.tcp_target.is_some() {
             let tcp_target = self.tcp_target.as_ref().unwrap();
             let _ = poll.deregister(tcp_target);
@@ -90,8 +332,37 @@ impl Connection {
         }
     }
 
+    fn write_access_log(&self) {
+        // the requested domain rather than the address it is resolved to
+        let target: &dyn std::fmt::Display = match (&self.sock5_addr, self.target_addr.as_ref()) {
+            (Sock5Address::Domain(_, _), _) | (_, None) => &self.sock5_addr,
+            (_, Some(addr)) => addr,
+        };
+        let entry = access_log::Entry {
+            conn_id: self.index,
+            remote_addr: self.proxy.peer_addr().ok(),
+            user: self.user(),
+            target,
+            target_addr: self.target_addr,
+            protocol: if self.is_udp() { "udp" } else { "tcp" },
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+            start_time: self.accept_time,
+        };
+        access_log::write(&entry);
+        if self.opened {
+            webhook::session_close(&entry);
+        }
+    }
+
+    fn open_session(&mut self) {
+        self.opened = true;
+        let protocol = if self.command == UDP_ASSOCIATE { "udp" } else { "tcp" };
+        webhook::session_open(self.index, self.proxy.peer_addr().ok(), self.user(), &self.sock5_addr, protocol);
+    }
+
     pub fn ready(&mut self, poll: &Poll, event: &Event, opts: &mut Opts) {
-        self.last_active_time = Instant::now();
+        self.last_active_time = net::now();
 
         if event.readiness().is_readable() {
             if event.token().0 % 2 == 0 {
@@ -99,7 +370,7 @@ impl Connection {
             } else {
                 match self.status {
                     Status::UDPForward => {
-                        self.try_read_udp_target();
+                        self.try_read_udp_target(opts);
                     }
                     Status::TCPForward => {
                         self.try_read_tcp_target();
@@ -107,6 +378,12 @@ impl Connection {
                     Status::DnsWait => {
                         self.try_resolve(opts, poll);
                     }
+                    Status::AuthWait => {
+                        self.try_auth_hook(opts, poll);
+                    }
+                    Status::TCPConnect => {
+                        self.try_connect_target(opts, poll);
+                    }
                     _ => {
                         log::error!("connection:{} has invalid status when target is readable", self.index);
                     }
@@ -125,6 +402,9 @@ impl Connection {
                     Status::TCPForward => {
                         self.try_send_tcp_target();
                     }
+                    Status::TCPConnect => {
+                        self.try_connect_target(opts, poll);
+                    }
                     _ => {
                         log::error!("connection:{} got invalid read status", self.index);
                     }
@@ -133,24 +413,80 @@ impl Connection {
         }
 
 
-        self.reregister(poll);
+        self.check_shutdown();
+        self.reregister(poll, opts.pending_limit);
         if self.closing {
             self.close_now(poll);
         }
     }
 
+    // a side which has finished sending gets a shutdown of the other once the data for it is all written
+    fn check_shutdown(&mut self) {
+        if self.closing {
+            return;
+        }
+        if self.proxy_eof && !self.target_shutdown && !self.target_session.wants_write() {
+            if let Some(tcp_target) = self.tcp_target.as_ref() {
+                log::debug!("connection:{} shutdown target write", self.index);
+                if let Err(err) = tcp_target.shutdown(Shutdown::Write) {
+                    log::warn!("connection:{} shutdown target failed:{}", self.index, err);
+                    self.closing = true;
+                    return;
+                }
+                self.target_shutdown = true;
+            }
+        }
+        if self.target_eof && !self.proxy_shutdown && !self.proxy_session.wants_write() {
+            log::debug!("connection:{} shutdown proxy write", self.index);
+            if let Err(err) = self.proxy.shutdown(Shutdown::Write) {
+                log::warn!("connection:{} shutdown proxy failed:{}", self.index, err);
+                self.closing = true;
+                return;
+            }
+            self.proxy_shutdown = true;
+        }
+        if self.proxy_shutdown && self.target_shutdown {
+            log::info!("connection:{} both sides finished", self.index);
+            self.closing = true;
+        }
+    }
+
+    // only relayed tcp streams are half closed, the other states have nothing to relay after eof
+    fn proxy_finished(&mut self) {
+        match self.status {
+            Status::AuthWait | Status::DnsWait | Status::TCPConnect | Status::TCPForward if self.command == CONNECT => {
+                log::info!("connection:{} proxy finished sending", self.index);
+                self.proxy_eof = true;
+            }
+            _ => {
+                log::info!("connection:{} encounter eof from proxy", self.index);
+                self.closing = true;
+            }
+        }
+    }
+
     fn try_resolve(&mut self, opts: &mut Opts, poll: &Poll) {
         if self.closing {
             return;
         }
         if let Sock5Address::Domain(domain, port) = &self.sock5_addr {
-            if let Some(address) = self.resolver.as_ref().unwrap().address() {
+            let resolver = self.resolver.as_ref().unwrap();
+            if let Some(address) = resolver.address() {
                 log::info!("connection:{} got resolve result {} = {}", self.index, domain, address);
-                opts.update_dns(domain.clone(), address);
-                let addr = SocketAddr::new(address, *port);
-                self.target_addr.replace(addr);
-                self.dispatch(&[], opts, poll);
+                let mut addresses = resolver.addresses();
+                opts.update_dns(domain.clone(), addresses.clone(), resolver.valid_until());
+                opts.dns_cache.sort(&mut addresses);
+                let port = *port;
+                if self.set_target_addrs(addresses, port, opts) {
+                    self.dispatch(&[], opts, poll);
+                } else {
+                    log::error!("connection:{} target {} is not allowed", self.index, address);
+                    self.closing = true;
+                }
             } else {
+                if resolver.no_records() {
+                    opts.update_dns_negative(domain.clone(), resolver.valid_until());
+                }
                 log::error!("connection:{} resolve host:{} failed", self.index, domain);
                 self.closing = true;
             }
@@ -161,6 +497,62 @@ impl Connection {
         let _ = self.resolver.take();
     }
 
+    // --auth-hook decides on an authenticated connection before its target is set up, true if it is asked
+    fn ask_auth_hook(&mut self, buffer: &[u8], opts: &Opts, poll: &Poll) -> bool {
+        let args = opts.server_args();
+        let hook = match args.auth_hook.as_ref() {
+            Some(hook) => hook.clone(),
+            None => return false,
+        };
+        let user = self.user.clone().unwrap_or_else(|| "-".to_string());
+        let ip = self.peer_ip.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        let hook = EventedAuthHook::new(hook, user, ip, Duration::new(args.auth_hook_timeout, 0), opts.tcp_opts.marker, opts.relay_args().ip_strategy);
+        if let Err(err) = poll.register(&hook, self.target_token(), Ready::readable(), PollOpt::level()) {
+            log::error!("connection:{} register auth hook failed:{}", self.index, err);
+            self.closing = true;
+            return true;
+        }
+        log::debug!("connection:{} asks auth hook", self.index);
+        self.auth_hook.replace(hook);
+        // the payload after the request is framed, what is read later is not
+        self.auth_pending = match self.padding.as_mut() {
+            Some(padding) => padding.read(buffer),
+            None => buffer.to_vec(),
+        };
+        self.status = Status::AuthWait;
+        true
+    }
+
+    fn try_auth_hook(&mut self, opts: &mut Opts, poll: &Poll) {
+        if self.closing {
+            return;
+        }
+        let hook = self.auth_hook.take().unwrap();
+        let _ = poll.deregister(&hook);
+        let user = self.user.as_ref().map_or("-", |user| user.as_str()).to_string();
+        match hook.allowed() {
+            Ok(true) => log::info!("connection:{} user:{} allowed by auth hook", self.index, user),
+            Ok(false) => {
+                log::warn!("connection:{} user:{} denied by auth hook", self.index, user);
+                self.security_event(SecurityEvent::AuthDenied, format!("user={}", user).as_str());
+                self.closing = true;
+                return;
+            }
+            Err(err) => {
+                log::error!("connection:{} auth hook failed:{}", self.index, err);
+                self.closing = true;
+                return;
+            }
+        }
+        self.open_session();
+        if !self.setup_target(opts, poll) {
+            return;
+        }
+        self.status = Status::DnsWait;
+        let data = std::mem::take(&mut self.auth_pending);
+        self.dispatch(data.as_slice(), opts, poll);
+    }
+
     fn try_send_proxy(&mut self) {
         if self.closing {
             return;
@@ -168,10 +560,13 @@ impl Connection {
         loop {
             if !self.proxy_session.wants_write() {
                 log::debug!("connection:{} finished proxy write", self.index);
+                self.proxy_pending = 0;
                 break;
             }
             match self.proxy_session.write_tls(&mut self.proxy) {
                 Ok(size) => {
+                    self.bytes_sent += size;
+                    self.client_write_time = net::now();
                     log::debug!("connection:{} sent {} bytes to proxy", self.index, size);
                 }
                 Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
@@ -197,12 +592,15 @@ impl Connection {
                 self.closing = true;
             }
             Ok(size) => {
+                if size > 0 {
+                    self.target_write_time = net::now();
+                }
                 log::debug!("connection:{} write {} bytes to target", self.index, size);
             }
         }
     }
 
-    fn try_read_udp_target(&mut self) {
+    fn try_read_udp_target(&mut self, opts: &Opts) {
         if self.closing {
             return;
         }
@@ -210,9 +608,21 @@ impl Connection {
         loop {
             match udp_socket.recv_from(self.udp_recv_body.as_mut_slice()) {
                 Ok((size, addr)) => {
+                    let addr = SocketAddr::new(cidr::unmap(addr.ip()), addr.port());
+                    self.target_read_time = net::now();
                     log::debug!("connection:{} got {} bytes udp data from:{}", self.index, size, addr);
+                    if size > opts.relay_args().max_udp_size {
+                        log::warn!("connection:{} udp packet from {} exceeds max udp size:{}, drop it", self.index, addr, opts.relay_args().max_udp_size);
+                        continue;
+                    }
+                    // the socket is drained all the same, datagrams the client does not take fast enough are lost
+                    if self.proxy_pending >= opts.pending_limit {
+                        log::debug!("connection:{} queues {} bytes to proxy, drop udp packet from {}", self.index, self.proxy_pending, addr);
+                        continue;
+                    }
                     self.udp_recv_head.clear();
                     UdpAssociate::generate(&mut self.udp_recv_head, &addr, size as u16);
+                    self.proxy_pending += self.udp_recv_head.len() + size;
                     if let Err(err) = self.proxy_session.write_all(self.udp_recv_head.as_ref()) {
                         log::error!("connection:{} write to session failed:{}", self.index, err);
                         self.closing = true;
@@ -238,17 +648,19 @@ impl Connection {
     }
 
     fn try_read_proxy(&mut self, opts: &mut Opts, poll: &Poll) {
-        if self.closing {
+        if self.closing || self.proxy_eof {
             return;
         }
+        let mut eof = false;
         loop {
             match self.proxy_session.read_tls(&mut self.proxy) {
                 Ok(size) => {
                     if size == 0 {
-                        log::info!("connection:{} encounter eof from proxy", self.index);
-                        self.closing = true;
-                        return;
+                        eof = true;
+                        break;
                     }
+                    self.bytes_received += size;
+                    self.client_read_time = net::now();
                     log::debug!("connection:{} got {} bytes proxy data", self.index, size);
                 }
                 Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
@@ -265,82 +677,286 @@ impl Connection {
 
         if let Err(err) = self.proxy_session.process_new_packets() {
             log::error!("connection:{} got proxy process error:{}", self.index, err);
+            if self.proxy_session.is_handshaking() && self.proxy_session.is_shadowsocks() {
+                // the first chunk fails to open with a wrong password
+                self.auth_failed = true;
+                self.security_event(SecurityEvent::AuthFailure, "");
+            } else if self.proxy_session.is_handshaking() {
+                self.security_event(SecurityEvent::MalformedHandshake, format!("error=\"{}\"", err).as_str());
+            }
             self.closing = true;
             return;
         }
+        if self.tls_done_time.is_none() && !self.proxy_session.is_handshaking() {
+            self.tls_done_time = Some(net::now());
+        }
 
         let mut buffer = Vec::new();
-        if let Err(err) = self.proxy_session.read_to_end(&mut buffer) {
-            log::warn!("connection:{} got proxy read error:{}", self.index, err);
-            self.closing = true;
-            return;
+        match self.proxy_session.read_to_end(&mut buffer) {
+            Ok(_) => {}
+            // close notify, the data before it is still relayed
+            Err(err) if err.kind() == std::io::ErrorKind::ConnectionAborted => eof = true,
+            Err(err) => {
+                log::warn!("connection:{} got proxy read error:{}", self.index, err);
+                self.closing = true;
+                return;
+            }
         }
 
+        if let Some(padding) = self.padding.as_mut() {
+            buffer = padding.read(buffer.as_slice());
+            // the answer keeps the path back open as well, a burst of them gets one
+            if padding.take_keepalive() && !self.target_eof {
+                log::debug!("connection:{} got keepalive", self.index);
+                if let Err(err) = padding.write_keepalive(&mut self.proxy_session) {
+                    log::error!("connection:{} write keepalive failed:{}", self.index, err);
+                    self.closing = true;
+                    return;
+                }
+                self.try_send_proxy();
+            }
+        }
         if !buffer.is_empty() {
             self.dispatch(buffer.as_slice(), opts, poll);
         }
+        if eof && !self.closing {
+            self.proxy_finished();
+        }
     }
 
-    pub fn setup(&mut self, poll: &Poll, opts: &Opts) -> bool {
-        if let Err(err) = poll.register(&self.proxy, self.proxy_token(), Ready::readable(), PollOpt::level()) {
+    pub fn setup(&mut self, poll: &Poll) -> bool {
+        if let Err(err) = poll.register(&*self.proxy, self.proxy_token(), Ready::readable(), PollOpt::level()) {
             log::error!("connection:{} register proxy failed:{}", self.index, err);
             false
-        } else if let Err(err) = sys::set_mark(&self.proxy, opts.marker) {
-            log::error!("connection:{} set mark failed:{}", self.index, err);
-            false
-        } else if let Err(err) = self.proxy.set_nodelay(true) {
-            log::error!("connection:{} set nodelay failed:{}", self.index, err);
-            false
         } else {
             true
         }
     }
 
     fn try_read_tcp_target(&mut self) {
-        if self.closing {
+        if self.closing || self.target_eof {
             return;
         }
         match self.target_session.read_backend(self.tcp_target.as_mut().unwrap()) {
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                log::info!("connection:{} target finished sending", self.index);
+                self.target_eof = true;
+            }
             Err(err) => {
                 log::warn!("connection:{} read from target failed:{}", self.index, err);
                 self.closing = true;
                 return;
             }
             Ok(size) => {
+                if size > 0 {
+                    self.target_read_time = net::now();
+                }
                 log::debug!("connection:{} read {} bytes from target", self.index, size);
             }
         }
 
         let buffer = self.target_session.read_all();
         if !buffer.is_empty() {
-            if let Err(err) = self.proxy_session.write_all(buffer.bytes()) {
+            self.proxy_pending += buffer.len();
+            let result = match self.padding.as_mut() {
+                Some(padding) => padding.write(buffer.bytes(), &mut self.proxy_session),
+                None => self.proxy_session.write_all(buffer.bytes()),
+            };
+            if let Err(err) = result {
                 log::error!("connection:{} write to proxy failed:{}", self.index, err);
                 self.closing = true;
                 return;
-            } else {
-                self.try_send_proxy();
             }
         }
+        if self.target_eof {
+            self.proxy_session.send_close_notify();
+        }
+        self.try_send_proxy();
     }
 
     fn try_handshake(&mut self, buffer: &mut &[u8], opts: &mut Opts, poll: &Poll) -> bool {
-        if let Some(request) = TrojanRequest::parse(buffer, opts) {
+        if self.proxy_session.is_shadowsocks() {
+            return self.try_shadowsocks_handshake(buffer, opts, poll);
+        }
+        let min_len = opts.server_args().first_packet_min_len;
+        let request = if self.banned {
+            log::info!("connection:{} is from a banned client, pass through", self.index);
+            None
+        } else if self.first_packet_late {
+            None
+        } else if self.request_data.is_empty() && buffer.len() < min_len {
+            log::info!("connection:{} sent a first packet of {} bytes, shorter than a request", self.index, buffer.len());
+            None
+        } else {
+            match TrojanRequest::parse(&mut self.request_parser, buffer, opts) {
+                RequestParseResult::Request(request) => Some(request),
+                RequestParseResult::InvalidProtocol => None,
+                RequestParseResult::PortNotAllowed => {
+                    log::info!("connection:{} asks for a port not allowed, close it", self.index);
+                    self.closing = true;
+                    return false;
+                }
+                RequestParseResult::Continued => {
+                    self.request_data.extend_from_slice(buffer);
+                    return false;
+                }
+            }
+        };
+        if !self.request_data.is_empty() {
+            self.request_data.extend_from_slice(buffer);
+        }
+        let data = if self.request_data.is_empty() { *buffer } else { self.request_data.as_slice() };
+        // a replayed request is answered like a wrong password, so that it tells nothing about the server
+        let request = match request {
+            Some(request) if opts.replay_cache.check(data, request.payload, net::now()) => {
+                log::warn!("connection:{} replays a recent handshake, pass through", self.index);
+                self.security_event(SecurityEvent::Replay, "");
+                None
+            }
+            request => request,
+        };
+        if let Some(request) = request {
+            log::info!("connection:{} authenticated as user:{}", self.index, request.user.as_ref().map_or("-", |user| user.as_str()));
+            self.authenticated = true;
+            self.user = request.user;
             self.command = request.command;
+            if self.command == CONNECT_PADDED {
+                log::debug!("connection:{} frames and pads its data", self.index);
+                self.command = CONNECT;
+                self.padding = Some(Padding::new());
+            }
+            if self.command == UDP_OVER_TCP {
+                log::debug!("connection:{} keeps its udp stream alive", self.index);
+                self.command = UDP_ASSOCIATE;
+                self.udp_keepalive = true;
+            }
             self.sock5_addr = request.address;
+            self.request_data = Vec::new();
             *buffer = request.payload;
+            if self.ask_auth_hook(buffer, opts, poll) {
+                return false;
+            }
+            self.open_session();
         } else {
+            // a slow client may still be a trojan client, the others have sent something else
+            if !self.banned && !self.first_packet_late {
+                self.auth_failed = true;
+                self.security_event(SecurityEvent::AuthFailure, "");
+            }
             log::info!("connection:{} does not get a trojan request, pass through", self.index);
             self.command = CONNECT;
             self.sock5_addr = Sock5Address::None;
+            // all that was read goes to the fallback, the last read included
+            if !self.request_data.is_empty() {
+                let data = std::mem::take(&mut self.request_data);
+                if let Err(err) = self.target_session.write_all(data.as_slice()) {
+                    log::error!("connection:{} write to target session failed:{}", self.index, err);
+                    self.closing = true;
+                    return false;
+                }
+                *buffer = &[];
+            }
+        }
+        self.setup_target(opts, poll)
+    }
+
+    // the cipher has authenticated the client already, the stream starts with the target address, there is no
+    // fallback for anything else
+    fn try_shadowsocks_handshake(&mut self, buffer: &mut &[u8], opts: &mut Opts, poll: &Poll) -> bool {
+        if self.banned {
+            log::info!("connection:{} is from a banned client, close it", self.index);
+            self.closing = true;
+            return false;
         }
+        self.request_data.extend_from_slice(buffer);
+        *buffer = &[];
+        let data = std::mem::take(&mut self.request_data);
+        let user = if let ProxySession::Shadowsocks(session) = &self.proxy_session {
+            session.user()
+        } else {
+            None
+        };
+        let request = match TrojanRequest::parse_shadowsocks(data.as_slice(), user, opts) {
+            RequestParseResult::Request(request) => request,
+            RequestParseResult::Continued => {
+                self.request_data = data;
+                return false;
+            }
+            RequestParseResult::InvalidProtocol => {
+                log::info!("connection:{} does not get a shadowsocks request, close it", self.index);
+                self.closing = true;
+                return false;
+            }
+            RequestParseResult::PortNotAllowed => {
+                log::info!("connection:{} asks for a port not allowed, close it", self.index);
+                self.closing = true;
+                return false;
+            }
+        };
+        if opts.replay_cache.check(data.as_slice(), request.payload, net::now()) {
+            log::warn!("connection:{} replays a recent handshake, close it", self.index);
+            self.security_event(SecurityEvent::Replay, "");
+            self.closing = true;
+            return false;
+        }
+        log::info!("connection:{} authenticated by shadowsocks as user:{}", self.index, request.user.as_ref().map_or("-", |user| user.as_str()));
+        self.authenticated = true;
+        self.user = request.user;
+        self.command = CONNECT;
+        self.sock5_addr = request.address;
+        if let Err(err) = self.target_session.write_all(request.payload) {
+            log::error!("connection:{} write to target session failed:{}", self.index, err);
+            self.closing = true;
+            return false;
+        }
+        if self.ask_auth_hook(&[], opts, poll) {
+            return false;
+        }
+        self.open_session();
+        self.setup_target(opts, poll)
+    }
+
+    // resolves or checks the target of the request
+    fn setup_target(&mut self, opts: &mut Opts, poll: &Poll) -> bool {
         match &self.sock5_addr {
-            Sock5Address::Domain(domain, _) => {
+            Sock5Address::Domain(domain, port) => {
                 if self.command != CONNECT {
                     //udp associate bind at 0.0.0.0:0, ignore all domain
                     return true;
                 }
+                if let Some(addresses) = opts.hosts.lookup(domain) {
+                    if addresses.is_empty() {
+                        log::warn!("connection:{} target {} is blocked by hosts file", self.index, domain);
+                        self.closing = true;
+                        return false;
+                    }
+                    let (addresses, port) = (addresses.clone(), *port);
+                    if !self.set_target_addrs(addresses, port, opts) {
+                        log::error!("connection:{} target {} is not allowed", self.index, self.sock5_addr);
+                        self.closing = true;
+                        return false;
+                    }
+                    log::info!("connection:{} got target addresses from hosts file:{:?}", self.index, self.target_addrs);
+                    return true;
+                }
+                if opts.is_dns_negative(domain) {
+                    log::error!("connection:{} domain {} has no addresses", self.index, domain);
+                    self.closing = true;
+                    return false;
+                }
+                let (domain, port) = (domain.clone(), *port);
+                let addresses = opts.query_dns(&domain);
+                if !addresses.is_empty() {
+                    if !self.set_target_addrs(addresses, port, opts) {
+                        log::error!("connection:{} target {} is not allowed", self.index, domain);
+                        self.closing = true;
+                        return false;
+                    }
+                    log::info!("connection:{} got cached target addresses:{:?}", self.index, self.target_addrs);
+                    return true;
+                }
                 log::info!("connection:{} has to resolve {}", self.index, domain);
-                let resolver = EventedResolver::new(domain.clone());
+                let resolver = EventedResolver::new(domain.clone(), opts.relay_args().ip_strategy);
                 if let Err(err) = poll.register(&resolver, self.target_token(), Ready::readable(), PollOpt::level()) {
                     self.closing = true;
                     log::error!("connection:{} register resolver failed:{}", self.index, err);
@@ -349,12 +965,21 @@ impl Connection {
                 self.resolver.replace(resolver);
             }
             Sock5Address::Socket(address) => {
+                if self.command == CONNECT && !self.target_allowed(address, opts) {
+                    log::error!("connection:{} target {} is not allowed", self.index, address);
+                    self.closing = true;
+                    return false;
+                }
                 log::info!("connection:{} got resolved target address:{}", self.index, address);
                 self.target_addr.replace(*address);
             }
             Sock5Address::None => {
-                log::info!("connection:{} got default target address:{}", self.index, opts.back_addr.as_ref().unwrap());
-                self.target_addr = opts.back_addr.clone();
+                if let Some(path) = opts.back_path.as_ref() {
+                    log::info!("connection:{} got default target path:{}", self.index, path);
+                } else {
+                    log::info!("connection:{} got default target address:{}", self.index, opts.back_addr.as_ref().unwrap());
+                    self.target_addr = opts.back_addr;
+                }
             }
         }
         true
@@ -365,9 +990,14 @@ impl Connection {
         loop {
             match self.status {
                 Status::HandShake => {
-                    if self.try_handshake(&mut buffer, opts, poll) {
-                        self.status = Status::DnsWait;
-                    } else {
+                    if !self.try_handshake(&mut buffer, opts, poll) {
+                        return;
+                    }
+                    self.status = Status::DnsWait;
+                    if let Some(padding) = self.padding.as_mut() {
+                        // the payload after the request is framed already
+                        let data = padding.read(buffer);
+                        self.dispatch(data.as_slice(), opts, poll);
                         return;
                     }
                 }
@@ -384,16 +1014,46 @@ impl Connection {
                             }
                         }
 
+                        if let (Sock5Address::None, Some(path)) = (&self.sock5_addr, opts.back_path.as_ref()) {
+                            if self.try_setup_unix_target(path.as_str(), poll) {
+                                self.status = Status::TCPForward;
+                                continue;
+                            } else {
+                                return;
+                            }
+                        }
+
                         if self.target_addr.is_none() {
                             log::warn!("connection:{} dns query not done yet", self.index);
                             return;
                         }
 
+                        // the server counts connections to the target before this one connects, targets of the
+                        // fallback are not limited
+                        let limited = match self.sock5_addr {
+                            Sock5Address::None => false,
+                            _ => opts.server_args().target_max_connections > 0,
+                        };
+                        if limited {
+                            match self.target_slot {
+                                TargetSlot::Granted => {}
+                                TargetSlot::None => {
+                                    self.target_slot = TargetSlot::Asking(net::now());
+                                    return;
+                                }
+                                _ => return,
+                            }
+                        }
+
                         if self.try_setup_tcp_target(opts, poll) {
-                            self.status = Status::TCPForward;
+                            self.status = Status::TCPConnect;
                         } else {
                             return;
                         }
+                    } else if opts.relay_args().no_udp {
+                        log::warn!("connection:{} udp relay is disabled, reject udp associate request", self.index);
+                        self.closing = true;
+                        return;
                     } else {
                         if self.try_setup_udp_target(opts, poll) {
                             self.status = Status::UDPForward;
@@ -402,6 +1062,21 @@ impl Connection {
                         }
                     }
                 }
+                Status::AuthWait => {
+                    self.auth_pending.extend_from_slice(buffer);
+                    if self.auth_pending.len() > opts.pending_limit {
+                        log::warn!("connection:{} sent too much while waiting for auth hook", self.index);
+                        self.closing = true;
+                    }
+                    break;
+                }
+                Status::TCPConnect => {
+                    if let Err(err) = self.target_session.write_all(buffer) {
+                        self.closing = true;
+                        log::error!("connection:{} write to target session failed:{}", self.index, err);
+                    }
+                    break;
+                }
                 Status::TCPForward => {
                     self.do_send_tcp_target(buffer);
                     break;
@@ -414,54 +1089,184 @@ impl Connection {
         }
     }
 
+    // the allowed ones of the addresses of a domain, false if there is none
+    fn set_target_addrs(&mut self, addresses: Vec<IpAddr>, port: u16, opts: &Opts) -> bool {
+        let addrs: Vec<SocketAddr> = addresses.into_iter().map(|address| SocketAddr::new(address, port)).collect();
+        self.target_addrs = addrs.into_iter().filter(|addr| self.target_allowed(addr, opts)).collect();
+        self.target_addr = self.target_addrs.first().cloned();
+        self.target_addr.is_some()
+    }
+
+    // an address of a domain which refused the connection is tried after the others for a while
+    fn update_failed(&mut self, connector: &mut HappyEyeballs, opts: &mut Opts) {
+        if let Sock5Address::Domain(_, _) = self.sock5_addr {
+            for addr in connector.take_failed() {
+                opts.update_dns_failed(addr.ip());
+            }
+        }
+    }
+
+    fn target_allowed(&self, addr: &SocketAddr, opts: &Opts) -> bool {
+        // a restricted user reaches its own ranges only, on top of the rules for everyone
+        if !opts.user_route(self.user.as_ref()).map_or(true, |route| route.allows(&addr.ip())) {
+            return false;
+        }
+        if opts.server_args().allow_private {
+            return true;
+        }
+        // any address of ours is reached by the listeners bound to all of them, the one client connected to included
+        let local = opts.is_local_addr(&addr.ip()) || self.proxy.local_addr().map_or(false, |local| local.ip() == addr.ip());
+        !local && !cidr::is_private(&addr.ip())
+    }
+
     fn try_setup_tcp_target(&mut self, opts: &mut Opts, poll: &Poll) -> bool {
-        log::info!("connection:{} make a target connection to {}", self.index, self.target_addr.unwrap());
-        match TcpStream::connect(self.target_addr.as_ref().unwrap()) {
-            Ok(tcp_target) => {
-                if let Err(err) = sys::set_mark(&tcp_target, opts.marker) {
-                    log::error!("connection:{} set mark failed:{}", self.index, err);
-                    self.closing = true;
-                    return false;
-                } else if let Err(err) = poll.register(&tcp_target, self.target_token(), Ready::readable(), PollOpt::edge()) {
+        let target_addr = self.target_addr.unwrap();
+        if self.target_addrs.is_empty() {
+            self.target_addrs.push(target_addr);
+        }
+        log::info!("connection:{} make a target connection to {:?}", self.index, self.target_addrs);
+        let mut connector = match opts.user_route(self.user.as_ref()).and_then(|route| route.bind) {
+            Some(bind_addr) => {
+                let mut tcp_opts = opts.tcp_opts.clone();
+                tcp_opts.bind_addr = Some(bind_addr);
+                HappyEyeballs::new(self.index, self.target_addrs.as_slice(), opts.attempt_duration, &tcp_opts)
+            }
+            None => HappyEyeballs::new(self.index, self.target_addrs.as_slice(), opts.attempt_duration, &opts.tcp_opts),
+        };
+        if !connector.connect(poll, self.target_token()) {
+            log::warn!("connection:{} connect to target failed", self.index);
+            self.update_failed(&mut connector, opts);
+            self.closing = true;
+            return false;
+        }
+        self.connector.replace(connector);
+        true
+    }
+
+    // a local socket is connected at once, or refused
+    #[cfg(unix)]
+    fn try_setup_unix_target(&mut self, path: &str, poll: &Poll) -> bool {
+        log::info!("connection:{} make a target connection to {}", self.index, path);
+        let stream = match UnixStream::connect(path) {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::warn!("connection:{} connect to target {} failed:{}", self.index, path, err);
+                self.closing = true;
+                return false;
+            }
+        };
+        if let Err(err) = stream.set_nonblocking(true) {
+            log::error!("connection:{} set nonblocking failed:{}", self.index, err);
+            self.closing = true;
+            return false;
+        }
+        let tcp_target = Backend::Unix(stream);
+        if let Err(err) = poll.register(&tcp_target, self.target_token(), self.target_readiness, PollOpt::edge()) {
+            log::error!("connection:{} register target failed:{}", self.index, err);
+            self.closing = true;
+            return false;
+        }
+        self.tcp_target.replace(tcp_target);
+        true
+    }
+
+    #[cfg(not(unix))]
+    fn try_setup_unix_target(&mut self, _path: &str, _poll: &Poll) -> bool {
+        self.closing = true;
+        false
+    }
+
+    fn try_connect_target(&mut self, opts: &mut Opts, poll: &Poll) {
+        if self.closing {
+            return;
+        }
+        let token = self.target_token();
+        let mut connector = self.connector.take().unwrap();
+        let result = connector.ready(poll, token);
+        self.update_failed(&mut connector, opts);
+        self.connector.replace(connector);
+        match result {
+            ConnectResult::Connected(tcp_target, addr) => {
+                log::info!("connection:{} connected to:{}", self.index, addr);
+                self.connector.take();
+                self.target_addr.replace(addr);
+                if let Err(err) = poll.reregister(&*tcp_target, self.target_token(), self.target_readiness, PollOpt::edge()) {
                     log::error!("connection:{} register target failed:{}", self.index, err);
                     self.closing = true;
-                    return false;
+                    return;
                 } else if let Err(err) = tcp_target.set_nodelay(true) {
                     log::error!("connection:{} set nodelay failed:{}", self.index, err);
                     self.closing = true;
-                    return false;
+                    return;
                 }
-                self.tcp_target.replace(tcp_target);
+                self.tcp_target.replace(Backend::Tcp(tcp_target));
+                self.status = Status::TCPForward;
+                self.try_send_tcp_target();
             }
-            Err(err) => {
-                log::warn!("connection:{} connect to target failed:{}", self.index, err);
+            ConnectResult::Pending => {
+                log::debug!("connection:{} still connecting to target", self.index);
+            }
+            ConnectResult::Failed => {
+                log::warn!("connection:{} connect to target failed", self.index);
                 self.closing = true;
-                return false;
             }
         }
-        true
     }
 
     fn try_setup_udp_target(&mut self, opts: &mut Opts, poll: &Poll) -> bool {
         log::debug!("connection:{} got udp connection", self.index);
-        match UdpSocket::bind(opts.empty_addr.as_ref().unwrap()) {
+        // the socket is never connected, so one binding per client relays packets from any peer, like a full cone nat.
+        // bind dual stack if possible, so that peers of both families see the same binding
+        let outbound_bind = opts.user_route(self.user.as_ref()).and_then(|route| route.bind).or(opts.relay_args().outbound_bind);
+        let bind_ip = outbound_bind.unwrap_or(IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+        let port_range = opts.server_args().udp_port_range;
+        let udp_target = match bind_udp_target_in(bind_ip, port_range) {
+            Err(err) if outbound_bind.is_none() && err.kind() != std::io::ErrorKind::AddrInUse => {
+                log::debug!("connection:{} bind dual stack udp socket failed:{}", self.index, err);
+                bind_udp_target_in(opts.empty_addr.unwrap().ip(), port_range)
+            }
+            result => result,
+        };
+        match udp_target {
             Err(err) => {
                 log::error!("connection:{} bind udp socket failed:{}", self.index, err);
                 self.closing = true;
                 return false;
             }
             Ok(udp_target) => {
-                if let Err(err) = sys::set_mark(&udp_target, opts.marker) {
+                if let Err(err) = sys::set_mark(&udp_target, opts.tcp_opts.marker) {
                     log::error!("connection:{} set mark failed:{}", self.index, err);
                     self.closing = true;
                     return false;
                 }
+                let udp_v6 = udp_target.local_addr().map_or(false, |addr| addr.is_ipv6());
+                if let Some(dscp) = opts.relay_args().udp_dscp {
+                    if let Err(err) = sys::set_dscp(&udp_target, !udp_v6, dscp) {
+                        log::error!("connection:{} set dscp failed:{}", self.index, err);
+                        self.closing = true;
+                        return false;
+                    }
+                    // ipv4 packets of dual stack sockets use the tos option
+                    if udp_v6 {
+                        let _ = sys::set_dscp(&udp_target, true, dscp);
+                    }
+                }
+                if let Some(device) = opts.relay_args().outbound_device.as_ref() {
+                    if let Err(err) = sys::bind_device(&udp_target, device.as_str()) {
+                        log::error!("connection:{} bind device failed:{}", self.index, err);
+                        self.closing = true;
+                        return false;
+                    }
+                }
                 if let Err(err) = poll.register(&udp_target, self.target_token(), Ready::readable(), PollOpt::edge()) {
                     log::error!("connection:{} register udp target failed:{}", self.index, err);
                     self.closing = true;
                     return false;
                 }
                 self.udp_target.replace(udp_target);
+                self.udp_v6 = udp_v6;
+                // one more byte to tell oversized datagrams, which are truncated by the kernel
+                self.udp_recv_body = vec![0u8; opts.relay_args().max_udp_size + 1];
             }
         }
         true
@@ -481,12 +1286,13 @@ impl Connection {
 
         let conn = self.tcp_target.as_mut().unwrap();
         loop {
-            if buffer.len() == 0 {
+            if buffer.is_empty() {
                 break;
             }
             match conn.write(buffer) {
                 Ok(size) => {
                     buffer = &buffer[size..];
+                    self.target_write_time = net::now();
                     log::debug!("connection:{} send {} bytes data to target", self.index, size);
                 }
                 Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
@@ -523,13 +1329,20 @@ impl Connection {
         loop {
             match UdpAssociate::parse(buffer, opts) {
                 UdpParseResult::Packet(packet) => {
-                    match self.udp_target.as_ref().unwrap().send_to(&packet.payload[..packet.length], &packet.address) {
+                    if !self.target_allowed(&packet.address, opts) {
+                        log::warn!("connection:{} udp target {} is not allowed, drop it", self.index, packet.address);
+                        buffer = &packet.payload[packet.length..];
+                        continue;
+                    }
+                    let target_addr = if self.udp_v6 { to_mapped(packet.address) } else { packet.address };
+                    match self.udp_target.as_ref().unwrap().send_to(&packet.payload[..packet.length], &target_addr) {
                         Ok(size) => {
                             if size != packet.length {
                                 log::error!("connection:{} udp packet is truncated, {}：{}", self.index, packet.length, size);
                                 self.closing = true;
                                 return;
                             }
+                            self.target_write_time = net::now();
                             log::debug!("connection:{} write {} bytes to udp target:{}", self.index, size, packet.address);
                             buffer = &packet.payload[packet.length..];
                         }
@@ -544,6 +1357,21 @@ impl Connection {
                         }
                     }
                 }
+                UdpParseResult::Dropped(remaining) => {
+                    buffer = remaining;
+                }
+                UdpParseResult::Keepalive(remaining) => {
+                    log::debug!("connection:{} got udp keepalive", self.index);
+                    self.udp_recv_head.clear();
+                    UdpAssociate::generate_keepalive(&mut self.udp_recv_head);
+                    if let Err(err) = self.proxy_session.write_all(self.udp_recv_head.as_ref()) {
+                        log::error!("connection:{} write to session failed:{}", self.index, err);
+                        self.closing = true;
+                        return;
+                    }
+                    self.try_send_proxy();
+                    buffer = remaining;
+                }
                 UdpParseResult::InvalidProtocol => {
                     log::error!("connection:{} got invalid udp protocol", self.index);
                     self.closing = true;
@@ -557,11 +1385,23 @@ impl Connection {
         }
     }
 
-    fn reregister(&mut self, poll: &Poll) {
+    // the side whose data is not taken fast enough by the other is not read until the queue drains
+    fn reregister(&mut self, poll: &Poll, max_pending: usize) {
         if self.closing {
             return;
         }
         let mut changed = false;
+        // the proxy is level triggered, and stays readable after eof
+        let proxy_readable = !self.proxy_eof && self.target_session.pending() + self.udp_send_buffer.len() < max_pending;
+        if proxy_readable != self.proxy_readiness.is_readable() {
+            if proxy_readable {
+                self.proxy_readiness.insert(Ready::readable());
+            } else {
+                log::debug!("connection:{} pause reading proxy", self.index);
+                self.proxy_readiness.remove(Ready::readable());
+            }
+            changed = true;
+        }
         if self.proxy_session.wants_write() && !self.proxy_readiness.is_writable() {
             self.proxy_readiness.insert(Ready::writable());
             changed = true;
@@ -573,7 +1413,7 @@ impl Connection {
             changed = true;
         }
         if changed {
-            if let Err(err) = poll.reregister(&self.proxy, self.proxy_token(), self.proxy_readiness, PollOpt::level()) {
+            if let Err(err) = poll.reregister(&*self.proxy, self.proxy_token(), self.proxy_readiness, PollOpt::level()) {
                 log::error!("connection:{} reregister proxy failed:{}", self.index, err);
                 self.closing = true;
             }
@@ -581,6 +1421,17 @@ impl Connection {
 
         if self.tcp_target.is_some() {
             let mut changed = false;
+            // edge triggered, reregistering reports the data which arrived while paused
+            let target_readable = self.proxy_pending < max_pending;
+            if target_readable != self.target_readiness.is_readable() {
+                if target_readable {
+                    self.target_readiness.insert(Ready::readable());
+                } else {
+                    log::debug!("connection:{} pause reading tcp target", self.index);
+                    self.target_readiness.remove(Ready::readable());
+                }
+                changed = true;
+            }
             if self.target_session.wants_write() && !self.target_readiness.is_writable() {
                 self.target_readiness.insert(Ready::writable());
                 changed = true;
@@ -636,3 +1487,118 @@ impl Connection {
         Token((self.index << 1) + 1)
     }
 }
+
+fn bind_udp_target(bind_addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    let domain = if bind_addr.is_ipv4() {
+        Domain::ipv4()
+    } else {
+        Domain::ipv6()
+    };
+    let socket = Socket::new(domain, Type::dgram(), Some(Protocol::udp()))?;
+    if bind_addr.is_ipv6() && bind_addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&SockAddr::from(bind_addr))?;
+    UdpSocket::from_socket(socket.into_udp_socket())
+}
+
+// any port without a range, otherwise the ports of the range from a random one on, skipping those in use
+fn bind_udp_target_in(ip: IpAddr, range: Option<PortRange>) -> std::io::Result<UdpSocket> {
+    let range = match range {
+        Some(range) => range,
+        None => return bind_udp_target(SocketAddr::new(ip, 0)),
+    };
+    let count = (range.last - range.first) as u32 + 1;
+    let mut random = [0u8; 4];
+    let start = if sys::fill_random(&mut random).is_ok() {
+        u32::from_ne_bytes(random) % count
+    } else {
+        0
+    };
+    for i in 0..count {
+        let port = range.first + ((start + i) % count) as u16;
+        match bind_udp_target(SocketAddr::new(ip, port)) {
+            Err(err) if err.kind() == std::io::ErrorKind::AddrInUse => continue,
+            result => return result,
+        }
+    }
+    Err(std::io::Error::new(std::io::ErrorKind::AddrInUse, "all ports of --udp-port-range are in use"))
+}
+
+fn to_mapped(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) => SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()),
+        _ => addr,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rustls::{ClientSession, ServerSession};
+    use webpki::DNSNameRef;
+
+    use crate::replay::{self, Machine, Replay};
+
+    use super::*;
+
+    struct Server {
+        conn: Connection,
+    }
+
+    // the part of the loop of the server a single connection goes through
+    impl Machine for Server {
+        fn ready(&mut self, event: &Event, opts: &mut Opts, poll: &Poll) {
+            if !self.conn.is_closed() {
+                self.conn.ready(poll, event, opts);
+            }
+        }
+
+        fn timeout(&mut self, now: Instant, opts: &mut Opts, poll: &Poll) {
+            if !self.conn.is_closed() && self.conn.is_racing() {
+                self.conn.check_racing(now, poll);
+            }
+            if !self.conn.is_closed() && self.conn.timeout(now, opts, poll) {
+                self.conn.close_now(poll);
+            }
+        }
+    }
+
+    fn play(trace: &str, args: &[&str]) {
+        let (server_config, client_config) = replay::tls_configs();
+        let mut argv = vec!["server", "-a", "192.0.2.1:443", "-p", "secret", "-c", "cert.pem", "-k", "key.pem", "--allow-private"];
+        argv.extend_from_slice(args);
+        let mut opts = replay::opts(argv.as_slice());
+        let mut replay = Replay::new();
+        replay.tls("client", Box::new(ClientSession::new(&client_config, DNSNameRef::try_from_ascii_str(replay::SERVER_NAME).unwrap())));
+        let stream = replay.accept("client", "203.0.113.1:50000".parse().unwrap(), "192.0.2.1:443".parse().unwrap());
+        let mut conn = Connection::new(1, stream, ProxySession::Tls(ServerSession::new(&server_config)), false);
+        assert!(conn.setup(replay.poll()));
+        replay.play(trace, &mut Server { conn }, &mut opts);
+    }
+
+    #[test]
+    fn test_half_close() {
+        play(include_str!("../../traces/server_half_close.trace"), &[]);
+    }
+
+    #[test]
+    fn test_backpressure() {
+        play(include_str!("../../traces/server_backpressure.trace"), &["--max-pending", "64"]);
+    }
+
+    #[test]
+    fn test_dns_wait() {
+        play(include_str!("../../traces/server_dns_wait.trace"), &[]);
+        play(include_str!("../../traces/server_dns_none.trace"), &[]);
+    }
+
+    #[test]
+    fn test_timers() {
+        play(include_str!("../../traces/server_attempt_delay.trace"), &[]);
+        play(include_str!("../../traces/server_refused.trace"), &[]);
+        play(include_str!("../../traces/server_connect_timeout.trace"), &[]);
+        play(include_str!("../../traces/server_first_packet_timeout.trace"), &["--first-packet-timeout", "5"]);
+    }
+}
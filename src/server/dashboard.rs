@@ -0,0 +1,167 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+
+const HISTORY_MINUTES: u64 = 60;
+
+// failed handshakes per minute over the last hour, for the trend on the dashboard
+pub struct AuthFailures {
+    start_time: Instant,
+    // the minute since start and the failures in it, minutes without any are left out
+    counts: VecDeque<(u64, usize)>,
+}
+
+impl AuthFailures {
+    pub fn new() -> AuthFailures {
+        AuthFailures {
+            start_time: Instant::now(),
+            counts: VecDeque::new(),
+        }
+    }
+
+    fn minute(&self, now: Instant) -> u64 {
+        (now - self.start_time).as_secs() / 60
+    }
+
+    pub fn add(&mut self, now: Instant) {
+        let minute = self.minute(now);
+        match self.counts.back_mut() {
+            Some((last, count)) if *last == minute => *count += 1,
+            _ => self.counts.push_back((minute, 1)),
+        }
+        while self.counts.front().map_or(false, |(first, _)| first + HISTORY_MINUTES <= minute) {
+            self.counts.pop_front();
+        }
+    }
+
+    // the failures of each of the last minutes, the current one last
+    pub fn history(&self, now: Instant) -> Vec<usize> {
+        let minute = self.minute(now);
+        let mut history = vec![0; HISTORY_MINUTES as usize];
+        for (time, count) in self.counts.iter() {
+            if time + HISTORY_MINUTES > minute {
+                history[(HISTORY_MINUTES - 1 - (minute - time)) as usize] = *count;
+            }
+        }
+        history
+    }
+}
+
+// the page polls /dashboard/stats with the token it was opened with, the traffic graphs are drawn from the
+// differences between polls, so they start empty
+pub const PAGE: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>trojan-rs</title>
+<style>
+body { font-family: sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; margin-bottom: 2em; }
+td, th { padding: 4px 12px; text-align: right; border-bottom: 1px solid #ddd; }
+td:first-child, th:first-child { text-align: left; }
+.cards span { display: inline-block; margin-right: 3em; font-size: 1.4em; }
+.cards small { display: block; font-size: 0.6em; color: #777; }
+canvas { border: 1px solid #ddd; }
+</style>
+</head>
+<body>
+<h2>trojan-rs</h2>
+<div class="cards">
+<span id="connections"><small>connections</small>-</span>
+<span id="udp"><small>udp sessions</small>-</span>
+<span id="bans"><small>banned clients</small>-</span>
+<span id="dns"><small>dns cache entries / hit rate</small>-</span>
+</div>
+<h3>users</h3>
+<table>
+<thead><tr><th>user</th><th>open</th><th>closed</th><th>sent</th><th>received</th><th>quota</th><th>rate, last 5 minutes</th></tr></thead>
+<tbody id="users"></tbody>
+</table>
+<h3>failed handshakes per minute, last hour</h3>
+<canvas id="failures" width="600" height="120"></canvas>
+<script>
+var rates = {};
+var last = null;
+
+function size(bytes) {
+    var units = ["B", "KB", "MB", "GB", "TB"];
+    var i = 0;
+    while (bytes >= 1024 && i < units.length - 1) {
+        bytes /= 1024;
+        i++;
+    }
+    return bytes.toFixed(i == 0 ? 0 : 1) + " " + units[i];
+}
+
+function draw(canvas, values, color) {
+    var context = canvas.getContext("2d");
+    var max = Math.max.apply(null, values.concat([1]));
+    var width = canvas.width / values.length;
+    context.clearRect(0, 0, canvas.width, canvas.height);
+    context.fillStyle = color;
+    values.forEach(function (value, i) {
+        var height = value / max * (canvas.height - 2);
+        context.fillRect(i * width, canvas.height - height, Math.max(width - 1, 1), height);
+    });
+}
+
+function cell(row, text) {
+    var td = document.createElement("td");
+    td.textContent = text;
+    row.appendChild(td);
+    return td;
+}
+
+function card(id, text) {
+    document.getElementById(id).lastChild.textContent = text;
+}
+
+function update(stats) {
+    var now = Date.now();
+    card("connections", stats.connections);
+    card("udp", stats.udp_sessions);
+    card("bans", stats.banned);
+    var queries = stats.dns_cache.hits + stats.dns_cache.misses;
+    card("dns", stats.dns_cache.entries + " / " + (queries ? Math.round(stats.dns_cache.hits * 100 / queries) : 0) + "%");
+    var body = document.getElementById("users");
+    body.textContent = "";
+    stats.users.forEach(function (user) {
+        var total = user.sent + user.received;
+        var samples = rates[user.user] || (rates[user.user] = []);
+        if (last && samples.total !== undefined) {
+            samples.push(Math.max(total - samples.total, 0) / ((now - last) / 1000));
+            if (samples.length > 150) {
+                samples.shift();
+            }
+        }
+        samples.total = total;
+        var row = document.createElement("tr");
+        cell(row, user.user);
+        cell(row, user.open);
+        cell(row, user.closed);
+        cell(row, size(user.sent));
+        cell(row, size(user.received));
+        cell(row, user.quota === null ? "-" : size(user.quota));
+        var canvas = document.createElement("canvas");
+        canvas.width = 300;
+        canvas.height = 30;
+        cell(row, samples.length ? size(samples[samples.length - 1]) + "/s " : "").appendChild(canvas);
+        draw(canvas, samples.length ? samples : [0], "#4a90d9");
+        body.appendChild(row);
+    });
+    draw(document.getElementById("failures"), stats.auth_failures, "#d9534f");
+    last = now;
+}
+
+function poll() {
+    fetch("/dashboard/stats" + location.search, {cache: "no-store"})
+        .then(function (response) { return response.json(); })
+        .then(update)
+        .catch(function (err) { console.log(err); })
+        .then(function () { setTimeout(poll, 2000); });
+}
+
+poll();
+</script>
+</body>
+</html>
+"##;
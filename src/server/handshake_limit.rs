@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+
+struct Bucket {
+    tokens: f64,
+    time: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: f64, burst: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.time).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.time = now;
+    }
+}
+
+// token buckets of new connections, one for all clients and one for each client ip, taken from as connections are
+// accepted, before the tls handshake, so that a flood of handshakes can not keep the cpu from established connections
+pub struct HandshakeLimit {
+    rate: f64,
+    burst: f64,
+    ip_rate: f64,
+    ip_burst: f64,
+    global: Bucket,
+    ips: HashMap<IpAddr, Bucket>,
+    // connections dropped since the last check, logged once a second rather than one by one
+    dropped: usize,
+}
+
+impl HandshakeLimit {
+    // a rate of 0 is no limit, a burst of 0 is a second of the rate
+    pub fn new(rate: u32, burst: u32, ip_rate: u32, ip_burst: u32) -> HandshakeLimit {
+        let burst = if burst == 0 { rate } else { burst } as f64;
+        let ip_burst = if ip_burst == 0 { ip_rate } else { ip_burst } as f64;
+        HandshakeLimit {
+            rate: rate as f64,
+            burst,
+            ip_rate: ip_rate as f64,
+            ip_burst,
+            global: Bucket {
+                tokens: burst,
+                time: Instant::now(),
+            },
+            ips: HashMap::new(),
+            dropped: 0,
+        }
+    }
+
+    // takes a token from the bucket of the ip and from the global one, an ip out of tokens takes none from the global
+    // bucket, so that one client can not use up the handshakes of the others
+    pub fn allow(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if self.ip_rate > 0.0 {
+            let (rate, burst) = (self.ip_rate, self.ip_burst);
+            let bucket = self.ips.entry(ip).or_insert(Bucket {
+                tokens: burst,
+                time: now,
+            });
+            bucket.refill(rate, burst, now);
+            if bucket.tokens < 1.0 {
+                self.dropped += 1;
+                return false;
+            }
+        }
+        if self.rate > 0.0 {
+            self.global.refill(self.rate, self.burst, now);
+            if self.global.tokens < 1.0 {
+                self.dropped += 1;
+                return false;
+            }
+            self.global.tokens -= 1.0;
+        }
+        if let Some(bucket) = self.ips.get_mut(&ip) {
+            bucket.tokens -= 1.0;
+        }
+        true
+    }
+
+    // forgets the ips whose buckets are full again, returns the connections dropped since the last check
+    pub fn check_timeout(&mut self, now: Instant) -> usize {
+        let (rate, burst) = (self.ip_rate, self.ip_burst);
+        self.ips.retain(|_, bucket| {
+            bucket.refill(rate, burst, now);
+            bucket.tokens < burst
+        });
+        std::mem::replace(&mut self.dropped, 0)
+    }
+}
@@ -1,5 +1,6 @@
 use std::fs::File;
 use std::io::BufReader;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
@@ -7,78 +8,300 @@ use mio::{Events, Poll, PollOpt, Ready, Token};
 use mio::net::TcpListener;
 use rustls::{KeyLogFile, NoClientAuth, ServerConfig};
 use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 
 pub use server::TlsServer;
 
-use crate::config::Opts;
+use crate::config::{MAX_LISTENERS, Opts};
+use crate::admin::Admin;
+use crate::budget::MemoryBudget;
+use crate::grpc::GrpcServer;
+use crate::health_http::HealthHttp;
+use crate::stats_file::StatsFile;
+use crate::error::{Error, Result};
+use crate::{firewall, log_level, privilege, sandbox, sys, systemd, upgrade};
 
+mod api;
+mod auth_hook;
+mod backend;
+mod ban;
+mod cert;
 mod connection;
+mod dashboard;
+mod handshake_limit;
 mod server;
-mod resolver;
+mod session;
+mod shadowsocks;
+mod targets;
+mod users;
 
-fn init_config(opts: &Opts) -> Arc<ServerConfig> {
+const FAST_OPEN_QUEUE_LEN: i32 = 256;
+const ADMIN: usize = 2;
+const ADMIN_CLIENT: usize = 3;
+const HEALTH: usize = 4;
+const HEALTH_CLIENT: usize = 5;
+const API: usize = 6;
+const API_CLIENT: usize = 7;
+const LISTENER: usize = 8;
+// tokens of connections start from 2 * MIN_INDEX, after those of the listeners
+const MIN_INDEX: usize = (LISTENER + MAX_LISTENERS + 1) / 2;
+
+// loads the certificates and the key, also used by --check
+pub fn init_config(opts: &Opts) -> Result<Arc<ServerConfig>> {
+    let args = opts.server_args();
     let mut config = ServerConfig::new(NoClientAuth::new());
     config.key_log = Arc::new(KeyLogFile::new());
-    let cert_file = File::open(opts.server_args().cert.clone()).unwrap();
+    let cert_file = File::open(args.cert.as_str()).map_err(|err| Error::io(format!("open certificate file {}", args.cert), err))?;
     let mut buff_reader = BufReader::new(cert_file);
-    let cert_chain = certs(&mut buff_reader).unwrap();
+    let cert_chain = certs(&mut buff_reader).map_err(|_| format!("invalid certificate file {}", args.cert))?;
+    if cert_chain.is_empty() {
+        return Err(Error::Config(format!("no certificate found in {}", args.cert)));
+    }
     let key_der = {
-        let key_file = File::open(opts.server_args().key.clone()).unwrap();
+        let key_file = File::open(args.key.as_str()).map_err(|err| Error::io(format!("open key file {}", args.key), err))?;
         let mut buff_reader = BufReader::new(key_file);
-        let keys = pkcs8_private_keys(&mut buff_reader).unwrap();
-        if let Some(key) = keys.get(0) {
+        let keys = pkcs8_private_keys(&mut buff_reader).map_err(|_| format!("invalid key file {}", args.key))?;
+        if let Some(key) = keys.first() {
             log::info!("pkcs8 private key found");
             key.clone()
         } else {
-            let key_file = File::open(opts.server_args().key.clone()).unwrap();
+            let key_file = File::open(args.key.as_str()).map_err(|err| Error::io(format!("open key file {}", args.key), err))?;
             let mut buff_reader = BufReader::new(key_file);
-            let keys = rsa_private_keys(&mut buff_reader).unwrap();
-            if let Some(key) = keys.get(0) {
+            let keys = rsa_private_keys(&mut buff_reader).map_err(|_| format!("invalid key file {}", args.key))?;
+            if let Some(key) = keys.first() {
                 log::info!("rsa private key found");
                 key.clone()
             } else {
-                panic!("no private key found");
+                return Err(Error::Config(format!("no private key found in {}", args.key)));
             }
         }
     };
-    config.set_single_cert(cert_chain, key_der).unwrap();
+    config.set_single_cert(cert_chain, key_der).map_err(|err| format!("invalid certificate or key:{}", err))?;
     let mut protocols: Vec<Vec<u8>> = Vec::new();
-    for protocol in &opts.server_args().alpn {
+    for protocol in &args.alpn {
         protocols.push(protocol.as_str().into());
     }
     if !protocols.is_empty() {
         config.set_protocols(&protocols);
     }
-    Arc::new(config)
+    Ok(Arc::new(config))
+}
+
+pub fn cert_expiry(opts: &Opts) -> Option<chrono::DateTime<chrono::Utc>> {
+    cert::expiry(opts.server_args().cert.as_str())
 }
 
-pub fn run(opts: &mut Opts) {
-    let config = init_config(opts);
-    let poll = Poll::new().unwrap();
-    let addr = opts.local_addr.parse().unwrap();
-    let listener = TcpListener::bind(&addr).unwrap();
-    poll.register(&listener, Token(1), Ready::readable(), PollOpt::edge()).unwrap();
-    let mut server = TlsServer::new(listener, config);
+fn bind(addr: &SocketAddr, only_v6: bool) -> std::io::Result<TcpListener> {
+    if !only_v6 {
+        return TcpListener::bind(addr);
+    }
+    let socket = Socket::new(Domain::ipv6(), Type::stream(), Some(Protocol::tcp()))?;
+    socket.set_only_v6(true)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SockAddr::from(*addr))?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into_tcp_listener())
+}
+
+pub fn run(opts: &mut Opts) -> Result<()> {
+    let config = init_config(opts)?;
+    let poll = Poll::new().map_err(|err| Error::io("create poll", err))?;
+    let mut listeners = Vec::new();
+    let mut ss_listener = None;
+    let mut inherited = systemd::tcp_listeners();
+    if !inherited.is_empty() {
+        // the shadowsocks listener is told by its address, it is handed over on upgrades as well
+        if let Some(addr) = opts.ss_addr.as_ref() {
+            if let Some(pos) = inherited.iter().position(|listener| listener.local_addr().ok().as_ref() == Some(addr)) {
+                ss_listener = Some(TcpListener::from_std(inherited.remove(pos)).map_err(|err| Error::io("use inherited listener", err))?);
+            }
+        }
+        log::warn!("using {} inherited listeners, {} is ignored", inherited.len(), opts.relay_args().local_addr.join(","));
+        // the shadowsocks listener takes the last token
+        let count = if opts.ss_addr.is_some() { MAX_LISTENERS - 1 } else { MAX_LISTENERS };
+        for listener in inherited.into_iter().take(count) {
+            listeners.push(TcpListener::from_std(listener).map_err(|err| Error::io("use inherited listener", err))?);
+        }
+    } else {
+        for addr in opts.local_addrs.iter() {
+            listeners.push(bind(addr, opts.only_v6(addr)).map_err(|err| Error::io(format!("listen on {}", addr), err))?);
+        }
+    }
+    for (addr, last_port) in opts.port_ranges.iter() {
+        log::warn!("ports {} to {} reach the listener on {} through a redirect rule like: {}", addr.port(), last_port, addr, firewall::redirect_rule(addr, *last_port));
+    }
+    for (i, listener) in listeners.iter().enumerate() {
+        // accepted sockets inherit these, and the window scale is decided before accepting
+        if let Err(err) = sys::set_buffer_size(listener, opts.relay_args().send_buffer, opts.relay_args().recv_buffer) {
+            log::error!("set listener buffer size failed:{}", err);
+        }
+        if opts.relay_args().fast_open {
+            if let Err(err) = sys::set_fast_open(listener, FAST_OPEN_QUEUE_LEN) {
+                log::error!("enable tcp fast open failed:{}", err);
+            }
+        }
+        poll.register(listener, Token(LISTENER + i), Ready::readable(), PollOpt::edge()).map_err(|err| Error::io("register listener", err))?;
+    }
+    if let Some(addr) = opts.ss_addr.as_ref() {
+        // salts are random, which windows does not support yet
+        sys::fill_random(&mut [0u8; 16]).map_err(|err| Error::io("shadowsocks listener", err))?;
+        if ss_listener.is_none() {
+            ss_listener = Some(bind(addr, opts.only_v6(addr)).map_err(|err| Error::io(format!("listen on shadowsocks address {}", addr), err))?);
+        }
+        poll.register(ss_listener.as_ref().unwrap(), Token(LISTENER + listeners.len()), Ready::readable(), PollOpt::edge())
+            .map_err(|err| Error::io("register shadowsocks listener", err))?;
+        log::warn!("shadowsocks listening on {}", addr);
+    }
+    let mut admin = match opts.server_args().admin_socket.as_ref() {
+        Some(path) => Some(Admin::new(path.as_str(), &poll, Token(ADMIN), Token(ADMIN_CLIENT))
+            .map_err(|err| Error::io(format!("listen on admin socket {}", path), err))?),
+        None => None,
+    };
+    let mut health = match opts.relay_args().health_addr.as_ref() {
+        Some(addr) => {
+            let addr: SocketAddr = addr.parse().map_err(|err| Error::Config(format!("invalid --health-addr {}:{}", addr, err)))?;
+            Some(HealthHttp::new(&addr, &poll, Token(HEALTH), Token(HEALTH_CLIENT))
+                .map_err(|err| Error::io(format!("listen on health address {}", addr), err))?)
+        }
+        None => None,
+    };
+    if let (Some(health), Some(token)) = (health.as_mut(), opts.server_args().dashboard_token.as_ref()) {
+        health.set_dashboard_token(token.clone());
+        log::warn!("dashboard served on {}/dashboard", opts.relay_args().health_addr.as_ref().unwrap());
+    }
+    let mut api = match opts.server_args().api_addr.as_ref() {
+        Some(addr) => {
+            let addr: SocketAddr = addr.parse().map_err(|err| Error::Config(format!("invalid --api-addr {}:{}", addr, err)))?;
+            Some(GrpcServer::new(&addr, &poll, Token(API), Token(API_CLIENT))
+                .map_err(|err| Error::io(format!("listen on api address {}", addr), err))?)
+        }
+        None => None,
+    };
+    let cert_expiry = cert_expiry(opts);
+    if let Some(expiry) = cert_expiry {
+        log::warn!("certificate expires at {}", expiry);
+    }
+    let stats_file = match opts.server_args().stats_file.as_ref() {
+        Some(path) => Some(StatsFile::open(path.as_str()).map_err(|err| Error::io(format!("open stats file {}", path), err))?),
+        None => None,
+    };
+    let mut server = TlsServer::new(listeners, ss_listener, config, stats_file, opts);
     let mut events = Events::with_capacity(1024);
-    let mut last_check_time = Instant::now();
+    let start_time = Instant::now();
+    let mut last_check_time = start_time;
     let check_duration = Duration::new(1, 0);
+    let racing_duration = Duration::from_millis(10);
+    let drain_duration = Duration::new(opts.relay_args().drain_timeout, 0);
+    let mut stop_time: Option<Instant> = None;
+    let mut upgrade: Option<upgrade::Upgrade> = None;
+    let mut budget = MemoryBudget::new();
+    opts.check_local_addrs();
+    // marks are set on every connection
+    privilege::drop(opts, opts.relay_args().marker != 0 || opts.tcp_opts.marker != 0);
+    sandbox::apply(opts);
+    let mut watchdog = systemd::Watchdog::new();
+    systemd::notify("READY=1");
     loop {
-        let nevent = poll.poll(&mut events, Some(check_duration)).unwrap();
+        if sys::trace_toggle_requested() {
+            log_level::toggle_trace();
+        }
+        if sys::upgrade_requested() && upgrade.is_none() && stop_time.is_none() {
+            upgrade = upgrade::start(opts, server.listeners(), &[]);
+        }
+        if stop_time.is_none() && sys::stopping() {
+            log::warn!("trojan is stopping, draining {} connections", server.conn_count());
+            systemd::notify("STOPPING=1");
+            server.stop_accept(&poll);
+            stop_time.replace(Instant::now());
+        }
+        if let Some(stop_time) = stop_time {
+            if server.conn_count() == 0 {
+                log::warn!("all connections drained");
+                break;
+            }
+            if stop_time.elapsed() >= drain_duration {
+                log::warn!("drain timeout, close {} connections", server.conn_count());
+                break;
+            }
+        }
+        server.start_granted(opts, &poll);
+        let timeout = if server.is_racing() {
+            racing_duration
+        } else {
+            check_duration
+        };
+        let nevent = poll.poll(&mut events, Some(timeout)).unwrap();
         log::trace!("poll got {} events", nevent);
         for event in &events {
             match event.token() {
-                Token(1) => {
-                    server.accept(&poll, opts);
+                Token(i) if (LISTENER..LISTENER + MAX_LISTENERS).contains(&i) => {
+                    server.accept(i - LISTENER, &poll, opts);
+                }
+                Token(ADMIN) => {
+                    if let Some(admin) = admin.as_mut() {
+                        admin.accept(&poll);
+                    }
+                }
+                Token(ADMIN_CLIENT) => {
+                    if let Some(admin) = admin.as_mut() {
+                        admin.ready(&poll, |command| server.admin_command(command, opts));
+                    }
+                }
+                Token(HEALTH) => {
+                    if let Some(health) = health.as_mut() {
+                        health.accept(&poll);
+                    }
+                }
+                Token(HEALTH_CLIENT) => {
+                    if let Some(health) = health.as_mut() {
+                        health.ready(&poll, || {
+                            if stop_time.is_some() {
+                                Err("stopping".to_string())
+                            } else if cert_expiry.map_or(false, |expiry| expiry < chrono::Utc::now()) {
+                                Err(format!("certificate expired at {}", cert_expiry.unwrap()))
+                            } else {
+                                Ok(())
+                            }
+                        }, |path| server.dashboard(path, opts));
+                    }
+                }
+                Token(API) => {
+                    if let Some(api) = api.as_mut() {
+                        api.accept(&poll);
+                    }
+                }
+                Token(API_CLIENT) => {
+                    if let Some(api) = api.as_mut() {
+                        let uptime = start_time.elapsed();
+                        api.ready(&poll, |path, message| server.api_call(path, message, opts, uptime));
+                    }
                 }
                 _ => {
                     server.do_conn_event(&poll, &event, opts);
                 }
             }
         }
+        if server.is_racing() {
+            server.check_racing(&poll);
+        }
         let now = Instant::now();
+        watchdog.check(now);
         if now - last_check_time > check_duration {
-            server.check_timeout(now - opts.idle_duration, &poll);
+            server.check_timeout(now, opts, &poll);
+            if stop_time.is_none() {
+                server.check_budget(now, &mut budget, opts, &poll);
+            }
+            opts.check_password_file();
+            opts.check_hosts_file();
+            opts.check_local_addrs();
+            if upgrade::check(&mut upgrade, now) {
+                server.hand_over_stats();
+                sys::stop();
+            }
             last_check_time = now;
         }
     }
+    // connections left at a drain timeout count as they are
+    server.save_stats();
+    Ok(())
 }
\ No newline at end of file
@@ -1,40 +1,118 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::net::IpAddr;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use mio::{Event, Poll};
+use mio::{Event, Poll, PollOpt, Ready, Token};
 use mio::net::TcpListener;
 use rustls::{ServerConfig, ServerSession};
 
-use crate::config::Opts;
+use crate::budget::MemoryBudget;
+use crate::config::{BanAction, Opts};
+use crate::{access_log, log_format, log_level, sys};
+use crate::server::{LISTENER, MIN_INDEX};
+use crate::server::api;
+use crate::server::ban::BanList;
 use crate::server::connection::Connection;
+use crate::server::dashboard::{self, AuthFailures};
+use crate::server::handshake_limit::HandshakeLimit;
+use crate::server::session::ProxySession;
+use crate::server::shadowsocks::{Key, ShadowsocksSession};
+use crate::server::targets::{Acquire, TargetLimits};
+use crate::server::users::Users;
+use crate::stats_file::StatsFile;
+use crate::timer_wheel::TimerWheel;
 
 pub struct TlsServer {
-    listener: TcpListener,
+    listeners: Vec<TcpListener>,
     config: Arc<ServerConfig>,
+    // the index of the shadowsocks listener, the last one, and its key
+    shadowsocks: Option<(usize, Arc<Key>)>,
     next_id: usize,
     conns: HashMap<usize, Connection>,
+    racing: HashSet<usize>,
+    udp_conns: HashSet<usize>,
+    ban_list: BanList,
+    handshake_limit: HandshakeLimit,
+    users: Users,
+    auth_failures: AuthFailures,
+    accept_paused: bool,
+    targets: TargetLimits,
+    // waiting connections whose target got a free slot
+    granted: Vec<usize>,
+    timers: TimerWheel,
 }
 
 impl TlsServer {
-    pub fn new(listener: TcpListener, config: Arc<ServerConfig>) -> TlsServer {
+    pub fn new(mut listeners: Vec<TcpListener>, ss_listener: Option<TcpListener>, config: Arc<ServerConfig>, stats_file: Option<StatsFile>,
+               opts: &Opts) -> TlsServer {
+        let args = opts.server_args();
+        let shadowsocks = ss_listener.map(|listener| {
+            listeners.push(listener);
+            (listeners.len() - 1, Arc::new(Key::new(args.ss_method, args.ss_password.as_ref().unwrap().as_str())))
+        });
+        let ban_list = BanList::new(args.ban_threshold, Duration::new(args.ban_window, 0), Duration::new(args.ban_time, 0));
+        // bans are not kept across restarts, neither is the list left by the last run
+        save_blocklist(&ban_list, opts);
         TlsServer {
-            listener,
+            listeners,
             config,
-            next_id: 2,
+            shadowsocks,
+            next_id: MIN_INDEX,
             conns: HashMap::new(),
+            racing: HashSet::new(),
+            udp_conns: HashSet::new(),
+            ban_list,
+            handshake_limit: HandshakeLimit::new(args.handshake_rate, args.handshake_burst, args.handshake_rate_per_ip, args.handshake_burst_per_ip),
+            users: Users::new(&args.user_quota, stats_file),
+            auth_failures: AuthFailures::new(),
+            accept_paused: false,
+            targets: TargetLimits::new(args.target_max_connections, args.target_queue_size),
+            granted: Vec::new(),
+            timers: TimerWheel::new(Duration::new(1, 0)),
         }
     }
 
-    pub fn accept(&mut self, poll: &Poll, opts: &Opts) {
+    pub fn accept(&mut self, listener: usize, poll: &Poll, opts: &Opts) {
         loop {
-            match self.listener.accept() {
+            match self.listeners[listener].accept() {
                 Ok((stream, addr)) => {
-                    log::debug!("get new connection, token:{}, address:{}", self.next_id, addr);
-                    let session = ServerSession::new(&self.config);
+                    if !opts.is_client_allowed(&addr.ip()) {
+                        log::debug!("connection from:{} is not allowed, drop it", addr);
+                        let _ = stream.set_linger(Some(Duration::new(0, 0)));
+                        continue;
+                    }
+                    let banned = self.ban_list.is_banned(&addr.ip(), Instant::now());
+                    if banned && opts.server_args().ban_action == BanAction::Drop {
+                        log::debug!("connection from:{} is banned, drop it", addr);
+                        let _ = stream.set_linger(Some(Duration::new(0, 0)));
+                        continue;
+                    }
+                    if !self.handshake_limit.allow(addr.ip(), Instant::now()) {
+                        log::debug!("connection from:{} is over the handshake rate, drop it", addr);
+                        let _ = stream.set_linger(Some(Duration::new(0, 0)));
+                        continue;
+                    }
+                    let session = match self.shadowsocks.as_ref() {
+                        Some((i, key)) if *i == listener => ProxySession::Shadowsocks(ShadowsocksSession::new(key.clone())),
+                        _ => ProxySession::Tls(ServerSession::new(&self.config)),
+                    };
                     let index = self.next_index();
-                    let mut conn = Connection::new(index, stream, session);
-                    if conn.setup(poll, opts) {
+                    log::debug!("connection:{} accepted from:{}", index, addr);
+                    if let Err(err) = sys::set_mark(&stream, opts.relay_args().marker) {
+                        log::error!("connection:{} set mark failed:{}", index, err);
+                        continue;
+                    } else if let Err(err) = stream.set_nodelay(true) {
+                        log::error!("connection:{} set nodelay failed:{}", index, err);
+                        continue;
+                    } else if let Err(err) = opts.tcp_opts.apply(&stream) {
+                        log::error!("connection:{} set tcp options failed:{}", index, err);
+                        continue;
+                    }
+                    let mut conn = Connection::new(index, Box::new(stream), session, banned);
+                    if conn.setup(poll) {
+                        self.timers.schedule(index, conn.deadline(opts));
                         self.conns.insert(index, conn);
                     } else {
                         conn.close_now(poll);
@@ -45,8 +123,10 @@ impl TlsServer {
                     break;
                 }
                 Err(err) => {
-                    log::error!("accept failed with error:{}, exit now", err);
-                    panic!(err)
+                    // out of fds most likely, the listener is registered again by the next budget check
+                    log::error!("accept failed with error:{}, pause accepting", err);
+                    self.stop_accept(poll);
+                    break;
                 }
             }
         }
@@ -56,7 +136,7 @@ impl TlsServer {
         let index = self.next_id;
         self.next_id += 1;
         if self.next_id == 0 {
-            self.next_id = 2;
+            self.next_id = MIN_INDEX;
         }
         index
     }
@@ -66,27 +146,346 @@ impl TlsServer {
         if self.conns.contains_key(&index) {
             let conn = self.conns.get_mut(&index).unwrap();
             conn.ready(poll, event, opts);
+            if let (true, Some(ip)) = (conn.take_auth_failed(), conn.peer_ip()) {
+                self.auth_failures.add(Instant::now());
+                if self.ban_list.auth_failed(ip, Instant::now()) {
+                    log::warn!("connection:{} client {} failed the handshake too often, banned for {}s", index, ip, opts.server_args().ban_time);
+                    save_blocklist(&self.ban_list, opts);
+                }
+            }
+            if conn.take_authenticated() {
+                if let Some(user) = conn.user() {
+                    if self.users.is_over_quota(user, 0) {
+                        log::warn!("connection:{} user:{} is over quota, close now", index, user);
+                        conn.close_now(poll);
+                    }
+                }
+            }
+            if let (false, Some(target)) = (conn.is_closed(), conn.asks_target_slot()) {
+                match self.targets.acquire(target, index) {
+                    Acquire::Connect => conn.grant_target(opts, poll),
+                    Acquire::Queued => conn.queue_target(),
+                    Acquire::Full => {
+                        log::warn!("connection:{} too many connections wait for the same target, close now", index);
+                        conn.close_now(poll);
+                    }
+                }
+            }
+            if !conn.is_closed() {
+                self.timers.schedule_earlier(index, conn.deadline(opts));
+            }
             if conn.is_closed() {
-                self.conns.remove(&index);
+                self.remove(index);
                 log::info!("connection:{} closed, remove from pool", index);
+            } else if conn.is_racing() {
+                self.racing.insert(index);
+            } else if conn.is_udp() && !self.udp_conns.contains(&index) {
+                self.udp_conns.insert(index);
+                self.limit_udp_sessions(index, opts, poll);
             }
         } else {
             log::error!("connection:{} not found", index);
         }
     }
 
-    pub fn check_timeout(&mut self, check_active_time: Instant, poll: &Poll) {
+    fn remove(&mut self, index: usize) {
+        if let Some(conn) = self.conns.remove(&index) {
+            self.users.closed(&conn);
+            if let Some(target) = conn.target_slot() {
+                if let Some(next) = self.targets.release(target.as_str(), index) {
+                    self.granted.push(next);
+                }
+            }
+        }
+        self.udp_conns.remove(&index);
+        self.timers.cancel(index);
+    }
+
+    // the waiting connections connect to their targets as others to the same targets close
+    pub fn start_granted(&mut self, opts: &mut Opts, poll: &Poll) {
+        while let Some(index) = self.granted.pop() {
+            if let Some(conn) = self.conns.get_mut(&index) {
+                log::info!("connection:{} got a slot for its target", index);
+                conn.grant_target(opts, poll);
+                if conn.is_closed() {
+                    self.remove(index);
+                } else {
+                    self.timers.schedule_earlier(index, conn.deadline(opts));
+                    if conn.is_racing() {
+                        self.racing.insert(index);
+                    }
+                }
+            }
+        }
+    }
+
+    // no more clients once stopping, the existing ones are drained
+    pub fn stop_accept(&mut self, poll: &Poll) {
+        if self.accept_paused {
+            return;
+        }
+        self.accept_paused = true;
+        for listener in self.listeners.iter() {
+            if let Err(err) = poll.deregister(listener) {
+                log::error!("deregister listener failed:{}", err);
+            }
+        }
+    }
+
+    // the clients waiting in the backlog are reported once registered again
+    fn resume_accept(&mut self, poll: &Poll) {
+        self.accept_paused = false;
+        for (i, listener) in self.listeners.iter().enumerate() {
+            if let Err(err) = poll.register(listener, Token(LISTENER + i), Ready::readable(), PollOpt::edge()) {
+                log::error!("register listener failed:{}", err);
+            }
+        }
+    }
+
+    // not called once stopping, so that accepting is not resumed
+    pub fn check_budget(&mut self, now: Instant, budget: &mut MemoryBudget, opts: &mut Opts, poll: &Poll) {
+        let queued = self.conns.values().map(|conn| conn.queued()).sum();
+        let shedding = budget.check(queued, opts);
+        if shedding && !self.accept_paused {
+            log::warn!("pause accepting new connections");
+            self.stop_accept(poll);
+        } else if !shedding && self.accept_paused {
+            log::warn!("resume accepting new connections");
+            self.resume_accept(poll);
+        }
+        let conns = self.conns.values().map(|conn| (conn.queued(), conn.last_active_time(), conn.index())).collect();
+        for index in budget.victims(conns, now, opts) {
+            if let Some(conn) = self.conns.get_mut(&index) {
+                log::warn!("connection:{} queues {} bytes while over the memory budget, close now", index, conn.queued());
+                conn.close_now(poll);
+            }
+            self.remove(index);
+        }
+    }
+
+    pub fn listeners(&self) -> &[TcpListener] {
+        self.listeners.as_slice()
+    }
+
+    pub fn conn_count(&self) -> usize {
+        self.conns.len()
+    }
+
+    pub fn is_racing(&self) -> bool {
+        !self.racing.is_empty()
+    }
+
+    pub fn check_racing(&mut self, poll: &Poll) {
+        let now = Instant::now();
+        let conns = &mut self.conns;
+        let mut closed = Vec::new();
+        self.racing.retain(|index| {
+            if let Some(conn) = conns.get_mut(index) {
+                conn.check_racing(now, poll);
+                if conn.is_closed() {
+                    closed.push(*index);
+                    false
+                } else {
+                    conn.is_racing()
+                }
+            } else {
+                false
+            }
+        });
+        for index in closed {
+            self.remove(index);
+        }
+    }
+
+    pub fn check_timeout(&mut self, now: Instant, opts: &mut Opts, poll: &Poll) {
+        if self.ban_list.check_timeout(now) {
+            save_blocklist(&self.ban_list, opts);
+        }
+        let dropped = self.handshake_limit.check_timeout(now);
+        if dropped > 0 {
+            log::warn!("{} connections dropped over the handshake rate", dropped);
+        }
         let mut list = Vec::new();
-        for (index, conn) in &mut self.conns {
-            if conn.timeout(check_active_time) {
-                list.push(*index);
-                log::warn!("connection:{} timeout, close now", index);
-                conn.close_now(poll)
+        // the connections due are checked alone, those which moved since are scheduled again
+        for index in self.timers.expire(now) {
+            if let Some(conn) = self.conns.get_mut(&index) {
+                if conn.timeout(now, opts, poll) {
+                    list.push(index);
+                    log::warn!("connection:{} timeout, close now", index);
+                    conn.close_now(poll)
+                } else {
+                    self.timers.schedule(index, conn.deadline(opts));
+                }
+            }
+        }
+        if self.users.has_quotas() {
+            // traffic of open connections counts against quotas as well
+            let mut open_bytes: HashMap<String, u64> = HashMap::new();
+            for conn in self.conns.values() {
+                if let Some(user) = conn.user() {
+                    *open_bytes.entry(user.to_string()).or_default() += (conn.bytes_sent() + conn.bytes_received()) as u64;
+                }
+            }
+            for (index, conn) in &mut self.conns {
+                if let (false, Some(user)) = (conn.is_closed(), conn.user()) {
+                    if self.users.is_over_quota(user, open_bytes[user]) {
+                        list.push(*index);
+                        log::warn!("connection:{} user:{} is over quota, close now", index, user);
+                        conn.close_now(poll)
+                    }
+                }
             }
         }
 
         for index in list {
-            self.conns.remove(&index);
+            self.remove(index);
+        }
+        self.save_stats();
+    }
+
+    pub fn save_stats(&mut self) {
+        self.users.save(self.conns.values());
+    }
+
+    pub fn hand_over_stats(&mut self) {
+        self.users.hand_over_stats_file();
+    }
+
+    pub fn admin_command(&mut self, command: &str, opts: &mut Opts) -> String {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        match words.as_slice() {
+            ["bans"] => self.ban_list.list(Instant::now()),
+            ["upgrade"] => {
+                log::warn!("binary upgrade is requested by admin");
+                sys::request_upgrade();
+                "upgrade requested\n".to_string()
+            }
+            ["users"] => self.users.list(self.conns.values()),
+            ["unban", ip] => match ip.parse::<IpAddr>() {
+                Ok(ip) if self.ban_list.unban(&ip) => {
+                    log::warn!("{} is unbanned by admin", ip);
+                    save_blocklist(&self.ban_list, opts);
+                    format!("{} unbanned\n", ip)
+                }
+                Ok(ip) => format!("{} is not banned\n", ip),
+                Err(_) => format!("invalid ip:{}\n", ip),
+            },
+            ["dns"] => opts.dns_cache.dump(),
+            ["dns", "delete", domain] => {
+                if opts.dns_cache.delete(domain) {
+                    log::warn!("{} is removed from dns cache by admin", domain);
+                    format!("{} deleted\n", domain)
+                } else {
+                    format!("{} is not cached\n", domain)
+                }
+            }
+            ["dns", "flush"] => {
+                let count = opts.dns_cache.flush();
+                log::warn!("dns cache is flushed by admin, {} entries removed", count);
+                format!("{} entries flushed\n", count)
+            }
+            ["log"] => {
+                let access_log = if !access_log::is_configured() {
+                    "none"
+                } else if access_log::is_enabled() {
+                    "on"
+                } else {
+                    "off"
+                };
+                format!("log level {}, access log {}\n", log_level::get(), access_log)
+            }
+            ["log", level] => match log_level::parse(level) {
+                Some(level) => {
+                    log_level::set(level);
+                    log::warn!("log level is set to {} by admin", level);
+                    format!("log level set to {}\n", level)
+                }
+                None => format!("invalid log level:{}, expected trace, debug, info, warn, error, off or 0 to 5\n", level),
+            },
+            ["access-log", state @ "on"] | ["access-log", state @ "off"] => {
+                if access_log::is_configured() {
+                    access_log::set_enabled(*state == "on");
+                    log::warn!("access log is turned {} by admin", state);
+                    format!("access log turned {}\n", state)
+                } else {
+                    "no access log configured\n".to_string()
+                }
+            }
+            _ => format!("unknown command:{}\ncommands are bans, unban <ip>, users, dns, dns delete <domain>, dns flush, log, \
+                          log <level> and access-log on|off\n", command),
+        }
+    }
+
+    // a call to the grpc api, the messages answering it or its status
+    pub fn api_call(&mut self, path: &str, message: &[u8], opts: &Opts, uptime: Duration) -> Result<Vec<Vec<u8>>, (u32, String)> {
+        api::call(path, message, &mut self.users, &self.conns, opts, uptime)
+    }
+
+    // the page of the dashboard and its stats, path is what follows /dashboard
+    pub fn dashboard(&self, path: &str, opts: &Opts) -> Option<(&'static str, String)> {
+        match path {
+            "" | "/" => Some(("text/html; charset=utf-8", dashboard::PAGE.to_string())),
+            "/stats" => {
+                let now = Instant::now();
+                let mut json = String::with_capacity(1024);
+                let _ = write!(json, "{{\"connections\":{},\"udp_sessions\":{},\"banned\":{},\"users\":",
+                               self.conns.len(), self.udp_conns.len(), self.ban_list.count(now));
+                self.users.write_json(self.conns.values(), &mut json);
+                json.push_str(",\"auth_failures\":[");
+                for (i, count) in self.auth_failures.history(now).into_iter().enumerate() {
+                    if i > 0 {
+                        json.push(',');
+                    }
+                    let _ = write!(json, "{}", count);
+                }
+                let (entries, hits, misses) = opts.dns_cache.stats();
+                json.push_str("],\"dns_cache\":{");
+                log_format::add_num(&mut json, "entries", Some(entries));
+                json.push(',');
+                log_format::add_num(&mut json, "hits", Some(hits));
+                json.push(',');
+                log_format::add_num(&mut json, "misses", Some(misses));
+                json.push_str("}}");
+                Some(("application/json", json))
+            }
+            _ => None,
+        }
+    }
+
+    // close the least recently active udp sessions of the client beyond the limit
+    fn limit_udp_sessions(&mut self, index: usize, opts: &Opts, poll: &Poll) {
+        if opts.server_args().max_udp_sessions_per_user == 0 {
+            return;
+        }
+        let peer_ip = match self.conns.get(&index).and_then(|conn| conn.peer_ip()) {
+            Some(ip) => ip,
+            None => return,
+        };
+        let conns = &self.conns;
+        let mut sessions: Vec<(Instant, usize)> = self.udp_conns.iter()
+            .filter_map(|index| conns.get(index))
+            .filter(|conn| conn.peer_ip() == Some(peer_ip))
+            .map(|conn| (conn.last_active_time(), conn.index()))
+            .collect();
+        if sessions.len() <= opts.server_args().max_udp_sessions_per_user {
+            return;
+        }
+        sessions.sort();
+        let count = sessions.len() - opts.server_args().max_udp_sessions_per_user;
+        for (_, index) in sessions.into_iter().take(count) {
+            if let Some(conn) = self.conns.get_mut(&index) {
+                log::warn!("connection:{} udp sessions of {} exceed the limit, close the least recently active one", index, peer_ip);
+                conn.close_now(poll);
+            }
+            self.remove(index);
+        }
+    }
+}
+
+fn save_blocklist(ban_list: &BanList, opts: &Opts) {
+    if let Some(path) = opts.server_args().blocklist_file.as_ref() {
+        if let Err(err) = ban_list.save(path.as_str()) {
+            log::error!("save blocklist file {} failed:{}", path, err);
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,84 @@
+use std::io::{Read, Result, Write};
+
+use rustls::{ServerSession, Session};
+
+use crate::server::shadowsocks::ShadowsocksSession;
+
+// the session of a client, tls for trojan, or the cipher of the shadowsocks listener
+pub enum ProxySession {
+    Tls(ServerSession),
+    Shadowsocks(ShadowsocksSession),
+}
+
+impl ProxySession {
+    pub fn read_tls(&mut self, reader: &mut dyn Read) -> Result<usize> {
+        match self {
+            ProxySession::Tls(session) => session.read_tls(reader),
+            ProxySession::Shadowsocks(session) => session.read_tls(reader),
+        }
+    }
+
+    pub fn write_tls(&mut self, writer: &mut dyn Write) -> Result<usize> {
+        match self {
+            ProxySession::Tls(session) => session.write_tls(writer),
+            ProxySession::Shadowsocks(session) => session.write_tls(writer),
+        }
+    }
+
+    pub fn process_new_packets(&mut self) -> std::result::Result<(), String> {
+        match self {
+            ProxySession::Tls(session) => session.process_new_packets().map_err(|err| err.to_string()),
+            ProxySession::Shadowsocks(session) => session.process_new_packets(),
+        }
+    }
+
+    pub fn wants_write(&self) -> bool {
+        match self {
+            ProxySession::Tls(session) => session.wants_write(),
+            ProxySession::Shadowsocks(session) => session.wants_write(),
+        }
+    }
+
+    pub fn is_handshaking(&self) -> bool {
+        match self {
+            ProxySession::Tls(session) => session.is_handshaking(),
+            ProxySession::Shadowsocks(session) => session.is_handshaking(),
+        }
+    }
+
+    // shadowsocks has no alert of its own, the shutdown of the socket tells the end
+    pub fn send_close_notify(&mut self) {
+        if let ProxySession::Tls(session) = self {
+            session.send_close_notify();
+        }
+    }
+
+    pub fn is_shadowsocks(&self) -> bool {
+        matches!(self, ProxySession::Shadowsocks(_))
+    }
+}
+
+impl Read for ProxySession {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            ProxySession::Tls(session) => session.read(buf),
+            ProxySession::Shadowsocks(session) => session.read(buf),
+        }
+    }
+}
+
+impl Write for ProxySession {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            ProxySession::Tls(session) => session.write(buf),
+            ProxySession::Shadowsocks(session) => session.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            ProxySession::Tls(session) => session.flush(),
+            ProxySession::Shadowsocks(session) => session.flush(),
+        }
+    }
+}
@@ -0,0 +1,386 @@
+use std::io::{Read, Result, Write};
+use std::sync::Arc;
+
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::aes::KeySize;
+use crypto::aes_gcm::AesGcm;
+use crypto::chacha20::ChaCha20;
+use crypto::digest::Digest;
+use crypto::hkdf::{hkdf_expand, hkdf_extract};
+use crypto::mac::Mac;
+use crypto::md5::Md5;
+use crypto::poly1305::Poly1305;
+use crypto::sha1::Sha1;
+use crypto::symmetriccipher::SynchronousStreamCipher;
+use crypto::util::fixed_time_eq;
+
+use crate::config::ShadowsocksMethod;
+use crate::sys;
+
+const TAG_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+// payloads of a chunk are limited to 14 bits by the aead spec of shadowsocks
+const MAX_PAYLOAD_LEN: usize = 0x3fff;
+
+// the master key of the shadowsocks listener, derived from the password like EVP_BytesToKey of openssl does
+pub struct Key {
+    method: ShadowsocksMethod,
+    key: Vec<u8>,
+    user: Option<String>,
+}
+
+impl Key {
+    // label:password or just the password, as in --password-file
+    pub fn new(method: ShadowsocksMethod, password: &str) -> Key {
+        let (user, password) = match password.find(':') {
+            Some(pos) if pos > 0 => (Some(password[..pos].to_string()), &password[pos + 1..]),
+            Some(_) => (None, &password[1..]),
+            None => (None, password),
+        };
+        let key_len = key_len(method);
+        let mut key = Vec::new();
+        let mut digest = [0u8; 16];
+        while key.len() < key_len {
+            let mut md5 = Md5::new();
+            if !key.is_empty() {
+                md5.input(&digest);
+            }
+            md5.input(password.as_bytes());
+            md5.result(&mut digest);
+            key.extend_from_slice(&digest);
+        }
+        key.truncate(key_len);
+        Key {
+            method,
+            key,
+            user,
+        }
+    }
+
+    pub fn user(&self) -> Option<&String> {
+        self.user.as_ref()
+    }
+}
+
+fn key_len(method: ShadowsocksMethod) -> usize {
+    match method {
+        ShadowsocksMethod::Aes128Gcm => 16,
+        ShadowsocksMethod::Aes256Gcm | ShadowsocksMethod::Chacha20Poly1305 => 32,
+    }
+}
+
+// the cipher of one direction, keyed by the salt sent first, the nonce counts the chunks sealed or opened
+struct Cipher {
+    method: ShadowsocksMethod,
+    key: Vec<u8>,
+    nonce: [u8; NONCE_LEN],
+}
+
+impl Cipher {
+    fn new(key: &Key, salt: &[u8]) -> Cipher {
+        let mut prk = [0u8; 20];
+        hkdf_extract(Sha1::new(), salt, key.key.as_slice(), &mut prk);
+        let mut subkey = vec![0u8; key.key.len()];
+        hkdf_expand(Sha1::new(), &prk, b"ss-subkey", subkey.as_mut_slice());
+        Cipher {
+            method: key.method,
+            key: subkey,
+            nonce: [0u8; NONCE_LEN],
+        }
+    }
+
+    // appends the sealed data and its tag to output
+    fn seal(&mut self, data: &[u8], output: &mut Vec<u8>) {
+        let start = output.len();
+        output.resize(start + data.len() + TAG_LEN, 0);
+        let (sealed, tag) = output[start..].split_at_mut(data.len());
+        match self.method {
+            ShadowsocksMethod::Aes128Gcm => AesGcm::new(KeySize::KeySize128, &self.key, &self.nonce, &[]).encrypt(data, sealed, tag),
+            ShadowsocksMethod::Aes256Gcm => AesGcm::new(KeySize::KeySize256, &self.key, &self.nonce, &[]).encrypt(data, sealed, tag),
+            ShadowsocksMethod::Chacha20Poly1305 => {
+                let (mut chacha, poly_key) = chacha20(&self.key, &self.nonce);
+                chacha.process(data, sealed);
+                tag.copy_from_slice(&poly1305(&poly_key, sealed));
+            }
+        }
+        self.next_nonce();
+    }
+
+    // data is the sealed data followed by its tag, output as long as the sealed data
+    fn open(&mut self, data: &[u8], output: &mut [u8]) -> bool {
+        let (sealed, tag) = data.split_at(data.len() - TAG_LEN);
+        let opened = match self.method {
+            ShadowsocksMethod::Aes128Gcm => AesGcm::new(KeySize::KeySize128, &self.key, &self.nonce, &[]).decrypt(sealed, output, tag),
+            ShadowsocksMethod::Aes256Gcm => AesGcm::new(KeySize::KeySize256, &self.key, &self.nonce, &[]).decrypt(sealed, output, tag),
+            ShadowsocksMethod::Chacha20Poly1305 => {
+                let (mut chacha, poly_key) = chacha20(&self.key, &self.nonce);
+                if fixed_time_eq(&poly1305(&poly_key, sealed), tag) {
+                    chacha.process(sealed, output);
+                    true
+                } else {
+                    false
+                }
+            }
+        };
+        self.next_nonce();
+        opened
+    }
+
+    fn next_nonce(&mut self) {
+        for byte in self.nonce.iter_mut() {
+            *byte = byte.wrapping_add(1);
+            if *byte != 0 {
+                break;
+            }
+        }
+    }
+}
+
+// rust-crypto only has chacha20-poly1305 with 8 bytes nonces, the construction of RFC 8439 is put together here,
+// the first block keys poly1305 and the data is ciphered from the second one
+fn chacha20(key: &[u8], nonce: &[u8]) -> (ChaCha20, [u8; 32]) {
+    let mut chacha = ChaCha20::new(key, nonce);
+    let mut block = [0u8; 64];
+    chacha.process(&[0u8; 64], &mut block);
+    let mut poly_key = [0u8; 32];
+    poly_key.copy_from_slice(&block[..32]);
+    (chacha, poly_key)
+}
+
+fn poly1305(key: &[u8], sealed: &[u8]) -> [u8; TAG_LEN] {
+    let mut mac = Poly1305::new(key);
+    mac.input(sealed);
+    if sealed.len() % 16 != 0 {
+        mac.input(&[0u8; 16][..16 - sealed.len() % 16]);
+    }
+    // the length of the empty additional data, then that of the sealed data
+    let mut lengths = [0u8; 16];
+    lengths[8..].copy_from_slice(&(sealed.len() as u64).to_le_bytes());
+    mac.input(&lengths);
+    let mut tag = [0u8; TAG_LEN];
+    mac.raw_result(&mut tag);
+    tag
+}
+
+// takes the place of the tls session for clients of the shadowsocks listener, each direction starts with its salt,
+// then chunks of a sealed length and a sealed payload follow
+pub struct ShadowsocksSession {
+    key: Arc<Key>,
+    decryptor: Option<Cipher>,
+    encryptor: Option<Cipher>,
+    received: Vec<u8>,
+    plaintext: Vec<u8>,
+    sendable: Vec<u8>,
+    // the length of the payload waited for, once the chunk of its length is opened
+    payload_len: Option<usize>,
+    established: bool,
+}
+
+impl ShadowsocksSession {
+    pub fn new(key: Arc<Key>) -> ShadowsocksSession {
+        ShadowsocksSession {
+            key,
+            decryptor: None,
+            encryptor: None,
+            received: Vec::new(),
+            plaintext: Vec::new(),
+            sendable: Vec::new(),
+            payload_len: None,
+            established: false,
+        }
+    }
+
+    pub fn user(&self) -> Option<String> {
+        self.key.user().cloned()
+    }
+
+    pub fn read_tls(&mut self, reader: &mut dyn Read) -> Result<usize> {
+        let mut buffer = [0u8; 16384];
+        let size = reader.read(&mut buffer)?;
+        self.received.extend_from_slice(&buffer[..size]);
+        Ok(size)
+    }
+
+    pub fn write_tls(&mut self, writer: &mut dyn Write) -> Result<usize> {
+        let size = writer.write(self.sendable.as_slice())?;
+        self.sendable.drain(..size);
+        Ok(size)
+    }
+
+    // opens the complete chunks received, a chunk failing to open means a wrong password or tampered data
+    pub fn process_new_packets(&mut self) -> std::result::Result<(), String> {
+        let mut pos = 0;
+        let result = loop {
+            if self.decryptor.is_none() {
+                let salt_len = key_len(self.key.method);
+                if self.received.len() < salt_len {
+                    break Ok(());
+                }
+                self.decryptor = Some(Cipher::new(&self.key, &self.received[..salt_len]));
+                pos = salt_len;
+            }
+            let cipher = self.decryptor.as_mut().unwrap();
+            let data = &self.received[pos..];
+            match self.payload_len {
+                None => {
+                    if data.len() < 2 + TAG_LEN {
+                        break Ok(());
+                    }
+                    let mut len = [0u8; 2];
+                    if !cipher.open(&data[..2 + TAG_LEN], &mut len) {
+                        break Err("invalid chunk length".to_string());
+                    }
+                    let len = u16::from_be_bytes(len) as usize;
+                    if len > MAX_PAYLOAD_LEN {
+                        break Err(format!("chunk length {} too large", len));
+                    }
+                    self.payload_len = Some(len);
+                    pos += 2 + TAG_LEN;
+                }
+                Some(len) => {
+                    if data.len() < len + TAG_LEN {
+                        break Ok(());
+                    }
+                    let start = self.plaintext.len();
+                    self.plaintext.resize(start + len, 0);
+                    if !cipher.open(&data[..len + TAG_LEN], &mut self.plaintext[start..]) {
+                        self.plaintext.truncate(start);
+                        break Err("invalid chunk payload".to_string());
+                    }
+                    self.payload_len = None;
+                    self.established = true;
+                    pos += len + TAG_LEN;
+                }
+            }
+        };
+        self.received.drain(..pos);
+        result
+    }
+
+    pub fn wants_write(&self) -> bool {
+        !self.sendable.is_empty()
+    }
+
+    // until the first chunk is opened, so that the handshake timeout applies
+    pub fn is_handshaking(&self) -> bool {
+        !self.established
+    }
+}
+
+impl Read for ShadowsocksSession {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let size = std::cmp::min(buf.len(), self.plaintext.len());
+        buf[..size].copy_from_slice(&self.plaintext[..size]);
+        self.plaintext.drain(..size);
+        Ok(size)
+    }
+}
+
+impl Write for ShadowsocksSession {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.encryptor.is_none() {
+            let mut salt = vec![0u8; key_len(self.key.method)];
+            sys::fill_random(salt.as_mut_slice())?;
+            self.encryptor = Some(Cipher::new(&self.key, salt.as_slice()));
+            self.sendable.extend_from_slice(salt.as_slice());
+        }
+        let cipher = self.encryptor.as_mut().unwrap();
+        for chunk in buf.chunks(MAX_PAYLOAD_LEN) {
+            cipher.seal(&(chunk.len() as u16).to_be_bytes(), &mut self.sendable);
+            cipher.seal(chunk, &mut self.sendable);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn salt(len: usize) -> Vec<u8> {
+        (0..len as u8).collect()
+    }
+
+    #[test]
+    fn derive_key() {
+        let key = Key::new(ShadowsocksMethod::Aes128Gcm, "password");
+        assert_eq!(key.key, hex("5f4dcc3b5aa765d61d8327deb882cf99"));
+        assert_eq!(key.user(), None);
+        let key = Key::new(ShadowsocksMethod::Aes256Gcm, "alice:password");
+        assert_eq!(key.key, hex("5f4dcc3b5aa765d61d8327deb882cf992b95990a9151374abd8ff8c5a7a0fe08"));
+        assert_eq!(key.user(), Some(&"alice".to_string()));
+        let key = Key::new(ShadowsocksMethod::Chacha20Poly1305, ":password");
+        assert_eq!(key.key, hex("5f4dcc3b5aa765d61d8327deb882cf992b95990a9151374abd8ff8c5a7a0fe08"));
+        assert_eq!(key.user(), None);
+    }
+
+    #[test]
+    fn derive_subkey() {
+        let key = Key::new(ShadowsocksMethod::Aes128Gcm, "password");
+        assert_eq!(Cipher::new(&key, &salt(16)).key, hex("ed2a618d9490d1701de885d82aa80616"));
+        let key = Key::new(ShadowsocksMethod::Aes256Gcm, "password");
+        assert_eq!(Cipher::new(&key, &salt(32)).key, hex("ee187aed3f87574907a39db98606f60a526114831288097cac66054b33a9464f"));
+    }
+
+    #[test]
+    fn seal_chunk() {
+        let vectors = [
+            (ShadowsocksMethod::Aes256Gcm, "7ea089e1d8874f484867a34f5b648078a737", "9d45b3194573671c53431294750d0362127bcf8679"),
+            (ShadowsocksMethod::Chacha20Poly1305, "ad4d5c2599d42f6d9b26804b82a3b96dc584", "e8adc7498c0ff41f578989fe0c5ded753038d91134"),
+        ];
+        for (method, length, payload) in vectors.iter() {
+            let mut cipher = Cipher::new(&Key::new(*method, "password"), &salt(32));
+            let mut output = Vec::new();
+            cipher.seal(&[0, 5], &mut output);
+            assert_eq!(output, hex(length));
+            output.clear();
+            cipher.seal(b"hello", &mut output);
+            assert_eq!(output, hex(payload));
+        }
+    }
+
+    #[test]
+    fn open_chunk() {
+        for method in [ShadowsocksMethod::Aes128Gcm, ShadowsocksMethod::Aes256Gcm, ShadowsocksMethod::Chacha20Poly1305].iter() {
+            let key = Arc::new(Key::new(*method, "password"));
+            let mut data = salt(key_len(*method));
+            let mut cipher = Cipher::new(&key, data.as_slice());
+            cipher.seal(&[0, 5], &mut data);
+            cipher.seal(b"hello", &mut data);
+
+            // the chunks are opened once complete, however they arrive
+            let mut session = ShadowsocksSession::new(key.clone());
+            let (first, second) = data.split_at(data.len() - 3);
+            session.read_tls(&mut &first[..]).unwrap();
+            session.process_new_packets().unwrap();
+            assert!(session.is_handshaking());
+            session.read_tls(&mut &second[..]).unwrap();
+            session.process_new_packets().unwrap();
+            assert!(!session.is_handshaking());
+            let mut plaintext = [0u8; 16];
+            assert_eq!(session.read(&mut plaintext).unwrap(), 5);
+            assert_eq!(&plaintext[..5], b"hello");
+
+            let mut tampered = data.clone();
+            *tampered.last_mut().unwrap() ^= 1;
+            let mut session = ShadowsocksSession::new(key.clone());
+            session.read_tls(&mut tampered.as_slice()).unwrap();
+            assert_eq!(session.process_new_packets(), Err("invalid chunk payload".to_string()));
+            assert_eq!(session.read(&mut plaintext).unwrap(), 0);
+
+            let mut tampered = data.clone();
+            tampered[key_len(*method) + 2] ^= 1;
+            let mut session = ShadowsocksSession::new(key);
+            session.read_tls(&mut tampered.as_slice()).unwrap();
+            assert_eq!(session.process_new_packets(), Err("invalid chunk length".to_string()));
+        }
+    }
+}
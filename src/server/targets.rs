@@ -0,0 +1,63 @@
+use std::collections::{HashMap, VecDeque};
+
+pub enum Acquire {
+    Connect,
+    Queued,
+    Full,
+}
+
+#[derive(Default)]
+struct Target {
+    connections: usize,
+    // the connections waiting for one of the others to close, first come first served
+    waiting: VecDeque<usize>,
+}
+
+// the connections to each target, host and port as the client asked, so that a single client can not flood an origin
+// with connections from the server's address
+pub struct TargetLimits {
+    max_connections: usize,
+    queue_size: usize,
+    targets: HashMap<String, Target>,
+}
+
+impl TargetLimits {
+    pub fn new(max_connections: usize, queue_size: usize) -> TargetLimits {
+        TargetLimits {
+            max_connections,
+            queue_size,
+            targets: HashMap::new(),
+        }
+    }
+
+    pub fn acquire(&mut self, target: String, index: usize) -> Acquire {
+        let entry = self.targets.entry(target).or_default();
+        if entry.connections < self.max_connections {
+            entry.connections += 1;
+            Acquire::Connect
+        } else if entry.waiting.len() < self.queue_size {
+            entry.waiting.push_back(index);
+            Acquire::Queued
+        } else {
+            Acquire::Full
+        }
+    }
+
+    // the connection counted for the target is gone, returns the waiting one which takes its place
+    pub fn release(&mut self, target: &str, index: usize) -> Option<usize> {
+        let entry = self.targets.get_mut(target)?;
+        let next = if let Some(i) = entry.waiting.iter().position(|waiting| *waiting == index) {
+            entry.waiting.remove(i);
+            None
+        } else if let Some(next) = entry.waiting.pop_front() {
+            Some(next)
+        } else {
+            entry.connections -= 1;
+            None
+        };
+        if entry.connections == 0 && entry.waiting.is_empty() {
+            self.targets.remove(target);
+        }
+        next
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::config::UserQuota;
+use crate::log_format;
+use crate::server::connection::Connection;
+use crate::stats_file::StatsFile;
+
+#[derive(Default, Clone)]
+struct Usage {
+    connections: usize,
+    bytes_sent: u64,
+    bytes_received: u64,
+}
+
+impl Usage {
+    fn add(&mut self, usage: &Usage) {
+        self.connections += usage.connections;
+        self.bytes_sent += usage.bytes_sent;
+        self.bytes_received += usage.bytes_received;
+    }
+}
+
+// traffic of labeled users since start, counted once their connections close
+pub struct Users {
+    quotas: HashMap<String, u64>,
+    usages: HashMap<String, Usage>,
+    // the traffic of each user when the api last reset its stats, taken off what the api reports
+    bases: HashMap<String, Usage>,
+    // --stats-file, and the traffic it held at start, to which that since start is added
+    stats_file: Option<StatsFile>,
+    saved: HashMap<String, Usage>,
+}
+
+impl Users {
+    pub fn new(quotas: &[UserQuota], stats_file: Option<StatsFile>) -> Users {
+        let saved = stats_file.as_ref().map_or(HashMap::new(), |stats_file| {
+            stats_file.records().into_iter().map(|record| (record.user, Usage {
+                connections: record.connections as usize,
+                bytes_sent: record.bytes_sent,
+                bytes_received: record.bytes_received,
+            })).collect()
+        });
+        Users {
+            quotas: quotas.iter().map(|quota| (quota.user.clone(), quota.bytes)).collect(),
+            usages: HashMap::new(),
+            bases: HashMap::new(),
+            stats_file,
+            saved,
+        }
+    }
+
+    pub fn closed(&mut self, conn: &Connection) {
+        if let Some(user) = conn.user() {
+            let usage = self.usages.entry(user.to_string()).or_default();
+            usage.connections += 1;
+            usage.bytes_sent += conn.bytes_sent() as u64;
+            usage.bytes_received += conn.bytes_received() as u64;
+        }
+    }
+
+    // open_bytes is the traffic of the connections still open
+    pub fn has_quotas(&self) -> bool {
+        !self.quotas.is_empty()
+    }
+
+    pub fn is_over_quota(&self, user: &str, open_bytes: u64) -> bool {
+        match self.quotas.get(user) {
+            Some(quota) => self.bytes(user) + open_bytes >= *quota,
+            None => false,
+        }
+    }
+
+    fn bytes(&self, user: &str) -> u64 {
+        self.usages.get(user).map_or(0, |usage| usage.bytes_sent + usage.bytes_received)
+    }
+
+    // the open connections and the closed ones of each user, sorted by name
+    fn totals<'a, I: Iterator<Item = &'a Connection>>(&'a self, conns: I) -> Vec<(&'a str, Usage, Usage)> {
+        let mut open: HashMap<&str, Usage> = HashMap::new();
+        for conn in conns {
+            if let Some(user) = conn.user() {
+                let usage = open.entry(user).or_default();
+                usage.connections += 1;
+                usage.bytes_sent += conn.bytes_sent() as u64;
+                usage.bytes_received += conn.bytes_received() as u64;
+            }
+        }
+        let mut users: Vec<&str> = self.usages.keys().map(|user| user.as_str())
+            .chain(open.keys().cloned())
+            .chain(self.quotas.keys().map(|user| user.as_str()))
+            .collect();
+        users.sort();
+        users.dedup();
+        users.into_iter().map(|user| {
+            let closed = self.usages.get(user).cloned().unwrap_or_default();
+            let opened = open.remove(user).unwrap_or_default();
+            (user, opened, closed)
+        }).collect()
+    }
+
+    // one line per user, with the open connections and the traffic including theirs
+    pub fn list<'a, I: Iterator<Item = &'a Connection>>(&'a self, conns: I) -> String {
+        let mut list = String::new();
+        for (user, opened, closed) in self.totals(conns) {
+            let _ = write!(list, "{} open={} closed={} sent={} received={}", user, opened.connections, closed.connections,
+                           closed.bytes_sent + opened.bytes_sent, closed.bytes_received + opened.bytes_received);
+            let _ = match self.quotas.get(user) {
+                Some(quota) => writeln!(list, " quota={}", quota),
+                None => writeln!(list),
+            };
+        }
+        list
+    }
+
+    // the same as list, as a json array for the dashboard
+    pub fn write_json<'a, I: Iterator<Item = &'a Connection>>(&'a self, conns: I, json: &mut String) {
+        json.push('[');
+        for (i, (user, opened, closed)) in self.totals(conns).into_iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push('{');
+            log_format::add_str(json, "user", Some(user));
+            let _ = write!(json, ",\"open\":{},\"closed\":{},\"sent\":{},\"received\":{},", opened.connections, closed.connections,
+                           closed.bytes_sent + opened.bytes_sent, closed.bytes_received + opened.bytes_received);
+            log_format::add_num(json, "quota", self.quotas.get(user));
+            json.push('}');
+        }
+        json.push(']');
+    }
+
+    // the uplink and the downlink of each user since the api last reset them, what the client sent and what it was sent
+    pub fn traffic<'a, I: Iterator<Item = &'a Connection>>(&'a self, conns: I) -> Vec<(&'a str, u64, u64)> {
+        self.totals(conns).into_iter().map(|(user, opened, closed)| {
+            let base = self.bases.get(user).cloned().unwrap_or_default();
+            (user, (closed.bytes_received + opened.bytes_received).saturating_sub(base.bytes_received),
+             (closed.bytes_sent + opened.bytes_sent).saturating_sub(base.bytes_sent))
+        }).collect()
+    }
+
+    // takes the uplink and the downlink the api has reported off what it reports next, quotas are left as they are
+    pub fn reset_traffic(&mut self, user: &str, uplink: u64, downlink: u64) {
+        let base = self.bases.entry(user.to_string()).or_default();
+        base.bytes_received += uplink;
+        base.bytes_sent += downlink;
+    }
+
+    // writes the traffic of each user including the open connections to --stats-file, a user with no traffic since
+    // start keeps its record as it is
+    pub fn save<'a, I: Iterator<Item = &'a Connection>>(&mut self, conns: I) {
+        let stats_file = match self.stats_file.as_mut() {
+            Some(stats_file) => stats_file,
+            None => return,
+        };
+        let mut totals: HashMap<&str, Usage> = HashMap::new();
+        for (user, usage) in self.usages.iter() {
+            totals.entry(user.as_str()).or_default().add(usage);
+        }
+        for conn in conns {
+            if let Some(user) = conn.user() {
+                let usage = totals.entry(user).or_default();
+                usage.connections += 1;
+                usage.bytes_sent += conn.bytes_sent() as u64;
+                usage.bytes_received += conn.bytes_received() as u64;
+            }
+        }
+        for (user, mut usage) in totals {
+            if let Some(saved) = self.saved.get(user) {
+                usage.add(saved);
+            }
+            if let Err(err) = stats_file.update(user, usage.connections as u64, usage.bytes_sent, usage.bytes_received) {
+                log::error!("update stats file failed:{}", err);
+                return;
+            }
+        }
+    }
+
+    // a new process took over the file with an upgrade, this one no longer writes it
+    pub fn hand_over_stats_file(&mut self) {
+        if self.stats_file.take().is_some() {
+            log::warn!("stats file handed over to the new process");
+        }
+    }
+}
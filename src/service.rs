@@ -0,0 +1,148 @@
+use crate::config::Opts;
+#[cfg(windows)]
+use crate::config::{ServiceAction, ServiceInstallArgs, ServiceNameArgs};
+#[cfg(windows)]
+use crate::error::{self, Error};
+
+#[cfg(windows)]
+use std::ffi::OsString;
+#[cfg(windows)]
+use std::time::Duration;
+
+#[cfg(windows)]
+use windows_service::{define_windows_service, service_dispatcher};
+#[cfg(windows)]
+use windows_service::service::{ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+                               ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType};
+#[cfg(windows)]
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle};
+#[cfg(windows)]
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+
+#[cfg(windows)]
+use crate::sys;
+
+// installs or removes the windows service
+#[cfg(windows)]
+pub fn control(opts: &Opts) {
+    let result = match &opts.service_args().action {
+        ServiceAction::Install(args) => install(args),
+        ServiceAction::Uninstall(args) => uninstall(args),
+    };
+    if let Err(err) = result {
+        error::exit(err);
+    }
+}
+
+#[cfg(windows)]
+fn install(args: &ServiceInstallArgs) -> error::Result<()> {
+    // global options are kept, and the service subcommand is replaced by the mode given after --
+    let argv: Vec<String> = std::env::args().skip(1).collect();
+    let pos = argv.iter().position(|arg| arg == "service").unwrap();
+    let mut launch_arguments: Vec<OsString> = argv[..pos].iter().map(OsString::from).collect();
+    launch_arguments.push(OsString::from("--service"));
+    launch_arguments.push(OsString::from(args.name.as_str()));
+    launch_arguments.extend(args.args.iter().map(OsString::from));
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE)
+        .map_err(|err| service_error("connect to service manager", err))?;
+    let info = ServiceInfo {
+        name: OsString::from(args.name.as_str()),
+        display_name: OsString::from(args.name.as_str()),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe().map_err(|err| Error::io("get executable path", err))?,
+        launch_arguments,
+        dependencies: vec![],
+        account_name: None,
+        account_password: None,
+    };
+    manager.create_service(&info, ServiceAccess::empty())
+        .map_err(|err| service_error(format!("create service {}", args.name), err))?;
+    log::warn!("service {} installed", args.name);
+    Ok(())
+}
+
+#[cfg(windows)]
+fn uninstall(args: &ServiceNameArgs) -> error::Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .map_err(|err| service_error("connect to service manager", err))?;
+    let service = manager.open_service(args.name.as_str(), ServiceAccess::DELETE)
+        .map_err(|err| service_error(format!("open service {}", args.name), err))?;
+    service.delete().map_err(|err| service_error(format!("delete service {}", args.name), err))?;
+    log::warn!("service {} uninstalled, it is removed once stopped", args.name);
+    Ok(())
+}
+
+// the service manager fails calls with an os error, the other errors are names or arguments it can not take
+#[cfg(windows)]
+fn service_error<S: Into<String>>(context: S, err: windows_service::Error) -> Error {
+    match err {
+        windows_service::Error::Winapi(err) => Error::io(context, err),
+        err => Error::Config(format!("{} failed:{}", context.into(), err)),
+    }
+}
+
+#[cfg(not(windows))]
+pub fn control(_opts: &Opts) {
+    log::error!("service is only supported on windows, use the init system instead");
+    std::process::exit(1);
+}
+
+#[cfg(windows)]
+define_windows_service!(ffi_service_main, service_main);
+
+// runs trojan under the service control manager, which calls service_main in another thread
+#[cfg(windows)]
+pub fn dispatch(name: &str) {
+    if let Err(err) = service_dispatcher::start(name, ffi_service_main) {
+        log::error!("start service {} failed:{}", name, err);
+    }
+}
+
+#[cfg(not(windows))]
+pub fn dispatch(_name: &str) {
+    log::error!("service is only supported on windows");
+    std::process::exit(1);
+}
+
+#[cfg(windows)]
+fn service_main(_arguments: Vec<OsString>) {
+    let opts = crate::parse_opts();
+    let name = opts.service.clone().unwrap();
+    let handler = move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            log::warn!("service stop requested");
+            sys::stop();
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+    let handle = match service_control_handler::register(name.as_str(), handler) {
+        Ok(handle) => handle,
+        Err(err) => {
+            log::error!("register service control handler failed:{}", err);
+            return;
+        }
+    };
+    set_status(&handle, ServiceState::Running, ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN);
+    crate::run(opts);
+    set_status(&handle, ServiceState::Stopped, ServiceControlAccept::empty());
+}
+
+#[cfg(windows)]
+fn set_status(handle: &ServiceStatusHandle, state: ServiceState, controls_accepted: ServiceControlAccept) {
+    let status = ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted,
+        exit_code: ServiceExitCode::Win32(0),
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    };
+    if let Err(err) = handle.set_service_status(status) {
+        log::error!("set service status failed:{}", err);
+    }
+}
@@ -3,13 +3,15 @@ use std::io::{Error, ErrorKind, Read, Result, Write};
 use bytes::{Buf, BytesMut};
 
 pub struct TcpSession {
+    index: usize,
     pub recv_buf: BytesMut,
     pub send_buf: BytesMut,
 }
 
 impl TcpSession {
-    pub fn new() -> TcpSession {
+    pub fn new(index: usize) -> TcpSession {
         TcpSession {
+            index,
             recv_buf: BytesMut::new(),
             send_buf: BytesMut::new(),
         }
@@ -24,11 +26,15 @@ impl TcpSession {
                 self.recv_buf.set_len(cap);
             }
             let buffer = &mut self.recv_buf.as_mut()[len..];
-            log::debug!("read from backend, len:{}, cap:{}, buffer:{}", len, cap, buffer.len());
+            log::debug!("connection:{} read from backend, len:{}, cap:{}, buffer:{}", self.index, len, cap, buffer.len());
             match reader.read(buffer) {
                 Ok(size) => {
-                    log::debug!("read {} bytes from backend", size);
+                    log::debug!("connection:{} read {} bytes from backend", self.index, size);
                     if size == 0 {
+                        // what was read before eof is kept for the caller
+                        unsafe {
+                            self.recv_buf.set_len(len);
+                        }
                         return Err(Error::from(ErrorKind::UnexpectedEof));
                     } else {
                         unsafe {
@@ -41,7 +47,7 @@ impl TcpSession {
                     unsafe {
                         self.recv_buf.set_len(len);
                     }
-                    log::debug!("read from backend blocked");
+                    log::debug!("connection:{} read from backend blocked", self.index);
                     break;
                 }
                 Err(err) => {
@@ -62,10 +68,10 @@ impl TcpSession {
                 Ok(size) => {
                     self.send_buf.advance(size);
                     len += size;
-                    log::debug!("session write {} byte to backend", size);
+                    log::debug!("connection:{} session write {} byte to backend", self.index, size);
                 }
                 Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
-                    log::debug!("session write blocked, remaining:{}", self.send_buf.len());
+                    log::debug!("connection:{} session write blocked, remaining:{}", self.index, self.send_buf.len());
                     break;
                 }
                 Err(err) => {
@@ -80,6 +86,10 @@ impl TcpSession {
         !self.send_buf.is_empty()
     }
 
+    pub fn pending(&self) -> usize {
+        self.send_buf.len()
+    }
+
     pub fn read_all(&mut self) -> BytesMut {
         self.recv_buf.split()
     }
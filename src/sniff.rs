@@ -0,0 +1,74 @@
+const HTTP_METHODS: [&str; 9] = ["GET ", "POST ", "HEAD ", "PUT ", "DELETE ", "OPTIONS ", "PATCH ", "CONNECT ", "TRACE "];
+
+pub fn sniff(data: &[u8]) -> Option<String> {
+    if data.first() == Some(&0x16) {
+        sniff_sni(data)
+    } else if HTTP_METHODS.iter().any(|method| data.starts_with(method.as_bytes())) {
+        sniff_host(data)
+    } else {
+        None
+    }
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Option<usize> {
+    if pos + 2 > data.len() {
+        None
+    } else {
+        Some(((data[pos] as usize) << 8) | data[pos + 1] as usize)
+    }
+}
+
+fn sniff_sni(data: &[u8]) -> Option<String> {
+    // record header(5), handshake type(1), length(3), version(2), random(32)
+    if data.len() < 43 || data[5] != 0x01 {
+        return None;
+    }
+    let mut pos = 43;
+    pos += 1 + *data.get(pos)? as usize;
+    pos += 2 + read_u16(data, pos)?;
+    pos += 1 + *data.get(pos)? as usize;
+    let end = pos + 2 + read_u16(data, pos)?;
+    pos += 2;
+    while pos + 4 <= end.min(data.len()) {
+        let typ = read_u16(data, pos)?;
+        let len = read_u16(data, pos + 2)?;
+        pos += 4;
+        if typ == 0 {
+            // server name list length(2), name type(1), name length(2)
+            if *data.get(pos + 2)? != 0 {
+                return None;
+            }
+            let name_len = read_u16(data, pos + 3)?;
+            let name = data.get(pos + 5..pos + 5 + name_len)?;
+            return String::from_utf8(name.to_vec()).ok();
+        }
+        pos += len;
+    }
+    None
+}
+
+fn sniff_host(data: &[u8]) -> Option<String> {
+    let head = String::from_utf8_lossy(data);
+    for line in head.split("\r\n").skip(1) {
+        if line.is_empty() {
+            break;
+        }
+        if line.len() > 5 && line.as_bytes()[..5].eq_ignore_ascii_case(b"host:") {
+            let host = line[5..].trim();
+            // strip the port, ipv6 literals are not domains anyway
+            let host = if host.starts_with('[') {
+                return None;
+            } else if let Some(pos) = host.rfind(':') {
+                &host[..pos]
+            } else {
+                host
+            };
+            return if host.is_empty() || host.parse::<std::net::IpAddr>().is_ok() {
+                None
+            } else {
+                Some(host.to_lowercase())
+            };
+        }
+    }
+    None
+}
@@ -0,0 +1,109 @@
+use std::fmt::Write;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::Opts;
+
+// latency and failures older than this say little about the servers now, addresses are resolved again anyway
+const MAX_HEALTH_AGE: u64 = 86400;
+
+pub struct ServerState {
+    pub hostname: String,
+    pub addrs: Vec<IpAddr>,
+    // none when the health is too old to be used
+    pub latency: Option<Duration>,
+    pub failures: u32,
+}
+
+// what the proxy learned before it was stopped, so that a restart does not begin cold
+#[derive(Default)]
+pub struct State {
+    pub servers: Vec<ServerState>,
+    pub fake_ips: Vec<(Ipv4Addr, String)>,
+    pub fake_next: Option<u32>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0)
+}
+
+// one record per line, the time it was saved at, the trojan servers with their latency in milliseconds or -, their
+// failures and addresses, then the fake ips handed out and the next one to be
+pub fn save(path: &str, opts: &Opts) -> std::io::Result<()> {
+    let mut state = String::new();
+    let _ = writeln!(state, "time {}", now());
+    for upstream in opts.upstreams.iter().filter(|upstream| upstream.plugin_addr().is_none() && upstream.proxy_addr().is_none()) {
+        let latency = upstream.latency.map_or("-".to_string(), |latency| latency.as_millis().to_string());
+        let addrs: Vec<String> = upstream.addrs().iter().map(|addr| addr.ip().to_string()).collect();
+        let addrs = if addrs.is_empty() { "-".to_string() } else { addrs.join(",") };
+        let _ = writeln!(state, "server {} {} {} {}", upstream.hostname, latency, upstream.failures(), addrs);
+    }
+    for (ip, domain) in opts.fake_dns.entries() {
+        // a name from a query may hold anything
+        if !domain.is_empty() && !domain.contains(char::is_whitespace) {
+            let _ = writeln!(state, "fake {} {}", ip, domain);
+        }
+    }
+    if let Some(next) = opts.fake_dns.next() {
+        let _ = writeln!(state, "fake-next {}", next);
+    }
+    let tmp_path = format!("{}.tmp", path);
+    std::fs::write(tmp_path.as_str(), state)?;
+    std::fs::rename(tmp_path.as_str(), path)
+}
+
+// a missing file is an empty state, lines which can not be parsed are skipped
+pub fn load(path: &str) -> std::io::Result<State> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(State::default()),
+        Err(err) => return Err(err),
+    };
+    let mut state = State::default();
+    let mut fresh = false;
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let parsed = match fields.as_slice() {
+            [] => true,
+            ["time", time] => match time.parse::<u64>() {
+                Ok(time) => {
+                    fresh = now().saturating_sub(time) < MAX_HEALTH_AGE;
+                    true
+                }
+                Err(_) => false,
+            },
+            ["server", hostname, latency, failures, addrs] => {
+                let addrs: Vec<IpAddr> = addrs.split(',').filter_map(|addr| addr.parse().ok()).collect();
+                let latency = latency.parse::<u64>().ok().map(Duration::from_millis).filter(|_| fresh);
+                let failures = if fresh { failures.parse::<u32>().unwrap_or(0) } else { 0 };
+                state.servers.push(ServerState {
+                    hostname: hostname.to_string(),
+                    addrs,
+                    latency,
+                    failures,
+                });
+                true
+            }
+            ["fake", ip, domain] => match ip.parse() {
+                Ok(ip) => {
+                    state.fake_ips.push((ip, domain.to_string()));
+                    true
+                }
+                Err(_) => false,
+            },
+            ["fake-next", next] => match next.parse() {
+                Ok(next) => {
+                    state.fake_next = Some(next);
+                    true
+                }
+                Err(_) => false,
+            },
+            _ => false,
+        };
+        if !parsed {
+            log::warn!("invalid line in state file {}:{}", path, line);
+        }
+    }
+    Ok(state)
+}
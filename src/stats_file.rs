@@ -0,0 +1,168 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Result};
+
+use crate::sys;
+
+// "TRJSTAT1", the number of records as u32 le, then 4 bytes left zero, so that the counters are aligned
+const MAGIC: &[u8] = b"TRJSTAT1";
+const HEADER_LEN: usize = 16;
+// the name of the user padded with zeros, then connections, bytes sent and bytes received as u64 le
+const RECORD_LEN: usize = 128;
+const NAME_LEN: usize = 104;
+const INITIAL_RECORDS: usize = 64;
+
+pub struct Record {
+    pub user: String,
+    pub connections: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+// cumulative traffic of each user in a file mapped into memory, counters are updated in place without a write call,
+// and a crash of the process loses nothing the kernel has not written yet
+pub struct StatsFile {
+    file: File,
+    ptr: *mut u8,
+    len: usize,
+    // the record of each user
+    positions: HashMap<String, usize>,
+    // names too long for a record, logged once
+    skipped: HashSet<String>,
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("not a trojan stats file, {}", message))
+}
+
+fn read_u64(data: &[u8]) -> u64 {
+    u64::from_le_bytes(data[..8].try_into().unwrap())
+}
+
+// the records of a whole file, or of its mapped memory
+fn parse(data: &[u8]) -> Result<Vec<Record>> {
+    if data.len() < HEADER_LEN || &data[..MAGIC.len()] != MAGIC {
+        return Err(invalid("bad magic"));
+    }
+    let count = u32::from_le_bytes(data[8..12].try_into().unwrap()) as usize;
+    if HEADER_LEN + count * RECORD_LEN > data.len() {
+        return Err(invalid("truncated"));
+    }
+    let mut records = Vec::with_capacity(count);
+    for i in 0..count {
+        let record = &data[HEADER_LEN + i * RECORD_LEN..HEADER_LEN + (i + 1) * RECORD_LEN];
+        let name = &record[..NAME_LEN];
+        let name_len = name.iter().position(|byte| *byte == 0).unwrap_or(NAME_LEN);
+        records.push(Record {
+            user: String::from_utf8_lossy(&name[..name_len]).to_string(),
+            connections: read_u64(&record[NAME_LEN..]),
+            bytes_sent: read_u64(&record[NAME_LEN + 8..]),
+            bytes_received: read_u64(&record[NAME_LEN + 16..]),
+        });
+    }
+    Ok(records)
+}
+
+// reads the file as it is, e.g. while a server is updating it, for the stats subcommand
+pub fn read(path: &str) -> Result<Vec<Record>> {
+    parse(std::fs::read(path)?.as_slice())
+}
+
+impl StatsFile {
+    // an empty or missing file is started with room for a few records, it doubles once they are taken
+    pub fn open(path: &str) -> Result<StatsFile> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let mut len = file.metadata()?.len() as usize;
+        let fresh = len == 0;
+        if fresh {
+            len = HEADER_LEN + INITIAL_RECORDS * RECORD_LEN;
+            file.set_len(len as u64)?;
+        } else if len < HEADER_LEN {
+            return Err(invalid("truncated"));
+        }
+        let ptr = sys::map_file(&file, len)?;
+        let mut stats_file = StatsFile {
+            file,
+            ptr,
+            len,
+            positions: HashMap::new(),
+            skipped: HashSet::new(),
+        };
+        if fresh {
+            stats_file.data_mut()[..MAGIC.len()].copy_from_slice(MAGIC);
+        }
+        for (i, record) in parse(stats_file.data())?.into_iter().enumerate() {
+            stats_file.positions.insert(record.user, i);
+        }
+        Ok(stats_file)
+    }
+
+    fn data(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    fn data_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+
+    pub fn records(&self) -> Vec<Record> {
+        parse(self.data()).unwrap_or_default()
+    }
+
+    fn grow(&mut self) -> Result<()> {
+        let len = HEADER_LEN + (self.len - HEADER_LEN) / RECORD_LEN * 2 * RECORD_LEN;
+        self.file.set_len(len as u64)?;
+        let ptr = sys::map_file(&self.file, len)?;
+        sys::unmap_file(self.ptr, self.len);
+        self.ptr = ptr;
+        self.len = len;
+        Ok(())
+    }
+
+    // counters which did not change are not written, so that their pages stay clean
+    pub fn update(&mut self, user: &str, connections: u64, bytes_sent: u64, bytes_received: u64) -> Result<()> {
+        if user.len() > NAME_LEN || user.contains('\0') {
+            if self.skipped.insert(user.to_string()) {
+                log::error!("user {} is not kept in the stats file, names are at most {} bytes", user, NAME_LEN);
+            }
+            return Ok(());
+        }
+        let position = match self.positions.get(user) {
+            Some(position) => *position,
+            None => {
+                let count = self.positions.len();
+                if HEADER_LEN + (count + 1) * RECORD_LEN > self.len {
+                    self.grow()?;
+                }
+                let data = self.data_mut();
+                let record = &mut data[HEADER_LEN + count * RECORD_LEN..HEADER_LEN + (count + 1) * RECORD_LEN];
+                for byte in record.iter_mut() {
+                    *byte = 0;
+                }
+                record[..user.len()].copy_from_slice(user.as_bytes());
+                // the record is complete before it is counted
+                data[8..12].copy_from_slice(&(count as u32 + 1).to_le_bytes());
+                self.positions.insert(user.to_string(), count);
+                count
+            }
+        };
+        let start = HEADER_LEN + position * RECORD_LEN + NAME_LEN;
+        let counters = &mut self.data_mut()[start..start + 24];
+        for (i, value) in [connections, bytes_sent, bytes_received].iter().enumerate() {
+            if read_u64(&counters[i * 8..]) != *value {
+                counters[i * 8..i * 8 + 8].copy_from_slice(&value.to_le_bytes());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for StatsFile {
+    fn drop(&mut self) {
+        if let Err(err) = sys::sync_file(self.ptr, self.len) {
+            log::error!("sync stats file failed:{}", err);
+        }
+        sys::unmap_file(self.ptr, self.len);
+    }
+}
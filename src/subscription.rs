@@ -0,0 +1,129 @@
+use std::io::{Error, ErrorKind, Result};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use mio::{Evented, Poll, PollOpt, Ready, Registration, Token};
+
+use crate::config::IpStrategy;
+use crate::http_client;
+use crate::upstream::Upstream;
+
+const FETCH_TIMEOUT: u64 = 30;
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+pub fn fetch(url: &str, marker: u8, strategy: IpStrategy) -> Result<String> {
+    let (status, body) = http_client::send(url, "GET", None, marker, strategy, Duration::new(FETCH_TIMEOUT, 0), MAX_BODY_SIZE)?;
+    if status != 200 {
+        return Err(Error::new(ErrorKind::InvalidData, format!("subscription request failed:status {}", status)));
+    }
+    Ok(body)
+}
+
+fn base64_value(c: u8) -> Option<u32> {
+    match c {
+        b'A'..=b'Z' => Some((c - b'A') as u32),
+        b'a'..=b'z' => Some((c - b'a') as u32 + 26),
+        b'0'..=b'9' => Some((c - b'0') as u32 + 52),
+        b'+' | b'-' => Some(62),
+        b'/' | b'_' => Some(63),
+        _ => None,
+    }
+}
+
+// subscriptions are usually base64 encoded, with or without padding, standard or url safe
+fn base64_decode(content: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in content.bytes() {
+        if c == b'=' || c.is_ascii_whitespace() {
+            continue;
+        }
+        bits = (bits << 6) | base64_value(c)?;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            decoded.push((bits >> count) as u8);
+            bits &= (1 << count) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+pub fn parse(content: &str) -> Vec<Upstream> {
+    let content = if content.contains("://") {
+        content.to_string()
+    } else {
+        match base64_decode(content) {
+            Some(decoded) => String::from_utf8_lossy(decoded.as_slice()).to_string(),
+            None => {
+                log::error!("invalid subscription content");
+                return Vec::new();
+            }
+        }
+    };
+    let mut upstreams = Vec::new();
+    for line in content.lines() {
+        let line = line.trim();
+        if !line.starts_with("trojan://") {
+            continue;
+        }
+        match line.parse::<Upstream>() {
+            Ok(upstream) => upstreams.push(upstream),
+            Err(err) => log::warn!("ignore subscription entry:{}", err),
+        }
+    }
+    upstreams
+}
+
+pub struct EventedSubscription {
+    registration: Registration,
+    result: Arc<Mutex<Option<Result<Vec<Upstream>>>>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl EventedSubscription {
+    pub fn new(url: String, marker: u8, strategy: IpStrategy) -> EventedSubscription {
+        let (registration, set_readiness) = Registration::new2();
+        let result = Arc::new(Mutex::new(None));
+        let result2 = result.clone();
+        let handle = std::thread::spawn(move || {
+            let upstreams = fetch(url.as_str(), marker, strategy).map(|content| parse(content.as_str()));
+            result2.lock().unwrap().replace(upstreams);
+            if let Err(err) = set_readiness.set_readiness(Ready::readable()) {
+                log::error!("set readiness failed:{}", err);
+            }
+        });
+        EventedSubscription {
+            registration,
+            result,
+            handle: Some(handle),
+        }
+    }
+
+    pub fn upstreams(&self) -> Result<Vec<Upstream>> {
+        self.result.lock().unwrap().take().unwrap_or_else(|| Err(Error::new(ErrorKind::Other, "subscription not fetched")))
+    }
+}
+
+impl Evented for EventedSubscription {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<()> {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<()> {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    #[allow(deprecated)]
+    fn deregister(&self, poll: &Poll) -> Result<()> {
+        self.registration.deregister(poll)
+    }
+}
+
+impl Drop for EventedSubscription {
+    fn drop(&mut self) {
+        let _ = self.handle.take().unwrap().join();
+    }
+}
@@ -0,0 +1,340 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+use mio::net::TcpStream;
+use mio::unix::EventedFd;
+
+// udp packets redirected by pf can not tell their original destination
+pub const UDP_TRANSPARENT: bool = false;
+
+// constants missing in libc, from the xnu headers
+const TCP_FASTOPEN: libc::c_int = 0x105;
+const IP_BOUND_IF: libc::c_int = 25;
+const IPV6_BOUND_IF: libc::c_int = 125;
+const PF_OUT: u8 = 2;
+// _IOWR('D', 23, struct pfioc_natlook)
+const DIOCNATLOOK: libc::c_ulong = 0xC000_0000 | ((std::mem::size_of::<PfiocNatlook>() as libc::c_ulong & 0x1fff) << 16) | ((b'D' as libc::c_ulong) << 8) | 23;
+
+// struct pfioc_natlook of xnu, addresses are 16 bytes unions and ports are 4 bytes unions
+#[repr(C)]
+struct PfiocNatlook {
+    saddr: [u8; 16],
+    daddr: [u8; 16],
+    rsaddr: [u8; 16],
+    rdaddr: [u8; 16],
+    sxport: [u8; 4],
+    dxport: [u8; 4],
+    rsxport: [u8; 4],
+    rdxport: [u8; 4],
+    af: libc::sa_family_t,
+    proto: u8,
+    proto_variant: u8,
+    direction: u8,
+}
+
+pub fn set_mark<T: AsRawFd>(_socket: &T, _mark: u8) -> Result<()> {
+    // pf tells the traffic of trojan by user or address rather than by marks
+    Ok(())
+}
+
+pub fn set_socket_opts<T: AsRawFd>(_v4: bool, is_udp: bool, _socket: &T) -> Result<()> {
+    if is_udp {
+        return Err(Error::new(ErrorKind::Other, "transparent udp not supported in macos"));
+    }
+    Ok(())
+}
+
+pub fn get_oridst_addr(s: &TcpStream) -> Result<SocketAddr> {
+    // pf has no tproxy, connections always come from rdr rules
+    get_original_dst(s)
+}
+
+// the pf state table is read through /dev/pf, which only root can open
+pub fn natlook_available() -> bool {
+    match OpenOptions::new().read(true).open("/dev/pf") {
+        Ok(_) => true,
+        Err(err) => {
+            log::warn!("open /dev/pf failed:{}", err);
+            false
+        }
+    }
+}
+
+// destination of connections redirected by pf rdr rules, looked up in the pf state table
+pub fn get_original_dst(s: &TcpStream) -> Result<SocketAddr> {
+    let peer_addr = s.peer_addr()?;
+    let local_addr = s.local_addr()?;
+    let pf = OpenOptions::new().read(true).open("/dev/pf")?;
+
+    let mut pnl: PfiocNatlook = unsafe { std::mem::zeroed() };
+    put_addr(&mut pnl.saddr, &mut pnl.sxport, &peer_addr);
+    put_addr(&mut pnl.daddr, &mut pnl.dxport, &local_addr);
+    pnl.af = if peer_addr.is_ipv4() { libc::AF_INET } else { libc::AF_INET6 } as libc::sa_family_t;
+    pnl.proto = libc::IPPROTO_TCP as u8;
+    pnl.direction = PF_OUT;
+
+    let ret = unsafe { libc::ioctl(pf.as_raw_fd(), DIOCNATLOOK, &mut pnl as *mut PfiocNatlook) };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+
+    let port = u16::from_be_bytes([pnl.rdxport[0], pnl.rdxport[1]]);
+    let ip = if peer_addr.is_ipv4() {
+        IpAddr::V4(Ipv4Addr::new(pnl.rdaddr[0], pnl.rdaddr[1], pnl.rdaddr[2], pnl.rdaddr[3]))
+    } else {
+        IpAddr::V6(Ipv6Addr::from(pnl.rdaddr))
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+fn put_addr(addr: &mut [u8; 16], port: &mut [u8; 4], socket_addr: &SocketAddr) {
+    match socket_addr.ip() {
+        IpAddr::V4(ip) => addr[..4].copy_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => addr.copy_from_slice(&ip.octets()),
+    }
+    port[..2].copy_from_slice(&socket_addr.port().to_be_bytes());
+}
+
+pub fn recv_from_with_destination<T: AsRawFd>(_socket: &T, _buf: &mut [u8]) -> Result<(usize, SocketAddr, SocketAddr)> {
+    Err(Error::new(ErrorKind::Other, "transparent udp not supported in macos"))
+}
+
+fn set_int_opt<T: AsRawFd>(socket: &T, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> Result<()> {
+    let fd = socket.as_raw_fd();
+    unsafe {
+        let ret = libc::setsockopt(fd, level, name,
+                                   &value as *const _ as *const _,
+                                   std::mem::size_of_val(&value) as libc::socklen_t,
+        );
+        if ret != 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub fn set_fast_open<T: AsRawFd>(listener: &T, _queue_len: i32) -> Result<()> {
+    // the queue length is a switch in macos
+    set_int_opt(listener, libc::IPPROTO_TCP, TCP_FASTOPEN, 1)
+}
+
+pub fn set_fast_open_connect<T: AsRawFd>(_socket: &T) -> Result<()> {
+    // client side fast open needs connectx, connect normally instead
+    Ok(())
+}
+
+pub fn set_keepalive<T: AsRawFd>(socket: &T, idle: u32) -> Result<()> {
+    let idle = idle as libc::c_int;
+    set_int_opt(socket, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    set_int_opt(socket, libc::IPPROTO_TCP, libc::TCP_KEEPALIVE, idle)?;
+    set_int_opt(socket, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, std::cmp::max(idle / 3, 1))?;
+    set_int_opt(socket, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, 3)
+}
+
+pub fn set_user_timeout<T: AsRawFd>(_socket: &T, _millis: u32) -> Result<()> {
+    Ok(())
+}
+
+pub fn set_buffer_size<T: AsRawFd>(socket: &T, send: u32, recv: u32) -> Result<()> {
+    if send > 0 {
+        set_int_opt(socket, libc::SOL_SOCKET, libc::SO_SNDBUF, send as libc::c_int)?;
+    }
+    if recv > 0 {
+        set_int_opt(socket, libc::SOL_SOCKET, libc::SO_RCVBUF, recv as libc::c_int)?;
+    }
+    Ok(())
+}
+
+pub fn set_congestion<T: AsRawFd>(_socket: &T, _algorithm: &str) -> Result<()> {
+    Err(Error::new(ErrorKind::Other, "congestion control not supported in macos"))
+}
+
+// binds to the interface index, the family of the socket decides the option
+pub fn bind_device<T: AsRawFd>(socket: &T, device: &str) -> Result<()> {
+    let name = std::ffi::CString::new(device).map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid device name"))?;
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) } as libc::c_int;
+    if index == 0 {
+        return Err(Error::last_os_error());
+    }
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut addr_len = std::mem::size_of_val(&addr) as libc::socklen_t;
+    let ret = unsafe { libc::getsockname(socket.as_raw_fd(), &mut addr as *mut _ as *mut _, &mut addr_len) };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    if addr.ss_family as libc::c_int == libc::AF_INET6 {
+        set_int_opt(socket, libc::IPPROTO_IPV6, IPV6_BOUND_IF, index)
+    } else {
+        set_int_opt(socket, libc::IPPROTO_IP, IP_BOUND_IF, index)
+    }
+}
+
+// dscp takes the upper 6 bits of the tos or traffic class byte
+pub fn set_dscp<T: AsRawFd>(socket: &T, v4: bool, dscp: u8) -> Result<()> {
+    let tos = ((dscp & 0x3f) << 2) as libc::c_int;
+    if v4 {
+        set_int_opt(socket, libc::IPPROTO_IP, libc::IP_TOS, tos)
+    } else {
+        set_int_opt(socket, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos)
+    }
+}
+
+pub fn fill_random(buf: &mut [u8]) -> Result<()> {
+    // getentropy takes at most 256 bytes at once
+    for chunk in buf.chunks_mut(256) {
+        if unsafe { libc::getentropy(chunk.as_mut_ptr() as *mut libc::c_void, chunk.len()) } != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// maps the start of a file shared and writable, stores to the memory reach the file through the page cache
+pub fn map_file(file: &File, len: usize) -> Result<*mut u8> {
+    let ptr = unsafe { libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, file.as_raw_fd(), 0) };
+    if ptr == libc::MAP_FAILED {
+        return Err(Error::last_os_error());
+    }
+    Ok(ptr as *mut u8)
+}
+
+pub fn unmap_file(ptr: *mut u8, len: usize) {
+    unsafe { libc::munmap(ptr as *mut libc::c_void, len) };
+}
+
+// writes the mapped pages to the disk, those of the page cache survive a crash of the process but not of the host
+pub fn sync_file(ptr: *mut u8, len: usize) -> Result<()> {
+    if unsafe { libc::msync(ptr as *mut libc::c_void, len, libc::MS_SYNC) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub const TUN_NAME: &str = "utun";
+
+const UTUN_CONTROL_NAME: &[u8] = b"com.apple.net.utun_control";
+
+// a utun device, each packet is led by the address family in network order
+pub struct TunDevice {
+    file: File,
+    name: String,
+}
+
+impl TunDevice {
+    // utunN takes unit N, utun the first free one
+    pub fn open(name: &str) -> Result<TunDevice> {
+        let unit = match name.strip_prefix("utun") {
+            Some("") => 0,
+            Some(number) => number.parse::<u32>().map_err(|_| Error::new(ErrorKind::InvalidInput, "tun device should be named utun or utunN in macos"))? + 1,
+            None => return Err(Error::new(ErrorKind::InvalidInput, "tun device should be named utun or utunN in macos")),
+        };
+        let fd = unsafe { libc::socket(libc::PF_SYSTEM, libc::SOCK_DGRAM, libc::SYSPROTO_CONTROL) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let file = unsafe { File::from_raw_fd(fd) };
+        let mut info: libc::ctl_info = unsafe { std::mem::zeroed() };
+        for (dst, src) in info.ctl_name.iter_mut().zip(UTUN_CONTROL_NAME.iter()) {
+            *dst = *src as libc::c_char;
+        }
+        if unsafe { libc::ioctl(fd, libc::CTLIOCGINFO, &mut info as *mut libc::ctl_info) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        let mut addr: libc::sockaddr_ctl = unsafe { std::mem::zeroed() };
+        addr.sc_len = std::mem::size_of::<libc::sockaddr_ctl>() as u8;
+        addr.sc_family = libc::AF_SYSTEM as u8;
+        addr.ss_sysaddr = libc::AF_SYS_CONTROL as u16;
+        addr.sc_id = info.ctl_id;
+        addr.sc_unit = unit;
+        let ret = unsafe { libc::connect(fd, &addr as *const _ as *const libc::sockaddr, std::mem::size_of_val(&addr) as libc::socklen_t) };
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+        let mut buf = [0u8; libc::IFNAMSIZ];
+        let mut len = buf.len() as libc::socklen_t;
+        let ret = unsafe { libc::getsockopt(fd, libc::SYSPROTO_CONTROL, libc::UTUN_OPT_IFNAME, buf.as_mut_ptr() as *mut libc::c_void, &mut len) };
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+        let end = buf.iter().position(|c| *c == 0).unwrap_or(len as usize);
+        let name = String::from_utf8_lossy(&buf[..end]).into_owned();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(TunDevice { file, name })
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        let mut family = [0u8; 4];
+        let iov = [
+            libc::iovec { iov_base: family.as_mut_ptr() as *mut libc::c_void, iov_len: family.len() },
+            libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() },
+        ];
+        let size = unsafe { libc::readv(self.file.as_raw_fd(), iov.as_ptr(), iov.len() as libc::c_int) };
+        if size < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok((size as usize).saturating_sub(family.len()))
+    }
+
+    pub fn send(&self, packet: &[u8]) -> Result<usize> {
+        let family = match packet.first().map(|first| first >> 4) {
+            Some(4) => libc::AF_INET,
+            Some(6) => libc::AF_INET6,
+            _ => return Err(Error::new(ErrorKind::InvalidInput, "not an ip packet")),
+        };
+        let family = (family as u32).to_be_bytes();
+        let iov = [
+            libc::iovec { iov_base: family.as_ptr() as *mut libc::c_void, iov_len: family.len() },
+            libc::iovec { iov_base: packet.as_ptr() as *mut libc::c_void, iov_len: packet.len() },
+        ];
+        let size = unsafe { libc::writev(self.file.as_raw_fd(), iov.as_ptr(), iov.len() as libc::c_int) };
+        if size < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok((size as usize).saturating_sub(family.len()))
+    }
+}
+
+impl Evented for TunDevice {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<()> {
+        EventedFd(&self.file.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<()> {
+        EventedFd(&self.file.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<()> {
+        EventedFd(&self.file.as_raw_fd()).deregister(poll)
+    }
+}
+
+extern "C" fn handle_stop(_signal: libc::c_int) {
+    super::stop();
+}
+
+extern "C" fn handle_trace_toggle(_signal: libc::c_int) {
+    super::request_trace_toggle();
+}
+
+pub fn set_stop_handler(_upgrade_on_hup: bool) -> Result<()> {
+    for signal in &[libc::SIGINT, libc::SIGTERM] {
+        if unsafe { libc::signal(*signal, handle_stop as extern "C" fn(libc::c_int) as libc::sighandler_t) } == libc::SIG_ERR {
+            return Err(Error::last_os_error());
+        }
+    }
+    if unsafe { libc::signal(libc::SIGUSR1, handle_trace_toggle as extern "C" fn(libc::c_int) as libc::sighandler_t) } == libc::SIG_ERR {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
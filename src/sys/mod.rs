@@ -1,11 +1,150 @@
+use std::io::Result;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicBool, Ordering};
+
 use cfg_if::cfg_if;
+use mio::net::TcpStream;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 
 cfg_if! {
-    if #[cfg(unix)] {
+    if #[cfg(target_os = "macos")] {
+        mod macos;
+        pub use self::macos::*;
+    } else if #[cfg(unix)] {
         mod unix;
         pub use self::unix::*;
     } else if #[cfg(windows)] {
         mod windows;
         pub use self::windows::*;
     }
-}
\ No newline at end of file
+}
+
+static STOPPING: AtomicBool = AtomicBool::new(false);
+static UPGRADE: AtomicBool = AtomicBool::new(false);
+static TRACE_TOGGLE: AtomicBool = AtomicBool::new(false);
+
+// asks the event loop to exit, called by signal, console and service control handlers
+pub fn stop() {
+    STOPPING.store(true, Ordering::SeqCst);
+}
+
+pub fn stopping() -> bool {
+    STOPPING.load(Ordering::SeqCst)
+}
+
+// asks the event loop to start the new binary, called by the SIGHUP handler and the upgrade admin command
+pub fn request_upgrade() {
+    UPGRADE.store(true, Ordering::SeqCst);
+}
+
+// true once for each request
+pub fn upgrade_requested() -> bool {
+    UPGRADE.swap(false, Ordering::SeqCst)
+}
+
+// asks the event loop to turn tracing on or off, called by the SIGUSR1 handler
+#[cfg(unix)]
+pub fn request_trace_toggle() {
+    TRACE_TOGGLE.store(true, Ordering::SeqCst);
+}
+
+// true once for each request
+pub fn trace_toggle_requested() -> bool {
+    TRACE_TOGGLE.swap(false, Ordering::SeqCst)
+}
+
+// the addresses of all interfaces, which a listener bound to any address accepts connections on
+#[cfg(unix)]
+pub fn local_addrs() -> Result<Vec<IpAddr>> {
+    let mut head: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut head) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let mut addrs = Vec::new();
+    let mut current = head;
+    while !current.is_null() {
+        let ifaddr = unsafe { &*current };
+        if !ifaddr.ifa_addr.is_null() {
+            match unsafe { (*ifaddr.ifa_addr).sa_family } as libc::c_int {
+                libc::AF_INET => {
+                    let addr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in) };
+                    addrs.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr))));
+                }
+                libc::AF_INET6 => {
+                    let addr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in6) };
+                    addrs.push(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)));
+                }
+                _ => {}
+            }
+        }
+        current = ifaddr.ifa_next;
+    }
+    unsafe { libc::freeifaddrs(head) };
+    Ok(addrs)
+}
+
+// options applied to tcp connections on both sides of the relay
+#[derive(Clone, Default)]
+pub struct TcpOpts {
+    pub marker: u8,
+    pub bind_addr: Option<IpAddr>,
+    pub device: Option<String>,
+    pub fast_open: bool,
+    pub keepalive: u32,
+    pub user_timeout: u32,
+    pub send_buffer: u32,
+    pub recv_buffer: u32,
+    pub congestion: Option<String>,
+    pub dscp: Option<u8>,
+}
+
+impl TcpOpts {
+    pub fn connect(&self, addr: &SocketAddr) -> Result<TcpStream> {
+        let domain = if addr.is_ipv4() {
+            Domain::ipv4()
+        } else {
+            Domain::ipv6()
+        };
+        let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+        set_mark(&socket, self.marker)?;
+        self.bind(&socket, addr)?;
+        if self.fast_open {
+            set_fast_open_connect(&socket)?;
+        }
+        // buffer sizes decide the window scale, which is negotiated in the syn
+        set_buffer_size(&socket, self.send_buffer, self.recv_buffer)?;
+        let stream = TcpStream::connect_stream(socket.into_tcp_stream(), addr)?;
+        self.apply(&stream)?;
+        Ok(stream)
+    }
+
+    // binds sockets going out to addr to the configured device and source address
+    pub fn bind(&self, socket: &Socket, addr: &SocketAddr) -> Result<()> {
+        if let Some(device) = self.device.as_ref() {
+            bind_device(socket, device.as_str())?;
+        }
+        match self.bind_addr {
+            Some(ip) if ip.is_ipv4() == addr.is_ipv4() => socket.bind(&SockAddr::from(SocketAddr::new(ip, 0))),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn apply(&self, stream: &TcpStream) -> Result<()> {
+        if self.keepalive > 0 {
+            set_keepalive(stream, self.keepalive)?;
+        }
+        if self.user_timeout > 0 {
+            set_user_timeout(stream, self.user_timeout * 1000)?;
+        }
+        set_buffer_size(stream, self.send_buffer, self.recv_buffer)?;
+        if let Some(congestion) = self.congestion.as_ref() {
+            set_congestion(stream, congestion.as_str())?;
+        }
+        if let Some(dscp) = self.dscp {
+            set_dscp(stream, stream.local_addr()?.is_ipv4(), dscp)?;
+        }
+        Ok(())
+    }
+}
@@ -1,10 +1,21 @@
+use mio::{Evented, Poll, PollOpt, Ready, Token};
 use mio::net::TcpStream;
+use mio::unix::EventedFd;
 use std::convert::TryFrom;
-use std::io::{Error, ErrorKind, Result};
-use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
-use std::os::unix::io::AsRawFd;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+use crate::cidr;
+
+pub const UDP_TRANSPARENT: bool = true;
 
 pub fn set_mark<T: AsRawFd>(socket: &T, mark: u8) -> Result<()> {
+    // 0 is no mark, which needs no CAP_NET_ADMIN
+    if mark == 0 {
+        return Ok(());
+    }
     let fd = socket.as_raw_fd();
     unsafe {
         let mark = mark as libc::c_int;
@@ -21,45 +32,19 @@ pub fn set_mark<T: AsRawFd>(socket: &T, mark: u8) -> Result<()> {
 }
 
 pub fn set_socket_opts<T: AsRawFd>(v4: bool, is_udp: bool, socket: &T) -> Result<()> {
-    let fd = socket.as_raw_fd();
-
-    let enable: libc::c_int = 1;
-    unsafe {
-        // 1. Set IP_TRANSPARENT to allow binding to non-local addresses
-        let sol = if v4 {
-            libc::SOL_IP
-        } else {
-            libc::SOL_IPV6
-        };
-        let ret = libc::setsockopt(
-            fd,
-            sol,
-            libc::IP_TRANSPARENT,
-            &enable as *const _ as *const _,
-            std::mem::size_of_val(&enable) as libc::socklen_t,
-        );
-        if ret != 0 {
-            return Err(Error::last_os_error());
-        }
+    // 1. Set IP_TRANSPARENT, IPV6_TRANSPARENT to allow binding to non-local addresses
+    // ipv6 sockets get the ipv4 options as well, so that dual stack listeners handle ipv4 packets
+    if !v4 {
+        set_int_opt(socket, libc::SOL_IPV6, libc::IPV6_TRANSPARENT, 1)?;
+    }
+    set_int_opt(socket, libc::SOL_IP, libc::IP_TRANSPARENT, 1)?;
 
-        if is_udp {
-            // 2. Set IP_RECVORIGDSTADDR, IPV6_RECVORIGDSTADDR
-            let (sol, opt) = if v4 {
-                (libc::SOL_IP, libc::IP_RECVORIGDSTADDR)
-            } else {
-                (libc::SOL_IPV6, libc::IPV6_RECVORIGDSTADDR)
-            };
-            let ret = libc::setsockopt(
-                fd,
-                sol,
-                opt,
-                &enable as *const _ as *const _,
-                std::mem::size_of_val(&enable) as libc::socklen_t,
-            );
-            if ret != 0 {
-                return Err(Error::last_os_error());
-            }
+    if is_udp {
+        // 2. Set IP_RECVORIGDSTADDR, IPV6_RECVORIGDSTADDR
+        if !v4 {
+            set_int_opt(socket, libc::SOL_IPV6, libc::IPV6_RECVORIGDSTADDR, 1)?;
         }
+        set_int_opt(socket, libc::SOL_IP, libc::IP_RECVORIGDSTADDR, 1)?;
     }
 
     Ok(())
@@ -87,6 +72,40 @@ pub fn get_oridst_addr(s: &TcpStream) -> Result<SocketAddr> {
     }
 }
 
+// SO_ORIGINAL_DST is always there
+pub fn natlook_available() -> bool {
+    true
+}
+
+// destination of connections redirected by iptables REDIRECT or DNAT
+pub fn get_original_dst(s: &TcpStream) -> Result<SocketAddr> {
+    let fd = s.as_raw_fd();
+    let (level, name) = if s.local_addr()?.is_ipv4() {
+        (libc::SOL_IP, libc::SO_ORIGINAL_DST)
+    } else {
+        (libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST)
+    };
+
+    unsafe {
+        let mut target_addr: libc::sockaddr_storage = std::mem::zeroed();
+        let mut target_addr_len = std::mem::size_of_val(&target_addr) as libc::socklen_t;
+
+        let ret = libc::getsockopt(
+            fd,
+            level,
+            name,
+            &mut target_addr as *mut _ as *mut _,
+            &mut target_addr_len,
+        );
+
+        if ret != 0 {
+            Err(Error::last_os_error())
+        } else {
+            sockaddr_to_std(&target_addr)
+        }
+    }
+}
+
 fn get_destination_addr(msg: &libc::msghdr) -> Option<libc::sockaddr_storage> {
     unsafe {
         let mut cmsg: *mut libc::cmsghdr = libc::CMSG_FIRSTHDR(msg);
@@ -124,6 +143,8 @@ fn get_destination_addr(msg: &libc::msghdr) -> Option<libc::sockaddr_storage> {
     None
 }
 
+// msg_controllen is usize on some platforms only
+#[allow(clippy::useless_conversion)]
 pub fn recv_from_with_destination<T: AsRawFd>(socket: &T, buf: &mut [u8]) -> Result<(usize, SocketAddr, SocketAddr)> {
     unsafe {
         let mut control_buf = [0u8; 64];
@@ -170,7 +191,8 @@ fn sockaddr_to_std(saddr: &libc::sockaddr_storage) -> Result<SocketAddr> {
         },
         libc::AF_INET6 => unsafe {
             let addr: SocketAddrV6 = std::mem::transmute_copy(saddr);
-            Ok(SocketAddr::V6(addr))
+            // ipv4 peers of dual stack sockets show up as ipv4-mapped addresses
+            Ok(SocketAddr::new(cidr::unmap(IpAddr::V6(*addr.ip())), addr.port()))
         },
         _ => {
             let err = Error::new(ErrorKind::InvalidData, "family must be either AF_INET or AF_INET6");
@@ -178,3 +200,241 @@ fn sockaddr_to_std(saddr: &libc::sockaddr_storage) -> Result<SocketAddr> {
         }
     }
 }
+
+fn set_int_opt<T: AsRawFd>(socket: &T, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> Result<()> {
+    let fd = socket.as_raw_fd();
+    unsafe {
+        let ret = libc::setsockopt(fd, level, name,
+                                   &value as *const _ as *const _,
+                                   std::mem::size_of_val(&value) as libc::socklen_t,
+        );
+        if ret != 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub fn set_fast_open<T: AsRawFd>(listener: &T, queue_len: i32) -> Result<()> {
+    set_int_opt(listener, libc::IPPROTO_TCP, libc::TCP_FASTOPEN, queue_len)
+}
+
+// the syn is sent along with the first write instead of in connect
+pub fn set_fast_open_connect<T: AsRawFd>(socket: &T) -> Result<()> {
+    set_int_opt(socket, libc::IPPROTO_TCP, libc::TCP_FASTOPEN_CONNECT, 1)
+}
+
+// probes start after idle seconds, and the connection is dropped after 3 unanswered ones
+pub fn set_keepalive<T: AsRawFd>(socket: &T, idle: u32) -> Result<()> {
+    let idle = idle as libc::c_int;
+    set_int_opt(socket, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    set_int_opt(socket, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, idle)?;
+    set_int_opt(socket, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, std::cmp::max(idle / 3, 1))?;
+    set_int_opt(socket, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, 3)
+}
+
+pub fn set_user_timeout<T: AsRawFd>(socket: &T, millis: u32) -> Result<()> {
+    set_int_opt(socket, libc::IPPROTO_TCP, libc::TCP_USER_TIMEOUT, millis as libc::c_int)
+}
+
+pub fn set_buffer_size<T: AsRawFd>(socket: &T, send: u32, recv: u32) -> Result<()> {
+    if send > 0 {
+        set_int_opt(socket, libc::SOL_SOCKET, libc::SO_SNDBUF, send as libc::c_int)?;
+    }
+    if recv > 0 {
+        set_int_opt(socket, libc::SOL_SOCKET, libc::SO_RCVBUF, recv as libc::c_int)?;
+    }
+    Ok(())
+}
+
+pub fn set_congestion<T: AsRawFd>(socket: &T, algorithm: &str) -> Result<()> {
+    let fd = socket.as_raw_fd();
+    unsafe {
+        let ret = libc::setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_CONGESTION,
+                                   algorithm.as_ptr() as *const _,
+                                   algorithm.len() as libc::socklen_t,
+        );
+        if ret != 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub fn bind_device<T: AsRawFd>(socket: &T, device: &str) -> Result<()> {
+    let fd = socket.as_raw_fd();
+    unsafe {
+        let ret = libc::setsockopt(fd, libc::SOL_SOCKET, libc::SO_BINDTODEVICE,
+                                   device.as_ptr() as *const _,
+                                   device.len() as libc::socklen_t,
+        );
+        if ret != 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+// dscp takes the upper 6 bits of the tos or traffic class byte
+pub fn set_dscp<T: AsRawFd>(socket: &T, v4: bool, dscp: u8) -> Result<()> {
+    let tos = ((dscp & 0x3f) << 2) as libc::c_int;
+    if v4 {
+        set_int_opt(socket, libc::IPPROTO_IP, libc::IP_TOS, tos)
+    } else {
+        set_int_opt(socket, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos)
+    }
+}
+
+pub fn fill_random(buf: &mut [u8]) -> Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let ret = unsafe { libc::syscall(libc::SYS_getrandom, buf[filled..].as_mut_ptr(), buf.len() - filled, 0) };
+        if ret < 0 {
+            let err = Error::last_os_error();
+            if err.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        filled += ret as usize;
+    }
+    Ok(())
+}
+
+// maps the start of a file shared and writable, stores to the memory reach the file through the page cache
+pub fn map_file(file: &File, len: usize) -> Result<*mut u8> {
+    let ptr = unsafe { libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, file.as_raw_fd(), 0) };
+    if ptr == libc::MAP_FAILED {
+        return Err(Error::last_os_error());
+    }
+    Ok(ptr as *mut u8)
+}
+
+pub fn unmap_file(ptr: *mut u8, len: usize) {
+    unsafe { libc::munmap(ptr as *mut libc::c_void, len) };
+}
+
+// writes the mapped pages to the disk, those of the page cache survive a crash of the process but not of the host
+pub fn sync_file(ptr: *mut u8, len: usize) -> Result<()> {
+    if unsafe { libc::msync(ptr as *mut libc::c_void, len, libc::MS_SYNC) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub const TUN_NAME: &str = "tun0";
+
+// struct ifreq with the flags member of its union, padded to the size of the union
+#[repr(C)]
+struct IfReq {
+    name: [libc::c_char; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    pad: [u8; 22],
+}
+
+// a tun device without packet information, each read or write is a bare ip packet
+pub struct TunDevice {
+    file: File,
+    name: String,
+}
+
+impl TunDevice {
+    pub fn open(name: &str) -> Result<TunDevice> {
+        if name.is_empty() || name.len() >= libc::IFNAMSIZ {
+            return Err(Error::new(ErrorKind::InvalidInput, "invalid tun device name"));
+        }
+        let file = OpenOptions::new().read(true).write(true).open("/dev/net/tun")?;
+        let mut req: IfReq = unsafe { std::mem::zeroed() };
+        for (dst, src) in req.name.iter_mut().zip(name.bytes()) {
+            *dst = src as libc::c_char;
+        }
+        req.flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+        if unsafe { libc::ioctl(file.as_raw_fd(), libc::TUNSETIFF, &mut req as *mut IfReq) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        // the kernel may have completed a name like tun%d
+        let len = req.name.iter().position(|c| *c == 0).unwrap_or(req.name.len());
+        let name = req.name[..len].iter().map(|c| *c as u8 as char).collect::<String>();
+        set_link_up(&mut req)?;
+        Ok(TunDevice { file, name })
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        (&self.file).read(buf)
+    }
+
+    pub fn send(&self, packet: &[u8]) -> Result<usize> {
+        (&self.file).write(packet)
+    }
+}
+
+impl Evented for TunDevice {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<()> {
+        EventedFd(&self.file.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> Result<()> {
+        EventedFd(&self.file.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<()> {
+        EventedFd(&self.file.as_raw_fd()).deregister(poll)
+    }
+}
+
+// addresses and routes of the device are left to the system, only the link is brought up
+fn set_link_up(req: &mut IfReq) -> Result<()> {
+    let socket = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if socket < 0 {
+        return Err(Error::last_os_error());
+    }
+    let socket = unsafe { File::from_raw_fd(socket) };
+    if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCGIFFLAGS, req as *mut IfReq) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    req.flags |= libc::IFF_UP as libc::c_short;
+    if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCSIFFLAGS, req as *mut IfReq) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+extern "C" fn handle_stop(_signal: libc::c_int) {
+    super::stop();
+}
+
+extern "C" fn handle_trace_toggle(_signal: libc::c_int) {
+    super::request_trace_toggle();
+}
+
+extern "C" fn handle_upgrade(_signal: libc::c_int) {
+    super::request_upgrade();
+}
+
+// SIGHUP upgrades only without a terminal, daemonized or under systemd, so that closing a terminal still stops trojan
+pub fn set_stop_handler(upgrade_on_hup: bool) -> Result<()> {
+    for signal in &[libc::SIGINT, libc::SIGTERM] {
+        if unsafe { libc::signal(*signal, handle_stop as extern "C" fn(libc::c_int) as libc::sighandler_t) } == libc::SIG_ERR {
+            return Err(Error::last_os_error());
+        }
+    }
+    // SIGUSR2 is taken by reopening the log file
+    if upgrade_on_hup && unsafe { libc::signal(libc::SIGHUP, handle_upgrade as extern "C" fn(libc::c_int) as libc::sighandler_t) } == libc::SIG_ERR {
+        return Err(Error::last_os_error());
+    }
+    if unsafe { libc::signal(libc::SIGUSR1, handle_trace_toggle as extern "C" fn(libc::c_int) as libc::sighandler_t) } == libc::SIG_ERR {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
@@ -1,19 +1,142 @@
 use std::any::Any;
-use std::io::Result;
-use std::net::SocketAddr;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr};
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+use winapi::shared::minwindef::{BOOL, DWORD, TRUE, ULONG};
+use winapi::um::consoleapi::SetConsoleCtrlHandler;
+use winapi::um::ntsecapi::RtlGenRandom;
+
+pub const UDP_TRANSPARENT: bool = false;
 
 pub fn set_mark<T: Any>(_socket: &T, _mark: u8) -> Result<()> {
     Ok(())
 }
 
 pub fn set_socket_opts<T: Any>(_v4: bool, _is_udp: bool, _socket: &T) -> Result<()> {
-    unimplemented!("proxy mode not supported in windows");
+    Err(Error::new(ErrorKind::Other, "transparent proxy not supported in windows"))
 }
 
 pub fn get_oridst_addr<T: Any>(_s: &T) -> Result<SocketAddr> {
-    unimplemented!("proxy mode not supported in windows");
+    Err(Error::new(ErrorKind::Other, "transparent proxy not supported in windows"))
+}
+
+pub fn natlook_available() -> bool {
+    false
+}
+
+pub fn get_original_dst<T: Any>(_s: &T) -> Result<SocketAddr> {
+    Err(Error::new(ErrorKind::Other, "redirect mode not supported in windows"))
 }
 
 pub fn recv_from_with_destination<T: Any>(_socket: &T, _buf: &mut [u8]) -> Result<(usize, SocketAddr, SocketAddr)> {
-    unimplemented!("proxy mode not supported in windows");
-}
\ No newline at end of file
+    Err(Error::new(ErrorKind::Other, "transparent proxy not supported in windows"))
+}
+
+// only the address each connection came in on is known to be ours
+pub fn local_addrs() -> Result<Vec<IpAddr>> {
+    Err(Error::new(ErrorKind::Other, "listing interface addresses not supported in windows"))
+}
+
+pub fn set_fast_open<T: Any>(_listener: &T, _queue_len: i32) -> Result<()> {
+    Ok(())
+}
+
+pub fn set_fast_open_connect<T: Any>(_socket: &T) -> Result<()> {
+    Ok(())
+}
+
+pub fn set_keepalive<T: Any>(_socket: &T, _idle: u32) -> Result<()> {
+    Ok(())
+}
+
+pub fn set_user_timeout<T: Any>(_socket: &T, _millis: u32) -> Result<()> {
+    Ok(())
+}
+
+pub fn set_buffer_size<T: Any>(_socket: &T, _send: u32, _recv: u32) -> Result<()> {
+    Ok(())
+}
+
+pub fn set_congestion<T: Any>(_socket: &T, _algorithm: &str) -> Result<()> {
+    Ok(())
+}
+
+pub fn bind_device<T: Any>(_socket: &T, _device: &str) -> Result<()> {
+    Err(Error::new(ErrorKind::Other, "binding to device not supported in windows"))
+}
+
+pub fn set_dscp<T: Any>(_socket: &T, _v4: bool, _dscp: u8) -> Result<()> {
+    Ok(())
+}
+
+// RtlGenRandom takes at most ULONG::MAX bytes a call
+pub fn fill_random(buf: &mut [u8]) -> Result<()> {
+    for chunk in buf.chunks_mut(ULONG::MAX as usize) {
+        if unsafe { RtlGenRandom(chunk.as_mut_ptr() as *mut _, chunk.len() as ULONG) } == 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+pub fn map_file(_file: &File, _len: usize) -> Result<*mut u8> {
+    Err(Error::new(ErrorKind::Other, "mapped files are not supported on windows"))
+}
+
+pub fn unmap_file(_ptr: *mut u8, _len: usize) {}
+
+pub fn sync_file(_ptr: *mut u8, _len: usize) -> Result<()> {
+    Ok(())
+}
+
+pub const TUN_NAME: &str = "tun0";
+
+// never opened, tun mode is refused on windows
+pub struct TunDevice;
+
+impl TunDevice {
+    pub fn open(_name: &str) -> Result<TunDevice> {
+        Err(Error::new(ErrorKind::Other, "tun device not supported in windows"))
+    }
+
+    pub fn name(&self) -> &str {
+        TUN_NAME
+    }
+
+    pub fn recv(&self, _buf: &mut [u8]) -> Result<usize> {
+        Err(Error::new(ErrorKind::Other, "tun device not supported in windows"))
+    }
+
+    pub fn send(&self, _packet: &[u8]) -> Result<usize> {
+        Err(Error::new(ErrorKind::Other, "tun device not supported in windows"))
+    }
+}
+
+impl Evented for TunDevice {
+    fn register(&self, _poll: &Poll, _token: Token, _interest: Ready, _opts: PollOpt) -> Result<()> {
+        Err(Error::new(ErrorKind::Other, "tun device not supported in windows"))
+    }
+
+    fn reregister(&self, _poll: &Poll, _token: Token, _interest: Ready, _opts: PollOpt) -> Result<()> {
+        Err(Error::new(ErrorKind::Other, "tun device not supported in windows"))
+    }
+
+    fn deregister(&self, _poll: &Poll) -> Result<()> {
+        Err(Error::new(ErrorKind::Other, "tun device not supported in windows"))
+    }
+}
+
+unsafe extern "system" fn handle_console(_ctrl_type: DWORD) -> BOOL {
+    super::stop();
+    TRUE
+}
+
+pub fn set_stop_handler(_upgrade_on_hup: bool) -> Result<()> {
+    if unsafe { SetConsoleCtrlHandler(Some(handle_console), TRUE) } == 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+use std::time::{Duration, Instant};
+
+#[cfg(target_os = "linux")]
+use std::io::{Error, ErrorKind, Result};
+#[cfg(target_os = "linux")]
+use std::net::{TcpListener, UdpSocket};
+#[cfg(target_os = "linux")]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{FromRawFd, RawFd};
+
+// the first file descriptor passed by socket activation
+#[cfg(target_os = "linux")]
+pub const LISTEN_FDS_START: RawFd = 3;
+
+// tells systemd about the state of a Type=notify service, does nothing when not started by systemd
+#[cfg(target_os = "linux")]
+pub fn notify(state: &str) {
+    let path = match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+    if let Err(err) = send_notify(path.as_bytes(), state) {
+        log::error!("notify systemd with {} failed:{}", state, err);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send_notify(path: &[u8], state: &str) -> Result<()> {
+    let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    if path.is_empty() || path.len() >= addr.sun_path.len() {
+        return Err(Error::new(ErrorKind::InvalidInput, "invalid NOTIFY_SOCKET"));
+    }
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (i, c) in path.iter().enumerate() {
+        addr.sun_path[i] = *c as libc::c_char;
+    }
+    // a leading @ stands for the abstract namespace
+    if path[0] == b'@' {
+        addr.sun_path[0] = 0;
+    }
+    let addr_len = (std::mem::size_of::<libc::sa_family_t>() + path.len()) as libc::socklen_t;
+    unsafe {
+        let fd = libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let ret = libc::sendto(fd, state.as_ptr() as *const _, state.len(), 0,
+                               &addr as *const _ as *const libc::sockaddr, addr_len);
+        let err = Error::last_os_error();
+        libc::close(fd);
+        if ret < 0 {
+            Err(err)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn notify(_state: &str) {}
+
+// started as a systemd service, which sets INVOCATION_ID for each run of a unit
+#[cfg(target_os = "linux")]
+pub fn is_service() -> bool {
+    std::env::var_os("INVOCATION_ID").is_some() || std::env::var_os("NOTIFY_SOCKET").is_some()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_service() -> bool {
+    false
+}
+
+// sockets passed by systemd socket activation, only if they are meant for this process,
+// or by the process being upgraded, which can not know the pid in advance
+#[cfg(target_os = "linux")]
+fn listen_fds() -> Vec<RawFd> {
+    let pid = std::env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+    let name = if pid == Some(std::process::id()) {
+        "LISTEN_FDS"
+    } else {
+        crate::upgrade::LISTEN_FDS
+    };
+    let count = std::env::var(name).ok().and_then(|count| count.parse::<RawFd>().ok()).unwrap_or(0);
+    (LISTEN_FDS_START..LISTEN_FDS_START + count).collect()
+}
+
+#[cfg(target_os = "linux")]
+fn socket_type(fd: RawFd) -> Option<libc::c_int> {
+    let mut typ: libc::c_int = 0;
+    let mut len = std::mem::size_of_val(&typ) as libc::socklen_t;
+    let ret = unsafe { libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_TYPE, &mut typ as *mut _ as *mut _, &mut len) };
+    if ret != 0 {
+        None
+    } else {
+        Some(typ)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn activated_sockets(typ: libc::c_int) -> Vec<RawFd> {
+    let fds: Vec<RawFd> = listen_fds().into_iter().filter(|fd| socket_type(*fd) == Some(typ)).collect();
+    // children like hooks should not inherit the listeners
+    for fd in fds.iter() {
+        unsafe {
+            libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+    }
+    fds
+}
+
+// in the order they are passed, a socket unit may listen on several addresses
+#[cfg(target_os = "linux")]
+pub fn tcp_listeners() -> Vec<TcpListener> {
+    activated_sockets(libc::SOCK_STREAM).into_iter().map(|fd| unsafe { TcpListener::from_raw_fd(fd) }).collect()
+}
+
+#[cfg(target_os = "linux")]
+pub fn udp_sockets() -> Vec<UdpSocket> {
+    activated_sockets(libc::SOCK_DGRAM).into_iter().map(|fd| unsafe { UdpSocket::from_raw_fd(fd) }).collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn tcp_listeners() -> Vec<std::net::TcpListener> {
+    Vec::new()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn udp_sockets() -> Vec<std::net::UdpSocket> {
+    Vec::new()
+}
+
+// pings systemd at half of WatchdogSec, so that a stuck event loop gets the service restarted
+pub struct Watchdog {
+    interval: Option<Duration>,
+    last_ping_time: Instant,
+}
+
+impl Watchdog {
+    pub fn new() -> Watchdog {
+        let pid = std::env::var("WATCHDOG_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+        let usec = std::env::var("WATCHDOG_USEC").ok().and_then(|usec| usec.parse::<u64>().ok());
+        let interval = match (usec, pid) {
+            (Some(usec), Some(pid)) if usec > 0 && pid == std::process::id() => Some(Duration::from_micros(usec / 2)),
+            (Some(usec), None) if usec > 0 => Some(Duration::from_micros(usec / 2)),
+            _ => None,
+        };
+        if let Some(interval) = interval {
+            log::info!("systemd watchdog enabled, ping every {:?}", interval);
+        }
+        Watchdog {
+            interval,
+            last_ping_time: Instant::now(),
+        }
+    }
+
+    pub fn check(&mut self, now: Instant) {
+        if let Some(interval) = self.interval {
+            if now - self.last_ping_time >= interval {
+                notify("WATCHDOG=1");
+                self.last_ping_time = now;
+            }
+        }
+    }
+}
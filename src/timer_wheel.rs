@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+const SLOT_BITS: u32 = 6;
+const SLOTS: u64 = 1 << SLOT_BITS;
+const LEVELS: u32 = 4;
+
+// deadlines of many keys at the resolution of a tick, counted from the start on the monotonic clock, so changes of
+// the wall clock do not fire or hold them. each level has slots 64 times as long as the one below, a key is moved down
+// a level as its slot comes up and fires from the lowest one, so a tick touches the keys due in it and not all of them
+pub struct TimerWheel {
+    start_time: Instant,
+    tick_millis: u64,
+    // the last tick handled
+    current: u64,
+    levels: Vec<Vec<Vec<(usize, u64)>>>,
+    // the tick each key is due at, entries in the slots for other ticks are left over from earlier schedules
+    deadlines: HashMap<usize, u64>,
+}
+
+impl TimerWheel {
+    pub fn new(tick: Duration) -> TimerWheel {
+        TimerWheel {
+            start_time: Instant::now(),
+            tick_millis: std::cmp::max(tick.as_millis() as u64, 1),
+            current: 0,
+            levels: (0..LEVELS).map(|_| (0..SLOTS).map(|_| Vec::new()).collect()).collect(),
+            deadlines: HashMap::new(),
+        }
+    }
+
+    // replaces the deadline of the key, one in the past fires on the next tick
+    pub fn schedule(&mut self, key: usize, time: Instant) {
+        let millis = time.saturating_duration_since(self.start_time).as_millis() as u64;
+        let tick = std::cmp::max((millis + self.tick_millis - 1) / self.tick_millis, self.current + 1);
+        if self.deadlines.insert(key, tick) != Some(tick) {
+            self.place(key, tick);
+        }
+    }
+
+    // moves the deadline of the key earlier only, for keys whose deadlines only grow while they are scheduled
+    pub fn schedule_earlier(&mut self, key: usize, time: Instant) {
+        let due = match self.deadlines.get(&key) {
+            Some(tick) => self.start_time + Duration::from_millis(tick * self.tick_millis),
+            None => return self.schedule(key, time),
+        };
+        if time < due {
+            self.schedule(key, time);
+        }
+    }
+
+    pub fn cancel(&mut self, key: usize) {
+        self.deadlines.remove(&key);
+    }
+
+    // the keys due by now, they are not scheduled anymore
+    pub fn expire(&mut self, now: Instant) -> Vec<usize> {
+        let target = now.saturating_duration_since(self.start_time).as_millis() as u64 / self.tick_millis;
+        let mut expired = Vec::new();
+        while self.current < target {
+            self.current += 1;
+            // the slots of higher levels starting at this tick go down a level first
+            for level in (1..LEVELS).rev() {
+                if self.current & ((1 << (SLOT_BITS * level)) - 1) == 0 {
+                    let slot = self.slot(level, self.current);
+                    for (key, tick) in std::mem::take(&mut self.levels[level as usize][slot]) {
+                        if self.deadlines.get(&key) == Some(&tick) {
+                            self.place(key, tick);
+                        }
+                    }
+                }
+            }
+            let slot = self.slot(0, self.current);
+            for (key, tick) in std::mem::take(&mut self.levels[0][slot]) {
+                if tick == self.current && self.deadlines.get(&key) == Some(&tick) {
+                    self.deadlines.remove(&key);
+                    expired.push(key);
+                }
+            }
+        }
+        expired
+    }
+
+    fn slot(&self, level: u32, tick: u64) -> usize {
+        ((tick >> (SLOT_BITS * level)) & (SLOTS - 1)) as usize
+    }
+
+    fn place(&mut self, key: usize, tick: u64) {
+        let delta = tick - self.current;
+        let mut level = 0;
+        while level < LEVELS - 1 && delta >= 1 << (SLOT_BITS * (level + 1)) {
+            level += 1;
+        }
+        // beyond the last level a key waits in its farthest slot and is placed again from there
+        let farthest = self.current + (1 << (SLOT_BITS * LEVELS)) - 1;
+        let slot = self.slot(level, std::cmp::min(tick, farthest));
+        self.levels[level as usize][slot].push((key, tick));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(wheel: &TimerWheel, millis: u64) -> Instant {
+        wheel.start_time + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn cross_levels() {
+        let mut wheel = TimerWheel::new(Duration::from_millis(1));
+        wheel.schedule(1, at(&wheel, 63));
+        wheel.schedule(2, at(&wheel, 100));
+        wheel.schedule(3, at(&wheel, 64 * 64 + 5));
+        assert!(wheel.expire(at(&wheel, 30)).is_empty());
+        // from a tick off the boundaries, the key waits on level 1 and goes down at tick 128
+        wheel.schedule(4, at(&wheel, 130));
+        assert!(wheel.expire(at(&wheel, 62)).is_empty());
+        assert_eq!(wheel.expire(at(&wheel, 63)), vec![1]);
+        assert!(wheel.expire(at(&wheel, 99)).is_empty());
+        assert_eq!(wheel.expire(at(&wheel, 100)), vec![2]);
+        assert!(wheel.expire(at(&wheel, 129)).is_empty());
+        assert_eq!(wheel.expire(at(&wheel, 130)), vec![4]);
+        assert!(wheel.expire(at(&wheel, 64 * 64 + 4)).is_empty());
+        assert_eq!(wheel.expire(at(&wheel, 64 * 64 + 5)), vec![3]);
+        assert!(wheel.deadlines.is_empty());
+    }
+
+    #[test]
+    fn beyond_farthest_slot() {
+        let mut wheel = TimerWheel::new(Duration::from_millis(1));
+        let far = (1 << (SLOT_BITS * LEVELS)) + 10;
+        wheel.schedule(1, at(&wheel, far));
+        // parked in the farthest slot of the last level, then placed again once it comes up
+        let farthest = (1 << (SLOT_BITS * LEVELS)) - 1;
+        let slot = wheel.slot(LEVELS - 1, farthest);
+        assert_eq!(wheel.levels[LEVELS as usize - 1][slot], vec![(1, far)]);
+        assert!(wheel.expire(at(&wheel, far - 1)).is_empty());
+        assert_eq!(wheel.expire(at(&wheel, far)), vec![1]);
+    }
+
+    #[test]
+    fn reschedule_and_cancel() {
+        let mut wheel = TimerWheel::new(Duration::from_millis(1));
+        wheel.schedule(1, at(&wheel, 10));
+        wheel.schedule(1, at(&wheel, 20));
+        wheel.schedule(2, at(&wheel, 30));
+        wheel.cancel(2);
+        wheel.schedule(3, at(&wheel, 50));
+        wheel.schedule(3, at(&wheel, 45));
+        wheel.schedule(4, at(&wheel, 60));
+        wheel.schedule_earlier(4, at(&wheel, 70));
+        wheel.schedule_earlier(4, at(&wheel, 55));
+        assert!(wheel.expire(at(&wheel, 19)).is_empty());
+        assert_eq!(wheel.expire(at(&wheel, 20)), vec![1]);
+        assert!(wheel.expire(at(&wheel, 44)).is_empty());
+        assert_eq!(wheel.expire(at(&wheel, 45)), vec![3]);
+        assert_eq!(wheel.expire(at(&wheel, 55)), vec![4]);
+        // the entries left in the slots of the old deadlines do not fire
+        assert!(wheel.expire(at(&wheel, 100)).is_empty());
+        // a cancelled key scheduled again fires once
+        wheel.schedule(2, at(&wheel, 110));
+        assert_eq!(wheel.expire(at(&wheel, 200)), vec![2]);
+    }
+
+    #[test]
+    fn past_fires_on_next_tick() {
+        let mut wheel = TimerWheel::new(Duration::from_millis(1));
+        assert!(wheel.expire(at(&wheel, 10)).is_empty());
+        wheel.schedule(1, at(&wheel, 0));
+        wheel.schedule(2, at(&wheel, 10));
+        assert!(wheel.expire(at(&wheel, 10)).is_empty());
+        let mut expired = wheel.expire(at(&wheel, 11));
+        expired.sort_unstable();
+        assert_eq!(expired, vec![1, 2]);
+    }
+}
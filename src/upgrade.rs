@@ -0,0 +1,161 @@
+use std::time::Instant;
+
+use mio::net::{TcpListener, UdpSocket};
+
+use crate::config::Opts;
+
+#[cfg(target_os = "linux")]
+use std::io::{Error, ErrorKind, Result};
+#[cfg(target_os = "linux")]
+use std::time::Duration;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(target_os = "linux")]
+use std::os::unix::process::CommandExt;
+#[cfg(target_os = "linux")]
+use std::process::{Child, Command};
+
+#[cfg(target_os = "linux")]
+use crate::{daemon, systemd};
+
+// number of listeners handed over to the new binary, which are passed from fd 3 on like socket activation
+pub const LISTEN_FDS: &str = "TROJAN_LISTEN_FDS";
+
+// the new process is taken as working if it is still running after this
+#[cfg(target_os = "linux")]
+const STARTUP_DURATION: Duration = Duration::from_secs(3);
+
+// copies of the listeners are made above this, so that moving them into place does not clobber one another
+#[cfg(target_os = "linux")]
+const HIGH_FD: RawFd = 64;
+
+// a new binary started on SIGHUP or by the upgrade admin command, both processes accept on the same listeners until it is up
+pub struct Upgrade {
+    #[cfg(target_os = "linux")]
+    child: Child,
+    #[cfg(target_os = "linux")]
+    start_time: Instant,
+}
+
+#[cfg(target_os = "linux")]
+pub fn start(opts: &Opts, tcp_listeners: &[TcpListener], udp_listeners: &[UdpSocket]) -> Option<Upgrade> {
+    if opts.sandbox {
+        log::error!("binary upgrade is not possible in sandbox");
+        return None;
+    }
+    let mut fds: Vec<RawFd> = tcp_listeners.iter().map(|listener| listener.as_raw_fd()).collect();
+    fds.extend(udp_listeners.iter().map(|listener| listener.as_raw_fd()));
+    // the new process locks the pid file at startup
+    daemon::release_pid_file();
+    match spawn(fds.as_slice()) {
+        Ok(child) => {
+            log::warn!("new process {} started for upgrade", child.id());
+            Some(Upgrade {
+                child,
+                start_time: Instant::now(),
+            })
+        }
+        Err(err) => {
+            log::error!("start new process failed:{}", err);
+            daemon::reclaim_pid_file();
+            None
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn spawn(fds: &[RawFd]) -> Result<Child> {
+    let mut args = std::env::args_os();
+    // the path of the running binary may be replaced already, so argv[0] is started rather than current_exe
+    let program = args.next().ok_or_else(|| Error::new(ErrorKind::NotFound, "program path not found"))?;
+    let mut copies = Vec::new();
+    for fd in fds {
+        let copy = unsafe { libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, HIGH_FD) };
+        if copy < 0 {
+            let err = Error::last_os_error();
+            close_all(copies.as_slice());
+            return Err(err);
+        }
+        copies.push(copy);
+    }
+    let targets = copies.clone();
+    let mut command = Command::new(program);
+    command.args(args)
+        .env(LISTEN_FDS, fds.len().to_string())
+        .env_remove("LISTEN_PID")
+        .env_remove("LISTEN_FDS");
+    unsafe {
+        // dup2 clears close-on-exec of the targets only
+        command.pre_exec(move || {
+            for (i, fd) in targets.iter().enumerate() {
+                if libc::dup2(*fd, systemd::LISTEN_FDS_START + i as RawFd) < 0 {
+                    return Err(Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let child = command.spawn();
+    close_all(copies.as_slice());
+    child
+}
+
+#[cfg(target_os = "linux")]
+fn close_all(fds: &[RawFd]) {
+    for fd in fds {
+        unsafe {
+            libc::close(*fd);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn start(_opts: &Opts, _tcp_listeners: &[TcpListener], _udp_listeners: &[UdpSocket]) -> Option<Upgrade> {
+    log::error!("binary upgrade is only supported on linux");
+    None
+}
+
+impl Upgrade {
+    // true once the new process is up, then the old one hands over and drains
+    #[cfg(target_os = "linux")]
+    fn check(&mut self, now: Instant) -> Option<bool> {
+        match self.child.try_wait() {
+            Ok(Some(status)) => {
+                log::error!("new process exited with {}, upgrade failed", status);
+                daemon::reclaim_pid_file();
+                Some(false)
+            }
+            Ok(None) if now - self.start_time > STARTUP_DURATION => {
+                log::warn!("new process {} is running, stop the old one", self.child.id());
+                systemd::notify(format!("MAINPID={}", self.child.id()).as_str());
+                Some(true)
+            }
+            Ok(None) => None,
+            Err(err) => {
+                log::error!("check new process failed:{}", err);
+                daemon::reclaim_pid_file();
+                Some(false)
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn check(&mut self, _now: Instant) -> Option<bool> {
+        Some(false)
+    }
+}
+
+// started by an upgrade, the old process is detached already
+pub fn upgraded() -> bool {
+    std::env::var_os(LISTEN_FDS).is_some()
+}
+
+// returns true when the upgrade is done and the old process should stop
+pub fn check(upgrade: &mut Option<Upgrade>, now: Instant) -> bool {
+    let done = match upgrade.as_mut().and_then(|upgrade| upgrade.check(now)) {
+        Some(done) => done,
+        None => return false,
+    };
+    upgrade.take();
+    done
+}
@@ -0,0 +1,406 @@
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use webpki::{DNSName, DNSNameRef};
+
+use crate::config::{self, IpStrategy};
+
+const MAX_FAILURES: u32 = 3;
+const MIN_BACKOFF: u64 = 1000;
+const MAX_BACKOFF: u64 = 60000;
+// health check tokens are reserved for each server
+pub const MAX_UPSTREAMS: usize = 64;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Balance {
+    Failover,
+    RoundRobin,
+    LeastConnections,
+    Latency,
+}
+
+impl FromStr for Balance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "failover" => Ok(Balance::Failover),
+            "round-robin" => Ok(Balance::RoundRobin),
+            "least-connections" => Ok(Balance::LeastConnections),
+            "latency" => Ok(Balance::Latency),
+            _ => Err(format!("invalid balance strategy:{}", s)),
+        }
+    }
+}
+
+const TROJAN_SCHEME: &str = "trojan://";
+
+// a port or a range of ports like 20000-21000 to hop among
+fn parse_ports(s: &str) -> Option<(u16, u16)> {
+    match s.find('-') {
+        Some(pos) => {
+            let first = s[..pos].parse::<u16>().ok()?;
+            let last = s[pos + 1..].parse::<u16>().ok()?;
+            if first <= last {
+                Some((first, last))
+            } else {
+                None
+            }
+        }
+        None => s.parse::<u16>().ok().map(|port| (port, port)),
+    }
+}
+
+fn random_seed() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.subsec_nanos() as u64).unwrap_or(0)
+}
+
+fn percent_decode(s: &str) -> Result<String, String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3).ok_or_else(|| format!("invalid percent encoding:{}", s))?;
+            decoded.push(u8::from_str_radix(hex, 16).map_err(|_| format!("invalid percent encoding:{}", s))?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| format!("invalid percent encoding:{}", s))
+}
+
+pub struct Upstream {
+    pub hostname: String,
+    pub port: u16,
+    first_port: u16,
+    last_port: u16,
+    last_hop_time: Instant,
+    pub label: Option<String>,
+    sni: Option<String>,
+    password: Option<String>,
+    sha_pass: String,
+    dns_name: Option<DNSName>,
+    addrs: Vec<SocketAddr>,
+    plugin_addr: Option<SocketAddr>,
+    proxy_addr: Option<SocketAddr>,
+    index: usize,
+    failures: u32,
+    refresh: bool,
+    last_resolve_time: Instant,
+    pub connections: usize,
+    pub latency: Option<Duration>,
+    pub subscribed: bool,
+    removed: bool,
+    retry_time: Option<Instant>,
+}
+
+impl FromStr for Upstream {
+    type Err = String;
+
+    // [password@]hostname[:port] or trojan://password@hostname[:port][?sni=name][#label], the port may be a range like 20000-21000
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with(TROJAN_SCHEME) {
+            return Upstream::from_url(s);
+        }
+        let (password, host) = if let Some(pos) = s.rfind('@') {
+            (Some(s[..pos].to_string()), &s[pos + 1..])
+        } else {
+            (None, s)
+        };
+        let (hostname, ports) = if let Some(pos) = host.rfind(':') {
+            let ports = parse_ports(&host[pos + 1..]).ok_or_else(|| format!("invalid upstream port:{}", s))?;
+            (&host[..pos], ports)
+        } else {
+            (host, (443, 443))
+        };
+        if hostname.is_empty() {
+            return Err(format!("invalid upstream hostname:{}", s));
+        }
+        Ok(Upstream::new(hostname.to_string(), ports, password))
+    }
+}
+
+impl Upstream {
+    // a random port of the range is used first
+    pub fn new(hostname: String, ports: (u16, u16), password: Option<String>) -> Upstream {
+        let (first_port, last_port) = ports;
+        let port = first_port + (random_seed() % ((last_port - first_port) as u64 + 1)) as u16;
+        Upstream {
+            hostname,
+            port,
+            first_port,
+            last_port,
+            last_hop_time: Instant::now(),
+            label: None,
+            sni: None,
+            password,
+            sha_pass: String::new(),
+            dns_name: None,
+            addrs: Vec::new(),
+            plugin_addr: None,
+            proxy_addr: None,
+            index: 0,
+            failures: 0,
+            refresh: true,
+            last_resolve_time: Instant::now(),
+            connections: 0,
+            latency: None,
+            subscribed: false,
+            removed: false,
+            retry_time: None,
+        }
+    }
+
+    fn from_url(s: &str) -> Result<Upstream, String> {
+        let mut url = &s[TROJAN_SCHEME.len()..];
+        let mut label = None;
+        if let Some(pos) = url.find('#') {
+            label = Some(percent_decode(&url[pos + 1..])?);
+            url = &url[..pos];
+        }
+        let mut sni = None;
+        if let Some(pos) = url.find('?') {
+            for param in url[pos + 1..].split('&') {
+                let mut kv = param.splitn(2, '=');
+                let key = kv.next().unwrap();
+                let value = percent_decode(kv.next().unwrap_or(""))?;
+                match key {
+                    "sni" | "peer" if !value.is_empty() => sni = Some(value),
+                    "type" if value != "tcp" => return Err(format!("unsupported transport {} in {}", value, s)),
+                    _ => {}
+                }
+            }
+            url = &url[..pos];
+        }
+        let url = url.trim_end_matches('/');
+        let pos = url.rfind('@').ok_or_else(|| format!("password missing in {}", s))?;
+        let password = percent_decode(&url[..pos])?;
+        let host = &url[pos + 1..];
+        let (hostname, port) = if host.starts_with('[') {
+            // ipv6 literal
+            let end = host.find(']').ok_or_else(|| format!("invalid upstream hostname:{}", s))?;
+            (&host[1..end], &host[end + 1..])
+        } else if let Some(pos) = host.rfind(':') {
+            (&host[..pos], &host[pos..])
+        } else {
+            (host, "")
+        };
+        let ports = if port.is_empty() {
+            (443, 443)
+        } else if let Some(port) = port.strip_prefix(':') {
+            parse_ports(port).ok_or_else(|| format!("invalid upstream port:{}", s))?
+        } else {
+            return Err(format!("invalid upstream hostname:{}", s));
+        };
+        if hostname.is_empty() || password.is_empty() {
+            return Err(format!("invalid trojan url:{}", s));
+        }
+        let mut upstream = Upstream::new(hostname.to_string(), ports, Some(password));
+        upstream.sni = sni;
+        upstream.label = label;
+        Ok(upstream)
+    }
+
+    pub fn name(&self) -> &str {
+        self.label.as_ref().unwrap_or(&self.hostname).as_str()
+    }
+
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.hostname.parse().ok()
+    }
+
+    pub fn setup(&mut self, default_password: &str) -> Result<(), String> {
+        // ip servers must come with a sni to verify the certificate against
+        let server_name = self.sni.as_ref().unwrap_or(&self.hostname);
+        let dns_name = DNSNameRef::try_from_ascii(server_name.as_bytes())
+            .map_err(|_| format!("invalid upstream server name:{}", server_name))?;
+        self.dns_name.replace(dns_name.to_owned());
+        self.sha_pass = config::password_digest(self.password.as_ref().map_or(default_password, |password| password.as_str()))?;
+        Ok(())
+    }
+
+    pub fn dns_name(&self) -> DNSNameRef<'_> {
+        self.dns_name.as_ref().unwrap().as_ref()
+    }
+
+    pub fn pass(&self) -> &str {
+        self.sha_pass.as_str()
+    }
+
+    pub fn is_available(&self) -> bool {
+        !self.removed && !self.addrs.is_empty()
+    }
+
+    pub fn same_server(&self, other: &Upstream) -> bool {
+        self.hostname == other.hostname && self.first_port == other.first_port && self.last_port == other.last_port && self.password == other.password && self.sni == other.sni
+    }
+
+    // removed servers keep their slot, connections still refer to them by index
+    pub fn set_removed(&mut self, removed: bool) {
+        self.removed = removed;
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.is_available() && self.failures < MAX_FAILURES
+    }
+
+    pub fn update_latency(&mut self, latency: Duration) {
+        // smooth the samples like tcp srtt does
+        let latency = match self.latency {
+            Some(old) => (old * 7 + latency) / 8,
+            None => latency,
+        };
+        self.latency.replace(latency);
+    }
+
+    pub fn addr(&self) -> Option<SocketAddr> {
+        self.addrs.get(self.index).cloned()
+    }
+
+    // the current address goes first, the others follow in the resolved order
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.addrs[self.index..].iter().chain(self.addrs[..self.index].iter()).cloned().collect()
+    }
+
+    // connections go to the local port of the plugin, which resolves the server by itself
+    pub fn set_plugin_addr(&mut self, addr: SocketAddr) {
+        self.plugin_addr.replace(addr);
+        self.addrs = vec![addr];
+        self.index = 0;
+    }
+
+    pub fn plugin_addr(&self) -> Option<SocketAddr> {
+        self.plugin_addr
+    }
+
+    // connections go to the upstream proxy, which connects to the server by its hostname
+    pub fn set_proxy_addr(&mut self, addr: SocketAddr) {
+        self.proxy_addr.replace(addr);
+        self.addrs = vec![addr];
+        self.index = 0;
+    }
+
+    pub fn proxy_addr(&self) -> Option<SocketAddr> {
+        self.proxy_addr
+    }
+
+    pub fn update_addrs(&mut self, mut addrs: Vec<IpAddr>, strategy: IpStrategy) {
+        self.last_resolve_time = Instant::now();
+        strategy.sort(&mut addrs);
+        self.refresh = false;
+        if addrs.is_empty() {
+            log::warn!("no address found for trojan server {}, keep using the old ones", self.hostname);
+            return;
+        }
+        let current = self.addr();
+        self.addrs = addrs.into_iter().map(|addr| SocketAddr::new(addr, self.port)).collect();
+        self.index = current.and_then(|current| self.addrs.iter().position(|addr| *addr == current)).unwrap_or(0);
+        log::info!("server {} addresses are {:?}, using {}", self.hostname, self.addrs, self.addrs[self.index]);
+    }
+
+    // the addresses and health saved before a restart, the addresses are resolved again soon
+    pub fn restore(&mut self, addrs: Vec<IpAddr>, strategy: IpStrategy, latency: Option<Duration>, failures: u32) {
+        if !addrs.is_empty() && self.addrs.is_empty() && self.ip().is_none() && self.plugin_addr.is_none() && self.proxy_addr.is_none() {
+            self.update_addrs(addrs, strategy);
+            self.refresh = true;
+        }
+        if latency.is_some() {
+            self.latency = latency;
+        }
+        self.failures = failures;
+        if self.failures >= MAX_FAILURES {
+            self.schedule_retry();
+        }
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn needs_resolve(&self, now: Instant, duration: Duration) -> bool {
+        !self.removed && self.plugin_addr.is_none() && self.proxy_addr.is_none() && self.ip().is_none() && (self.refresh || now - self.last_resolve_time > duration)
+    }
+
+    pub fn start_resolve(&mut self) {
+        self.refresh = false;
+        self.last_resolve_time = Instant::now();
+    }
+
+    // servers given with a port range move to another port of it once in a while, only new connections use it
+    pub fn hop(&mut self, now: Instant, duration: Duration) {
+        if self.first_port != self.last_port && now - self.last_hop_time >= duration {
+            self.hop_port(now);
+        }
+    }
+
+    fn hop_port(&mut self, now: Instant) {
+        // the plugin was started with the port it knows of
+        if self.plugin_addr.is_some() {
+            return;
+        }
+        self.last_hop_time = now;
+        let count = (self.last_port - self.first_port) as u64;
+        // any port of the range but the current one
+        let offset = (self.port - self.first_port) as u64 + 1 + random_seed() % count;
+        self.port = self.first_port + (offset % (count + 1)) as u16;
+        // the upstream proxy is asked for the new port instead
+        if self.proxy_addr.is_none() {
+            for addr in self.addrs.iter_mut() {
+                addr.set_port(self.port);
+            }
+        }
+        log::info!("trojan server {} hops to port {}", self.name(), self.port);
+    }
+
+    // returns true if the server keeps failing and should be replaced
+    pub fn failed(&mut self) -> bool {
+        self.refresh = true;
+        self.failures += 1;
+        if self.first_port != self.last_port {
+            // the port may be the one blocked
+            self.hop_port(Instant::now());
+        }
+        if self.addrs.len() > 1 {
+            self.index = (self.index + 1) % self.addrs.len();
+            log::warn!("trojan server {} failed, switch to {}", self.hostname, self.addrs[self.index]);
+        }
+        if self.failures >= MAX_FAILURES {
+            self.schedule_retry();
+        }
+        self.failures >= MAX_FAILURES
+    }
+
+    // exponential backoff with jitter, so that clients do not retry in lockstep
+    fn schedule_retry(&mut self) {
+        let backoff = (MIN_BACKOFF << (self.failures - MAX_FAILURES).min(6)).min(MAX_BACKOFF);
+        let delay = backoff / 2 + random_seed() % (backoff / 2 + 1);
+        self.retry_time.replace(Instant::now() + Duration::from_millis(delay));
+        log::warn!("trojan server {} is unreachable, retry in {}ms", self.name(), delay);
+    }
+
+    pub fn is_backing_off(&self, now: Instant) -> bool {
+        self.retry_time.map_or(false, |time| now < time)
+    }
+
+    pub fn needs_retry(&self, now: Instant) -> bool {
+        self.retry_time.map_or(false, |time| now >= time)
+    }
+
+    // the failures were those of the old network, and the addresses may resolve to others on the new one
+    pub fn network_changed(&mut self) {
+        self.failures = 0;
+        self.retry_time = None;
+        self.refresh = true;
+    }
+
+    pub fn succeeded(&mut self) {
+        if self.retry_time.take().is_some() {
+            log::warn!("trojan server {} is reachable again", self.name());
+        }
+        self.failures = 0;
+    }
+}
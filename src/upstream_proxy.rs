@@ -0,0 +1,230 @@
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+
+// the longest reply of a proxy to a connect request that is waited for
+const MAX_REPLY_SIZE: usize = 8192;
+const BASE64_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum ProxyKind {
+    Socks5,
+    Http,
+}
+
+// a socks5 or http proxy the connections to trojan servers go through, for networks without direct outbound access
+#[derive(Clone)]
+pub struct UpstreamProxy {
+    pub kind: ProxyKind,
+    pub addr: SocketAddr,
+    credentials: Option<(String, String)>,
+}
+
+impl UpstreamProxy {
+    // socks5://[user:password@]host:port or http://[user:password@]host:port, the host is resolved once here
+    pub fn parse(url: &str) -> std::result::Result<UpstreamProxy, String> {
+        let (kind, rest) = if let Some(rest) = url.strip_prefix("socks5://") {
+            (ProxyKind::Socks5, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (ProxyKind::Http, rest)
+        } else {
+            return Err(format!("invalid upstream proxy {}:only socks5:// and http:// are supported", url));
+        };
+        let rest = rest.trim_end_matches('/');
+        let (credentials, host) = match rest.rfind('@') {
+            Some(pos) => {
+                let userinfo = &rest[..pos];
+                let colon = userinfo.find(':').ok_or_else(|| format!("invalid upstream proxy {}:user without password", url))?;
+                let (user, password) = (&userinfo[..colon], &userinfo[colon + 1..]);
+                if kind == ProxyKind::Socks5 && (user.is_empty() || user.len() > 255 || password.len() > 255) {
+                    return Err(format!("invalid upstream proxy {}:socks5 user and password take 1 to 255 bytes", url));
+                }
+                (Some((user.to_string(), password.to_string())), &rest[pos + 1..])
+            }
+            None => (None, rest),
+        };
+        let addr = host.to_socket_addrs().ok().and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| format!("invalid upstream proxy {}:can not resolve {}", url, host))?;
+        Ok(UpstreamProxy {
+            kind,
+            addr,
+            credentials,
+        })
+    }
+
+    pub fn tunnel(&self, host: &str, port: u16) -> Tunnel {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let request = match self.kind {
+            ProxyKind::Socks5 => socks5_request(host, port, self.credentials.as_ref()),
+            ProxyKind::Http => http_request(host, port, self.credentials.as_ref()),
+        };
+        Tunnel {
+            kind: self.kind,
+            auth: self.credentials.is_some(),
+            request,
+            written: 0,
+            reply: Vec::new(),
+        }
+    }
+}
+
+// the connect request to a proxy and its reply on a connection to it, sent before the tls handshake with the server
+pub struct Tunnel {
+    kind: ProxyKind,
+    auth: bool,
+    request: Vec<u8>,
+    written: usize,
+    reply: Vec<u8>,
+}
+
+impl Tunnel {
+    // returns true once the proxy has connected to the server, the whole request is sent at once as the trojan server
+    // sends nothing before the client hello, so all read is the reply
+    pub fn handshake<T: Read + Write>(&mut self, stream: &mut T) -> Result<bool> {
+        while self.written < self.request.len() {
+            match stream.write(&self.request[self.written..]) {
+                Ok(0) => return Err(Error::from(ErrorKind::WriteZero)),
+                Ok(size) => self.written += size,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(err) => return Err(err),
+            }
+        }
+        let mut buffer = [0u8; 1024];
+        loop {
+            if let Some(size) = self.reply_size()? {
+                if self.reply.len() > size {
+                    return Err(Error::new(ErrorKind::InvalidData, "unexpected data after the proxy reply"));
+                }
+                return Ok(true);
+            }
+            if self.reply.len() > MAX_REPLY_SIZE {
+                return Err(Error::new(ErrorKind::InvalidData, "proxy reply is too long"));
+            }
+            match stream.read(&mut buffer) {
+                Ok(0) => return Err(Error::from(ErrorKind::UnexpectedEof)),
+                Ok(size) => self.reply.extend_from_slice(&buffer[..size]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    // the size of the whole reply once enough of it is read
+    fn reply_size(&self) -> Result<Option<usize>> {
+        match self.kind {
+            ProxyKind::Socks5 => socks5_reply_size(self.reply.as_slice(), self.auth),
+            ProxyKind::Http => http_reply_size(self.reply.as_slice()),
+        }
+    }
+}
+
+// the greeting, the authentication and the connect request are sent together, only the method the credentials need
+// is offered so that the proxy can not choose another one
+fn socks5_request(host: &str, port: u16, credentials: Option<&(String, String)>) -> Vec<u8> {
+    let mut request = match credentials {
+        Some((user, password)) => {
+            let mut request = vec![5, 1, 2, 1, user.len() as u8];
+            request.extend_from_slice(user.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            request
+        }
+        None => vec![5, 1, 0],
+    };
+    request.extend_from_slice(&[5, 1, 0]);
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let host = &host.as_bytes()[..std::cmp::min(host.len(), 255)];
+            request.push(3);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host);
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    request
+}
+
+fn socks5_reply_size(reply: &[u8], auth: bool) -> Result<Option<usize>> {
+    let invalid = |message: String| Err(Error::new(ErrorKind::InvalidData, message));
+    if reply.len() < 2 {
+        return Ok(None);
+    }
+    if reply[0] != 5 {
+        return invalid("proxy is not a socks5 proxy".to_string());
+    }
+    match (reply[1], auth) {
+        (0, false) | (2, true) => {}
+        (0xff, _) => return invalid("socks5 proxy accepts none of the authentication methods offered".to_string()),
+        (method, _) => return invalid(format!("socks5 proxy chose unexpected authentication method {}", method)),
+    }
+    let mut offset = 2;
+    if auth {
+        if reply.len() < 4 {
+            return Ok(None);
+        }
+        if reply[3] != 0 {
+            return invalid("socks5 proxy rejected the user and password".to_string());
+        }
+        offset = 4;
+    }
+    if reply.len() < offset + 5 {
+        return Ok(None);
+    }
+    if reply[offset + 1] != 0 {
+        return invalid(format!("socks5 proxy failed to connect with reply {}", reply[offset + 1]));
+    }
+    let size = match reply[offset + 3] {
+        1 => offset + 10,
+        4 => offset + 22,
+        3 => offset + 7 + reply[offset + 4] as usize,
+        kind => return invalid(format!("socks5 proxy replied with invalid address type {}", kind)),
+    };
+    Ok(if reply.len() >= size { Some(size) } else { None })
+}
+
+fn http_request(host: &str, port: u16, credentials: Option<&(String, String)>) -> Vec<u8> {
+    let authority = if host.contains(':') { format!("[{}]:{}", host, port) } else { format!("{}:{}", host, port) };
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", authority, authority);
+    if let Some((user, password)) = credentials {
+        request.push_str("Proxy-Authorization: Basic ");
+        request.push_str(base64_encode(format!("{}:{}", user, password).as_bytes()).as_str());
+        request.push_str("\r\n");
+    }
+    request.push_str("\r\n");
+    request.into_bytes()
+}
+
+fn http_reply_size(reply: &[u8]) -> Result<Option<usize>> {
+    let end = match reply.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(pos) => pos + 4,
+        None => return Ok(None),
+    };
+    let header = String::from_utf8_lossy(&reply[..end]);
+    let status = header.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(Error::new(ErrorKind::InvalidData, format!("http proxy answered {}", status)));
+    }
+    Ok(Some(end))
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| bits | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_CHARS[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
@@ -0,0 +1,191 @@
+use std::net::{IpAddr, SocketAddr};
+use std::ptr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::time::{Duration, Instant};
+
+use crate::access_log::Entry;
+use crate::config::IpStrategy;
+use crate::error::{Error, Result};
+use crate::{http_client, log_format};
+
+const EVENTS: [&str; 8] = ["session_open", "session_close", "auth_failure", "auth_denied", "replay", "malformed_handshake", "ban", "unban"];
+// events waiting for the webhook, more are dropped rather than piling up while it is down
+const QUEUE_SIZE: usize = 10000;
+const MAX_BATCH: usize = 500;
+const RETRIES: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+const TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+// set once at startup, null without --webhook
+static WEBHOOK: AtomicPtr<Webhook> = AtomicPtr::new(ptr::null_mut());
+
+// events are queued by the event loop and posted as json arrays by a thread of their own, so that a slow or unreachable
+// webhook never holds up the relay
+struct Webhook {
+    sender: Mutex<SyncSender<String>>,
+    // the events posted, all of them if empty
+    events: Vec<String>,
+    // events dropped with a full queue, logged with the next post
+    dropped: AtomicUsize,
+}
+
+pub fn check(url: &str, events: &[String]) -> Result<()> {
+    if !http_client::is_url(url) {
+        return Err(Error::Config(format!("invalid --webhook {}, an http or https url is expected", url)));
+    }
+    if let Some(event) = events.iter().find(|event| !EVENTS.contains(&event.as_str())) {
+        return Err(Error::Config(format!("invalid --webhook-event {}, one of {}", event, EVENTS.join(", "))));
+    }
+    Ok(())
+}
+
+pub fn setup(url: &str, interval: u64, events: &[String], marker: u8, strategy: IpStrategy) -> Result<()> {
+    check(url, events)?;
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+    let webhook = Box::new(Webhook {
+        sender: Mutex::new(sender),
+        events: events.to_vec(),
+        dropped: AtomicUsize::new(0),
+    });
+    WEBHOOK.store(Box::into_raw(webhook), Ordering::Release);
+    let url = url.to_string();
+    let interval = Duration::new(interval, 0);
+    std::thread::spawn(move || post_batches(receiver, url.as_str(), interval, marker, strategy));
+    // the url is not logged, those of chat services hold their token
+    log::warn!("posting events to the webhook every {}s", interval.as_secs());
+    Ok(())
+}
+
+fn webhook(event: &str) -> Option<&'static Webhook> {
+    let webhook = unsafe { WEBHOOK.load(Ordering::Acquire).as_ref() }?;
+    if webhook.events.is_empty() || webhook.events.iter().any(|name| name == event) {
+        Some(webhook)
+    } else {
+        None
+    }
+}
+
+fn queue(webhook: &Webhook, event: String) {
+    let sent = webhook.sender.lock().map_or(false, |sender| sender.try_send(event).is_ok());
+    if !sent {
+        webhook.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn start(event: &str) -> String {
+    let mut json = String::with_capacity(256);
+    json.push('{');
+    log_format::add_str(&mut json, "event", Some(event));
+    json.push(',');
+    log_format::add_str(&mut json, "timestamp", Some(chrono::Local::now().to_rfc3339().as_str()));
+    json
+}
+
+fn add_addr(json: &mut String, name: &str, addr: Option<SocketAddr>) {
+    let addr = addr.map(|addr| addr.to_string());
+    json.push(',');
+    log_format::add_str(json, name, addr.as_deref());
+}
+
+// a connection authenticated and on its way to the target
+pub fn session_open(conn_id: usize, remote_addr: Option<SocketAddr>, user: Option<&str>, target: &dyn std::fmt::Display, protocol: &str) {
+    let webhook = match webhook("session_open") {
+        Some(webhook) => webhook,
+        None => return,
+    };
+    let mut json = start("session_open");
+    json.push(',');
+    log_format::add_num(&mut json, "conn_id", Some(conn_id));
+    add_addr(&mut json, "remote_addr", remote_addr);
+    json.push(',');
+    log_format::add_str(&mut json, "user", user);
+    json.push(',');
+    log_format::add_str(&mut json, "target", Some(target.to_string().as_str()));
+    json.push(',');
+    log_format::add_str(&mut json, "protocol", Some(protocol));
+    json.push('}');
+    queue(webhook, json);
+}
+
+pub fn session_close(entry: &Entry) {
+    let webhook = match webhook("session_close") {
+        Some(webhook) => webhook,
+        None => return,
+    };
+    let mut json = start("session_close");
+    json.push(',');
+    log_format::add_num(&mut json, "conn_id", Some(entry.conn_id));
+    add_addr(&mut json, "remote_addr", entry.remote_addr);
+    json.push(',');
+    log_format::add_str(&mut json, "user", entry.user);
+    json.push(',');
+    log_format::add_str(&mut json, "target", Some(entry.target.to_string().as_str()));
+    json.push(',');
+    log_format::add_str(&mut json, "protocol", Some(entry.protocol));
+    json.push(',');
+    log_format::add_num(&mut json, "bytes_sent", Some(entry.bytes_sent));
+    json.push(',');
+    log_format::add_num(&mut json, "bytes_received", Some(entry.bytes_received));
+    json.push(',');
+    log_format::add_num(&mut json, "duration", Some(format!("{:.3}", entry.start_time.elapsed().as_secs_f64())));
+    json.push('}');
+    queue(webhook, json);
+}
+
+// the events of the security log, with its key=value details as they are
+pub fn security_event(event: &str, ip: IpAddr, details: &str) {
+    let webhook = match webhook(event) {
+        Some(webhook) => webhook,
+        None => return,
+    };
+    let mut json = start(event);
+    json.push(',');
+    log_format::add_str(&mut json, "ip", Some(ip.to_string().as_str()));
+    json.push(',');
+    log_format::add_str(&mut json, "details", Some(details));
+    json.push('}');
+    queue(webhook, json);
+}
+
+// takes the events of an interval, up to a batch, and posts them, trying again a few times with a growing delay before
+// dropping them
+fn post_batches(receiver: Receiver<String>, url: &str, interval: Duration, marker: u8, strategy: IpStrategy) {
+    while let Ok(event) = receiver.recv() {
+        let mut batch = vec![event];
+        let deadline = Instant::now() + interval;
+        while batch.len() < MAX_BATCH {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            match receiver.recv_timeout(deadline - now) {
+                Ok(event) => batch.push(event),
+                Err(_) => break,
+            }
+        }
+        let dropped = unsafe { WEBHOOK.load(Ordering::Acquire).as_ref() }.map_or(0, |webhook| webhook.dropped.swap(0, Ordering::Relaxed));
+        if dropped > 0 {
+            log::error!("{} webhook events dropped with a full queue", dropped);
+        }
+        let body = format!("[{}]", batch.join(","));
+        for attempt in 0..=RETRIES {
+            if attempt > 0 {
+                std::thread::sleep(RETRY_DELAY * (1 << (attempt - 1)));
+            }
+            match http_client::send(url, "POST", Some(("application/json", body.as_bytes())), marker, strategy, TIMEOUT, MAX_BODY_SIZE) {
+                Ok((status, _)) if (200..300).contains(&status) => {
+                    log::debug!("posted {} events to the webhook", batch.len());
+                    break;
+                }
+                Ok((status, _)) => log::warn!("webhook answered {} events with status {}", batch.len(), status),
+                Err(err) => log::warn!("post {} events to the webhook failed:{}", batch.len(), err),
+            }
+            if attempt == RETRIES {
+                log::error!("{} webhook events dropped after {} retries", batch.len(), RETRIES);
+            }
+        }
+    }
+}